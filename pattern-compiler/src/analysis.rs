@@ -0,0 +1,124 @@
+//! Analysis of a compiled `PatternCfg`.
+//!
+//! Since every clause has its own `Leaf` node and every value not matched
+//! by any clause ends up in the `Fail` node, we can find clauses that can
+//! never match, and describe the values that will not be matched.
+
+use ::std::collections::HashMap;
+
+use ::petgraph::Direction;
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::visit::EdgeRef;
+
+use super::pattern::PatternProvider;
+use super::cfg::{ PatternCfg, CfgNodeKind };
+
+/// What is known about a single variable on a path through the CFG.
+#[derive(Debug, Derivative)]
+#[derivative(Clone(bound=""))]
+pub enum VariableConstraint<P> where P: PatternProvider {
+    /// The value is of the given kind, its children are the given
+    /// variables.
+    Is(P::PatternNodeKind, Vec<P::CfgVariable>),
+    /// The value is of none of the given kinds.
+    IsNot(Vec<P::PatternNodeKind>),
+}
+
+/// A path from the entry of the CFG to the `Fail` node. Every value
+/// satisfying all the constraints will fail to match, given that the
+/// guards on the path fail.
+///
+/// Variables without a constraint can take any value.
+#[derive(Debug, Derivative)]
+#[derivative(Clone(bound=""))]
+pub struct FailPath<P> where P: PatternProvider {
+    pub roots: Vec<P::CfgVariable>,
+    pub constraints: HashMap<P::CfgVariable, VariableConstraint<P>>,
+}
+
+/// Returns the clauses whose leaf can never be reached, in clause order.
+pub fn unreachable_clauses<P>(cfg: &PatternCfg<P>) -> Vec<usize>
+    where P: PatternProvider
+{
+    let mut unreachable: Vec<usize> = cfg.leaves.iter()
+        .filter(|&(_, leaf)| {
+            cfg.graph.neighbors_directed(*leaf, Direction::Incoming)
+                .next().is_none()
+        })
+        .map(|(clause, _)| *clause)
+        .collect();
+    unreachable.sort();
+    unreachable
+}
+
+/// Returns up to `limit` distinct paths that end in the `Fail` node.
+/// An empty result means the match is exhaustive.
+pub fn fail_paths<P>(cfg: &PatternCfg<P>, limit: usize) -> Vec<FailPath<P>>
+    where P: PatternProvider
+{
+    let mut paths = Vec::new();
+    let mut constraints = HashMap::new();
+
+    let roots = match cfg.graph.edges_directed(cfg.entry, Direction::Outgoing).next() {
+        Some(edge) => edge.weight().variable_binds.clone(),
+        None => return paths,
+    };
+
+    fail_paths_inner(cfg, cfg.entry, &roots, &mut constraints,
+                     &mut paths, limit);
+    paths
+}
+
+fn fail_paths_inner<P>(cfg: &PatternCfg<P>, node: NodeIndex,
+                       roots: &[P::CfgVariable],
+                       constraints: &mut HashMap<P::CfgVariable, VariableConstraint<P>>,
+                       paths: &mut Vec<FailPath<P>>, limit: usize)
+    where P: PatternProvider
+{
+    if paths.len() >= limit {
+        return;
+    }
+
+    match cfg.graph[node] {
+        CfgNodeKind::Fail => {
+            paths.push(FailPath {
+                roots: roots.to_vec(),
+                constraints: constraints.clone(),
+            });
+        },
+        CfgNodeKind::Leaf(_) => (),
+        // A guard can both succeed and fail for any value
+        CfgNodeKind::Root | CfgNodeKind::Guard(_) => {
+            for edge in cfg.graph.edges_directed(node, Direction::Outgoing) {
+                fail_paths_inner(cfg, edge.target(), roots, constraints,
+                                 paths, limit);
+            }
+        },
+        CfgNodeKind::Match(var) => {
+            let edges: Vec<_> = cfg.graph.edges_directed(node, Direction::Outgoing)
+                .collect();
+            let specialized_kinds: Vec<_> = edges.iter()
+                .map(|e| e.weight().kind)
+                .filter(|k| *k != P::WILDCARD)
+                .collect();
+
+            for edge in edges.iter() {
+                let weight = edge.weight();
+                let constraint = if weight.kind == P::WILDCARD {
+                    VariableConstraint::IsNot(specialized_kinds.clone())
+                } else {
+                    VariableConstraint::Is(weight.kind,
+                                           weight.variable_binds.clone())
+                };
+
+                let prev = constraints.insert(var, constraint);
+                fail_paths_inner(cfg, edge.target(), roots, constraints,
+                                 paths, limit);
+                match prev {
+                    Some(prev) => constraints.insert(var, prev),
+                    None => constraints.remove(&var),
+                };
+            }
+        },
+    }
+}
@@ -1,6 +1,6 @@
 use ::std::collections::HashMap;
 
-use ::petgraph::Graph;
+use ::petgraph::{ Graph, Direction };
 use ::petgraph::graph::NodeIndex;
 
 mod generate_dot;
@@ -58,6 +58,26 @@ impl<P> PatternCfg<P> where P: PatternProvider {
         self.graph.add_edge(parent, child, edge);
     }
 
+    pub fn add_guard(&mut self, clause: usize) -> CfgNodeIndex {
+        self.graph.add_node(CfgNodeKind::Guard(clause))
+    }
+
+    /// Returns the targets of the success and failure edges of a guard
+    /// node, in that order.
+    pub fn guard_targets(&self, node: CfgNodeIndex) -> (CfgNodeIndex, CfgNodeIndex) {
+        let clause = match self.graph[node] {
+            CfgNodeKind::Guard(clause) => clause,
+            ref kind => panic!("not a guard node: {:?}", kind),
+        };
+        let ok = self.leaves[&clause];
+
+        let targets: Vec<_> = self.graph.neighbors_directed(node, Direction::Outgoing)
+            .collect();
+        assert!(targets.len() == 2);
+        let fail = *targets.iter().find(|t| **t != ok).unwrap();
+        (ok, fail)
+    }
+
     pub fn add_child(&mut self, parent: CfgNodeIndex, typ: CfgEdge<P>,
                      var: P::CfgVariable) -> CfgNodeIndex {
         let child = self.graph.add_node(CfgNodeKind::Match(var));
@@ -84,6 +104,10 @@ impl<P> ::std::fmt::Debug for CfgEdge<P> where P: PatternProvider {
 pub enum CfgNodeKind<CVT> {
     Root,
     Match(CVT),
+    /// Runs the guard of the given clause. Has two outgoing edges, one to
+    /// the leaf of the clause which is taken when the guard succeeds, and
+    /// one to the rest of the match which is taken when it fails.
+    Guard(usize),
     Fail,
     Leaf(usize),
 }
@@ -0,0 +1,219 @@
+//! Column selection heuristics for the match compiler.
+//!
+//! See section 8 of the paper referenced in `lib.rs` for descriptions of
+//! the individual heuristics. All heuristics only ever consider columns
+//! where the first row of the matrix is not a wildcard, since specializing
+//! on any other column would not make progress towards the first clause.
+//!
+//! Ties left by a heuristic go to the leftmost column. Before heuristics
+//! could be chosen, the match compiler scored columns like
+//! `ConstructorPrefix`, but over all columns, and took the rightmost of
+//! the tied ones. Decision trees built by `to_decision_tree` may
+//! therefore test the columns in a different order than they used to,
+//! and have a different shape. The clause selected for a value is the
+//! same.
+
+use ::std::collections::HashSet;
+use ::std::fmt::Debug;
+
+use super::pattern::PatternProvider;
+use super::matrix::MatchMatrix;
+
+pub trait Heuristic<P>: Debug where P: PatternProvider {
+
+    /// Narrows down `candidates` to the columns preferred by this heuristic.
+    /// Given a non-empty set of candidates, this must never return an
+    /// empty set.
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize>;
+
+}
+
+/// Selects the column the match compiler should specialize on next.
+/// Ties left by the heuristic are broken by picking the leftmost column.
+pub fn select_column<P>(heuristic: &dyn Heuristic<P>, pattern: &P,
+                        matrix: &MatchMatrix<P>) -> usize
+    where P: PatternProvider
+{
+    let candidates: Vec<usize> = (0..matrix.variables.len())
+        .filter(|&col| !is_wildcard(pattern, matrix, 0, col))
+        .collect();
+    assert!(!candidates.is_empty());
+
+    let selected = heuristic.select(pattern, matrix, candidates);
+    *selected.iter().min().unwrap()
+}
+
+fn is_wildcard<P>(pattern: &P, matrix: &MatchMatrix<P>,
+                  row: usize, col: usize) -> bool where P: PatternProvider {
+    pattern.is_wildcard(pattern.get_kind(matrix.get_node(row, col)))
+}
+
+/// Keeps the candidates with the highest score.
+fn keep_max<F>(candidates: Vec<usize>, score: F) -> Vec<usize>
+    where F: Fn(usize) -> i64
+{
+    let scores: Vec<(usize, i64)> = candidates.iter()
+        .map(|&col| (col, score(col)))
+        .collect();
+    let max = scores.iter().map(|&(_, s)| s).max().unwrap();
+    scores.iter()
+        .filter(|&&(_, s)| s == max)
+        .map(|&(col, _)| col)
+        .collect()
+}
+
+/// Approximation of the necessity relation from the paper.
+///
+/// Column `col` is needed by row `row` if the row has a constructor in the
+/// column, or if an earlier row that could match the same values has a
+/// constructor in the column. Compatibility between rows is only checked
+/// on the kinds of the top level nodes, which makes this a conservative
+/// approximation.
+fn is_needed<P>(pattern: &P, matrix: &MatchMatrix<P>,
+                row: usize, col: usize) -> bool where P: PatternProvider
+{
+    if !is_wildcard(pattern, matrix, row, col) {
+        return true;
+    }
+
+    (0..row)
+        .filter(|&prev| !is_wildcard(pattern, matrix, prev, col))
+        .any(|prev| {
+            (0..matrix.variables.len())
+                .filter(|&other| other != col)
+                .all(|other| {
+                    is_wildcard(pattern, matrix, prev, other)
+                        || is_wildcard(pattern, matrix, row, other)
+                        || pattern.get_kind(matrix.get_node(prev, other))
+                        == pattern.get_kind(matrix.get_node(row, other))
+                })
+        })
+}
+
+/// Number of consecutive rows from the top satisfying `pred` in a column.
+fn prefix_len<F>(num_rows: usize, pred: F) -> i64 where F: Fn(usize) -> bool {
+    (0..num_rows).take_while(|&row| pred(row)).count() as i64
+}
+
+/// f: Prefers columns where the first row has a constructor.
+///
+/// Since this is already a requirement for all candidate columns, this
+/// simply selects the leftmost candidate.
+#[derive(Debug, Copy, Clone)]
+pub struct FirstRow;
+impl<P> Heuristic<P> for FirstRow where P: PatternProvider {
+    fn select(&self, _pattern: &P, _matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        candidates
+    }
+}
+
+/// q: Prefers the column with the longest run of constructors from the top.
+///
+/// This is the default heuristic.
+#[derive(Debug, Copy, Clone)]
+pub struct ConstructorPrefix;
+impl<P> Heuristic<P> for ConstructorPrefix where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            prefix_len(matrix.clause_leaves.len(),
+                       |row| !is_wildcard(pattern, matrix, row, col))
+        })
+    }
+}
+
+/// p: Prefers the column with the longest run of rows needing it from the
+/// top.
+#[derive(Debug, Copy, Clone)]
+pub struct NeededPrefix;
+impl<P> Heuristic<P> for NeededPrefix where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            prefix_len(matrix.clause_leaves.len(),
+                       |row| is_needed(pattern, matrix, row, col))
+        })
+    }
+}
+
+/// n: Prefers the column needed by the largest number of rows.
+#[derive(Debug, Copy, Clone)]
+pub struct NeededColumns;
+impl<P> Heuristic<P> for NeededColumns where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            (0..matrix.clause_leaves.len())
+                .filter(|&row| is_needed(pattern, matrix, row, col))
+                .count() as i64
+        })
+    }
+}
+
+/// d: Prefers the column with the fewest wildcards, as those rows are
+/// copied into every specialization.
+#[derive(Debug, Copy, Clone)]
+pub struct SmallDefaults;
+impl<P> Heuristic<P> for SmallDefaults where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            -((0..matrix.clause_leaves.len())
+              .filter(|&row| is_wildcard(pattern, matrix, row, col))
+              .count() as i64)
+        })
+    }
+}
+
+/// b: Prefers the column with the fewest distinct node kinds, which
+/// minimizes the number of outgoing edges of the generated node.
+#[derive(Debug, Copy, Clone)]
+pub struct SmallBranching;
+impl<P> Heuristic<P> for SmallBranching where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            -(matrix.collect_specialization_types(pattern, col).len() as i64)
+        })
+    }
+}
+
+/// a: Prefers the column where the sum of the arities of the distinct
+/// node kinds is the smallest.
+#[derive(Debug, Copy, Clone)]
+pub struct SmallArity;
+impl<P> Heuristic<P> for SmallArity where P: PatternProvider {
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        keep_max(candidates, |col| {
+            let mut seen = HashSet::new();
+            let mut sum = 0;
+            for row in 0..matrix.clause_leaves.len() {
+                let node = matrix.get_node(row, col);
+                let kind = pattern.get_kind(node);
+                if !pattern.is_wildcard(kind) && seen.insert(kind) {
+                    sum += pattern.get_arity(node) as i64;
+                }
+            }
+            -sum
+        })
+    }
+}
+
+/// Applies the first heuristic, breaking ties with the second.
+///
+/// Combinations from the paper, like `pba`, can be built by nesting,
+/// `Chain(NeededPrefix, Chain(SmallBranching, SmallArity))`.
+#[derive(Debug, Copy, Clone)]
+pub struct Chain<A, B>(pub A, pub B);
+impl<P, A, B> Heuristic<P> for Chain<A, B>
+    where P: PatternProvider, A: Heuristic<P>, B: Heuristic<P>
+{
+    fn select(&self, pattern: &P, matrix: &MatchMatrix<P>,
+              candidates: Vec<usize>) -> Vec<usize> {
+        let first = self.0.select(pattern, matrix, candidates);
+        self.1.select(pattern, matrix, first)
+    }
+}
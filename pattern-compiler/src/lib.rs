@@ -6,6 +6,7 @@ extern crate prettytable;
 extern crate either;
 extern crate util;
 #[macro_use] extern crate derivative;
+#[cfg(test)] extern crate rand;
 
 use ::std::collections::HashMap;
 
@@ -13,9 +14,15 @@ mod pattern;
 pub use self::pattern::{ PatternProvider, ExpandedClauseNodes };
 
 mod cfg;
-pub use self::cfg::{ PatternCfg, CfgEdge };
+pub use self::cfg::{ PatternCfg, CfgEdge, CfgNodeKind };
 
 mod matrix;
+pub use self::matrix::MatchMatrix;
+
+pub mod heuristic;
+pub use self::heuristic::Heuristic;
+
+pub mod analysis;
 
 pub mod simple_pattern;
 
@@ -23,6 +30,13 @@ use ::petgraph::graph::NodeIndex;
 
 use ::util::hashmap_stack::HashMapStack;
 
+/// Identifies a `MatchMatrix` state for the purpose of sharing CFG nodes.
+/// Two matrices with the same variables, clause leaves and pattern nodes
+/// will always compile to identical subtrees.
+type MatrixKey<P> = (Vec<<P as PatternProvider>::CfgVariable>,
+                     Vec<cfg::CfgNodeIndex>,
+                     Vec<<P as PatternProvider>::PatternNodeKey>);
+
 #[derive(Debug)]
 pub struct MatchCompileContext<'a, P> where P: pattern::PatternProvider + 'a {
     pattern: &'a mut P,
@@ -30,12 +44,18 @@ pub struct MatchCompileContext<'a, P> where P: pattern::PatternProvider + 'a {
     cfg: cfg::PatternCfg<P>,
     leaf_bindings: HashMap<NodeIndex, HashMap<P::CfgVariable, P::PatternNodeKey>>,
 
+    /// Already compiled matrices, used to turn the decision tree into a DAG.
+    node_cache: HashMap<MatrixKey<P>, cfg::CfgNodeIndex>,
+
+    /// Selects the column to specialize on for every matrix.
+    heuristic: Box<dyn Heuristic<P>>,
+
     root_matrix: matrix::MatchMatrix<P>,
     fail_leaf: NodeIndex,
 }
 impl<'a, P> MatchCompileContext<'a, P> where P: PatternProvider {
 
-    pub fn new(pattern: &'a mut P) -> Self {
+    pub fn new(pattern: &'a mut P, heuristic: Box<dyn Heuristic<P>>) -> Self {
         let root = pattern.get_root();
 
         let mut cfg = cfg::PatternCfg::new();
@@ -60,6 +80,9 @@ impl<'a, P> MatchCompileContext<'a, P> where P: PatternProvider {
             cfg: cfg,
             leaf_bindings: leaf_bindings,
 
+            node_cache: HashMap::new(),
+            heuristic,
+
             root_matrix: root_matrix,
             fail_leaf: fail_leaf,
         }
@@ -71,40 +94,67 @@ impl<'a, P> MatchCompileContext<'a, P> where P: PatternProvider {
 
 }
 
-fn matrix_to_decision_tree<P>(parent: cfg::CfgNodeIndex,
-                              ctx: &mut MatchCompileContext<P>,
-                              spec: P::PatternNodeKind,
-                              matrix: &matrix::MatchMatrix<P>,
-                              introduced_vars: Vec<P::CfgVariable>)
+/// Compiles the given matrix, returning the CFG node that performs the
+/// match. Matrices that have already been compiled are not compiled again,
+/// instead the existing node is reused.
+fn matrix_to_decision_tree<P>(ctx: &mut MatchCompileContext<P>,
+                              matrix: &matrix::MatchMatrix<P>)
+                              -> cfg::CfgNodeIndex
     where P: PatternProvider
 {
-    let edge = cfg::CfgEdge {
-        kind: spec.clone(),
-        variable_binds: introduced_vars,
-    };
-
     // Matrix is empty, no specializations can be done.
     if matrix.is_empty() {
-        ctx.cfg.add_edge(parent, ctx.fail_leaf, edge);
-        return;
+        return ctx.fail_leaf;
     }
 
-    // If the head of the matrix has only wildcards, none of the other rows
-    // can happen.
-    if let Some(node) = matrix.has_wildcard_head(&ctx.pattern) {
-        ctx.cfg.add_edge(parent, node, edge);
-        return;
+    let key = matrix.cache_key();
+    if let Some(node) = ctx.node_cache.get(&key) {
+        return *node;
     }
 
+    // Add variable bindings for the clauses still alive in this matrix
+    for (leaf, clause) in matrix.iterate_clauses() {
+        let leaf_bindings = ctx.leaf_bindings.get_mut(&leaf).unwrap();
+        for (variable_num, variable_node) in clause.iter().enumerate() {
+            leaf_bindings.insert(matrix.get_var(variable_num), variable_node.node);
+        }
+    }
+
+    // If the head of the matrix has only wildcards, none of the other rows
+    // can happen, unless the clause has a guard that fails.
+    if let Some(leaf) = matrix.has_wildcard_head(ctx.pattern) {
+        let clause = match ctx.cfg.graph[leaf] {
+            cfg::CfgNodeKind::Leaf(clause) => clause,
+            _ => unreachable!(),
+        };
+        if !ctx.pattern.clause_has_guard(clause) {
+            ctx.node_cache.insert(key, leaf);
+            return leaf;
+        }
+
+        let wildcard = ctx.pattern.get_wildcard();
+        let guard_node = ctx.cfg.add_guard(clause);
+        ctx.cfg.add_edge(guard_node, leaf, cfg::CfgEdge {
+            kind: wildcard,
+            variable_binds: vec![],
+        });
+        let fail = matrix_to_decision_tree(ctx, &matrix.without_first_row());
+        ctx.cfg.add_edge(guard_node, fail, cfg::CfgEdge {
+            kind: wildcard,
+            variable_binds: vec![],
+        });
+
+        ctx.node_cache.insert(key, guard_node);
+        return guard_node;
+    }
 
     // Select the variable we should specialize on.
-    // This will be the column with the most consecutive non-wildcards
-    // at the head.
-    let specialize_variable = matrix.select_specialize_variable(&ctx.pattern);
+    let specialize_variable = heuristic::select_column(
+        &*ctx.heuristic, &*ctx.pattern, matrix);
     let specialize_variable_cfg_var = matrix.get_var(specialize_variable);
 
     // Add new CFG node for current
-    let cfg_node = ctx.cfg.add_child(parent, edge, specialize_variable_cfg_var);
+    let cfg_node = ctx.cfg.add_node(specialize_variable_cfg_var);
 
     // Find what pattern types we have as children, so that we can
     // specialize and branch to them in the CFG
@@ -115,55 +165,51 @@ fn matrix_to_decision_tree<P>(parent: cfg::CfgNodeIndex,
     for specialization in specialization_types.iter() {
         let (introduced, specialized) = matrix.specialize(ctx, specialize_variable,
                                                           *specialization);
-
-        // TODO: Dedup
-        // Add variable bindings to the current specializations
-        for (leaf, clause) in specialized.iterate_clauses() {
-            let leaf_bindings = ctx.leaf_bindings.get_mut(&leaf).unwrap();
-            for (variable_num, variable_node) in clause.iter().enumerate() {
-                leaf_bindings.insert(specialized.get_var(variable_num), variable_node.node);
-            }
-        }
-
-        matrix_to_decision_tree(
-            cfg_node, ctx, *specialization,
-            &specialized, introduced);
+        let child = matrix_to_decision_tree(ctx, &specialized);
+        ctx.cfg.add_edge(cfg_node, child, cfg::CfgEdge {
+            kind: *specialization,
+            variable_binds: introduced,
+        });
     }
 
     // Specialize on default matrix
     let (introduced, default) = matrix.default(ctx, specialize_variable);
-
-    // TODO: Dedup
-    // Add variable bindings to the current specializations
-    for (leaf, clause) in default.iterate_clauses() {
-        let leaf_bindings = ctx.leaf_bindings.get_mut(&leaf).unwrap();
-        for (variable_num, variable_node) in clause.iter().enumerate() {
-            leaf_bindings.insert(default.get_var(variable_num), variable_node.node);
-        }
-    }
-
+    let child = matrix_to_decision_tree(ctx, &default);
     let wildcard = ctx.pattern.get_wildcard();
-    matrix_to_decision_tree(
-        cfg_node, ctx,
-        wildcard,
-        &default, introduced);
+    ctx.cfg.add_edge(cfg_node, child, cfg::CfgEdge {
+        kind: wildcard,
+        variable_binds: introduced,
+    });
 
+    ctx.node_cache.insert(key, cfg_node);
+    cfg_node
 }
 
+/// Compiles the pattern using the default column selection heuristic,
+/// `heuristic::ConstructorPrefix`.
 pub fn to_decision_tree<P>(pattern: &mut P) -> cfg::PatternCfg<P>
     where P: PatternProvider
 {
-    let mut context = MatchCompileContext::new(pattern);
+    to_decision_tree_with_heuristic(pattern, Box::new(heuristic::ConstructorPrefix))
+}
+
+pub fn to_decision_tree_with_heuristic<P>(pattern: &mut P,
+                                          heuristic: Box<dyn Heuristic<P>>)
+                                          -> cfg::PatternCfg<P>
+    where P: PatternProvider
+{
+    let mut context = MatchCompileContext::new(pattern, heuristic);
 
     let root: matrix::MatchMatrix<P> = (*context.root_matrix()).clone();
 
     let root_cfg = context.cfg.get_entry();
     let wildcard = context.pattern.get_wildcard();
 
-    matrix_to_decision_tree(root_cfg, &mut context,
-                            wildcard,
-                            &root,
-                            root.variables.clone());
+    let child = matrix_to_decision_tree(&mut context, &root);
+    context.cfg.add_edge(root_cfg, child, cfg::CfgEdge {
+        kind: wildcard,
+        variable_binds: root.variables.clone(),
+    });
 
     let mut cfg = context.cfg;
     cfg.leaf_bindings = context.leaf_bindings;
@@ -66,30 +66,16 @@ impl<P> MatchMatrix<P> where P: PatternProvider {
         }
     }
 
-    /// Selects which variable should be specialized on in this matrix.
-    /// This will always select the variable which has the most consecutive
-    /// wildcards from the top, as this will minimize the amount of
-    /// comparisons we will have to perform.
-    pub fn select_specialize_variable(&self, pattern: &P) -> usize
-    {
-        let mut sums = vec![(0, true); self.variables.len()];
-
-        let clauses = self.data.chunks(self.variables.len());
-        for clause in clauses {
-            for variable_pattern in clause.iter() {
-                if pattern.is_wildcard(pattern.get_kind(variable_pattern.node)) {
-                    sums[variable_pattern.variable_num].1 = false;
-                } else {
-                    if sums[variable_pattern.variable_num].1 {
-                        sums[variable_pattern.variable_num].0 += 1;
-                    }
-                }
-            }
-        }
+    /// The pattern node at the given row and column.
+    pub fn get_node(&self, clause: usize, variable: usize) -> P::PatternNodeKey {
+        self.data[clause * self.variables.len() + variable].node
+    }
 
-        sums.iter().enumerate()
-            .max_by_key(|&(_, s)| s.0)
-            .map(|(i, _)| i).unwrap()
+    /// Key identifying this matrix state. See `MatrixKey`.
+    pub fn cache_key(&self) -> super::MatrixKey<P> {
+        (self.variables.clone(),
+         self.clause_leaves.clone(),
+         self.data.iter().map(|e| e.node).collect())
     }
 
     pub fn get_var(&self, var: usize) -> P::CfgVariable {
@@ -199,6 +185,23 @@ impl<P> MatchMatrix<P> where P: PatternProvider {
         self.specialize(ctx, variable, wildcard)
     }
 
+    /// The matrix without its first row. This is what remains to be
+    /// matched when the guard of the first clause fails.
+    pub fn without_first_row(&self) -> MatchMatrix<P> {
+        let num_vars = self.variables.len();
+        MatchMatrix {
+            data: self.data[num_vars..].iter()
+                .map(|elem| MatchMatrixElement {
+                    node: elem.node,
+                    variable_num: elem.variable_num,
+                    clause_num: elem.clause_num - 1,
+                })
+                .collect(),
+            variables: self.variables.clone(),
+            clause_leaves: self.clause_leaves[1..].to_vec(),
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.clause_leaves.len() == 0
     }
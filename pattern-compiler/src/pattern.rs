@@ -52,6 +52,20 @@ pub trait PatternProvider: Debug {
     /// `PatternNodeKind`.
     fn get_kind(&self, key: Self::PatternNodeKey) -> Self::PatternNodeKind;
 
+    /// Whether the given clause can fail after its patterns have matched,
+    /// usually because it has a guard. Such clauses get a guard node in
+    /// the CFG, which continues matching with the later clauses on
+    /// failure.
+    fn clause_has_guard(&self, _clause: usize) -> bool {
+        false
+    }
+
+    /// Number of child nodes `key` expands to when specialized on.
+    /// Only used by column selection heuristics.
+    fn get_arity(&self, _key: Self::PatternNodeKey) -> usize {
+        0
+    }
+
     fn is_wildcard(&self, kind: Self::PatternNodeKind) -> bool {
         kind == Self::WILDCARD
     }
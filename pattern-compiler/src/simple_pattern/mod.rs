@@ -1,5 +1,7 @@
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod prop_test;
 
 use ::{ PatternProvider, ExpandedClauseNodes };
 
@@ -29,6 +31,7 @@ enum NodeKind {
 struct SimplePatternProvider {
     pattern: Graph<NodeKind, ()>,
     roots: Vec<NodeIndex>,
+    guards: Vec<bool>,
     root_var: CfgVar,
     curr_var: CfgVar,
 }
@@ -39,6 +42,7 @@ impl SimplePatternProvider {
         SimplePatternProvider {
             pattern: Graph::new(),
             roots: Vec::new(),
+            guards: Vec::new(),
             root_var: CfgVar(0),
             curr_var: CfgVar(0),
         }
@@ -50,9 +54,26 @@ impl SimplePatternProvider {
         res
     }
 
+    /// Children of a pattern node, in the order they were added.
+    fn children(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        // Neighbors are listed in reverse order of addition.
+        let mut children: Vec<_> = self.pattern
+            .neighbors_directed(node, Direction::Outgoing)
+            .collect();
+        children.reverse();
+        children
+    }
+
     fn add_clause(&mut self, kind: NodeKind) -> NodeIndex {
         let res = self.pattern.add_node(kind);
         self.roots.push(res);
+        self.guards.push(false);
+        res
+    }
+
+    fn add_guarded_clause(&mut self, kind: NodeKind) -> NodeIndex {
+        let res = self.add_clause(kind);
+        *self.guards.last_mut().unwrap() = true;
         res
     }
 }
@@ -76,7 +97,8 @@ impl PatternProvider for SimplePatternProvider {
 
     fn kind_includes(&self, kind: Self::PatternNodeKind,
                      key: Self::PatternNodeKey) -> bool {
-        self.pattern[key] == kind
+        let key_kind = self.pattern[key];
+        key_kind == kind || (key_kind == NodeKind::Wildcard && kind != NodeKind::RootValues)
     }
 
     fn expand_clause_nodes(&mut self, clause_nodes: Vec<Self::PatternNodeKey>)
@@ -91,19 +113,31 @@ impl PatternProvider for SimplePatternProvider {
             };
         }
 
-        let typ = self.pattern[clause_nodes[0]];
-        let base_len = self.pattern.edges_directed(clause_nodes[0], Direction::Outgoing).count();
+        // Wildcards are included in every specialization, the kind and
+        // arity we are specializing on is determined by the first
+        // non-wildcard node.
+        let base = clause_nodes.iter()
+            .find(|n| self.pattern[**n] != NodeKind::Wildcard).copied();
+        let (typ, base_len) = match base {
+            Some(node) => (
+                self.pattern[node],
+                self.pattern.edges_directed(node, Direction::Outgoing).count(),
+            ),
+            None => (NodeKind::Wildcard, 0),
+        };
         for node in &clause_nodes {
-            assert!(self.pattern.edges_directed(clause_nodes[0], Direction::Outgoing).count()
-                    == base_len);
-            assert!(self.pattern[*node] == typ);
+            let kind = self.pattern[*node];
+            assert!(kind == typ || kind == NodeKind::Wildcard);
+            if kind != NodeKind::Wildcard {
+                assert!(self.pattern.edges_directed(*node, Direction::Outgoing).count()
+                        == base_len);
+            }
         }
 
         let mut curr_var = self.curr_var;
         let mut exp = ExpandedClauseNodes {
             clauses: clause_nodes.len(),
-            variables: self.pattern
-                .edges_directed(clause_nodes[0], Direction::Outgoing)
+            variables: (0..base_len)
                 .map(|_| {
                     curr_var.0 += 1;
                     curr_var
@@ -114,28 +148,22 @@ impl PatternProvider for SimplePatternProvider {
         self.curr_var = curr_var;
 
         match typ {
-            NodeKind::RootValues => {
+            NodeKind::RootValues | NodeKind::ListCell | NodeKind::Tuple => {
                 for node in &clause_nodes {
-                    for child in self.pattern.edges_directed(*node, Direction::Outgoing) {
-                        use ::petgraph::visit::EdgeRef;
-                        exp.nodes.push(child.target());
-                    }
-                }
-            },
-            NodeKind::ListCell => {
-                for node in &clause_nodes {
-                    for child in self.pattern.edges_directed(*node, Direction::Outgoing) {
-                        use ::petgraph::visit::EdgeRef;
-                        exp.nodes.push(child.target());
+                    if self.pattern[*node] == NodeKind::Wildcard {
+                        for _ in 0..base_len {
+                            let child = self.pattern.add_node(NodeKind::Wildcard);
+                            exp.nodes.push(child);
+                        }
+                    } else {
+                        exp.nodes.extend(self.children(*node));
                     }
                 }
             },
             NodeKind::Wildcard => {},
             NodeKind::Terminal => {},
-            typ => unimplemented!("{:?}", typ),
         }
 
-        println!("{:?}", exp);
         exp
     }
 
@@ -143,4 +171,12 @@ impl PatternProvider for SimplePatternProvider {
         self.pattern[key]
     }
 
+    fn clause_has_guard(&self, clause: usize) -> bool {
+        self.guards[clause]
+    }
+
+    fn get_arity(&self, key: Self::PatternNodeKey) -> usize {
+        self.pattern.edges_directed(key, Direction::Outgoing).count()
+    }
+
 }
@@ -0,0 +1,302 @@
+//! Randomized tests checking the generated CFGs against naive first match
+//! semantics.
+//!
+//! Every case is a random clause matrix and a set of random values. For
+//! every value, the CFG is evaluated and the selected clause is compared
+//! with the first clause whose patterns match and whose guard succeeds.
+//! When a clause is selected, the values bound to the variables in
+//! `leaf_bindings` are checked against the pattern nodes they refer to.
+
+use ::std::collections::HashMap;
+
+use ::rand::{ Rng, SeedableRng, XorShiftRng };
+
+use ::petgraph::Direction;
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::visit::EdgeRef;
+
+use ::PatternCfg;
+use ::cfg::CfgNodeKind;
+use ::heuristic::*;
+
+use super::{ SimplePatternProvider, NodeKind, CfgVar };
+
+const CASES: u32 = 300;
+const VALUES_PER_CASE: usize = 20;
+const MAX_DEPTH: u32 = 3;
+const TUPLE_ARITY: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Terminal,
+    ListCell(Box<Value>, Box<Value>),
+    Tuple(Vec<Value>),
+    /// Matched by wildcards only.
+    Other,
+    RootValues(Vec<Value>),
+}
+
+impl Value {
+    fn kind(&self) -> NodeKind {
+        match *self {
+            Value::Terminal => NodeKind::Terminal,
+            Value::ListCell(_, _) => NodeKind::ListCell,
+            Value::Tuple(_) => NodeKind::Tuple,
+            Value::Other => NodeKind::Wildcard,
+            Value::RootValues(_) => NodeKind::RootValues,
+        }
+    }
+
+    fn children(&self) -> Vec<&Value> {
+        match *self {
+            Value::ListCell(ref head, ref tail) => vec![&**head, &**tail],
+            Value::Tuple(ref elems) | Value::RootValues(ref elems) =>
+                elems.iter().collect(),
+            _ => vec![],
+        }
+    }
+}
+
+struct Case {
+    pattern: SimplePatternProvider,
+    columns: usize,
+}
+
+fn gen_pattern_node<R: Rng>(rng: &mut R, pattern: &mut SimplePatternProvider,
+                            parent: NodeIndex, depth: u32) {
+    let leaf_only = depth >= MAX_DEPTH;
+    match rng.gen_range(0, if leaf_only { 2 } else { 4 }) {
+        0 => { pattern.add_child(parent, NodeKind::Wildcard); },
+        1 => { pattern.add_child(parent, NodeKind::Terminal); },
+        2 => {
+            let node = pattern.add_child(parent, NodeKind::ListCell);
+            gen_pattern_node(rng, pattern, node, depth + 1);
+            gen_pattern_node(rng, pattern, node, depth + 1);
+        },
+        _ => {
+            let node = pattern.add_child(parent, NodeKind::Tuple);
+            for _ in 0..TUPLE_ARITY {
+                gen_pattern_node(rng, pattern, node, depth + 1);
+            }
+        },
+    }
+}
+
+fn gen_case<R: Rng>(rng: &mut R) -> Case {
+    let columns = rng.gen_range(1, 4);
+    let clauses = rng.gen_range(1, 7);
+
+    let mut pattern = SimplePatternProvider::new();
+    for _ in 0..clauses {
+        let clause = if rng.gen_weighted_bool(4) {
+            pattern.add_guarded_clause(NodeKind::RootValues)
+        } else {
+            pattern.add_clause(NodeKind::RootValues)
+        };
+        for _ in 0..columns {
+            gen_pattern_node(rng, &mut pattern, clause, 0);
+        }
+    }
+
+    Case {
+        pattern,
+        columns,
+    }
+}
+
+fn gen_value<R: Rng>(rng: &mut R, depth: u32) -> Value {
+    let leaf_only = depth >= MAX_DEPTH;
+    match rng.gen_range(0, if leaf_only { 2 } else { 4 }) {
+        0 => Value::Terminal,
+        1 => Value::Other,
+        2 => Value::ListCell(Box::new(gen_value(rng, depth + 1)),
+                             Box::new(gen_value(rng, depth + 1))),
+        _ => Value::Tuple((0..TUPLE_ARITY).map(|_| gen_value(rng, depth + 1)).collect()),
+    }
+}
+
+/// Generates a value matching the given pattern node, with random values
+/// in place of wildcards.
+fn instantiate<R: Rng>(rng: &mut R, pattern: &SimplePatternProvider,
+                       node: NodeIndex, depth: u32) -> Value {
+    let children: Vec<_> = pattern.children(node).iter()
+        .map(|child| instantiate(rng, pattern, *child, depth + 1))
+        .collect();
+    match pattern.pattern[node] {
+        NodeKind::Wildcard => gen_value(rng, depth),
+        NodeKind::Terminal => Value::Terminal,
+        NodeKind::ListCell => {
+            let mut children = children.into_iter();
+            Value::ListCell(Box::new(children.next().unwrap()),
+                            Box::new(children.next().unwrap()))
+        },
+        NodeKind::Tuple => Value::Tuple(children),
+        NodeKind::RootValues => Value::RootValues(children),
+    }
+}
+
+/// Half of the values are made to match a random clause, as fully random
+/// values rarely match deep patterns.
+fn gen_root_value<R: Rng>(rng: &mut R, case: &Case) -> Value {
+    if rng.gen() {
+        let clause = *rng.choose(&case.pattern.roots).unwrap();
+        instantiate(rng, &case.pattern, clause, 0)
+    } else {
+        Value::RootValues((0..case.columns).map(|_| gen_value(rng, 0)).collect())
+    }
+}
+
+/// Matches `value` against the pattern node, collecting the value matched
+/// by every pattern node.
+fn naive_match<'a>(pattern: &SimplePatternProvider, node: NodeIndex,
+                   value: &'a Value, binds: &mut HashMap<NodeIndex, &'a Value>)
+                   -> bool {
+    binds.insert(node, value);
+    let kind = pattern.pattern[node];
+    if kind == NodeKind::Wildcard {
+        return true;
+    }
+    if kind != value.kind() {
+        return false;
+    }
+
+    let children = pattern.children(node);
+    let values = value.children();
+    assert!(children.len() == values.len());
+    children.iter().zip(values.iter())
+        .all(|(child, value)| naive_match(pattern, *child, value, binds))
+}
+
+/// Returns the first clause that matches and passes its guard.
+fn naive_select<'a>(pattern: &SimplePatternProvider, value: &'a Value,
+                    guards: &[bool])
+                    -> Option<(usize, HashMap<NodeIndex, &'a Value>)> {
+    for (clause, root) in pattern.roots.iter().enumerate() {
+        let mut binds = HashMap::new();
+        let guard_ok = !pattern.guards[clause] || guards[clause];
+        if naive_match(pattern, *root, value, &mut binds) && guard_ok {
+            return Some((clause, binds));
+        }
+    }
+    None
+}
+
+/// Walks the CFG for the given value, returning the leaf and the values
+/// bound to every variable on the way.
+fn evaluate<'a>(cfg: &PatternCfg<SimplePatternProvider>, value: &'a Value,
+                guards: &[bool])
+                -> (NodeIndex, HashMap<CfgVar, &'a Value>) {
+    let mut env: HashMap<CfgVar, &Value> = HashMap::new();
+
+    let root_edge = cfg.graph.edges_directed(cfg.entry, Direction::Outgoing)
+        .next().unwrap();
+    assert!(root_edge.weight().variable_binds.len() == 1);
+    env.insert(root_edge.weight().variable_binds[0], value);
+
+    let mut node = root_edge.target();
+    loop {
+        match cfg.graph[node] {
+            CfgNodeKind::Root => unreachable!(),
+            CfgNodeKind::Fail | CfgNodeKind::Leaf(_) => return (node, env),
+            CfgNodeKind::Guard(clause) => {
+                let (ok, fail) = cfg.guard_targets(node);
+                node = if guards[clause] { ok } else { fail };
+            },
+            CfgNodeKind::Match(var) => {
+                let value = env[&var];
+                let edges: Vec<_> = cfg.graph.edges_directed(node, Direction::Outgoing)
+                    .collect();
+                let edge = edges.iter()
+                    .find(|e| e.weight().kind == value.kind()
+                          && value.kind() != NodeKind::Wildcard)
+                    .or_else(|| edges.iter()
+                             .find(|e| e.weight().kind == NodeKind::Wildcard))
+                    .unwrap();
+
+                let binds = &edge.weight().variable_binds;
+                if edge.weight().kind != NodeKind::Wildcard {
+                    let children = value.children();
+                    assert!(binds.len() == children.len());
+                    for (var, child) in binds.iter().zip(children) {
+                        env.insert(*var, child);
+                    }
+                } else {
+                    assert!(binds.is_empty());
+                }
+                node = edge.target();
+            },
+        }
+    }
+}
+
+fn check_case(case: &Case, heuristic: Box<dyn (::Heuristic<SimplePatternProvider>)>,
+              values: &[(Value, Vec<bool>)]) {
+    let mut pattern = case.pattern.clone();
+    let cfg = ::to_decision_tree_with_heuristic(&mut pattern, heuristic);
+    let unreachable = ::analysis::unreachable_clauses(&cfg);
+
+    for (value, guards) in values.iter() {
+        let expected = naive_select(&case.pattern, value, guards);
+        let (node, env) = evaluate(&cfg, value, guards);
+
+        match (expected, &cfg.graph[node]) {
+            (None, &CfgNodeKind::Fail) => (),
+            (Some((clause, node_values)), &CfgNodeKind::Leaf(leaf_clause)) => {
+                assert!(clause == leaf_clause,
+                        "expected clause {}, got {} for {:?}", clause, leaf_clause, value);
+                assert!(!unreachable.contains(&clause));
+
+                // Every variable bound on the path must hold the value
+                // matched by the pattern node it refers to.
+                let bindings = &cfg.leaf_bindings[&node];
+                for (var, pattern_node) in bindings.iter() {
+                    if let (Some(actual), Some(expected)) =
+                        (env.get(var), node_values.get(pattern_node)) {
+                        assert!(actual == expected,
+                                "{:?} bound to {:?}, expected {:?}", var, actual, expected);
+                    }
+                }
+
+                // Every node in the clause pattern must be bound.
+                for pattern_node in node_values.keys() {
+                    assert!(bindings.iter().any(|(var, n)| {
+                        n == pattern_node && env.contains_key(var)
+                    }), "{:?} not bound", pattern_node);
+                }
+            },
+            (expected, actual) =>
+                panic!("expected {:?}, got {:?} for {:?}",
+                       expected.map(|e| e.0), actual, value),
+        }
+    }
+}
+
+#[test]
+fn random_matrices_match_naive_semantics() {
+    let mut rng = XorShiftRng::from_seed([0x193a_6754, 0xa8a7_d469,
+                                          0x9783_0e05, 0x113b_a7bb]);
+
+    for _ in 0..CASES {
+        let case = gen_case(&mut rng);
+        let values: Vec<_> = (0..VALUES_PER_CASE)
+            .map(|_| {
+                let value = gen_root_value(&mut rng, &case);
+                let guards = (0..case.pattern.roots.len())
+                    .map(|_| rng.gen())
+                    .collect();
+                (value, guards)
+            })
+            .collect();
+
+        let heuristics: Vec<Box<dyn (::Heuristic<SimplePatternProvider>)>> = vec![
+            Box::new(ConstructorPrefix),
+            Box::new(FirstRow),
+            Box::new(NeededPrefix),
+            Box::new(Chain(NeededPrefix, Chain(SmallBranching, SmallArity))),
+            Box::new(SmallDefaults),
+        ];
+        for heuristic in heuristics {
+            check_case(&case, heuristic, &values);
+        }
+    }
+}
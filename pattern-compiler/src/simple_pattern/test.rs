@@ -5,8 +5,7 @@ use ::petgraph::graph::NodeIndex;
 
 use super::{ SimplePatternProvider, NodeKind };
 
-#[test]
-fn list_merge_pattern() {
+fn list_merge_clauses() -> SimplePatternProvider {
 
     // fn ([], _)
     // fn (_, [])
@@ -36,6 +35,12 @@ fn list_merge_pattern() {
         pattern.add_child(list_cell_2, NodeKind::Wildcard);
     }
 
+    pattern
+}
+
+#[test]
+fn list_merge_pattern() {
+    let mut pattern = list_merge_clauses();
     let res = ::to_decision_tree(&mut pattern);
 
     let mut file = ::std::fs::File::create("cfg.dot").unwrap();
@@ -45,3 +50,275 @@ fn list_merge_pattern() {
     println!("{:#?}", res.leaf_bindings);
 
 }
+
+/// Number of nodes the CFG would have if it was unfolded into a tree.
+fn unfolded_size(cfg: &::PatternCfg<SimplePatternProvider>, node: NodeIndex) -> usize {
+    1 + cfg.graph.neighbors_directed(node, Direction::Outgoing)
+        .map(|child| unfolded_size(cfg, child))
+        .sum::<usize>()
+}
+
+/// Length of the longest path from `node` to a leaf.
+fn depth(cfg: &::PatternCfg<SimplePatternProvider>, node: NodeIndex) -> usize {
+    cfg.graph.neighbors_directed(node, Direction::Outgoing)
+        .map(|child| 1 + depth(cfg, child))
+        .max()
+        .unwrap_or(0)
+}
+
+const COLUMNS: usize = 20;
+
+fn independent_columns_clauses() -> SimplePatternProvider {
+
+    // fn ([], [], [], ...)
+    // fn ([_ | _], _, _, ...)
+    // fn (_, [_ | _], _, ...)
+    // fn (_, _, [_ | _], ...)
+    // ...
+
+    let mut pattern = SimplePatternProvider::new();
+
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        for _ in 0..COLUMNS {
+            pattern.add_child(clause, NodeKind::Terminal);
+        }
+    }
+    for cell_col in 0..COLUMNS {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        for col in 0..COLUMNS {
+            if col == cell_col {
+                let cell = pattern.add_child(clause, NodeKind::ListCell);
+                pattern.add_child(cell, NodeKind::Wildcard);
+                pattern.add_child(cell, NodeKind::Wildcard);
+            } else {
+                pattern.add_child(clause, NodeKind::Wildcard);
+            }
+        }
+    }
+
+    pattern
+}
+
+#[test]
+fn shared_independent_columns() {
+    let mut pattern = independent_columns_clauses();
+    let res = ::to_decision_tree(&mut pattern);
+
+    let dag_size = res.graph.node_count();
+    let tree_size = unfolded_size(&res, res.entry);
+    println!("dag: {} tree: {}", dag_size, tree_size);
+
+    // Without sharing, every `[]` branch would compile its own copy of the
+    // remaining default matrices, making the tree quadratic in size.
+    assert!(dag_size < 4 * COLUMNS);
+    assert!(dag_size * 5 < tree_size);
+}
+
+fn wide_tuple_clauses() -> SimplePatternProvider {
+
+    // fn ([], {_, _, _})
+    // fn ([], _)
+    // fn ([_ | _], {_, _, _})
+    // fn (_, [])
+
+    let mut pattern = SimplePatternProvider::new();
+
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Terminal);
+        let tuple = pattern.add_child(clause, NodeKind::Tuple);
+        for _ in 0..3 {
+            pattern.add_child(tuple, NodeKind::Wildcard);
+        }
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Terminal);
+        pattern.add_child(clause, NodeKind::Wildcard);
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        let cell = pattern.add_child(clause, NodeKind::ListCell);
+        pattern.add_child(cell, NodeKind::Wildcard);
+        pattern.add_child(cell, NodeKind::Wildcard);
+        let tuple = pattern.add_child(clause, NodeKind::Tuple);
+        for _ in 0..3 {
+            pattern.add_child(tuple, NodeKind::Wildcard);
+        }
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Wildcard);
+        pattern.add_child(clause, NodeKind::Terminal);
+    }
+
+    pattern
+}
+
+fn late_column_clauses() -> SimplePatternProvider {
+
+    // fn ([], [])
+    // fn (_, [_ | _])
+    // fn (_, [])
+
+    let mut pattern = SimplePatternProvider::new();
+
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Terminal);
+        pattern.add_child(clause, NodeKind::Terminal);
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Wildcard);
+        let cell = pattern.add_child(clause, NodeKind::ListCell);
+        pattern.add_child(cell, NodeKind::Wildcard);
+        pattern.add_child(cell, NodeKind::Wildcard);
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Wildcard);
+        pattern.add_child(clause, NodeKind::Terminal);
+    }
+
+    pattern
+}
+
+/// Compares the heuristics against each other on the clause sets above.
+///
+/// This is not a benchmark. The clause sets are small and synthetic, and
+/// there is no measurement of the heuristics on real Erlang code. It only
+/// checks that every heuristic builds a correct CFG, and that the default
+/// heuristic does at least as well as simply taking the first row.
+#[test]
+fn heuristic_comparison() {
+    use ::heuristic::*;
+
+    let clause_sets: Vec<fn() -> SimplePatternProvider> = vec![
+        list_merge_clauses, independent_columns_clauses, wide_tuple_clauses,
+        late_column_clauses,
+    ];
+    let late_column = 3;
+
+    type MakeHeuristic = fn() -> Box<dyn (::Heuristic<SimplePatternProvider>)>;
+    let heuristics: Vec<MakeHeuristic> = vec![
+        || Box::new(FirstRow),
+        || Box::new(ConstructorPrefix),
+        || Box::new(NeededPrefix),
+        || Box::new(NeededColumns),
+        || Box::new(SmallDefaults),
+        || Box::new(SmallBranching),
+        || Box::new(SmallArity),
+        || Box::new(Chain(NeededPrefix, Chain(SmallBranching, SmallArity))),
+        || Box::new(Chain(ConstructorPrefix, Chain(SmallBranching, SmallArity))),
+    ];
+
+    // (nodes, depth) for every heuristic, for every clause set
+    let results: Vec<Vec<(usize, usize)>> = clause_sets.iter().map(|&clauses| {
+        heuristics.iter().map(|&heuristic| {
+            let mut pattern = clauses();
+            let num_clauses = pattern.roots.len();
+            let res = ::to_decision_tree_with_heuristic(&mut pattern, heuristic());
+
+            // Every clause in the sets is reachable, no heuristic may
+            // change that.
+            for clause in 0..num_clauses {
+                let leaf = res.graph.node_indices()
+                    .find(|n| match res.graph[*n] {
+                        ::cfg::CfgNodeKind::Leaf(c) => c == clause,
+                        _ => false,
+                    })
+                    .unwrap();
+                assert!(res.graph.neighbors_directed(leaf, Direction::Incoming)
+                        .count() > 0);
+            }
+
+            (res.graph.node_count(), depth(&res, res.entry))
+        }).collect()
+    }).collect();
+
+    let (first_row, default) = (0, 1);
+    for (set, results) in results.iter().enumerate() {
+        let (naive, chosen) = (results[first_row], results[default]);
+        assert!(chosen.0 <= naive.0, "set {}: {:?}", set, results);
+        assert!(chosen.1 <= naive.1, "set {}: {:?}", set, results);
+    }
+    // The first column of these clauses is only tested by the first one,
+    // specializing on it first costs a node
+    let results = &results[late_column];
+    assert!(results[default].0 < results[first_row].0, "{:?}", results);
+}
+
+#[test]
+fn analysis_unreachable_and_fail() {
+    use ::analysis::{ unreachable_clauses, fail_paths, VariableConstraint };
+
+    // fn (_, _)
+    // fn ([], _)
+    let mut pattern = SimplePatternProvider::new();
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Wildcard);
+        pattern.add_child(clause, NodeKind::Wildcard);
+    }
+    {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Terminal);
+        pattern.add_child(clause, NodeKind::Wildcard);
+    }
+    let res = ::to_decision_tree(&mut pattern);
+    assert!(unreachable_clauses(&res) == vec![1]);
+
+    // The only failing values are the ones that are not `RootValues`,
+    // which can not happen with this provider.
+    let paths = fail_paths(&res, 10);
+    assert!(paths.len() == 1);
+    match paths[0].constraints[&paths[0].roots[0]] {
+        VariableConstraint::IsNot(ref kinds) =>
+            assert!(*kinds == vec![NodeKind::RootValues]),
+        _ => panic!(),
+    }
+
+    let mut pattern = list_merge_clauses();
+    let res = ::to_decision_tree(&mut pattern);
+    assert!(unreachable_clauses(&res).is_empty());
+
+    let paths = fail_paths(&res, 10);
+    assert!(paths.len() > 1);
+    assert!(fail_paths(&res, 1).len() == 1);
+}
+
+#[test]
+fn guard_failure_resumes_in_subtree() {
+    use ::analysis::unreachable_clauses;
+    use ::cfg::CfgNodeKind;
+
+    // fn ([], _) when ...
+    // fn ([], _)
+    // fn (_, _)
+    let mut pattern = SimplePatternProvider::new();
+    {
+        let clause = pattern.add_guarded_clause(NodeKind::RootValues);
+        pattern.add_child(clause, NodeKind::Terminal);
+        pattern.add_child(clause, NodeKind::Wildcard);
+    }
+    for &kind in [NodeKind::Terminal, NodeKind::Wildcard].iter() {
+        let clause = pattern.add_clause(NodeKind::RootValues);
+        pattern.add_child(clause, kind);
+        pattern.add_child(clause, NodeKind::Wildcard);
+    }
+
+    let res = ::to_decision_tree(&mut pattern);
+    assert!(unreachable_clauses(&res).is_empty());
+
+    let guard = res.graph.node_indices()
+        .find(|n| matches!(res.graph[*n], CfgNodeKind::Guard(0)))
+        .unwrap();
+
+    // When the guard fails, the first argument is already known to be
+    // `[]`, so the second clause is selected without matching again.
+    let (ok, fail) = res.guard_targets(guard);
+    assert!(ok == res.leaves[&0]);
+    assert!(fail == res.leaves[&1]);
+}
@@ -48,7 +48,7 @@ fn erl_to_ir(erlang_code: &str) -> Module {
 fn simple_add() {
     let module = erl_to_ir(r##"
 -module(test).
--export([add/2, add_two/3, return_closure/1]).
+-export([add/2, add_two/3, return_closure/1, matching/2]).
 
 add(A, B) ->
     A + B.
@@ -73,13 +73,32 @@ matching(A, B) ->
 
     "##);
 
-    use ::interpreter::{ ExecutionContext, Term };
+    use ::interpreter::{ ExecutionContext, Term, CallReturn };
     let mut ctx = ExecutionContext::new();
 
     ctx.add_native_module(::interpreter::lib::make_erlang());
     ctx.add_erlang_module(module);
 
-    let args = vec![Term::Nil, Term::Nil];
-    let result = ctx.call("test", "add", &args);
-    println!("{:?}", result);
+    let returned = |ret: CallReturn| match ret {
+        CallReturn::Return { term } => term,
+        CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+    };
+    let int = Term::new_i64;
+
+    let result = returned(ctx.call("test", "add", &[int(1), int(2)]));
+    assert!(result.erl_exact_eq(&int(3)));
+
+    let result = returned(ctx.call("test", "add_two", &[int(1), int(2), int(3)]));
+    assert!(result.erl_exact_eq(&int(6)));
+
+    let closure = returned(ctx.call("test", "return_closure", &[int(1)]));
+    let result = returned(ctx.apply(&closure, &[int(2)]));
+    assert!(result.erl_exact_eq(&int(3)));
+
+    let result = returned(ctx.call("test", "matching", &[Term::Nil, Term::Nil]));
+    assert!(result.erl_exact_eq(&Term::new_atom("one")));
+
+    let (a, b) = (Term::new_atom("a"), Term::new_atom("b"));
+    let result = returned(ctx.call("test", "matching", &[a.clone(), b.clone()]));
+    assert!(result.erl_exact_eq(&Term::Tuple(vec![a, b])));
 }
@@ -0,0 +1,331 @@
+//! Construction and matching of binary segments.
+//!
+//! Every segment of a binary has four options in Core, the size, the unit,
+//! the type and a list of flags, e.g. `#<X>(8,1,'integer',['unsigned'|['big']])`.
+
+use ::num_bigint::{ BigInt, Sign };
+use ::num_traits::{ One, Zero, ToPrimitive };
+
+use super::{ Term, BitString };
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SegmentType {
+    Integer,
+    Float,
+    Binary,
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SegmentSpec {
+    /// None when the size is `all` or not applicable.
+    pub size: Option<usize>,
+    pub unit: usize,
+    pub typ: SegmentType,
+    pub signed: bool,
+    pub little: bool,
+}
+impl SegmentSpec {
+
+    /// Reads the options of a segment. Returns None if they are invalid.
+    pub fn from_opts(opts: &[Term]) -> Option<SegmentSpec> {
+        assert!(opts.len() == 4);
+        let size = match opts[0] {
+            Term::Integer(ref int) => Some(int.to_usize()?),
+            Term::Atom(_) => None,
+            _ => return None,
+        };
+        let unit = match opts[1] {
+            Term::Integer(ref int) => int.to_usize()?,
+            _ => return None,
+        };
+        let typ = match opts[2] {
+            Term::Atom(ref atom) => match &**atom {
+                "integer" => SegmentType::Integer,
+                "float" => SegmentType::Float,
+                "binary" => SegmentType::Binary,
+                "utf8" => SegmentType::Utf8,
+                "utf16" => SegmentType::Utf16,
+                "utf32" => SegmentType::Utf32,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let mut spec = SegmentSpec {
+            size,
+            unit,
+            typ,
+            signed: false,
+            little: false,
+        };
+        let mut flags = &opts[3];
+        loop {
+            match *flags {
+                Term::Nil => break,
+                Term::List(ref head, ref tail) => {
+                    for flag in head.iter() {
+                        match *flag {
+                            Term::Atom(ref atom) => match &**atom {
+                                "signed" => spec.signed = true,
+                                "unsigned" => spec.signed = false,
+                                "little" => spec.little = true,
+                                "big" | "native" => spec.little = false,
+                                _ => return None,
+                            },
+                            _ => return None,
+                        }
+                    }
+                    flags = tail;
+                },
+                _ => return None,
+            }
+        }
+        Some(spec)
+    }
+
+    /// Number of bits in the segment, if fixed.
+    fn bits(&self) -> Option<usize> {
+        self.size.map(|size| size * self.unit)
+    }
+
+}
+
+/// The low `bits` bits of `int` in two's complement, most significant
+/// first.
+fn int_to_bits(int: &BigInt, bits: usize, little: bool) -> BitString {
+    let modulus = BigInt::one() << bits;
+    let mut value = int % &modulus;
+    if value.sign() == Sign::Minus {
+        value += &modulus;
+    }
+
+    let mut bytes = value.to_bytes_be().1;
+    let num_bytes = bits.div_ceil(8);
+    while bytes.len() < num_bytes {
+        bytes.insert(0, 0);
+    }
+    if little && bits.is_multiple_of(8) {
+        bytes.reverse();
+    }
+    let padded = BitString::from_bytes(bytes);
+    padded.slice(padded.bit_len - bits, bits)
+}
+
+fn bits_to_int(bits: &BitString, signed: bool, little: bool) -> BigInt {
+    let mut bits = bits.clone();
+    if little && bits.bit_len.is_multiple_of(8) {
+        bits.bytes.reverse();
+    }
+    let mut value = BigInt::zero();
+    for idx in 0..bits.bit_len {
+        value <<= 1;
+        if bits.bit(idx) {
+            value += BigInt::one();
+        }
+    }
+    if signed && bits.bit_len > 0 && bits.bit(0) {
+        value -= BigInt::one() << bits.bit_len;
+    }
+    value
+}
+
+fn encode_char(spec: &SegmentSpec, code: u32) -> Option<BitString> {
+    let c = ::std::char::from_u32(code)?;
+    let bytes = match spec.typ {
+        SegmentType::Utf8 => {
+            let mut buf = [0; 4];
+            c.encode_utf8(&mut buf).as_bytes().to_vec()
+        },
+        SegmentType::Utf16 => {
+            let mut buf = [0; 2];
+            let mut bytes = Vec::new();
+            for unit in c.encode_utf16(&mut buf).iter() {
+                let mut unit_bytes = vec![(*unit >> 8) as u8, *unit as u8];
+                if spec.little {
+                    unit_bytes.reverse();
+                }
+                bytes.extend(unit_bytes);
+            }
+            bytes
+        },
+        SegmentType::Utf32 => return Some(int_to_bits(
+            &BigInt::from(code), 32, spec.little)),
+        _ => unreachable!(),
+    };
+    Some(BitString::from_bytes(bytes))
+}
+
+/// Appends the segment to the binary. Returns None if the value does not
+/// fit the spec.
+pub fn construct_segment(bin: &mut BitString, value: &Term, spec: &SegmentSpec)
+                         -> Option<()> {
+    let segment = match (spec.typ, value) {
+        (SegmentType::Integer, Term::Integer(int)) =>
+            int_to_bits(int, spec.bits()?, spec.little),
+        (SegmentType::Float, _) => {
+            let float = match *value {
+                Term::Integer(ref int) => int.to_f64()?,
+                Term::Float(float) => float,
+                _ => return None,
+            };
+            match spec.bits()? {
+                64 => int_to_bits(&BigInt::from(float.to_bits()), 64, spec.little),
+                32 => int_to_bits(&BigInt::from((float as f32).to_bits()), 32, spec.little),
+                _ => return None,
+            }
+        },
+        (SegmentType::Binary, Term::BitString(bits)) => match spec.bits() {
+            None if bits.bit_len % spec.unit == 0 => bits.clone(),
+            Some(len) if len <= bits.bit_len => bits.slice(0, len),
+            _ => return None,
+        },
+        (SegmentType::Utf8, &Term::Integer(ref int)) |
+        (SegmentType::Utf16, &Term::Integer(ref int)) |
+        (SegmentType::Utf32, &Term::Integer(ref int)) =>
+            encode_char(spec, int.to_u32()?)?,
+        _ => return None,
+    };
+    bin.append(&segment);
+    Some(())
+}
+
+fn decode_char(spec: &SegmentSpec, bin: &BitString, offset: usize)
+               -> Option<(Term, usize)> {
+    let remaining = bin.bit_len - offset;
+    let read_byte = |idx: usize| -> Option<u8> {
+        if (idx + 1) * 8 > remaining {
+            None
+        } else {
+            Some(bin.slice(offset + idx * 8, 8).bytes[0])
+        }
+    };
+    let (code, bits) = match spec.typ {
+        SegmentType::Utf8 => {
+            let first = read_byte(0)?;
+            let len = if first < 0x80 { 1 }
+                else if first >> 5 == 0b110 { 2 }
+                else if first >> 4 == 0b1110 { 3 }
+                else if first >> 3 == 0b11110 { 4 }
+                else { return None };
+            let bytes: Option<Vec<u8>> = (0..len).map(&read_byte).collect();
+            let string = String::from_utf8(bytes?).ok()?;
+            (string.chars().next()? as u32, len * 8)
+        },
+        SegmentType::Utf16 => {
+            let read_unit = |idx: usize| -> Option<u16> {
+                let (a, b) = (read_byte(idx * 2)?, read_byte(idx * 2 + 1)?);
+                Some(if spec.little { (b as u16) << 8 | a as u16 }
+                     else { (a as u16) << 8 | b as u16 })
+            };
+            let first = read_unit(0)?;
+            let units = if (0xD800..0xDC00).contains(&first) {
+                vec![first, read_unit(1)?]
+            } else {
+                vec![first]
+            };
+            let c = ::std::char::decode_utf16(units.iter().cloned()).next()?.ok()?;
+            (c as u32, units.len() * 16)
+        },
+        SegmentType::Utf32 => {
+            if remaining < 32 {
+                return None;
+            }
+            let code = bits_to_int(&bin.slice(offset, 32), false, spec.little);
+            let code = code.to_u32()?;
+            ::std::char::from_u32(code)?;
+            (code, 32)
+        },
+        _ => unreachable!(),
+    };
+    Some((Term::new_i64(code as i64), bits))
+}
+
+/// Reads a segment from the binary, starting at the bit `offset`. Returns
+/// the value along with the number of bits read, or None if the binary
+/// does not match the spec.
+pub fn match_segment(bin: &BitString, offset: usize, spec: &SegmentSpec)
+                     -> Option<(Term, usize)> {
+    let remaining = bin.bit_len - offset;
+    match spec.typ {
+        SegmentType::Integer => {
+            let bits = spec.bits()?;
+            if bits > remaining {
+                return None;
+            }
+            let int = bits_to_int(&bin.slice(offset, bits), spec.signed, spec.little);
+            Some((Term::Integer(int), bits))
+        },
+        SegmentType::Float => {
+            let bits = spec.bits()?;
+            if bits > remaining {
+                return None;
+            }
+            let raw = bits_to_int(&bin.slice(offset, bits), false, spec.little);
+            let float = match bits {
+                64 => f64::from_bits(raw.to_u64()?),
+                32 => f32::from_bits(raw.to_u32()?) as f64,
+                _ => return None,
+            };
+            if !float.is_finite() {
+                return None;
+            }
+            Some((Term::Float(float), bits))
+        },
+        SegmentType::Binary => {
+            let bits = match spec.bits() {
+                Some(bits) => bits,
+                None if remaining.is_multiple_of(spec.unit) => remaining,
+                None => return None,
+            };
+            if bits > remaining {
+                return None;
+            }
+            Some((Term::BitString(bin.slice(offset, bits)), bits))
+        },
+        SegmentType::Utf8 | SegmentType::Utf16 | SegmentType::Utf32 =>
+            decode_char(spec, bin, offset),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ Term, BitString };
+    use super::{ SegmentSpec, construct_segment, match_segment };
+
+    fn spec(size: Term, unit: i64, typ: &str, flags: &[&str]) -> SegmentSpec {
+        let flags = Term::proper_list(flags.iter().map(|f| Term::new_atom(f)).collect());
+        SegmentSpec::from_opts(&[size, Term::new_i64(unit), Term::new_atom(typ), flags])
+            .unwrap()
+    }
+
+    #[test]
+    fn roundtrip_segments() {
+        let segments = [
+            (Term::new_i64(-2), spec(Term::new_i64(16), 1, "integer", &["signed", "little"])),
+            (Term::new_i64(5), spec(Term::new_i64(3), 1, "integer", &["unsigned", "big"])),
+            (Term::Float(1.5), spec(Term::new_i64(64), 1, "float", &["big"])),
+            (Term::new_i64(0x20AC), spec(Term::new_atom("undefined"), 1, "utf8", &[])),
+            (Term::BitString(BitString::from_bytes(vec![1, 2])),
+             spec(Term::new_atom("all"), 8, "binary", &[])),
+        ];
+
+        let mut bin = BitString::from_bytes(vec![]);
+        for (value, spec) in segments.iter() {
+            construct_segment(&mut bin, value, spec).unwrap();
+        }
+        assert!(bin.bit_len == 16 + 3 + 64 + 24 + 16);
+        assert!(bin.slice(0, 16).bytes == vec![0xFE, 0xFF]);
+
+        let mut offset = 0;
+        for (value, spec) in segments.iter() {
+            let (read, bits) = match_segment(&bin, offset, spec).unwrap();
+            assert!(read.erl_exact_eq(value), "{} {}", read, value);
+            offset += bits;
+        }
+        assert!(offset == bin.bit_len);
+    }
+
+}
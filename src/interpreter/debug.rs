@@ -0,0 +1,217 @@
+//! Debugging of interpreted code.
+//!
+//! `run` stops when a frame enters a block with a breakpoint, before its
+//! first OP is executed, and `step` executes a single OP. In between, the
+//! call stacks of the processes and the variables of their frames can be
+//! inspected. Only Erlang functions are shown, native functions waiting
+//! for a fun they applied are left out.
+
+use ::std::str::FromStr;
+use ::std::fmt;
+
+use ::intern::{ Atom, Variable };
+use ::ir::{ FunctionIdent, SSAVariable };
+use ::ir::lir::LabelN;
+use super::{ ExecutionContext, ModuleType, Frame, StackFrame, Term, Pid };
+
+/// The position of an Erlang function on the call stack of a process.
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    pub module: Atom,
+    pub function: FunctionIdent,
+    pub label: LabelN,
+    /// Index of the next OP to execute in the block.
+    pub op: usize,
+}
+impl fmt::Display for FrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} {}:{}", self.module, self.function, self.label, self.op)
+    }
+}
+
+impl ExecutionContext {
+
+    pub fn add_breakpoint(&self, module: &str, fun: FunctionIdent, label: LabelN) {
+        let module = Atom::from_str(module).unwrap();
+        self.breakpoints.borrow_mut().insert((module, fun, label));
+    }
+
+    /// Returns false if there was no such breakpoint.
+    pub fn remove_breakpoint(&self, module: &str, fun: FunctionIdent, label: LabelN) -> bool {
+        let module = Atom::from_str(module).unwrap();
+        self.breakpoints.borrow_mut().remove(&(module, fun, label))
+    }
+
+    /// Whether the innermost frame is at the start of a block with a
+    /// breakpoint.
+    pub(super) fn at_breakpoint(&self, frames: &[Frame]) -> bool {
+        let breakpoints = self.breakpoints.borrow();
+        if breakpoints.is_empty() {
+            return false;
+        }
+        match frames.last().and_then(Frame::as_erlang) {
+            Some(frame) if frame.op == 0 => breakpoints.contains(
+                &(frame.module.clone(), frame.function.clone(), frame.label)),
+            _ => false,
+        }
+    }
+
+    /// Calls `fun` with the Erlang frames of a process, innermost last.
+    /// Returns None if the process is not alive or is running.
+    fn with_frames<F, R>(&self, pid: Pid, fun: F) -> Option<R>
+        where F: FnOnce(&[&StackFrame]) -> R {
+        self.processes.borrow().get(&pid).map(|process| {
+            let frames: Vec<_> = process.frames.iter().filter_map(Frame::as_erlang).collect();
+            fun(&frames)
+        })
+    }
+
+    /// The call stack of a process, innermost first.
+    pub fn call_stack(&self, pid: Pid) -> Option<Vec<FrameInfo>> {
+        self.with_frames(pid, |frames| {
+            frames.iter().rev()
+                .map(|frame| FrameInfo {
+                    module: frame.module.clone(),
+                    function: frame.function.clone(),
+                    label: frame.label,
+                    op: frame.op,
+                })
+                .collect()
+        })
+    }
+
+    /// Prints the call stack of a process along with the next OP of each
+    /// frame.
+    pub fn print_call_stack(&self, pid: Pid) {
+        println!("Call stack of {:?}:", pid);
+        self.with_frames(pid, |frames| {
+            for (depth, frame) in frames.iter().rev().enumerate() {
+                let (_, lir) = self.erlang_function(&frame.module, &frame.function).unwrap();
+                let op = &lir.block(frame.label).ops[frame.op];
+                println!("  #{} {}:{} {}:{} {:?}",
+                         depth, frame.module, frame.function, frame.label, frame.op, op);
+            }
+        });
+    }
+
+    /// The variables of a frame of a process, `depth` 0 being the
+    /// innermost frame. Variables are listed with the Core variable they
+    /// were assigned for, where it is known.
+    pub fn frame_variables(&self, pid: Pid, depth: usize)
+                           -> Option<Vec<(SSAVariable, Option<Variable>, Term)>> {
+        self.with_frames(pid, |frames| {
+            let frame = match frames.iter().rev().nth(depth) {
+                Some(frame) => frame,
+                None => return None,
+            };
+            let names = self.variable_names(&frame.module, &frame.function);
+            let mut variables: Vec<_> = frame.variables.iter()
+                .map(|(ssa, term)| (*ssa, names.get(ssa).cloned(), term.clone()))
+                .collect();
+            variables.sort_by_key(|&(ssa, _, _)| ssa);
+            Some(variables)
+        }).and_then(|variables| variables)
+    }
+
+    /// Looks up a variable of a frame of a process by the Core variable
+    /// it was assigned for. Variables assigned several times, like in
+    /// case clauses, have the value assigned last in the SSA order.
+    pub fn frame_variable(&self, pid: Pid, depth: usize, name: &str) -> Option<Term> {
+        self.frame_variables(pid, depth)
+            .and_then(|variables| variables.into_iter()
+                      .filter(|(_, var, _)| var.as_ref().map(|v| &**v) == Some(name))
+                      .map(|(_, _, term)| term)
+                      .last())
+    }
+
+    fn variable_names(&self, module: &Atom, fun: &FunctionIdent)
+                      -> ::std::collections::HashMap<SSAVariable, Variable> {
+        let module_str: &str = module;
+        match self.modules.get(module_str) {
+            Some(ModuleType::Erlang(module)) => module.functions.iter()
+                .find(|function| &function.ident == fun)
+                .map(|function| function.hir_fun.variable_names())
+                .unwrap_or_default(),
+            _ => Default::default(),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+    use ::intern::Atom;
+    use ::interpreter::{ ExecutionContext, CallReturn, RunResult, Term };
+    use ::ir::FunctionIdent;
+
+    #[test]
+    fn breakpoints() {
+        let core = r##"
+module 'dbg' ['fact'/1] attributes []
+'fact'/1 =
+    fun (N) ->
+        case N of
+          <0> when 'true' -> 1
+          <M> when 'true' ->
+              let <M1> = call 'erlang':'-'(M, 1)
+              in let <F> = apply 'fact'/1(M1)
+                 in call 'erlang':'*'(M, F)
+        end
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let module = ::ir::from_parsed(&parsed.0);
+        let fact = FunctionIdent {
+            name: Atom::from_str("fact").unwrap(),
+            arity: 1,
+            lambda: None,
+        };
+        let entry = module.functions.iter()
+            .find(|function| function.ident == fact).unwrap()
+            .lir_function.as_ref().unwrap().entry();
+
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(::interpreter::lib::make_erlang());
+        ctx.add_erlang_module(module);
+        ctx.add_breakpoint("dbg", fact.clone(), entry);
+
+        ctx.start("dbg", "fact", &[Term::new_i64(2)]);
+        let pid = ctx.self_pid();
+        let mut depths = Vec::new();
+        let ret = loop {
+            match ctx.run(usize::MAX) {
+                RunResult::Breakpoint { pid: at } => {
+                    assert!(at == pid);
+                    let stack = ctx.call_stack(pid).unwrap();
+                    assert!(stack[0].label == entry && stack[0].op == 0);
+                    depths.push(stack.len());
+                    ctx.print_call_stack(pid);
+                },
+                RunResult::Finished(ret) => break ret,
+                RunResult::Yielded => unreachable!(),
+            }
+            // The arguments are bound by the first OP
+            match ctx.step() {
+                RunResult::Yielded => (),
+                ret => panic!("expected yield, got {:?}", ret),
+            }
+            let n = ctx.frame_variable(pid, 0, "N").unwrap();
+            assert!(n.erl_exact_eq(&Term::new_i64(3 - depths.len() as i64)), "{}", n);
+        };
+        assert!(depths == vec![1, 2, 3], "{:?}", depths);
+        match ret {
+            CallReturn::Return { term } =>
+                assert!(term.erl_exact_eq(&Term::new_i64(2))),
+            ret => panic!("unexpected {:?}", ret),
+        }
+
+        assert!(ctx.remove_breakpoint("dbg", fact, entry));
+        match ctx.call("dbg", "fact", &[Term::new_i64(3)]) {
+            CallReturn::Return { term } =>
+                assert!(term.erl_exact_eq(&Term::new_i64(6))),
+            ret => panic!("unexpected {:?}", ret),
+        }
+    }
+
+}
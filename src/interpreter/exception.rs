@@ -0,0 +1,198 @@
+//! Exceptions raised by Erlang code.
+//!
+//! An exception is passed along the throw edges of the LIR until it is
+//! caught by a try, or returned from the function. The catch variables
+//! of a try are bound to the class, the reason and the raw stack trace,
+//! which is `{Class, StackTrace}` with the stack trace in the form
+//! returned by the `build_stacktrace` primop, so that the `raise` primop
+//! can rethrow the exception unchanged.
+
+use ::std::fmt;
+
+use ::intern::Atom;
+use ::ir::FunctionIdent;
+use super::Term;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Error,
+    Throw,
+    Exit,
+}
+impl ExceptionClass {
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ExceptionClass::Error => "error",
+            ExceptionClass::Throw => "throw",
+            ExceptionClass::Exit => "exit",
+        }
+    }
+
+    pub fn from_term(term: &Term) -> Option<ExceptionClass> {
+        match *term {
+            Term::Atom(ref atom) => match &**atom {
+                "error" => Some(ExceptionClass::Error),
+                "throw" => Some(ExceptionClass::Throw),
+                "exit" => Some(ExceptionClass::Exit),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+}
+
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub class: ExceptionClass,
+    pub reason: Term,
+    /// The functions on the stack when the exception was raised, along
+    /// with their modules. Innermost first.
+    pub stacktrace: Vec<(Atom, FunctionIdent)>,
+}
+impl Exception {
+
+    /// Makes an exception with an empty stack trace, it is filled in by
+    /// the interpreter when raised.
+    pub fn new(class: ExceptionClass, reason: Term) -> Self {
+        Exception {
+            class,
+            reason,
+            stacktrace: Vec::new(),
+        }
+    }
+
+    pub fn error(reason: Term) -> Self {
+        Exception::new(ExceptionClass::Error, reason)
+    }
+
+    /// The stack trace as a list of `{Module, Function, Arity, Location}`.
+    /// Lambdas have `{lambda, N}` in their location.
+    pub fn stacktrace_term(&self) -> Term {
+        let entries = self.stacktrace.iter().map(|(module, ident)| {
+            let location = match ident.lambda {
+                Some(num) => vec![Term::Tuple(vec![
+                    Term::new_atom("lambda"), Term::new_i64(num as i64)])],
+                None => vec![],
+            };
+            Term::Tuple(vec![
+                Term::Atom(module.clone()),
+                Term::Atom(ident.name.clone()),
+                Term::new_i64(ident.arity as i64),
+                Term::proper_list(location),
+            ])
+        }).collect();
+        Term::proper_list(entries)
+    }
+
+    /// The raw stack trace bound by the third variable of a catch.
+    pub fn raw_stacktrace(&self) -> Term {
+        Term::Tuple(vec![Term::new_atom(self.class.as_str()), self.stacktrace_term()])
+    }
+
+    /// The reason a process exits with when the exception is not
+    /// caught.
+    pub fn exit_reason(&self) -> Term {
+        match self.class {
+            ExceptionClass::Exit => self.reason.clone(),
+            ExceptionClass::Error =>
+                Term::Tuple(vec![self.reason.clone(), self.stacktrace_term()]),
+            ExceptionClass::Throw => Term::Tuple(vec![
+                Term::Tuple(vec![Term::new_atom("nocatch"), self.reason.clone()]),
+                self.stacktrace_term(),
+            ]),
+        }
+    }
+
+    /// Rebuilds an exception from a raw stack trace, keeping its class
+    /// and stack trace. Returns None if the term is not a raw stack trace.
+    pub fn from_raw_stacktrace(raw: &Term, reason: Term) -> Option<Exception> {
+        let elems = match *raw {
+            Term::Tuple(ref elems) if elems.len() == 2 => elems,
+            _ => return None,
+        };
+        let class = ExceptionClass::from_term(&elems[0])?;
+        let stacktrace = elems[1].as_proper_list()?.iter()
+            .map(stacktrace_entry)
+            .collect::<Option<Vec<_>>>()?;
+        Some(Exception {
+            class,
+            reason,
+            stacktrace,
+        })
+    }
+
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.class.as_str(), self.reason)?;
+        for (module, ident) in self.stacktrace.iter() {
+            write!(f, "\n    in {}:{}", module, ident)?;
+        }
+        Ok(())
+    }
+}
+
+fn stacktrace_entry(entry: &Term) -> Option<(Atom, FunctionIdent)> {
+    let elems = match *entry {
+        Term::Tuple(ref elems) if elems.len() == 4 => elems,
+        _ => return None,
+    };
+    let lambda = elems[3].as_proper_list()?.iter()
+        .filter_map(|loc| match *loc {
+            Term::Tuple(ref kv) if kv.len() == 2 && kv[0] == Term::new_atom("lambda") =>
+                Some(&kv[1]),
+            _ => None,
+        })
+        .next();
+    match (&elems[0], &elems[1], &elems[2], lambda) {
+        (Term::Atom(module), Term::Atom(name), Term::Integer(arity), lambda) => {
+            use ::num_traits::ToPrimitive;
+            let lambda = match lambda {
+                Some(Term::Integer(num)) => Some(num.to_u32()?),
+                Some(_) => return None,
+                None => None,
+            };
+            Some((module.clone(), FunctionIdent {
+                name: name.clone(),
+                arity: arity.to_u32()?,
+                lambda,
+            }))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::Term;
+    use ::ir::FunctionIdent;
+    use super::{ Exception, ExceptionClass };
+
+    #[test]
+    fn raw_stacktrace_roundtrip() {
+        let module: ::intern::Atom = "m".parse().unwrap();
+        let ident = |name: &str, lambda| FunctionIdent {
+            name: name.parse().unwrap(),
+            arity: 1,
+            lambda,
+        };
+        let exception = Exception {
+            class: ExceptionClass::Throw,
+            reason: Term::new_atom("oops"),
+            stacktrace: vec![(module.clone(), ident("f", Some(0))),
+                             (module.clone(), ident("g", None))],
+        };
+
+        let raw = exception.raw_stacktrace();
+        let rethrown = Exception::from_raw_stacktrace(&raw, Term::new_i64(1)).unwrap();
+        assert!(rethrown.class == ExceptionClass::Throw);
+        assert!(rethrown.reason.erl_exact_eq(&Term::new_i64(1)));
+        assert!(rethrown.stacktrace == exception.stacktrace);
+
+        assert!(Exception::from_raw_stacktrace(&Term::Nil, Term::Nil).is_none());
+    }
+
+}
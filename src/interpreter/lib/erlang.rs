@@ -1,12 +1,828 @@
-use ::interpreter::{ NativeModule, Term, CallReturn };
+//! Native implementation of the BIFs in the `erlang` module.
 
-fn add(args: &[Term]) -> CallReturn {
-    // TODO
-    CallReturn::Return { term: Term::Nil }
+use ::std::str::FromStr;
+
+use ::num_bigint::BigInt;
+use ::num_traits::{ FromPrimitive, ToPrimitive, Zero, Signed };
+
+use ::intern::Atom;
+use ::ir::FunctionIdent;
+use ::interpreter::{ NativeModule, ExecutionContext, Term, CallReturn, ExceptionClass };
+use super::{ make_module, ok, raise, error, badarg, badarith, badmap, badkey };
+
+/// Largest shift accepted by `bsl`, larger shifts are a system limit.
+const MAX_SHIFT: usize = 1 << 24;
+
+fn to_float(term: &Term) -> Option<f64> {
+    match *term {
+        Term::Integer(ref int) => int.to_f64(),
+        Term::Float(float) => Some(float),
+        _ => None,
+    }
+}
+
+fn float_result(float: f64) -> CallReturn {
+    if float.is_finite() {
+        ok(Term::Float(float))
+    } else {
+        badarith()
+    }
+}
+
+fn to_bool(term: &Term) -> Option<bool> {
+    match *term {
+        Term::Atom(ref atom) if &**atom == "true" => Some(true),
+        Term::Atom(ref atom) if &**atom == "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Index of the 1-based position in a sequence of `len` elements.
+fn position(term: &Term, len: usize) -> Option<usize> {
+    match *term {
+        Term::Integer(ref int) => match int.to_usize() {
+            Some(pos) if pos >= 1 && pos <= len => Some(pos - 1),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn string_to_list(string: &str) -> Term {
+    Term::proper_list(string.chars().map(|c| Term::new_i64(c as i64)).collect())
+}
+
+fn list_to_string(term: &Term) -> Option<String> {
+    term.as_proper_list()?.iter()
+        .map(|elem| match *elem {
+            Term::Integer(ref int) => ::std::char::from_u32(int.to_u32()?),
+            _ => None,
+        })
+        .collect()
+}
+
+// ======== Arithmetic ========
+
+/// Applies a binary arithmetic operator. Integers are kept as integers,
+/// a float operand makes both floats.
+fn arith<I, F>(args: &[Term], int_op: I, float_op: F) -> CallReturn
+    where I: Fn(&BigInt, &BigInt) -> BigInt, F: Fn(f64, f64) -> f64
+{
+    match (&args[0], &args[1]) {
+        (Term::Integer(a), Term::Integer(b)) => ok(Term::Integer(int_op(a, b))),
+        (a, b) => match (to_float(a), to_float(b)) {
+            (Some(a), Some(b)) => float_result(float_op(a, b)),
+            _ => badarith(),
+        },
+    }
+}
+
+/// Applies an operator only defined on integers.
+fn int_arith<F>(args: &[Term], op: F) -> CallReturn
+    where F: Fn(&BigInt, &BigInt) -> Option<BigInt>
+{
+    match (&args[0], &args[1]) {
+        (Term::Integer(a), Term::Integer(b)) => match op(a, b) {
+            Some(int) => ok(Term::Integer(int)),
+            None => badarith(),
+        },
+        _ => badarith(),
+    }
+}
+
+fn add(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    arith(args, |a, b| a + b, |a, b| a + b)
+}
+
+fn sub(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    arith(args, |a, b| a - b, |a, b| a - b)
+}
+
+fn mul(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    arith(args, |a, b| a * b, |a, b| a * b)
+}
+
+fn fdiv(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (to_float(&args[0]), to_float(&args[1])) {
+        (Some(_), Some(0.0)) => badarith(),
+        (Some(a), Some(b)) => float_result(a / b),
+        _ => badarith(),
+    }
+}
+
+fn div(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    int_arith(args, |a, b| if b.is_zero() { None } else { Some(a / b) })
+}
+
+fn rem(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    int_arith(args, |a, b| if b.is_zero() { None } else { Some(a % b) })
+}
+
+fn band(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    int_arith(args, |a, b| Some(a & b))
+}
+
+fn bor(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    int_arith(args, |a, b| Some(a | b))
+}
+
+fn bxor(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    int_arith(args, |a, b| Some(a ^ b))
+}
+
+/// Shifts left by `shift` bits, right when negative.
+fn shift(int: &BigInt, shift: &BigInt) -> CallReturn {
+    let result = if shift.is_negative() {
+        match (-shift).to_usize() {
+            Some(bits) => int >> bits,
+            // Shifted out entirely
+            None => if int.is_negative() { BigInt::from(-1) } else { BigInt::zero() },
+        }
+    } else {
+        match shift.to_usize() {
+            Some(bits) if bits <= MAX_SHIFT => int << bits,
+            _ if int.is_zero() => BigInt::zero(),
+            _ => return error("system_limit"),
+        }
+    };
+    ok(Term::Integer(result))
+}
+
+fn bsl(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], &args[1]) {
+        (Term::Integer(a), Term::Integer(b)) => shift(a, b),
+        _ => badarith(),
+    }
+}
+
+fn bsr(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], &args[1]) {
+        (Term::Integer(a), Term::Integer(b)) => shift(a, &-b),
+        _ => badarith(),
+    }
+}
+
+fn bnot(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Integer(ref int) => ok(Term::Integer(-int - 1)),
+        _ => badarith(),
+    }
+}
+
+fn neg(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Integer(ref int) => ok(Term::Integer(-int)),
+        Term::Float(float) => ok(Term::Float(-float)),
+        _ => badarith(),
+    }
+}
+
+fn pos(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    if args[0].is_number() {
+        ok(args[0].clone())
+    } else {
+        badarith()
+    }
+}
+
+fn abs(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Integer(ref int) => ok(Term::Integer(int.abs())),
+        Term::Float(float) => ok(Term::Float(float.abs())),
+        _ => badarg(),
+    }
+}
+
+fn float(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match to_float(&args[0]) {
+        Some(float) => float_result(float),
+        None => badarg(),
+    }
+}
+
+/// Converts a number to an integer, rounding floats with `round`.
+fn to_integer<F>(term: &Term, round: F) -> CallReturn where F: Fn(f64) -> f64 {
+    match *term {
+        Term::Integer(_) => ok(term.clone()),
+        Term::Float(float) => match BigInt::from_f64(round(float)) {
+            Some(int) => ok(Term::Integer(int)),
+            None => badarg(),
+        },
+        _ => badarg(),
+    }
+}
+
+fn trunc(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    to_integer(&args[0], f64::trunc)
+}
+
+fn round(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    to_integer(&args[0], f64::round)
+}
+
+// ======== Comparison ========
+
+fn equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_eq(&args[1])))
+}
+
+fn not_equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(!args[0].erl_eq(&args[1])))
+}
+
+fn exact_equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_exact_eq(&args[1])))
+}
+
+fn exact_not_equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(!args[0].erl_exact_eq(&args[1])))
+}
+
+fn less(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_cmp(&args[1]).is_lt()))
+}
+
+fn greater(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_cmp(&args[1]).is_gt()))
+}
+
+fn less_equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_cmp(&args[1]).is_le()))
+}
+
+fn greater_equal(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(args[0].erl_cmp(&args[1]).is_ge()))
+}
+
+/// Returns the first of the two terms if they compare equal.
+fn min(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    if args[1].erl_cmp(&args[0]).is_lt() { ok(args[1].clone()) } else { ok(args[0].clone()) }
+}
+
+fn max(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    if args[1].erl_cmp(&args[0]).is_gt() { ok(args[1].clone()) } else { ok(args[0].clone()) }
+}
+
+// ======== Booleans ========
+
+fn bool_op<F>(args: &[Term], op: F) -> CallReturn where F: Fn(bool, bool) -> bool {
+    match (to_bool(&args[0]), to_bool(&args[1])) {
+        (Some(a), Some(b)) => ok(Term::new_bool(op(a, b))),
+        _ => badarg(),
+    }
+}
+
+fn and(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    bool_op(args, |a, b| a && b)
+}
+
+fn or(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    bool_op(args, |a, b| a || b)
+}
+
+fn xor(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    bool_op(args, |a, b| a != b)
+}
+
+fn not(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match to_bool(&args[0]) {
+        Some(val) => ok(Term::new_bool(!val)),
+        None => badarg(),
+    }
+}
+
+// ======== Type tests ========
+
+fn type_test<F>(args: &[Term], test: F) -> CallReturn where F: Fn(&Term) -> bool {
+    ok(Term::new_bool(test(&args[0])))
+}
+
+fn is_atom(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Atom(_)))
+}
+
+fn is_binary(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| match *t { Term::BitString(ref b) => b.is_binary(), _ => false })
+}
+
+fn is_bitstring(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::BitString(_)))
+}
+
+fn is_boolean(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| to_bool(t).is_some())
+}
+
+fn is_float(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Float(_)))
+}
+
+fn is_integer(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Integer(_)))
+}
+
+fn is_number(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| t.is_number())
+}
+
+fn is_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Nil | Term::List(_, _)))
+}
+
+fn is_map(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Map(_)))
+}
+
+fn is_pid(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Pid(_)))
+}
+
+/// There are no ports in the interpreter.
+fn is_port(_ctx: &ExecutionContext, _args: &[Term]) -> CallReturn {
+    ok(Term::new_bool(false))
+}
+
+fn is_reference(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Reference(_)))
+}
+
+fn is_tuple(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| matches!(*t, Term::Tuple(_)))
+}
+
+fn fun_arity(term: &Term) -> Option<u32> {
+    match *term {
+        Term::BoundLambda { ref fun, .. } => Some(fun.arity),
+        Term::CapturedFunction { arity, .. } => Some(arity),
+        _ => None,
+    }
+}
+
+fn is_function(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    type_test(args, |t| fun_arity(t).is_some())
+}
+
+fn is_function_arity(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Integer(ref arity) if !arity.is_negative() =>
+            ok(Term::new_bool(fun_arity(&args[0]).map(BigInt::from) == Some(arity.clone()))),
+        _ => badarg(),
+    }
+}
+
+// ======== Tuples, lists and maps ========
+
+fn element(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Tuple(ref elems) => match position(&args[0], elems.len()) {
+            Some(idx) => ok(elems[idx].clone()),
+            None => badarg(),
+        },
+        _ => badarg(),
+    }
+}
+
+fn setelement(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Tuple(ref elems) => match position(&args[0], elems.len()) {
+            Some(idx) => {
+                let mut elems = elems.clone();
+                elems[idx] = args[2].clone();
+                ok(Term::Tuple(elems))
+            },
+            None => badarg(),
+        },
+        _ => badarg(),
+    }
+}
+
+fn tuple_size(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Tuple(ref elems) => ok(Term::new_i64(elems.len() as i64)),
+        _ => badarg(),
+    }
+}
+
+/// The size of a tuple, or the number of bytes in a binary.
+fn size(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::BitString(_) => byte_size(ctx, args),
+        _ => tuple_size(ctx, args),
+    }
+}
+
+fn tuple_to_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Tuple(ref elems) => ok(Term::proper_list(elems.clone())),
+        _ => badarg(),
+    }
+}
+
+fn list_to_tuple(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0].as_proper_list() {
+        Some(elems) => ok(Term::Tuple(elems.to_vec())),
+        None => badarg(),
+    }
+}
+
+fn length(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0].as_proper_list() {
+        Some(elems) => ok(Term::new_i64(elems.len() as i64)),
+        None => badarg(),
+    }
+}
+
+fn hd(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::List(ref head, _) => ok(head[0].clone()),
+        _ => badarg(),
+    }
+}
+
+fn tl(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::List(ref head, ref tail) =>
+            ok(Term::list(head[1..].to_vec(), (**tail).clone())),
+        _ => badarg(),
+    }
+}
+
+fn append(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0].as_proper_list() {
+        Some(elems) => ok(Term::list(elems.to_vec(), args[1].clone())),
+        None => badarg(),
+    }
+}
+
+/// Removes the first occurrence of each element of the second list from
+/// the first.
+fn subtract(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (args[0].as_proper_list(), args[1].as_proper_list()) {
+        (Some(elems), Some(remove)) => {
+            let mut elems = elems.to_vec();
+            for term in remove.iter() {
+                if let Some(idx) = elems.iter().position(|e| e.erl_exact_eq(term)) {
+                    elems.remove(idx);
+                }
+            }
+            ok(Term::proper_list(elems))
+        },
+        _ => badarg(),
+    }
+}
+
+fn map_size(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Map(ref map) => ok(Term::new_i64(map.len() as i64)),
+        _ => badmap(&args[0]),
+    }
+}
+
+fn map_get(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Map(ref map) => match map.get(&args[0]) {
+            Some(value) => ok(value.clone()),
+            None => badkey(&args[0]),
+        },
+        _ => badmap(&args[1]),
+    }
+}
+
+fn is_map_key(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Map(ref map) => ok(Term::new_bool(map.contains_key(&args[0]))),
+        _ => badmap(&args[1]),
+    }
+}
+
+fn byte_size(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::BitString(ref bin) => ok(Term::new_i64(bin.bit_len.div_ceil(8) as i64)),
+        _ => badarg(),
+    }
+}
+
+fn bit_size(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::BitString(ref bin) => ok(Term::new_i64(bin.bit_len as i64)),
+        _ => badarg(),
+    }
+}
+
+// ======== Conversions ========
+
+fn atom_to_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Atom(ref atom) => ok(string_to_list(atom)),
+        _ => badarg(),
+    }
+}
+
+fn list_to_atom(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match list_to_string(&args[0]) {
+        Some(ref string) if string.chars().count() > 255 => error("system_limit"),
+        Some(string) => ok(Term::Atom(Atom::from_str(&string).unwrap())),
+        None => badarg(),
+    }
+}
+
+fn integer_to_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Integer(ref int) => ok(string_to_list(&int.to_string())),
+        _ => badarg(),
+    }
+}
+
+fn list_to_integer(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    let string = match list_to_string(&args[0]) {
+        Some(string) => string,
+        None => return badarg(),
+    };
+    // BigInt accepts a leading plus, as does Erlang
+    let digits = string.trim_start_matches(&['+', '-'][..]);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return badarg();
+    }
+    match BigInt::from_str(&string) {
+        Ok(int) => ok(Term::Integer(int)),
+        Err(_) => badarg(),
+    }
+}
+
+// ======== Exceptions and processes ========
+
+fn throw(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    raise(ExceptionClass::Throw, args[0].clone())
+}
+
+/// `error/1` and `error/2`, the arguments given to `error/2` are not
+/// kept.
+fn error_bif(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    raise(ExceptionClass::Error, args[0].clone())
+}
+
+fn exit(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    raise(ExceptionClass::Exit, args[0].clone())
+}
+
+fn make_ref(ctx: &ExecutionContext, _args: &[Term]) -> CallReturn {
+    ok(Term::Reference(ctx.make_ref()))
+}
+
+fn self_bif(ctx: &ExecutionContext, _args: &[Term]) -> CallReturn {
+    ok(Term::Pid(ctx.self_pid()))
+}
+
+/// Spawns a process calling a fun without arguments.
+fn spawn_fun(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match ::interpreter::resolve_fun(&args[0], Vec::new()) {
+        Ok((module, fun, args)) => ok(Term::Pid(ctx.spawn(module, fun, args))),
+        Err(_) => badarg(),
+    }
+}
+
+/// Spawns a process calling `Module:Function(Args...)`.
+fn spawn_mfa(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], &args[1], args[2].as_proper_list()) {
+        (Term::Atom(module), Term::Atom(name), Some(fun_args)) => {
+            let fun = FunctionIdent {
+                name: name.clone(),
+                arity: fun_args.len() as u32,
+                lambda: None,
+            };
+            ok(Term::Pid(ctx.spawn(module.clone(), fun, fun_args.to_vec())))
+        },
+        _ => badarg(),
+    }
+}
+
+fn spawn_link_fun(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    link_spawned(ctx, spawn_fun(ctx, args))
+}
+
+fn spawn_link_mfa(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    link_spawned(ctx, spawn_mfa(ctx, args))
+}
+
+/// Links to the process returned by a spawn, before it first runs.
+fn link_spawned(ctx: &ExecutionContext, spawned: CallReturn) -> CallReturn {
+    if let CallReturn::Return { term: Term::Pid(pid) } = spawned {
+        ctx.link(pid);
+    }
+    spawned
+}
+
+/// Linking to a process that is not alive fails with `noproc`, or sends
+/// an exit message when trapping exits.
+fn link(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    let pid = match args[0] {
+        Term::Pid(pid) => pid,
+        _ => return badarg(),
+    };
+    if ctx.link(pid) {
+        return ok(Term::new_bool(true));
+    }
+    if ctx.traps_exit() {
+        let message = Term::Tuple(vec![
+            Term::new_atom("EXIT"), args[0].clone(), Term::new_atom("noproc")]);
+        ctx.send(ctx.self_pid(), message);
+        ok(Term::new_bool(true))
+    } else {
+        error("noproc")
+    }
+}
+
+fn unlink(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Pid(pid) => {
+            ctx.unlink(pid);
+            ok(Term::new_bool(true))
+        },
+        _ => badarg(),
+    }
+}
+
+/// `monitor(process, Pid)`, returns the reference of the monitor.
+fn monitor(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], &args[1]) {
+        (Term::Atom(kind), &Term::Pid(pid)) if &**kind == "process" =>
+            ok(Term::Reference(ctx.monitor(pid))),
+        _ => badarg(),
+    }
+}
+
+fn demonitor(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Reference(reference) => {
+            ctx.demonitor(reference);
+            ok(Term::new_bool(true))
+        },
+        _ => badarg(),
+    }
+}
+
+/// Only the `trap_exit` flag is supported, returns its old value.
+fn process_flag(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], to_bool(&args[1])) {
+        (Term::Atom(flag), Some(value)) if &**flag == "trap_exit" =>
+            ok(Term::new_bool(ctx.set_trap_exit(value))),
+        _ => badarg(),
+    }
+}
+
+/// Sends an exit signal to a process, `kill` can not be trapped.
+fn exit_signal(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Pid(pid) => {
+            let kill = args[1] == Term::new_atom("kill");
+            ctx.exit_signal(ctx.self_pid(), pid, args[1].clone(), kill);
+            ok(Term::new_bool(true))
+        },
+        _ => badarg(),
+    }
+}
+
+fn is_process_alive(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Pid(pid) => ok(Term::new_bool(ctx.is_alive(pid))),
+        _ => badarg(),
+    }
+}
+
+/// `!/2` and `send/2`, returns the message.
+fn send(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Pid(pid) => {
+            ctx.send(pid, args[1].clone());
+            ok(args[1].clone())
+        },
+        _ => badarg(),
+    }
 }
 
 pub fn make_erlang() -> NativeModule {
-    let mut module = NativeModule::new("erlang".to_string());
-    module.add_fun("+".to_string(), 2, Box::new(add));
-    module
+    make_module("erlang", &[
+        ("+", 2, add), ("-", 2, sub), ("*", 2, mul), ("/", 2, fdiv),
+        ("div", 2, div), ("rem", 2, rem),
+        ("band", 2, band), ("bor", 2, bor), ("bxor", 2, bxor),
+        ("bsl", 2, bsl), ("bsr", 2, bsr), ("bnot", 1, bnot),
+        ("-", 1, neg), ("+", 1, pos), ("abs", 1, abs),
+        ("float", 1, float), ("trunc", 1, trunc), ("round", 1, round),
+
+        ("==", 2, equal), ("/=", 2, not_equal),
+        ("=:=", 2, exact_equal), ("=/=", 2, exact_not_equal),
+        ("<", 2, less), (">", 2, greater), ("=<", 2, less_equal), (">=", 2, greater_equal),
+        ("min", 2, min), ("max", 2, max),
+
+        ("and", 2, and), ("or", 2, or), ("xor", 2, xor), ("not", 1, not),
+
+        ("is_atom", 1, is_atom), ("is_binary", 1, is_binary),
+        ("is_bitstring", 1, is_bitstring), ("is_boolean", 1, is_boolean),
+        ("is_float", 1, is_float), ("is_function", 1, is_function),
+        ("is_function", 2, is_function_arity), ("is_integer", 1, is_integer),
+        ("is_list", 1, is_list), ("is_map", 1, is_map), ("is_number", 1, is_number),
+        ("is_pid", 1, is_pid), ("is_port", 1, is_port),
+        ("is_reference", 1, is_reference), ("is_tuple", 1, is_tuple),
+
+        ("element", 2, element), ("setelement", 3, setelement),
+        ("tuple_size", 1, tuple_size), ("size", 1, size),
+        ("tuple_to_list", 1, tuple_to_list), ("list_to_tuple", 1, list_to_tuple),
+        ("length", 1, length), ("hd", 1, hd), ("tl", 1, tl),
+        ("++", 2, append), ("--", 2, subtract),
+        ("map_size", 1, map_size), ("map_get", 2, map_get), ("is_map_key", 2, is_map_key),
+        ("byte_size", 1, byte_size), ("bit_size", 1, bit_size),
+
+        ("atom_to_list", 1, atom_to_list), ("list_to_atom", 1, list_to_atom),
+        ("integer_to_list", 1, integer_to_list), ("list_to_integer", 1, list_to_integer),
+
+        ("throw", 1, throw), ("error", 1, error_bif), ("error", 2, error_bif),
+        ("exit", 1, exit), ("make_ref", 0, make_ref), ("self", 0, self_bif),
+        ("spawn", 1, spawn_fun), ("spawn", 3, spawn_mfa), ("!", 2, send), ("send", 2, send),
+        ("spawn_link", 1, spawn_link_fun), ("spawn_link", 3, spawn_link_mfa),
+        ("link", 1, link), ("unlink", 1, unlink),
+        ("monitor", 2, monitor), ("demonitor", 1, demonitor),
+        ("process_flag", 2, process_flag), ("exit", 2, exit_signal),
+        ("is_process_alive", 1, is_process_alive),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, Term, ExceptionClass };
+
+    fn call(ctx: &ExecutionContext, name: &str, args: &[Term]) -> Result<Term, (ExceptionClass, Term)> {
+        match ctx.call("erlang", name, args) {
+            CallReturn::Return { term } => Ok(term),
+            CallReturn::Throw { exception } => Err((exception.class, exception.reason)),
+        }
+    }
+
+    #[test]
+    fn bifs() {
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(super::make_erlang());
+        let int = Term::new_i64;
+        let atom = Term::new_atom;
+        let returns = |name: &str, args: &[Term], expected: Term| {
+            let ret = call(&ctx, name, args).unwrap();
+            assert!(ret.erl_exact_eq(&expected), "{}: {} /= {}", name, ret, expected);
+        };
+        let errors = |name: &str, args: &[Term], reason: &str| {
+            let (class, ret) = call(&ctx, name, args).unwrap_err();
+            assert!(class == ExceptionClass::Error);
+            assert!(ret.erl_exact_eq(&atom(reason)), "{}: {}", name, ret);
+        };
+
+        returns("+", &[int(1), Term::Float(0.5)], Term::Float(1.5));
+        returns("-", &[int(7), int(2)], int(5));
+        returns("/", &[int(1), int(2)], Term::Float(0.5));
+        returns("div", &[int(-7), int(2)], int(-3));
+        returns("rem", &[int(-7), int(2)], int(-1));
+        returns("bsr", &[int(-5), int(1)], int(-3));
+        returns("bsl", &[int(1), int(70)], Term::Integer(::num_bigint::BigInt::from(1) << 70));
+        returns("round", &[Term::Float(-2.5)], int(-3));
+        errors("+", &[atom("a"), int(1)], "badarith");
+        errors("div", &[int(1), int(0)], "badarith");
+        errors("/", &[int(1), int(0)], "badarith");
+
+        returns("==", &[int(1), Term::Float(1.0)], atom("true"));
+        returns("=:=", &[int(1), Term::Float(1.0)], atom("false"));
+        returns("<", &[int(1), atom("a")], atom("true"));
+        returns("and", &[atom("true"), atom("false")], atom("false"));
+        errors("and", &[atom("true"), int(1)], "badarg");
+
+        let tuple = Term::Tuple(vec![atom("a"), atom("b")]);
+        returns("element", &[int(2), tuple.clone()], atom("b"));
+        returns("setelement", &[int(1), tuple.clone(), int(1)],
+                Term::Tuple(vec![int(1), atom("b")]));
+        errors("element", &[int(3), tuple.clone()], "badarg");
+        returns("tuple_size", ::std::slice::from_ref(&tuple), int(2));
+        returns("is_tuple", &[tuple], atom("true"));
+
+        let list = Term::proper_list(vec![int(1), int(2), int(1)]);
+        returns("length", ::std::slice::from_ref(&list), int(3));
+        returns("hd", ::std::slice::from_ref(&list), int(1));
+        returns("tl", ::std::slice::from_ref(&list), Term::proper_list(vec![int(2), int(1)]));
+        returns("--", &[list.clone(), Term::proper_list(vec![int(1)])],
+                Term::proper_list(vec![int(2), int(1)]));
+        errors("length", &[Term::list(vec![int(1)], int(2))], "badarg");
+        errors("hd", &[Term::Nil], "badarg");
+
+        returns("atom_to_list", &[atom("hi")], Term::proper_list(vec![int(104), int(105)]));
+        returns("list_to_atom", &[Term::proper_list(vec![int(104), int(105)])], atom("hi"));
+        returns("integer_to_list", &[int(-12)],
+                Term::proper_list(vec![int(45), int(49), int(50)]));
+        returns("list_to_integer", &[Term::proper_list(vec![int(45), int(49)])], int(-1));
+        errors("list_to_integer", &[Term::proper_list(vec![int(97)])], "badarg");
+
+        let (class, reason) = call(&ctx, "throw", &[int(1)]).unwrap_err();
+        assert!(class == ExceptionClass::Throw && reason.erl_exact_eq(&int(1)));
+        let (class, _) = call(&ctx, "exit", &[atom("normal")]).unwrap_err();
+        assert!(class == ExceptionClass::Exit);
+
+        let (a, b) = (call(&ctx, "make_ref", &[]).unwrap(), call(&ctx, "make_ref", &[]).unwrap());
+        assert!(!a.erl_exact_eq(&b));
+        returns("is_pid", &[call(&ctx, "self", &[]).unwrap()], atom("true"));
+    }
+
 }
@@ -0,0 +1,222 @@
+//! Native implementation of the commonly used functions of the `lists`
+//! module. Higher order functions apply the funs one element at a time,
+//! continuing with the next element when the fun returns, see
+//! `NativeStep`.
+
+use ::num_bigint::BigInt;
+use ::num_traits::ToPrimitive;
+
+use ::interpreter::{ NativeModule, NativeStep, ExecutionContext, Term, CallReturn };
+use super::{ make_module, add_applying, ok, badarg };
+
+fn reverse(ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    reverse_tail(ctx, &[args[0].clone(), Term::Nil])
+}
+
+/// Reverses the list in front of the tail.
+fn reverse_tail(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0].as_proper_list() {
+        Some(elems) => ok(Term::list(elems.iter().rev().cloned().collect(), args[1].clone())),
+        None => badarg(),
+    }
+}
+
+fn member(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1].as_proper_list() {
+        Some(elems) => ok(Term::new_bool(elems.iter().any(|e| e.erl_exact_eq(&args[0])))),
+        None => badarg(),
+    }
+}
+
+/// Finds the first tuple whose nth element compares equal to the key,
+/// returns false if there is none.
+fn keyfind(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    let (key, list) = (&args[0], &args[2]);
+    let idx = match args[1] {
+        Term::Integer(ref int) => match int.to_usize() {
+            Some(pos) if pos >= 1 => pos - 1,
+            _ => return badarg(),
+        },
+        _ => return badarg(),
+    };
+    let elems = match list.as_proper_list() {
+        Some(elems) => elems,
+        None => return badarg(),
+    };
+    let found = elems.iter().find(|elem| match **elem {
+        Term::Tuple(ref tuple) => tuple.len() > idx && tuple[idx].erl_eq(key),
+        _ => false,
+    });
+    match found {
+        Some(tuple) => ok(tuple.clone()),
+        None => ok(Term::new_bool(false)),
+    }
+}
+
+fn append(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0].as_proper_list() {
+        Some(elems) => ok(Term::list(elems.to_vec(), args[1].clone())),
+        None => badarg(),
+    }
+}
+
+fn map(_ctx: &ExecutionContext, args: &[Term]) -> NativeStep {
+    match args[1].as_proper_list() {
+        Some(elems) => map_next(args[0].clone(), elems.to_vec(), Vec::new()),
+        None => NativeStep::Return(badarg()),
+    }
+}
+
+/// Applies the fun to the first element not yet mapped.
+fn map_next(fun: Term, elems: Vec<Term>, mut mapped: Vec<Term>) -> NativeStep {
+    if mapped.len() == elems.len() {
+        return NativeStep::Return(ok(Term::proper_list(mapped)));
+    }
+    let args = vec![elems[mapped.len()].clone()];
+    NativeStep::Apply {
+        fun: fun.clone(),
+        args,
+        then: Box::new(move |_ctx, ret| match ret {
+            CallReturn::Return { term } => {
+                mapped.push(term);
+                map_next(fun, elems, mapped)
+            },
+            throw => NativeStep::Return(throw),
+        }),
+    }
+}
+
+fn foldl(_ctx: &ExecutionContext, args: &[Term]) -> NativeStep {
+    match args[2].as_proper_list() {
+        Some(elems) => foldl_next(args[0].clone(), args[1].clone(), elems.to_vec(), 0),
+        None => NativeStep::Return(badarg()),
+    }
+}
+
+/// Applies the fun to the element at `idx` and the accumulator.
+fn foldl_next(fun: Term, acc: Term, elems: Vec<Term>, idx: usize) -> NativeStep {
+    if idx == elems.len() {
+        return NativeStep::Return(ok(acc));
+    }
+    let args = vec![elems[idx].clone(), acc];
+    NativeStep::Apply {
+        fun: fun.clone(),
+        args,
+        then: Box::new(move |_ctx, ret| match ret {
+            CallReturn::Return { term } => foldl_next(fun, term, elems, idx + 1),
+            throw => NativeStep::Return(throw),
+        }),
+    }
+}
+
+/// The integers from the first to the last, which may be one less than
+/// the first for an empty list.
+fn seq(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    let (from, to) = match (&args[0], &args[1]) {
+        (Term::Integer(from), Term::Integer(to)) => (from, to),
+        _ => return badarg(),
+    };
+    if *to < from - 1 {
+        return badarg();
+    }
+    let mut elems = Vec::new();
+    let mut int: BigInt = from.clone();
+    while int <= *to {
+        elems.push(Term::Integer(int.clone()));
+        int += 1;
+    }
+    ok(Term::proper_list(elems))
+}
+
+pub fn make_lists() -> NativeModule {
+    let mut module = make_module("lists", &[
+        ("reverse", 1, reverse), ("reverse", 2, reverse_tail),
+        ("member", 2, member), ("keyfind", 3, keyfind), ("append", 2, append),
+        ("seq", 2, seq),
+    ]);
+    add_applying(&mut module, &[("map", 2, map), ("foldl", 3, foldl)]);
+    module
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, RunResult, Term };
+
+    #[test]
+    fn higher_order_funs() {
+        let core = r##"
+module 'test' ['double'/0, 'sum'/1, 'worker'/2, 'collect'/1] attributes []
+'double'/0 =
+    fun () -> fun (X) -> call 'erlang':'*'(X, 2)
+'worker'/2 =
+    fun (Parent, N) ->
+        let <Self> = call 'erlang':'self'()
+        in let <R> = call 'erlang':'*'(N, 2)
+           in call 'erlang':'!'(Parent, {Self, R})
+'collect'/1 =
+    fun (L) ->
+        let <Parent> = call 'erlang':'self'()
+        in let <Pids> = call 'lists':'map'
+                   (fun (N) -> call 'erlang':'spawn'('test', 'worker', [Parent|[N|[]]]), L)
+           in call 'lists':'map'
+                  (fun (P) ->
+                       receive <{From, R}> when call 'erlang':'=:='(From, P) -> R
+                       after 'infinity' -> 'timeout', Pids)
+'sum'/1 =
+    fun (L) ->
+        call 'lists':'foldl'(fun 'erlang':'+'/2, 0, L)
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(::interpreter::lib::make_erlang());
+        ctx.add_native_module(super::make_lists());
+        ctx.add_erlang_module(::ir::from_parsed(&parsed.0));
+
+        let returned = |ret: CallReturn| match ret {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+        };
+        let ints = |ints: &[i64]| Term::proper_list(ints.iter().map(|i| Term::new_i64(*i)).collect());
+
+        let seq = returned(ctx.call("lists", "seq", &[Term::new_i64(1), Term::new_i64(3)]));
+        assert!(seq.erl_exact_eq(&ints(&[1, 2, 3])));
+
+        let double = returned(ctx.call("test", "double", &[]));
+        let doubled = returned(ctx.call("lists", "map", &[double, seq.clone()]));
+        assert!(doubled.erl_exact_eq(&ints(&[2, 4, 6])), "{}", doubled);
+
+        let sum = returned(ctx.call("test", "sum", &[doubled]));
+        assert!(sum.erl_exact_eq(&Term::new_i64(12)));
+
+        let reversed = returned(ctx.call("lists", "reverse", &[seq]));
+        assert!(reversed.erl_exact_eq(&ints(&[3, 2, 1])));
+
+        // The exception from the fun is passed through
+        let not_numbers = Term::proper_list(vec![Term::new_atom("a")]);
+        match ctx.call("test", "sum", &[not_numbers]) {
+            CallReturn::Throw { exception } =>
+                assert!(exception.reason.erl_exact_eq(&Term::new_atom("badarith"))),
+            ret => panic!("expected throw, got {:?}", ret),
+        }
+
+        // The funs run in the calling process, and can wait for messages
+        let ret = returned(ctx.call("test", "collect", &[ints(&[1, 2, 3])]));
+        assert!(ret.erl_exact_eq(&ints(&[2, 4, 6])), "{}", ret);
+
+        // One OP at a time, rather than all at once
+        let double = returned(ctx.call("test", "double", &[]));
+        ctx.start("lists", "map", &[double, ints(&[1, 2, 3])]);
+        let mut steps = 0;
+        let doubled = loop {
+            match ctx.step() {
+                RunResult::Yielded => steps += 1,
+                RunResult::Finished(ret) => break returned(ret),
+                RunResult::Breakpoint { .. } => unreachable!(),
+            }
+        };
+        assert!(doubled.erl_exact_eq(&ints(&[2, 4, 6])), "{}", doubled);
+        assert!(steps >= 3, "{}", steps);
+    }
+
+}
@@ -0,0 +1,141 @@
+//! Native implementation of the commonly used functions of the `maps`
+//! module.
+
+use ::std::collections::BTreeMap;
+
+use ::interpreter::{ NativeModule, ExecutionContext, Term, CallReturn };
+use super::{ make_module, ok, badarg, badmap, badkey };
+
+fn get(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Map(ref map) => match map.get(&args[0]) {
+            Some(value) => ok(value.clone()),
+            None => badkey(&args[0]),
+        },
+        _ => badmap(&args[1]),
+    }
+}
+
+fn get_default(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Map(ref map) => ok(map.get(&args[0]).unwrap_or(&args[2]).clone()),
+        _ => badmap(&args[1]),
+    }
+}
+
+fn put(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[2] {
+        Term::Map(ref map) => {
+            let mut map = map.clone();
+            map.insert(args[0].clone(), args[1].clone());
+            ok(Term::Map(map))
+        },
+        _ => badmap(&args[2]),
+    }
+}
+
+/// Returns `{ok, Value}`, or `error` if the key is not in the map.
+fn find(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[1] {
+        Term::Map(ref map) => match map.get(&args[0]) {
+            Some(value) => ok(Term::Tuple(vec![Term::new_atom("ok"), value.clone()])),
+            None => ok(Term::new_atom("error")),
+        },
+        _ => badmap(&args[1]),
+    }
+}
+
+fn keys(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Map(ref map) => ok(Term::proper_list(map.keys().cloned().collect())),
+        _ => badmap(&args[0]),
+    }
+}
+
+fn to_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match args[0] {
+        Term::Map(ref map) => ok(Term::proper_list(
+            map.iter()
+                .map(|(k, v)| Term::Tuple(vec![k.clone(), v.clone()]))
+                .collect())),
+        _ => badmap(&args[0]),
+    }
+}
+
+/// Builds a map from a list of `{Key, Value}`, later keys replace
+/// earlier ones.
+fn from_list(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    let elems = match args[0].as_proper_list() {
+        Some(elems) => elems,
+        None => return badarg(),
+    };
+    let mut map = BTreeMap::new();
+    for elem in elems.iter() {
+        match *elem {
+            Term::Tuple(ref kv) if kv.len() == 2 => {
+                map.insert(kv[0].clone(), kv[1].clone());
+            },
+            _ => return badarg(),
+        }
+    }
+    ok(Term::Map(map))
+}
+
+/// Merges two maps, the values of the second take precedence.
+fn merge(_ctx: &ExecutionContext, args: &[Term]) -> CallReturn {
+    match (&args[0], &args[1]) {
+        (Term::Map(a), Term::Map(b)) => {
+            let mut map = a.clone();
+            map.extend(b.iter().map(|(k, v)| (k.clone(), v.clone())));
+            ok(Term::Map(map))
+        },
+        (&Term::Map(_), other) | (other, _) => badmap(other),
+    }
+}
+
+pub fn make_maps() -> NativeModule {
+    make_module("maps", &[
+        ("get", 2, get), ("get", 3, get_default), ("put", 3, put), ("find", 2, find),
+        ("keys", 1, keys), ("to_list", 1, to_list), ("from_list", 1, from_list),
+        ("merge", 2, merge),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, Term };
+
+    #[test]
+    fn map_functions() {
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(super::make_maps());
+        let call = |name: &str, args: &[Term]| match ctx.call("maps", name, args) {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+        };
+        let pair = |k: &str, v: i64| Term::Tuple(vec![Term::new_atom(k), Term::new_i64(v)]);
+
+        let map = call("from_list", &[Term::proper_list(vec![
+            pair("b", 1), pair("a", 2), pair("b", 3)])]);
+        let expected = Term::proper_list(vec![pair("a", 2), pair("b", 3)]);
+        assert!(call("to_list", ::std::slice::from_ref(&map)).erl_exact_eq(&expected));
+
+        let other = call("put", &[Term::new_atom("c"), Term::new_i64(4), map.clone()]);
+        let merged = call("merge", &[map.clone(), other]);
+        let keys = Term::proper_list(vec![
+            Term::new_atom("a"), Term::new_atom("b"), Term::new_atom("c")]);
+        assert!(call("keys", ::std::slice::from_ref(&merged)).erl_exact_eq(&keys));
+
+        assert!(call("find", &[Term::new_atom("c"), merged]).erl_exact_eq(&pair("ok", 4)));
+        assert!(call("get", &[Term::new_atom("x"), map.clone(), Term::Nil])
+                .erl_exact_eq(&Term::Nil));
+        match ctx.call("maps", "get", &[Term::new_atom("x"), map]) {
+            CallReturn::Throw { exception } => {
+                let reason = Term::Tuple(vec![Term::new_atom("badkey"), Term::new_atom("x")]);
+                assert!(exception.reason.erl_exact_eq(&reason));
+            },
+            ret => panic!("expected throw, got {:?}", ret),
+        }
+    }
+
+}
@@ -1,2 +1,59 @@
+//! Native modules for the interpreter.
+
+use ::interpreter::{ NativeModule, NativeStep, ExecutionContext, Term, CallReturn,
+                     Exception, ExceptionClass };
+
 mod erlang;
 pub use self::erlang::make_erlang;
+mod lists;
+pub use self::lists::make_lists;
+mod maps;
+pub use self::maps::make_maps;
+
+type Bif = fn(&ExecutionContext, &[Term]) -> CallReturn;
+
+/// A BIF that applies funs, see `NativeStep`.
+type ApplyingBif = fn(&ExecutionContext, &[Term]) -> NativeStep;
+
+fn make_module(name: &str, funs: &[(&str, u32, Bif)]) -> NativeModule {
+    let mut module = NativeModule::new(name.to_string());
+    for &(fun_name, arity, fun) in funs.iter() {
+        module.add_fun(fun_name.to_string(), arity,
+                       Box::new(move |ctx, args| NativeStep::Return(fun(ctx, args))));
+    }
+    module
+}
+
+fn add_applying(module: &mut NativeModule, funs: &[(&str, u32, ApplyingBif)]) {
+    for &(fun_name, arity, fun) in funs.iter() {
+        module.add_fun(fun_name.to_string(), arity, Box::new(fun));
+    }
+}
+
+fn ok(term: Term) -> CallReturn {
+    CallReturn::Return { term }
+}
+
+fn raise(class: ExceptionClass, reason: Term) -> CallReturn {
+    CallReturn::Throw { exception: Exception::new(class, reason) }
+}
+
+fn error(reason: &str) -> CallReturn {
+    raise(ExceptionClass::Error, Term::new_atom(reason))
+}
+
+fn badarg() -> CallReturn {
+    error("badarg")
+}
+
+fn badarith() -> CallReturn {
+    error("badarith")
+}
+
+pub(super) fn badmap(term: &Term) -> CallReturn {
+    raise(ExceptionClass::Error, Term::Tuple(vec![Term::new_atom("badmap"), term.clone()]))
+}
+
+pub(super) fn badkey(key: &Term) -> CallReturn {
+    raise(ExceptionClass::Error, Term::Tuple(vec![Term::new_atom("badkey"), key.clone()]))
+}
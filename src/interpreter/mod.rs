@@ -1,22 +1,71 @@
 //! LIR interpreter with zero consideration of performance.
 //! Made as an experiment to narrow down relevant implementation
 //! details.
+//!
+//! Erlang code runs in processes, see `process`. Every process keeps
+//! the functions it executes on a stack of its own, so that it can be
+//! suspended between any two OPs. Erlang calls never recurse in Rust.
+//! Native functions that apply funs, like `lists:map`, return the fun to
+//! apply along with a continuation, which waits on the stack for the fun
+//! to return, see `NativeStep`.
+//!
+//! `call` runs a function to completion. `start` and `run` execute it a
+//! bounded number of OPs at a time instead, and stop at breakpoints, see
+//! `debug`.
 
 use ::intern::Atom;
 use ::ir::{ Module, FunctionIdent, SSAVariable };
-use ::ir::lir::{ BasicBlock, LabelN, OpKind, Source };
-use ::parser::{ Constant, AtomicLiteral };
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind, Source };
+use ::parser::MapPairType;
+use std::cell::{ Cell, RefCell };
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet, BTreeMap, VecDeque };
 
 pub mod lib;
 
+mod term;
+pub use self::term::{ Term, BitString, Pid, Reference };
+
+mod exception;
+pub use self::exception::{ Exception, ExceptionClass };
+
+mod primop;
+pub use self::primop::{ PrimOpFun, PrimOpReturn };
+
+mod process;
+use self::process::{ Process, Mailbox, ROOT_PID };
+
+mod signal;
+use self::signal::Signals;
+
+mod debug;
+pub use self::debug::FrameInfo;
+
+mod binary;
+mod pattern;
+
+/// A native function is called with the context it runs in, and its
+/// arguments.
+pub type NativeFun = Box<dyn Fn(&ExecutionContext, &[Term]) -> NativeStep>;
+
+/// Continues a native function with the result of the fun it applied.
+pub type Continuation = Box<dyn FnOnce(&ExecutionContext, CallReturn) -> NativeStep>;
+
+/// What a native function does next.
+pub enum NativeStep {
+    Return(CallReturn),
+    /// Applies the fun in the calling process, like a call from Erlang
+    /// code. The fun can be preempted and wait for messages, `then` is
+    /// called with its result or the exception it raised.
+    Apply { fun: Term, args: Vec<Term>, then: Continuation },
+}
+
 pub struct NativeModule {
     name: String,
-    functions: HashMap<(String, u32), Box<Fn(&[Term]) -> CallReturn>>,
+    functions: HashMap<(String, u32), NativeFun>,
 }
 impl NativeModule {
-    
+
     fn new(name: String) -> Self {
         NativeModule {
             name: name,
@@ -24,7 +73,7 @@ impl NativeModule {
         }
     }
 
-    fn add_fun(&mut self, name: String, arity: u32, fun: Box<Fn(&[Term]) -> CallReturn>) {
+    fn add_fun(&mut self, name: String, arity: u32, fun: NativeFun) {
         self.functions.insert((name, arity), fun);
     }
 
@@ -35,69 +84,279 @@ enum ModuleType {
     Native(NativeModule),
 }
 
-#[derive(Debug, Clone)]
-pub enum Term {
-    Nil,
-    Atom(Atom),
+#[derive(Debug)]
+pub enum CallReturn {
+    Return { term: Term },
+    Throw { exception: Exception },
+}
+
+/// Result of running a started call for a budget of OPs.
+#[derive(Debug)]
+pub enum RunResult {
+    /// The budget ran out, the call continues with the next `run`.
+    Yielded,
+    /// A frame of the process entered a block with a breakpoint, the
+    /// call continues with the next `run`.
+    Breakpoint { pid: Pid },
+    Finished(CallReturn),
 }
-impl Term {
-    
-    fn atom_str<'a>(&'a self) -> &'a str {
-        if let Term::Atom(ref atom) = *self {
-            *&atom
-        } else {
-            panic!();
-        }
-    }
 
+/// State of a case structure, kept from the `Case` OP until control flow
+/// leaves the structure.
+struct CaseState {
+    /// The values bound by the patterns of the clause.
+    binds: Vec<Term>,
+    /// Where matching resumes in the decision tree if the guard of the
+    /// clause fails.
+    resume: Option<pattern::TreeWalk>,
+    /// Set by `CaseGuardFail`.
+    guard_failed: bool,
 }
 
-#[derive(Debug)]
-pub enum CallReturn {
-    Return { term: Term },
-    Throw,
+struct ReceiveState {
+    /// Virtual time the receive times out at, None for infinity.
+    deadline: Option<u64>,
+    /// Position in the mailbox of the message being matched.
+    cursor: usize,
+    /// Whether the message at the cursor has been fetched by a
+    /// `ReceiveGetMessage`.
+    peeked: bool,
 }
 
+/// An Erlang function being executed.
 struct StackFrame {
+    module: Atom,
+    function: FunctionIdent,
+    /// The block being executed, and the block jumped to it from.
+    label: LabelN,
+    prev: Option<LabelN>,
+    /// Index of the next OP to execute in the block.
+    op: usize,
     variables: HashMap<SSAVariable, Term>,
+    /// Case and receive structures, keyed by their pseudo-value.
+    cases: HashMap<SSAVariable, CaseState>,
+    receives: HashMap<SSAVariable, ReceiveState>,
+    /// The exception passed along the last throw edge taken.
+    exception: Option<Exception>,
 }
 impl StackFrame {
 
-    fn new() -> Self {
+    fn new(module: Atom, function: FunctionIdent, entry: LabelN) -> Self {
         StackFrame {
+            module,
+            function,
+            label: entry,
+            prev: None,
+            op: 0,
             variables: HashMap::new(),
+            cases: HashMap::new(),
+            receives: HashMap::new(),
+            exception: None,
         }
     }
 
-    fn read(&self, src: &Source) -> Term {
+    fn read(&self, module: &Module, src: &Source) -> Term {
         match *src {
             Source::Variable(ref var) => self.variables[var].clone(),
-            Source::Constant(ref literal) => {
-                match *literal {
-                    AtomicLiteral::Atom(ref atom) => Term::Atom(atom.clone()),
-                    _ => unimplemented!(),
-                }
-            }
+            Source::Constant(ref literal) => Term::from_literal(literal),
+            Source::Literal(id) => Term::from_constant(module.constants.get(id)),
+        }
+    }
+
+    fn read_all(&self, module: &Module, srcs: &[Source]) -> Vec<Term> {
+        srcs.iter().map(|src| self.read(module, src)).collect()
+    }
+
+}
+
+/// A native function waiting for a fun it applied to return.
+struct NativeFrame {
+    module: Atom,
+    function: FunctionIdent,
+    then: Continuation,
+}
+
+enum Frame {
+    Erlang(Box<StackFrame>),
+    Native(NativeFrame),
+}
+impl Frame {
+
+    fn ident(&self) -> (Atom, FunctionIdent) {
+        match *self {
+            Frame::Erlang(ref frame) => (frame.module.clone(), frame.function.clone()),
+            Frame::Native(ref frame) => (frame.module.clone(), frame.function.clone()),
+        }
+    }
+
+    fn as_erlang(&self) -> Option<&StackFrame> {
+        match *self {
+            Frame::Erlang(ref frame) => Some(frame),
+            Frame::Native(_) => None,
+        }
+    }
+
+}
+
+/// The functions of the frames, innermost first.
+fn stacktrace(frames: &[Frame]) -> Vec<(Atom, FunctionIdent)> {
+    frames.iter().rev().map(Frame::ident).collect()
+}
+
+/// The innermost frame, which is the Erlang function being executed. A
+/// native frame is only on top while its continuation runs.
+fn top_frame(frames: &mut [Frame]) -> (&mut StackFrame, &[Frame]) {
+    match frames.split_last_mut() {
+        Some((&mut Frame::Erlang(ref mut frame), outer)) => (frame, outer),
+        _ => unreachable!("no Erlang function is being executed"),
+    }
+}
+
+/// Fills in the stack trace of an exception raised in the function of
+/// the frame, `outer` are the frames of its callers. Rethrown exceptions
+/// keep their stack trace.
+fn raise(mut exception: Exception, frame: &StackFrame, outer: &[Frame]) -> Exception {
+    if exception.stacktrace.is_empty() {
+        exception.stacktrace.push((frame.module.clone(), frame.function.clone()));
+        exception.stacktrace.extend(stacktrace(outer));
+    }
+    exception
+}
+
+/// Continues after an OP that may throw, through edge 0 when it
+/// returned and edge 1 along with the exception when it threw.
+fn resume_call(op: &Op, ret: CallReturn, frame: &mut StackFrame,
+               outer: &[Frame]) -> OpResult {
+    match ret {
+        CallReturn::Return { term } => {
+            frame.variables.insert(op.writes[0], term);
+            OpResult::Branch { slot: 0 }
+        }
+        CallReturn::Throw { exception } => {
+            let exception = raise(exception, frame, outer);
+            frame.exception = Some(exception);
+            OpResult::Branch { slot: 1 }
         }
     }
+}
+
+/// The pseudo-value of a case or receive structure read by the OP.
+fn structure_var(op: &Op) -> SSAVariable {
+    match op.reads[0] {
+        Source::Variable(var) => var,
+        _ => panic!(),
+    }
+}
+
+/// The virtual time a receive with the timeout times out at, None for
+/// infinity.
+fn receive_deadline(now: u64, timeout: &Term) -> Result<Option<u64>, Exception> {
+    use ::num_traits::ToPrimitive;
+    match *timeout {
+        Term::Atom(ref atom) if &**atom == "infinity" => Ok(None),
+        Term::Integer(ref int) if int.to_u64().is_some() =>
+            Ok(Some(now + int.to_u64().unwrap())),
+        _ => Err(Exception::error(Term::new_atom("timeout_value"))),
+    }
+}
 
+/// Resolves a fun and its arguments to the function to call. The
+/// environment of a lambda is passed after the arguments. Returns the
+/// exception to raise if the term is not a fun of the right arity.
+fn resolve_fun(fun: &Term, mut args: Vec<Term>)
+               -> Result<(Atom, FunctionIdent, Vec<Term>), Exception> {
+    match *fun {
+        Term::CapturedFunction { ref module, ref name, arity }
+        if arity as usize == args.len() => {
+            let ident = FunctionIdent {
+                name: name.clone(),
+                arity,
+                lambda: None,
+            };
+            Ok((module.clone(), ident, args))
+        },
+        Term::BoundLambda { ref module, ref fun, ref env }
+        if fun.arity as usize == args.len() => {
+            args.extend(env.iter().cloned());
+            Ok((module.clone(), fun.clone(), args))
+        },
+        Term::CapturedFunction { .. } | Term::BoundLambda { .. } =>
+            Err(Exception::error(Term::Tuple(vec![
+                Term::new_atom("badarity"),
+                Term::Tuple(vec![fun.clone(), Term::proper_list(args)]),
+            ]))),
+        _ => Err(Exception::error(Term::Tuple(vec![
+            Term::new_atom("badfun"), fun.clone()]))),
+    }
 }
 
-enum BlockResult {
+/// Result of executing an OP.
+enum OpResult {
+    /// Continue with the next OP of the block.
+    Next,
     Branch { slot: usize },
     Return { term: Term },
-    Throw,
+    Throw { exception: Exception },
+    /// Calls the function, the result is passed to the current OP.
+    Call { module: Atom, fun: FunctionIdent, args: Vec<Term> },
+    /// Calls the function in place of the current one.
+    TailCall { module: Atom, fun: FunctionIdent, args: Vec<Term> },
+    /// Waits for a message, the OP is executed again when the process
+    /// is woken up.
+    Wait { deadline: Option<u64> },
+}
+
+/// Result of executing an OP in a process.
+enum Step {
+    Op,
+    /// A function was called, which costs a reduction.
+    Call,
+    Wait { deadline: Option<u64> },
+    /// The outermost function returned.
+    Done(CallReturn),
 }
 
 pub struct ExecutionContext {
     modules: HashMap<String, ModuleType>,
+    primops: HashMap<(String, u32), PrimOpFun>,
+    /// The processes that are not running, the running process is taken
+    /// out while it runs.
+    processes: RefCell<BTreeMap<Pid, Process>>,
+    /// Mailboxes of the live processes.
+    mailboxes: RefCell<BTreeMap<Pid, Mailbox>>,
+    signals: RefCell<BTreeMap<Pid, Signals>>,
+    run_queue: RefCell<VecDeque<Pid>>,
+    /// The running process.
+    current: Cell<Pid>,
+    next_pid: Cell<usize>,
+    next_ref: Cell<usize>,
+    /// Virtual time in milliseconds.
+    clock: Cell<u64>,
+    /// Blocks `run` stops at when they are entered.
+    breakpoints: RefCell<HashSet<(Atom, FunctionIdent, LabelN)>>,
 }
 
 impl ExecutionContext {
 
     pub fn new() -> Self {
+        let mut processes = BTreeMap::new();
+        processes.insert(ROOT_PID, Process::new_root());
+        let mut mailboxes = BTreeMap::new();
+        mailboxes.insert(ROOT_PID, Mailbox::new());
+        let mut signals = BTreeMap::new();
+        signals.insert(ROOT_PID, Signals::new());
         ExecutionContext {
             modules: HashMap::new(),
+            primops: primop::make_primops(),
+            processes: RefCell::new(processes),
+            mailboxes: RefCell::new(mailboxes),
+            signals: RefCell::new(signals),
+            run_queue: RefCell::new(VecDeque::new()),
+            current: Cell::new(ROOT_PID),
+            next_pid: Cell::new(ROOT_PID.0 + 1),
+            next_ref: Cell::new(0),
+            clock: Cell::new(0),
+            breakpoints: RefCell::new(HashSet::new()),
         }
     }
 
@@ -109,128 +368,915 @@ impl ExecutionContext {
         self.modules.insert(module.name.clone(), ModuleType::Native(module));
     }
 
-    fn exec_block(&self, module: &Module, block: &BasicBlock, 
-                  prev: Option<LabelN>, frame: &mut StackFrame) -> BlockResult {
+    /// Adds or replaces the implementation of a primop.
+    pub fn add_primop(&mut self, name: &str, arity: u32, fun: PrimOpFun) {
+        self.primops.insert((name.to_string(), arity), fun);
+    }
+
+    /// Makes a reference unique within the context.
+    pub fn make_ref(&self) -> Reference {
+        let num = self.next_ref.get();
+        self.next_ref.set(num + 1);
+        Reference(num)
+    }
 
-        // Apply phi nodes
-        assert!(block.phi_nodes.len() == 0);
+    /// Raises `{unimplemented_primop, Name, Arity}` for primops without
+    /// an implementation.
+    fn prim_op(&self, name: &str, args: &[Term]) -> PrimOpReturn {
+        match self.primops.get(&(name.to_string(), args.len() as u32)) {
+            Some(fun) => fun(self, args),
+            None => PrimOpReturn::Throw(Exception::error(Term::Tuple(vec![
+                Term::new_atom("unimplemented_primop"),
+                Term::new_atom(name),
+                Term::new_i64(args.len() as i64),
+            ]))),
+        }
+    }
 
-        let mut block_ret: Option<BlockResult> = None;
-        for op in &block.ops {
-            assert!(block_ret.is_none());
-            match op.kind {
-                OpKind::Arguments => {
-                    assert!(op.reads.len() == 0);
-                    assert!(op.writes.iter().all(|w| frame.variables.contains_key(w)))
-                }
-                OpKind::Move => {
-                    assert!(op.reads.len() == 1);
-                    assert!(op.writes.len() == 1);
-                    let res = frame.read(&op.reads[0]);
-                    frame.variables.insert(op.writes[0], res);
+    /// The LIR of an Erlang function, along with its module.
+    fn erlang_function(&self, module: &Atom, fun: &FunctionIdent)
+                       -> Option<(&Module, &FunctionCfg)> {
+        let module_str: &str = module;
+        match self.modules.get(module_str) {
+            Some(ModuleType::Erlang(module)) => {
+                module.functions.iter()
+                    .find(|function| &function.ident == fun)
+                    .map(|function| (module, function.lir_function.as_ref().unwrap()))
+            },
+            _ => None,
+        }
+    }
+
+    /// Executes the OP the frame is at. `outer` are the frames of its
+    /// callers.
+    fn exec_op(&self, module: &Module, op: &Op, frame: &mut StackFrame,
+               outer: &[Frame]) -> OpResult {
+        match op.kind {
+            OpKind::Arguments => {
+                assert!(op.reads.is_empty());
+                assert!(op.writes.iter().all(|w| frame.variables.contains_key(w)))
+            }
+            OpKind::Move => {
+                assert!(op.reads.len() == 1);
+                assert!(op.writes.len() == 1);
+                let res = frame.read(module, &op.reads[0]);
+                frame.variables.insert(op.writes[0], res);
+            }
+            OpKind::Call => {
+                assert!(op.reads.len() >= 2);
+                assert!(op.writes.len() == 1);
+
+                let module_term = frame.read(module, &op.reads[0]);
+                let fun_term = frame.read(module, &op.reads[1]);
+                let args = frame.read_all(module, &op.reads[2..]);
+
+                return match (module_term, fun_term) {
+                    (Term::Atom(module_name), Term::Atom(name)) => OpResult::Call {
+                        module: module_name,
+                        fun: FunctionIdent {
+                            name,
+                            arity: args.len() as u32,
+                            lambda: None,
+                        },
+                        args,
+                    },
+                    _ => resume_call(op, CallReturn::Throw {
+                        exception: Exception::error(Term::new_atom("badarg")),
+                    }, frame, outer),
+                };
+            }
+            OpKind::Apply => {
+                assert!(!op.reads.is_empty());
+                assert!(op.writes.len() == 1);
+
+                let fun = frame.read(module, &op.reads[0]);
+                let args = frame.read_all(module, &op.reads[1..]);
+                return match resolve_fun(&fun, args) {
+                    Ok((module_name, ident, args)) => OpResult::Call {
+                        module: module_name,
+                        fun: ident,
+                        args,
+                    },
+                    Err(exception) => resume_call(
+                        op, CallReturn::Throw { exception }, frame, outer),
+                };
+            }
+            OpKind::TailCall => {
+                assert!(op.reads.len() >= 2);
+                assert!(op.writes.is_empty());
+
+                let module_term = frame.read(module, &op.reads[0]);
+                let fun_term = frame.read(module, &op.reads[1]);
+                let args = frame.read_all(module, &op.reads[2..]);
+
+                return match (module_term, fun_term) {
+                    (Term::Atom(module_name), Term::Atom(name)) => OpResult::TailCall {
+                        module: module_name,
+                        fun: FunctionIdent {
+                            name,
+                            arity: args.len() as u32,
+                            lambda: None,
+                        },
+                        args,
+                    },
+                    _ => OpResult::Throw {
+                        exception: raise(Exception::error(Term::new_atom("badarg")),
+                                         frame, outer),
+                    },
+                };
+            }
+            OpKind::TailApply => {
+                assert!(!op.reads.is_empty());
+                assert!(op.writes.is_empty());
+
+                let fun = frame.read(module, &op.reads[0]);
+                let args = frame.read_all(module, &op.reads[1..]);
+                return match resolve_fun(&fun, args) {
+                    Ok((module_name, ident, args)) => OpResult::TailCall {
+                        module: module_name,
+                        fun: ident,
+                        args,
+                    },
+                    Err(exception) => OpResult::Throw {
+                        exception: raise(exception, frame, outer),
+                    },
+                };
+            }
+            OpKind::CaptureNamedFunction(ref ident) => {
+                frame.variables.insert(op.writes[0], Term::CapturedFunction {
+                    module: module.name.clone(),
+                    name: ident.name.clone(),
+                    arity: ident.arity,
+                });
+            }
+            OpKind::CaptureExternalNamedFunction(ref fun_module, ref ident) => {
+                frame.variables.insert(op.writes[0], Term::CapturedFunction {
+                    module: fun_module.clone(),
+                    name: ident.name.clone(),
+                    arity: ident.arity,
+                });
+            }
+            OpKind::MakeTuple => {
+                let elems = frame.read_all(module, &op.reads);
+                frame.variables.insert(op.writes[0], Term::Tuple(elems));
+            }
+            OpKind::MakeList => {
+                assert!(!op.reads.is_empty());
+                let tail = frame.read(module, &op.reads[0]);
+                let head = frame.read_all(module, &op.reads[1..]);
+                frame.variables.insert(op.writes[0], Term::list(head, tail));
+            }
+            OpKind::MakeMap => {
+                assert!(op.reads.len().is_multiple_of(2));
+                let mut map = BTreeMap::new();
+                for kv in frame.read_all(module, &op.reads).chunks(2) {
+                    map.insert(kv[0].clone(), kv[1].clone());
                 }
-                OpKind::Call => {
-                    assert!(op.reads.len() >= 2);
-                    assert!(op.writes.len() == 1);
-
-                    let module_term = frame.read(&op.reads[0]);
-                    let fun_term = frame.read(&op.reads[1]);
-                    let args: Vec<Term> = op.reads[2..].iter()
-                        .map(|arg| frame.read(arg)).collect();
-                    
-                    let ret = self.call(module_term.atom_str(), fun_term.atom_str(), &args);
-                    match ret {
-                        CallReturn::Return { term } => {
-                            frame.variables.insert(op.writes[0], term);
-                            block_ret = Some(BlockResult::Branch { slot: 1 });
+                frame.variables.insert(op.writes[0], Term::Map(map));
+            }
+            OpKind::UpdateMap(ref types) => {
+                assert!(op.reads.len() == types.len() * 2 + 1);
+                let mut reads = frame.read_all(module, &op.reads);
+                let base = reads.remove(0);
+                let ret = match base {
+                    Term::Map(mut map) => {
+                        let missing = types.iter().zip(reads.chunks(2))
+                            .filter_map(|(typ, kv)| {
+                                if *typ == MapPairType::Exact && !map.contains_key(&kv[0]) {
+                                    return Some(kv[0].clone());
+                                }
+                                map.insert(kv[0].clone(), kv[1].clone());
+                                None
+                            })
+                            .next();
+                        match missing {
+                            Some(key) => lib::badkey(&key),
+                            None => CallReturn::Return { term: Term::Map(map) },
                         }
-                        CallReturn::Throw => unimplemented!(),
+                    },
+                    base => lib::badmap(&base),
+                };
+                return resume_call(op, ret, frame, outer);
+            }
+            OpKind::MakeBinary => {
+                // Every segment is read as the value followed by
+                // its four options
+                assert!(op.reads.len().is_multiple_of(5));
+                let mut bin = BitString::from_bytes(vec![]);
+                let valid = frame.read_all(module, &op.reads).chunks(5).all(|segment| {
+                    binary::SegmentSpec::from_opts(&segment[1..])
+                        .and_then(|spec| binary::construct_segment(
+                            &mut bin, &segment[0], &spec))
+                        .is_some()
+                });
+                let ret = if valid {
+                    CallReturn::Return { term: Term::BitString(bin) }
+                } else {
+                    CallReturn::Throw {
+                        exception: Exception::error(Term::new_atom("badarg")),
                     }
+                };
+                return resume_call(op, ret, frame, outer);
+            }
+            OpKind::MakeClosureEnv { .. } => {
+                let captured = frame.read_all(module, &op.reads);
+                frame.variables.insert(op.writes[0], Term::Tuple(captured));
+            }
+            OpKind::BindClosure { ref ident } => {
+                let env = match frame.read(module, &op.reads[0]) {
+                    Term::Tuple(env) => env,
+                    _ => panic!(),
+                };
+                frame.variables.insert(op.writes[0], Term::BoundLambda {
+                    module: module.name.clone(),
+                    fun: ident.clone(),
+                    env,
+                });
+            }
+            OpKind::Jump => {
+                return OpResult::Branch { slot: 0 };
+            }
+            OpKind::PrimOp(ref name) => {
+                let args = frame.read_all(module, &op.reads);
+                return match self.prim_op(name, &args) {
+                    PrimOpReturn::Values(values) => {
+                        assert!(values.len() == op.writes.len());
+                        for (var, term) in op.writes.iter().zip(values) {
+                            frame.variables.insert(*var, term);
+                        }
+                        OpResult::Branch { slot: 0 }
+                    },
+                    PrimOpReturn::Throw(exception) => resume_call(
+                        op, CallReturn::Throw { exception }, frame, outer),
+                    PrimOpReturn::Wait { deadline } => OpResult::Wait { deadline },
+                };
+            }
+            OpKind::ReturnOk => {
+                assert!(op.reads.len() == 1);
+                assert!(op.writes.is_empty());
+                return OpResult::Return { term: frame.read(module, &op.reads[0]) };
+            }
+            OpKind::ReturnThrow => {
+                return OpResult::Throw {
+                    exception: frame.exception.take().unwrap(),
+                };
+            }
+            OpKind::CatchValues => {
+                assert!(op.writes.len() == 3);
+                let exception = frame.exception.take().unwrap();
+                frame.variables.insert(
+                    op.writes[0], Term::new_atom(exception.class.as_str()));
+                frame.variables.insert(op.writes[1], exception.reason.clone());
+                frame.variables.insert(op.writes[2], exception.raw_stacktrace());
+            }
+            OpKind::IfTruthy => {
+                let slot = match frame.read(module, &op.reads[0]) {
+                    Term::Atom(ref atom) if &**atom == "true" => 0,
+                    _ => 1,
+                };
+                return OpResult::Branch { slot };
+            }
+            OpKind::Case { ref vars, ref clauses, ref value_vars, ref decision_tree } => {
+                let terms: Vec<_> = vars.iter()
+                    .map(|var| frame.variables[var].clone()).collect();
+                let values: Vec<_> = value_vars.iter()
+                    .map(|var| frame.variables[var].clone()).collect();
+                let structure = op.writes[0];
+
+                // After a failed guard, matching resumes from the failure
+                // edge of the guard node
+                let start = match frame.cases.remove(&structure) {
+                    Some(state) if state.guard_failed =>
+                        Some(state.resume.expect("guard failed without a guard node")),
+                    _ => None,
+                };
+
+                let tree = decision_tree.as_ref().expect("case without a decision tree");
+                let mut slot = 0;
+                if let Some(matched) = pattern::match_tree(
+                    tree, clauses, &terms, &values, start) {
+                    slot = matched.clause + 1;
+                    frame.cases.insert(structure, CaseState {
+                        binds: matched.binds,
+                        resume: matched.resume,
+                        guard_failed: false,
+                    });
+                }
+                if slot == 0 {
+                    let value = if terms.len() == 1 {
+                        terms[0].clone()
+                    } else {
+                        Term::Tuple(terms)
+                    };
+                    let exception = raise(Exception::error(Term::Tuple(vec![
+                        Term::new_atom("case_clause"), value])), frame, outer);
+                    frame.exception = Some(exception);
                 }
-                OpKind::ReturnOk => {
-                    assert!(op.reads.len() == 1);
-                    assert!(op.writes.len() == 0);
-                    block_ret = Some(BlockResult::Return { term: frame.read(&op.reads[0]) });
+                return OpResult::Branch { slot };
+            }
+            OpKind::CaseValues => {
+                let binds = frame.cases[&structure_var(op)].binds.clone();
+                assert!(binds.len() == op.writes.len());
+                for (var, term) in op.writes.iter().zip(binds) {
+                    frame.variables.insert(*var, term);
+                }
+            }
+            OpKind::CaseGuardOk => (),
+            OpKind::CaseGuardFail => {
+                frame.cases.get_mut(&structure_var(op)).unwrap().guard_failed = true;
+                return OpResult::Branch { slot: 0 };
+            }
+            OpKind::ReceiveStart => {
+                let timeout = frame.read(module, &op.reads[0]);
+                let deadline = match receive_deadline(self.now(), &timeout) {
+                    Ok(deadline) => deadline,
+                    Err(exception) => {
+                        frame.exception = Some(raise(exception, frame, outer));
+                        return OpResult::Branch { slot: 1 };
+                    },
+                };
+                frame.receives.insert(op.writes[0], ReceiveState {
+                    deadline,
+                    cursor: 0,
+                    peeked: false,
+                });
+                return OpResult::Branch { slot: 0 };
+            }
+            OpKind::ReceiveWait => {
+                let state = frame.receives.get_mut(&structure_var(op)).unwrap();
+                // Coming back after fetching a message means it did
+                // not match
+                if state.peeked {
+                    state.cursor += 1;
+                    state.peeked = false;
                 }
-                _ => {
-                    println!("Unimpl: {:?}", op);
-                    unimplemented!()
+
+                if state.cursor < self.with_mailbox(|mailbox| mailbox.messages.len()) {
+                    return OpResult::Branch { slot: 0 };
                 }
+                return match state.deadline {
+                    Some(deadline) if deadline <= self.now() => OpResult::Branch { slot: 1 },
+                    deadline => OpResult::Wait { deadline },
+                };
             }
+            OpKind::ReceiveGetMessage => {
+                let state = frame.receives.get_mut(&structure_var(op)).unwrap();
+                state.peeked = true;
+                let message = self.with_mailbox(|mailbox| mailbox.messages[state.cursor].clone());
+                frame.variables.insert(op.writes[0], message);
+            }
+            OpKind::ReceiveFinish => {
+                let state = frame.receives.remove(&structure_var(op)).unwrap();
+                self.with_mailbox(|mailbox| mailbox.messages.remove(state.cursor));
+            }
+            OpKind::TombstoneSSA(_) => (),
         }
+        OpResult::Next
+    }
 
-        return block_ret.unwrap();
+    /// Executes the next OP of the innermost frame.
+    fn step_frames(&self, frames: &mut Vec<Frame>) -> Step {
+        let result = {
+            let (frame, outer) = top_frame(frames);
+            let (module, lir) = self.erlang_function(&frame.module, &frame.function).unwrap();
+            let op = &lir.block(frame.label).ops[frame.op];
+            self.exec_op(module, op, frame, outer)
+        };
+
+        let ret = match result {
+            OpResult::Next => {
+                top_frame(frames).0.op += 1;
+                return Step::Op;
+            },
+            OpResult::Branch { slot } => {
+                self.branch(top_frame(frames).0, slot);
+                return Step::Op;
+            },
+            OpResult::Wait { deadline } => return Step::Wait { deadline },
+            OpResult::Return { term } => {
+                frames.pop();
+                CallReturn::Return { term }
+            },
+            OpResult::Throw { exception } => {
+                frames.pop();
+                CallReturn::Throw { exception }
+            },
+            OpResult::Call { module, fun, args } => {
+                match self.enter(frames, module, fun, args) {
+                    Some(ret) => ret,
+                    None => return Step::Call,
+                }
+            },
+            OpResult::TailCall { module, fun, args } => {
+                frames.pop();
+                match self.enter(frames, module, fun, args) {
+                    Some(ret) => ret,
+                    None => return Step::Call,
+                }
+            },
+        };
+        match self.resume(frames, ret) {
+            Some(ret) => Step::Done(ret),
+            None => Step::Op,
+        }
     }
 
-    fn call_erlang_module(&self, module: &Module, fun_ident: &FunctionIdent, 
-                          args: &[Term]) -> CallReturn {
-        let fun_opt = module.functions.iter().find(|fun| &fun.ident == fun_ident);
-        if fun_opt.is_none() {
-            panic!("function {} not found in module {:?}", fun_ident, module.name);
+    /// Jumps along an outgoing edge of the block the frame is in.
+    fn branch(&self, frame: &mut StackFrame, slot: usize) {
+        let (_, lir) = self.erlang_function(&frame.module, &frame.function).unwrap();
+        let target = lir.edge_target(lir.block(frame.label).outgoing_edges[slot]);
+        frame.prev = Some(frame.label);
+        frame.label = target;
+        frame.op = 0;
+
+        // Apply phi nodes, all of them read their values before any is
+        // assigned
+        let phi_values: Vec<_> = lir.block(target).phi_nodes.iter()
+            .map(|phi| {
+                let &(_, var) = phi.entries.iter()
+                    .find(|&&(label, _)| Some(label) == frame.prev).unwrap();
+                (phi.ssa, frame.variables[&var].clone())
+            })
+            .collect();
+        for (ssa, term) in phi_values {
+            frame.variables.insert(ssa, term);
+        }
+    }
+
+    /// Passes the result of a call to the innermost frame that made it.
+    /// A native function continues, and may apply another fun. Returns
+    /// the result if there is no frame left.
+    fn resume(&self, frames: &mut Vec<Frame>, mut ret: CallReturn) -> Option<CallReturn> {
+        loop {
+            let native = match frames.pop() {
+                None => return Some(ret),
+                Some(Frame::Native(native)) => native,
+                Some(frame) => {
+                    frames.push(frame);
+                    break;
+                },
+            };
+            let step = (native.then)(self, ret);
+            ret = self.native_step(frames, native.module, native.function, step)?;
         }
-        let fun = fun_opt.unwrap();
 
-        let lir = fun.lir_function.as_ref().unwrap();
+        let slot = {
+            let (frame, outer) = top_frame(frames);
+            let (_, lir) = self.erlang_function(&frame.module, &frame.function).unwrap();
+            let op = &lir.block(frame.label).ops[frame.op];
+            match resume_call(op, ret, frame, outer) {
+                OpResult::Branch { slot } => slot,
+                _ => unreachable!(),
+            }
+        };
+        self.branch(top_frame(frames).0, slot);
+        None
+    }
 
-        let mut frame = StackFrame::new();
+    /// Calls a function on top of the frames. An Erlang function gets a
+    /// frame of its own, a native function is called right away. Returns
+    /// the result if the function returned without leaving a frame.
+    fn enter(&self, frames: &mut Vec<Frame>, module_name: Atom,
+             fun_ident: FunctionIdent, args: Vec<Term>) -> Option<CallReturn> {
+        if let Some((_, lir)) = self.erlang_function(&module_name, &fun_ident) {
+            let mut frame = StackFrame::new(module_name, fun_ident, lir.entry());
 
-        // Insert arguments into frame
-        for (var_def, term) in fun.hir_fun.args.iter().zip(args) {
-            frame.variables.insert(var_def.ssa, term.clone());
+            // Insert arguments into frame, lambdas also take their
+            // environment
+            let entry_op = &lir.block(lir.entry()).ops[0];
+            match entry_op.kind {
+                OpKind::Arguments => (),
+                _ => panic!(),
+            }
+            assert!(entry_op.writes.len() == args.len());
+            for (var, term) in entry_op.writes.iter().zip(args) {
+                frame.variables.insert(*var, term);
+            }
+            frames.push(Frame::Erlang(Box::new(frame)));
+            return None;
         }
 
-        // Execute blocks
-        let mut prev_block_id: Option<LabelN> = None;
-        let mut curr_block_id = lir.entry();
+        let module_str: &str = &module_name;
+        let step = match self.modules.get(module_str) {
+            Some(ModuleType::Native(native_module)) =>
+                self.call_native_module(native_module, &fun_ident, &args),
+            _ => NativeStep::Return(CallReturn::Throw {
+                exception: Exception::error(Term::new_atom("undef")),
+            }),
+        };
+        self.native_step(frames, module_name, fun_ident, step)
+    }
+
+    /// Carries out what a native function does next. A fun it applies is
+    /// entered on top of a frame holding the continuation. Returns the
+    /// result if the native function returned.
+    fn native_step(&self, frames: &mut Vec<Frame>, module_name: Atom,
+                   fun_ident: FunctionIdent, mut step: NativeStep) -> Option<CallReturn> {
         loop {
-            let block = lir.block(curr_block_id);
-            let slots = lir.branch_slots(curr_block_id);
-            for slot in &slots {
-                let edge = lir.cfg.find_edge(curr_block_id.0, slot.0).unwrap();
-                println!("{:?}", lir.cfg.edge_weight(edge));
-            }
-            let ret = self.exec_block(module, block, prev_block_id, &mut frame);
-            match ret {
-                BlockResult::Branch { slot } => {
-                    prev_block_id = Some(curr_block_id);
-                    curr_block_id = slots[slot];
-                }
-                BlockResult::Return { term } => {
-                    return CallReturn::Return { term };
-                }
-                BlockResult::Throw => {
-                    return CallReturn::Throw;
-                }
-            }
+            let (ret, then) = match step {
+                NativeStep::Return(ret) => {
+                    // Exceptions from native functions get their stack
+                    // trace here
+                    return Some(match ret {
+                        CallReturn::Throw { mut exception } => {
+                            if exception.stacktrace.is_empty() {
+                                exception.stacktrace.push((module_name, fun_ident));
+                                exception.stacktrace.extend(stacktrace(frames));
+                            }
+                            CallReturn::Throw { exception }
+                        },
+                        ret => ret,
+                    });
+                },
+                NativeStep::Apply { fun, args, then } => match resolve_fun(&fun, args) {
+                    Ok((module, ident, args)) => {
+                        frames.push(Frame::Native(NativeFrame {
+                            module: module_name.clone(),
+                            function: fun_ident.clone(),
+                            then,
+                        }));
+                        // A native fun returns right away
+                        let ret = self.enter(frames, module, ident, args)?;
+                        match frames.pop() {
+                            Some(Frame::Native(native)) => (ret, native.then),
+                            _ => unreachable!(),
+                        }
+                    },
+                    Err(exception) => (CallReturn::Throw { exception }, then),
+                },
+            };
+            step = then(self, ret);
         }
     }
 
     fn call_native_module(&self, module: &NativeModule, fun_ident: &FunctionIdent,
-                          args: &[Term]) -> CallReturn {
+                          args: &[Term]) -> NativeStep {
         assert!(args.len() == fun_ident.arity as usize);
         // bad
         let fun_name_str: &str = &fun_ident.name;
-        module.functions[&(fun_name_str.to_string(), fun_ident.arity)](args)
+        match module.functions.get(&(fun_name_str.to_string(), fun_ident.arity)) {
+            Some(fun) => fun(self, args),
+            None => NativeStep::Return(CallReturn::Throw {
+                exception: Exception::error(Term::new_atom("undef")),
+            }),
+        }
     }
 
+    /// Calls a function in the root process, running the other processes
+    /// as well until it returns. Breakpoints are passed over.
     pub fn call(&self, module_name: &str, fun_name: &str, args: &[Term]) -> CallReturn {
+        self.start(module_name, fun_name, args);
+        self.finish()
+    }
+
+    /// Calls a fun in the root process, like `call`. Native functions
+    /// apply funs through `NativeStep::Apply` instead.
+    pub fn apply(&self, fun: &Term, args: &[Term]) -> CallReturn {
+        match resolve_fun(fun, args.to_vec()) {
+            Ok((module, ident, args)) => {
+                self.start_root(module, ident, args);
+                self.finish()
+            },
+            Err(exception) => CallReturn::Throw { exception },
+        }
+    }
+
+    fn finish(&self) -> CallReturn {
+        loop {
+            if let RunResult::Finished(ret) = self.run(usize::MAX) {
+                return ret;
+            }
+        }
+    }
+
+    /// Starts a call in the root process, it is executed by `run`.
+    pub fn start(&self, module_name: &str, fun_name: &str, args: &[Term]) {
         let fun_ident = FunctionIdent {
             name: Atom::from_str(fun_name).unwrap(),
             arity: args.len() as u32,
             lambda: None,
         };
+        self.start_root(Atom::from_str(module_name).unwrap(), fun_ident, args.to_vec());
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ ExecutionContext, CallReturn, RunResult, Term, BitString,
+                 Exception, ExceptionClass };
+
+    fn context(core: &str) -> ExecutionContext {
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let module = ::ir::from_parsed(&parsed.0);
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(super::lib::make_erlang());
+        ctx.add_erlang_module(module);
+        ctx
+    }
 
-        if !self.modules.contains_key(module_name) {
-            panic!("module {:?} not found", module_name);
+    fn returned(ret: CallReturn) -> Term {
+        match ret {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
         }
-        let module = &self.modules[module_name];
+    }
 
-        match module {
-            &ModuleType::Erlang(ref erl_module) => 
-                self.call_erlang_module(erl_module, &fun_ident, args),
-            &ModuleType::Native(ref native_module) => 
-                self.call_native_module(native_module, &fun_ident, args),
+    fn thrown(ret: CallReturn) -> Exception {
+        match ret {
+            CallReturn::Throw { exception } => exception,
+            CallReturn::Return { term } => panic!("expected throw, got {}", term),
         }
     }
 
+    #[test]
+    fn interpret_ops() {
+        let ctx = context(r##"
+module 'test' ['len'/2, 'pick'/1, 'is_a'/1, 'closure'/1, 'reverse'/1,
+               'build'/2, 'bin'/1, 'recv'/0]
+    attributes []
+'len'/2 =
+    fun (L, Acc) ->
+        case L of
+          <[]> when 'true' -> Acc
+          <[_H|T]> when 'true' ->
+              let <Acc1> = call 'erlang':'+'(Acc, 1)
+              in apply 'len'/2(T, Acc1)
+        end
+'is_a'/1 =
+    fun (X) ->
+        case X of
+          <'a'> when 'true' -> 'true'
+          <_Other> when 'true' -> 'false'
+        end
+'pick'/1 =
+    fun (X) ->
+        case X of
+          <{A, _B}> when call 'test':'is_a'(A) -> 'first_a'
+          <{_A, B}> when call 'test':'is_a'(B) -> 'second_a'
+          <_Other> when 'true' -> 'none'
+        end
+'adder'/1 =
+    fun (N) -> fun (X) -> call 'erlang':'+'(X, N)
+'closure'/1 =
+    fun (X) ->
+        let <F> = apply 'adder'/1(5)
+        in apply F(X)
+'reverse'/1 =
+    fun (L) ->
+        letrec 'go'/2 =
+            fun (Xs, Acc) ->
+                case Xs of
+                  <[]> when 'true' -> Acc
+                  <[Y|Rest]> when 'true' -> apply 'go'/2(Rest, [Y|Acc])
+                end
+        in apply 'go'/2(L, [])
+'build'/2 =
+    fun (K, V) ->
+        let <M> = ~{K=>V, 'b'=>'w'}~
+        in case M of
+             <~{'b':='w', K:=Found}~> when 'true' -> {Found, [K|V]}
+             <_Other> when 'true' -> 'nomatch'
+           end
+'bin'/1 =
+    fun (X) ->
+        let <B> = #{#<X>(16,1,'integer',['unsigned'|['big']]),
+                    #<7>(8,1,'integer',['unsigned'|['big']])}#
+        in case B of
+             <#{#<Hi>(8,1,'integer',['unsigned'|['big']]),
+                #<Rest>('all',8,'binary',['unsigned'|['big']])}#> when 'true' ->
+                 {Hi, Rest}
+           end
+'recv'/0 =
+    fun () ->
+        receive
+          <{'msg', X}> when 'true' -> X
+        after 0 -> 'timeout'
+end
+"##);
+
+        let list = Term::proper_list(vec![Term::new_i64(1), Term::new_i64(2), Term::new_i64(3)]);
+        let ret = returned(ctx.call("test", "len", &[list.clone(), Term::new_i64(0)]));
+        assert!(ret.erl_exact_eq(&Term::new_i64(3)));
+
+        let pair = |a: &str, b: &str| Term::Tuple(vec![Term::new_atom(a), Term::new_atom(b)]);
+        assert!(returned(ctx.call("test", "pick", &[pair("a", "b")]))
+                .erl_exact_eq(&Term::new_atom("first_a")));
+        assert!(returned(ctx.call("test", "pick", &[pair("b", "a")]))
+                .erl_exact_eq(&Term::new_atom("second_a")));
+        assert!(returned(ctx.call("test", "pick", &[pair("b", "b")]))
+                .erl_exact_eq(&Term::new_atom("none")));
+
+        let ret = returned(ctx.call("test", "closure", &[Term::new_i64(2)]));
+        assert!(ret.erl_exact_eq(&Term::new_i64(7)));
+
+        let ret = returned(ctx.call("test", "reverse", &[list]));
+        let reversed = Term::proper_list(vec![Term::new_i64(3), Term::new_i64(2), Term::new_i64(1)]);
+        assert!(ret.erl_exact_eq(&reversed), "{}", ret);
+
+        let ret = returned(ctx.call("test", "build", &[Term::new_atom("k"), Term::new_i64(1)]));
+        let expected = Term::Tuple(vec![
+            Term::new_i64(1), Term::list(vec![Term::new_atom("k")], Term::new_i64(1))]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+
+        let ret = returned(ctx.call("test", "bin", &[Term::new_i64(0x0102)]));
+        let expected = Term::Tuple(vec![
+            Term::new_i64(1), Term::BitString(BitString::from_bytes(vec![2, 7]))]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+
+        assert!(returned(ctx.call("test", "recv", &[])).erl_exact_eq(&Term::new_atom("timeout")));
+        let pid = ctx.self_pid();
+        ctx.send(pid, Term::new_atom("other"));
+        ctx.send(pid, Term::Tuple(vec![Term::new_atom("msg"), Term::new_i64(4)]));
+        assert!(returned(ctx.call("test", "recv", &[])).erl_exact_eq(&Term::new_i64(4)));
+        assert!(ctx.with_mailbox(|mailbox| mailbox.messages.len()) == 1);
+    }
+
+    #[test]
+    fn exceptions() {
+        let ctx = context(r##"
+module 'exc' ['safe'/1, 'rethrow'/1, 'unmatched'/1, 'unknown'/0,
+              'wait'/1, 'safe_wait'/1]
+    attributes []
+'inc'/1 =
+    fun (X) -> call 'erlang':'+'(X, 1)
+'safe'/1 =
+    fun (X) ->
+        try apply 'inc'/1(X)
+        of <R> -> {'ok', R}
+        catch <Class, Reason, Trace> ->
+            {Class, Reason, primop 'build_stacktrace'(Trace)}
+'rethrow'/1 =
+    fun (X) ->
+        try apply 'inc'/1(X)
+        of <R> -> R
+        catch <_Class, Reason, Trace> ->
+            primop 'raise'(Trace, {'wrapped', Reason})
+'unmatched'/1 =
+    fun (X) ->
+        case X of
+          <'a'> when 'true' -> 'ok'
+        end
+'unknown'/0 =
+    fun () -> primop 'no_such_primop'(1, 2)
+'wait'/1 =
+    fun (T) ->
+        receive <M> when 'true' -> M
+        after T -> 'timeout'
+'safe_wait'/1 =
+    fun (T) ->
+        try apply 'wait'/1(T)
+        of <R> -> R
+        catch <Class, Reason, _Trace> -> {Class, Reason}
+end
+"##);
+
+        let ret = returned(ctx.call("exc", "safe", &[Term::new_i64(1)]));
+        let expected = Term::Tuple(vec![Term::new_atom("ok"), Term::new_i64(2)]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+
+        // The call to inc/1 is a tail call, its frame is not in the trace
+        let ret = returned(ctx.call("exc", "safe", &[Term::new_atom("a")]));
+        let frame = |m: &str, f: &str, a| Term::Tuple(vec![
+            Term::new_atom(m), Term::new_atom(f), Term::new_i64(a), Term::Nil]);
+        let expected = Term::Tuple(vec![
+            Term::new_atom("error"),
+            Term::new_atom("badarith"),
+            Term::proper_list(vec![frame("erlang", "+", 2), frame("exc", "safe", 1)]),
+        ]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+
+        match ctx.call("exc", "rethrow", &[Term::new_atom("a")]) {
+            CallReturn::Throw { exception } => {
+                assert!(exception.class == ExceptionClass::Error);
+                let reason = Term::Tuple(vec![
+                    Term::new_atom("wrapped"), Term::new_atom("badarith")]);
+                assert!(exception.reason.erl_exact_eq(&reason), "{}", exception);
+                let trace: Vec<_> = exception.stacktrace.iter()
+                    .map(|(m, f)| format!("{}:{}", m, f)).collect();
+                assert!(trace == vec!["erlang:+/2", "exc:rethrow/1"], "{:?}", trace);
+            },
+            ret => panic!("expected throw, got {:?}", ret),
+        }
+
+        match ctx.call("exc", "unmatched", &[Term::new_atom("b")]) {
+            CallReturn::Throw { exception } => {
+                let reason = Term::Tuple(vec![
+                    Term::new_atom("case_clause"), Term::new_atom("b")]);
+                assert!(exception.reason.erl_exact_eq(&reason), "{}", exception);
+                assert!(exception.stacktrace.len() == 1);
+            },
+            ret => panic!("expected throw, got {:?}", ret),
+        }
+        assert!(ctx.processes.borrow()[&ctx.self_pid()].frames.is_empty());
+
+        let exception = thrown(ctx.call("exc", "unknown", &[]));
+        let reason = Term::Tuple(vec![
+            Term::new_atom("unimplemented_primop"),
+            Term::new_atom("no_such_primop"),
+            Term::new_i64(2),
+        ]);
+        assert!(exception.reason.erl_exact_eq(&reason), "{}", exception);
+
+        // Invalid timeouts raise, and can be caught
+        let exception = thrown(ctx.call("exc", "wait", &[Term::new_atom("foo")]));
+        assert!(exception.reason.erl_exact_eq(&Term::new_atom("timeout_value")),
+                "{}", exception);
+        let ret = returned(ctx.call("exc", "safe_wait", &[Term::new_i64(-1)]));
+        let expected = Term::Tuple(vec![
+            Term::new_atom("error"), Term::new_atom("timeout_value")]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+        let ret = returned(ctx.call("exc", "safe_wait", &[Term::new_i64(0)]));
+        assert!(ret.erl_exact_eq(&Term::new_atom("timeout")), "{}", ret);
+    }
+
+    #[test]
+    fn char_literals() {
+        let ctx = context(r##"
+module 'chars' ['pick'/1] attributes []
+'pick'/1 =
+    fun (X) ->
+        case X of
+          <[$a, 1]> when 'true' -> 'char'
+          <[97, 2]> when 'true' -> 'integer'
+          <_Other> when 'true' -> 'other'
+        end
+end
+"##);
+        // Both clauses test the same value in the first element
+        let list = |a, b| Term::proper_list(vec![Term::new_i64(a), Term::new_i64(b)]);
+        let ret = returned(ctx.call("chars", "pick", &[list(97, 1)]));
+        assert!(ret.erl_exact_eq(&Term::new_atom("char")), "{}", ret);
+        let ret = returned(ctx.call("chars", "pick", &[list(97, 2)]));
+        assert!(ret.erl_exact_eq(&Term::new_atom("integer")), "{}", ret);
+    }
+
+    #[test]
+    fn map_update() {
+        let ctx = context(r##"
+module 'maps' ['assoc'/1, 'exact'/1] attributes []
+'assoc'/1 =
+    fun (M) -> ~{'b'=>2 | M}~
+'exact'/1 =
+    fun (M) -> ~{'a':=3 | M}~
+end
+"##);
+        let map = |pairs: &[(&str, i64)]| Term::Map(pairs.iter()
+            .map(|&(k, v)| (Term::new_atom(k), Term::new_i64(v)))
+            .collect());
+        let error = |tag: &str, term: Term| Term::Tuple(vec![Term::new_atom(tag), term]);
+
+        // The pairs are added to the base map
+        let ret = returned(ctx.call("maps", "assoc", &[map(&[("a", 1)])]));
+        assert!(ret.erl_exact_eq(&map(&[("a", 1), ("b", 2)])), "{}", ret);
+
+        let ret = returned(ctx.call("maps", "exact", &[map(&[("a", 1), ("b", 2)])]));
+        assert!(ret.erl_exact_eq(&map(&[("a", 3), ("b", 2)])), "{}", ret);
+
+        let exc = thrown(ctx.call("maps", "exact", &[map(&[("b", 2)])]));
+        assert!(exc.class == ExceptionClass::Error);
+        assert!(exc.reason.erl_exact_eq(&error("badkey", Term::new_atom("a"))), "{}", exc);
+
+        let exc = thrown(ctx.call("maps", "assoc", &[Term::new_i64(1)]));
+        assert!(exc.class == ExceptionClass::Error);
+        assert!(exc.reason.erl_exact_eq(&error("badmap", Term::new_i64(1))), "{}", exc);
+    }
+
+    #[test]
+    fn resumable() {
+        let ctx = context(r##"
+module 'deep' ['depth'/1] attributes []
+'depth'/1 =
+    fun (N) ->
+        case N of
+          <0> when 'true' -> 0
+          <_Other> when 'true' ->
+              let <N1> = call 'erlang':'-'(N, 1)
+              in let <D> = apply 'depth'/1(N1)
+                 in call 'erlang':'+'(D, 1)
+        end
+end
+"##);
+
+        // Far deeper than the native stack would allow
+        let n = Term::new_i64(100000);
+        assert!(returned(ctx.call("deep", "depth", ::std::slice::from_ref(&n))).erl_exact_eq(&n));
+
+        ctx.start("deep", "depth", &[Term::new_i64(10)]);
+        match ctx.step() {
+            RunResult::Yielded => (),
+            ret => panic!("expected yield, got {:?}", ret),
+        }
+        let mut yields = 0;
+        let ret = loop {
+            match ctx.run(10) {
+                RunResult::Yielded => yields += 1,
+                RunResult::Finished(ret) => break returned(ret),
+                RunResult::Breakpoint { .. } => unreachable!(),
+            }
+        };
+        assert!(ret.erl_exact_eq(&Term::new_i64(10)));
+        assert!(yields > 5, "{}", yields);
+    }
+
 }
@@ -0,0 +1,261 @@
+//! Matching of terms against the patterns of case and receive clauses.
+//!
+//! The interpreter walks the decision tree made by `compile_pattern`. The
+//! clause it arrives at is then matched against its full patterns, which
+//! binds the variables and performs the checks left out of the tree, like
+//! map keys and binary segments.
+
+use ::std::collections::HashMap;
+
+use ::petgraph::Direction;
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::visit::EdgeRef;
+use ::pattern_compiler::CfgNodeKind;
+
+use ::Variable;
+use ::ir::hir::{ Pattern, PatternNode };
+use ::ir::hir::pass::pattern::{ DecisionTree, CfgVar, NodeKind };
+use ::ir::lir::Clause;
+use super::Term;
+use super::binary::{ SegmentSpec, match_segment };
+
+/// A position in a decision tree, along with the values of the tree
+/// variables tested so far.
+pub struct TreeWalk {
+    node: NodeIndex,
+    vars: HashMap<CfgVar, Term>,
+}
+
+/// A clause matched by `match_tree`.
+pub struct TreeMatch {
+    pub clause: usize,
+    /// The values bound by the patterns of the clause.
+    pub binds: Vec<Term>,
+    /// For a clause with a guard, where matching resumes when the guard
+    /// fails.
+    pub resume: Option<TreeWalk>,
+}
+
+/// Matches the terms against the clauses by walking the decision tree,
+/// from the entry or from where an earlier match left off. Returns None
+/// if no clause matches.
+pub fn match_tree(tree: &DecisionTree, clauses: &[Clause], terms: &[Term],
+                  values: &[Term], start: Option<TreeWalk>) -> Option<TreeMatch> {
+    let cfg = &tree.cfg;
+    let (mut node, mut vars) = match start {
+        Some(walk) => (walk.node, walk.vars),
+        None => (cfg.entry, HashMap::new()),
+    };
+    loop {
+        match cfg.graph[node] {
+            CfgNodeKind::Root => {
+                let edge = cfg.graph.edges_directed(node, Direction::Outgoing)
+                    .next().unwrap();
+                assert!(edge.weight().variable_binds.len() == terms.len());
+                for (var, term) in edge.weight().variable_binds.iter().zip(terms) {
+                    vars.insert(*var, term.clone());
+                }
+                node = edge.target();
+            },
+            CfgNodeKind::Match(var) => {
+                let term = vars[&var].clone();
+                let mut default = None;
+                let mut next = None;
+                for edge in cfg.graph.edges_directed(node, Direction::Outgoing) {
+                    if edge.weight().kind == NodeKind::Wildcard {
+                        default = Some(edge);
+                    } else if let Some(children) = specialize(tree, edge.weight().kind, &term) {
+                        next = Some((edge, children));
+                        break;
+                    }
+                }
+                let (edge, children) = next
+                    .unwrap_or_else(|| (default.unwrap(), Vec::new()));
+                assert!(edge.weight().variable_binds.len() == children.len());
+                for (var, child) in edge.weight().variable_binds.iter().zip(children) {
+                    vars.insert(*var, child);
+                }
+                node = edge.target();
+            },
+            CfgNodeKind::Guard(clause) => {
+                let (_, fail) = cfg.guard_targets(node);
+                // The patterns may still fail on the checks left out of
+                // the tree
+                match match_clause(&clauses[clause].patterns, terms, values) {
+                    Some(binds) => return Some(TreeMatch {
+                        clause,
+                        binds,
+                        resume: Some(TreeWalk { node: fail, vars }),
+                    }),
+                    None => node = fail,
+                }
+            },
+            CfgNodeKind::Leaf(clause) => {
+                let binds = match_clause(&clauses[clause].patterns, terms, values)
+                    .expect("decision tree matched a clause its patterns do not");
+                return Some(TreeMatch {
+                    clause,
+                    binds,
+                    resume: None,
+                });
+            },
+            CfgNodeKind::Fail => return None,
+        }
+    }
+}
+
+/// The values of the children of the term if it is of the kind, in the
+/// order of the tree variables bound by the edge.
+fn specialize(tree: &DecisionTree, kind: NodeKind, term: &Term) -> Option<Vec<Term>> {
+    match (kind, term) {
+        (NodeKind::Tuple(arity), Term::Tuple(elems)) if elems.len() == arity =>
+            Some(elems.clone()),
+        (NodeKind::ListCell, Term::List(cells, tail)) =>
+            Some(vec![cells[0].clone(), Term::list(cells[1..].to_vec(), (**tail).clone())]),
+        (NodeKind::Nil, &Term::Nil) => Some(Vec::new()),
+        (NodeKind::Literal(num), term)
+            if Term::from_literal(tree.provider.get_literal(num)).erl_exact_eq(term) =>
+            Some(Vec::new()),
+        (NodeKind::Map, &Term::Map(_)) => Some(Vec::new()),
+        (NodeKind::Binary, &Term::BitString(_)) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Matches the terms against the patterns of a clause. `values` are the
+/// values referenced by map and binary patterns. On success, returns the
+/// values bound by the patterns, in the order of their bindings.
+pub fn match_clause(patterns: &[Pattern], terms: &[Term], values: &[Term])
+                    -> Option<Vec<Term>> {
+    assert!(patterns.len() == terms.len());
+    let mut binds = HashMap::new();
+    for (pattern, term) in patterns.iter().zip(terms.iter()) {
+        if !match_node(&pattern.node, term, values, &mut binds) {
+            return None;
+        }
+    }
+    Some(patterns.iter()
+         .flat_map(|pattern| pattern.binds.iter())
+         .map(|(var, _)| binds[var].clone())
+         .collect())
+}
+
+fn bind(var: &Variable, term: &Term, binds: &mut HashMap<Variable, Term>) -> bool {
+    if let Some(bound) = binds.get(var) {
+        return bound.erl_exact_eq(term);
+    }
+    binds.insert(var.clone(), term.clone());
+    true
+}
+
+fn match_node(node: &PatternNode, term: &Term, values: &[Term],
+              binds: &mut HashMap<Variable, Term>) -> bool {
+    match *node {
+        PatternNode::Wildcard => true,
+        PatternNode::BindVar(ref var, ref inner) =>
+            match_node(inner, term, values, binds) && bind(var, term, binds),
+        PatternNode::Atomic(ref literal) =>
+            term.erl_exact_eq(&Term::from_literal(literal)),
+        PatternNode::Tuple(ref elems) => match *term {
+            Term::Tuple(ref terms) if terms.len() == elems.len() =>
+                elems.iter().zip(terms.iter())
+                .all(|(elem, term)| match_node(elem, term, values, binds)),
+            _ => false,
+        },
+        PatternNode::List(ref head, ref tail) => {
+            let mut rest = term.clone();
+            for elem in head.iter() {
+                let (first, tail) = match rest {
+                    Term::List(mut cells, tail) => {
+                        let first = cells.remove(0);
+                        (first, Term::list(cells, *tail))
+                    },
+                    _ => return false,
+                };
+                if !match_node(elem, &first, values, binds) {
+                    return false;
+                }
+                rest = tail;
+            }
+            match_node(tail, &rest, values, binds)
+        },
+        PatternNode::Map(ref entries) => match *term {
+            Term::Map(ref map) => entries.iter().all(|&(key_idx, ref node)| {
+                match map.get(&values[key_idx]) {
+                    Some(value) => match_node(node, value, values, binds),
+                    None => false,
+                }
+            }),
+            _ => false,
+        },
+        PatternNode::Binary(ref elems) => {
+            let bin = match *term {
+                Term::BitString(ref bin) => bin,
+                _ => return false,
+            };
+            let mut offset = 0;
+            for (elem, opts) in elems.iter() {
+                let opts: Vec<_> = opts.iter().map(|idx| values[*idx].clone()).collect();
+                let segment = SegmentSpec::from_opts(&opts)
+                    .and_then(|spec| match_segment(bin, offset, &spec));
+                match segment {
+                    Some((value, bits)) => {
+                        if !match_node(elem, &value, values, binds) {
+                            return false;
+                        }
+                        offset += bits;
+                    },
+                    None => return false,
+                }
+            }
+            offset == bin.bit_len
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+    use ::intern::Atom;
+    use ::parser::AtomicLiteral;
+    use ::ir::hir::{ Pattern, PatternNode };
+    use ::ir::hir::pass::pattern::DecisionTree;
+    use ::ir::lir::Clause;
+    use ::interpreter::Term;
+
+    #[test]
+    fn resume_after_guard() {
+        // <{'a', _}> when Guard, <{'b', _}>, <{_, _}>
+        let tag = |name: &str| PatternNode::Atomic(
+            AtomicLiteral::Atom(Atom::from_str(name).unwrap()));
+        let clause = |first: PatternNode, has_guard| Clause {
+            patterns: vec![Pattern {
+                binds: vec![],
+                node: PatternNode::Tuple(vec![first, PatternNode::Wildcard]),
+            }],
+            has_guard,
+        };
+        let clauses = vec![
+            clause(tag("a"), true), clause(tag("b"), false),
+            clause(PatternNode::Wildcard, false),
+        ];
+        let tree = DecisionTree::compile(
+            1, clauses.iter().map(|c| (c.patterns.as_slice(), c.has_guard)));
+
+        let terms = vec![Term::Tuple(vec![Term::new_atom("a"), Term::new_i64(1)])];
+        let matched = super::match_tree(&tree, &clauses, &terms, &[], None).unwrap();
+        assert!(matched.clause == 0);
+
+        // The tuple and its tag are not tested again
+        let resume = matched.resume.unwrap();
+        assert!(resume.node != tree.cfg.entry);
+        assert!(resume.vars.len() == 3);
+        let matched = super::match_tree(&tree, &clauses, &terms, &[], Some(resume)).unwrap();
+        assert!(matched.clause == 2);
+        assert!(matched.resume.is_none());
+
+        let terms = vec![Term::new_atom("a")];
+        assert!(super::match_tree(&tree, &clauses, &terms, &[], None).is_none());
+    }
+
+}
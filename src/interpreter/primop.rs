@@ -0,0 +1,193 @@
+//! The primitive operations called by `primop` expressions in Core
+//! Erlang.
+//!
+//! `erlc` lowers receive expressions to a loop over the
+//! `recv_peek_message`, `recv_next`, `remove_message`,
+//! `recv_wait_timeout` and `timeout` primops. They walk the mailbox with
+//! the receive cursor of the mailbox, which is reset whenever a receive
+//! is finished.
+
+use ::std::collections::HashMap;
+
+use super::{ ExecutionContext, Term, BitString, Exception, receive_deadline };
+
+pub enum PrimOpReturn {
+    /// Primops may return several values, like `recv_peek_message`,
+    /// they are then bound by a let.
+    Values(Vec<Term>),
+    Throw(Exception),
+    /// The process waits for a message until the deadline, the primop
+    /// is called again when it is woken up.
+    Wait { deadline: Option<u64> },
+}
+
+pub type PrimOpFun = fn(&ExecutionContext, &[Term]) -> PrimOpReturn;
+
+fn badarg() -> Exception {
+    Exception::error(Term::new_atom("badarg"))
+}
+
+fn match_fail(_ctx: &ExecutionContext, args: &[Term]) -> PrimOpReturn {
+    PrimOpReturn::Throw(Exception::error(args[0].clone()))
+}
+
+/// Rethrows an exception caught by a try, given its raw stack trace and
+/// reason.
+fn raise(_ctx: &ExecutionContext, args: &[Term]) -> PrimOpReturn {
+    PrimOpReturn::Throw(Exception::from_raw_stacktrace(&args[0], args[1].clone())
+        .unwrap_or_else(badarg))
+}
+
+fn build_stacktrace(_ctx: &ExecutionContext, args: &[Term]) -> PrimOpReturn {
+    match Exception::from_raw_stacktrace(&args[0], Term::Nil) {
+        Some(exception) => PrimOpReturn::Values(vec![exception.stacktrace_term()]),
+        None => PrimOpReturn::Throw(badarg()),
+    }
+}
+
+/// Binaries are never written in place, the size is only a hint.
+fn bs_init_writable(_ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    PrimOpReturn::Values(vec![Term::BitString(BitString::from_bytes(Vec::new()))])
+}
+
+/// Match contexts are the binaries themselves.
+fn bs_context_to_binary(_ctx: &ExecutionContext, args: &[Term]) -> PrimOpReturn {
+    PrimOpReturn::Values(vec![args[0].clone()])
+}
+
+/// Returns `<true, Message>` with the message at the receive cursor, or
+/// `<false, []>` when there are no more messages.
+fn recv_peek_message(ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    ctx.with_mailbox(|mailbox| match mailbox.messages.get(mailbox.recv_cursor) {
+        Some(message) => PrimOpReturn::Values(vec![Term::new_bool(true), message.clone()]),
+        None => PrimOpReturn::Values(vec![Term::new_bool(false), Term::Nil]),
+    })
+}
+
+fn recv_next(ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    ctx.with_mailbox(|mailbox| mailbox.recv_cursor += 1);
+    PrimOpReturn::Values(vec![Term::Nil])
+}
+
+/// Removes the message at the receive cursor, finishing the receive.
+fn remove_message(ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    ctx.with_mailbox(|mailbox| {
+        mailbox.messages.remove(mailbox.recv_cursor);
+        mailbox.recv_cursor = 0;
+        mailbox.recv_deadline = None;
+    });
+    PrimOpReturn::Values(vec![Term::Nil])
+}
+
+/// Waits for a new message, returning false when one arrives and true
+/// when the timeout expires first. The timeout starts with the first
+/// wait of the receive.
+fn recv_wait_timeout(ctx: &ExecutionContext, args: &[Term]) -> PrimOpReturn {
+    let now = ctx.now();
+    ctx.with_mailbox(|mailbox| {
+        if mailbox.recv_cursor < mailbox.messages.len() {
+            return PrimOpReturn::Values(vec![Term::new_bool(false)]);
+        }
+        let deadline = match mailbox.recv_deadline {
+            Some(deadline) => Some(deadline),
+            None => match receive_deadline(now, &args[0]) {
+                Ok(deadline) => deadline,
+                Err(exception) => return PrimOpReturn::Throw(exception),
+            },
+        };
+        mailbox.recv_deadline = deadline;
+        match deadline {
+            Some(deadline) if deadline <= now => PrimOpReturn::Values(vec![Term::new_bool(true)]),
+            deadline => PrimOpReturn::Wait { deadline },
+        }
+    })
+}
+
+/// Finishes a receive through its timeout.
+fn timeout(ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    ctx.with_mailbox(|mailbox| {
+        mailbox.recv_cursor = 0;
+        mailbox.recv_deadline = None;
+    });
+    PrimOpReturn::Values(vec![Term::Nil])
+}
+
+/// Starts a function to be replaced by a NIF, there are none.
+fn nif_start(_ctx: &ExecutionContext, _args: &[Term]) -> PrimOpReturn {
+    PrimOpReturn::Values(vec![Term::Nil])
+}
+
+/// The primops emitted by `erlc`, keyed by name and arity.
+pub fn make_primops() -> HashMap<(String, u32), PrimOpFun> {
+    let primops: &[(&str, u32, PrimOpFun)] = &[
+        ("match_fail", 1, match_fail), ("raise", 2, raise),
+        ("build_stacktrace", 1, build_stacktrace),
+        ("bs_init_writable", 1, bs_init_writable),
+        ("bs_context_to_binary", 1, bs_context_to_binary),
+        ("recv_peek_message", 0, recv_peek_message), ("recv_next", 0, recv_next),
+        ("remove_message", 0, remove_message),
+        ("recv_wait_timeout", 1, recv_wait_timeout), ("timeout", 0, timeout),
+        ("nif_start", 0, nif_start),
+    ];
+    primops.iter()
+        .map(|&(name, arity, fun)| ((name.to_string(), arity), fun))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, Term };
+
+    #[test]
+    fn primop_receive() {
+        // As emitted by erlc for
+        // receive {ok, V} -> V after 0 -> none end
+        let core = r##"
+module 'test' ['recv'/0] attributes []
+'recv'/0 =
+    fun () ->
+        letrec
+            'recv$^0'/0 =
+                fun () ->
+                    let <PeekSucceeded, Message> = primop 'recv_peek_message'()
+                    in case PeekSucceeded of
+                         <'true'> when 'true' ->
+                             case Message of
+                               <{'ok', V}> when 'true' ->
+                                   do primop 'remove_message'()
+                                      V
+                               <_Other> when 'true' ->
+                                   do primop 'recv_next'()
+                                      apply 'recv$^0'/0()
+                             end
+                         <'false'> when 'true' ->
+                             let <TimedOut> = primop 'recv_wait_timeout'(0)
+                             in case TimedOut of
+                                  <'true'> when 'true' ->
+                                      do primop 'timeout'()
+                                         'none'
+                                  <'false'> when 'true' ->
+                                      apply 'recv$^0'/0()
+                                end
+                       end
+        in apply 'recv$^0'/0()
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.add_erlang_module(::ir::from_parsed(&parsed.0));
+
+        let recv = || match ctx.call("test", "recv", &[]) {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+        };
+
+        let pid = ctx.self_pid();
+        ctx.send(pid, Term::new_atom("other"));
+        ctx.send(pid, Term::Tuple(vec![Term::new_atom("ok"), Term::new_i64(1)]));
+        assert!(recv().erl_exact_eq(&Term::new_i64(1)));
+        assert!(recv().erl_exact_eq(&Term::new_atom("none")));
+        assert!(ctx.with_mailbox(|mailbox| mailbox.messages.len()) == 1);
+    }
+
+}
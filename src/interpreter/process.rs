@@ -0,0 +1,466 @@
+//! Lightweight processes and their scheduler.
+//!
+//! Processes are run one at a time by a deterministic scheduler. A
+//! process runs until it has made `REDUCTIONS` function calls, waits in
+//! a receive, or finishes. Preempted processes go to the back of the run
+//! queue, waiting ones are put back when a message is sent to them.
+//!
+//! Time is virtual, it only passes when every process is waiting. The
+//! clock then jumps to the earliest receive timeout, so runs are
+//! reproducible. When no process has a timeout, the call of the root
+//! process can never finish, and raises `error:deadlock`.
+//!
+//! The root process makes the calls through `ExecutionContext::call`,
+//! or `start` and `run`, and is idle in between. `run` executes at most
+//! a budget of OPs, and continues where the last one stopped, so the
+//! schedule does not depend on the budgets. A call ends with an exit
+//! exception when the root process gets an exit signal. The other
+//! processes are spawned by Erlang code, and exit when their function
+//! returns or throws, or when they get an exit signal, see `signal`.
+
+use ::intern::Atom;
+use ::ir::FunctionIdent;
+use super::{ ExecutionContext, Frame, Term, Pid, CallReturn, RunResult,
+             Step, Exception, ExceptionClass, stacktrace };
+use super::signal::Signals;
+
+pub const ROOT_PID: Pid = Pid(0);
+
+/// Function calls a process makes before it is preempted, as in BEAM.
+pub const REDUCTIONS: usize = 4000;
+
+pub struct Mailbox {
+    /// Oldest first.
+    pub messages: Vec<Term>,
+    /// Position of the message matched by a receive made of primops.
+    pub recv_cursor: usize,
+    /// Virtual time a receive made of primops times out at, set once it
+    /// has waited.
+    pub recv_deadline: Option<u64>,
+}
+impl Mailbox {
+
+    pub fn new() -> Self {
+        Mailbox {
+            messages: Vec::new(),
+            recv_cursor: 0,
+            recv_deadline: None,
+        }
+    }
+
+}
+
+enum Status {
+    Runnable,
+    /// Waiting in a receive, until the virtual time of the deadline.
+    Waiting { deadline: Option<u64> },
+    /// The root process between calls.
+    Idle,
+}
+
+pub struct Process {
+    /// The functions being executed, innermost last.
+    pub frames: Vec<Frame>,
+    /// The call the process starts with, made when it is first run.
+    start: Option<(Atom, FunctionIdent, Vec<Term>)>,
+    status: Status,
+    /// Function calls left before the process is preempted.
+    reductions: usize,
+}
+impl Process {
+
+    pub fn new_root() -> Self {
+        Process {
+            frames: Vec::new(),
+            start: None,
+            status: Status::Idle,
+            reductions: REDUCTIONS,
+        }
+    }
+
+}
+
+/// Why a process stopped running.
+enum Slice {
+    Preempted,
+    /// The budget of the run ran out.
+    Yielded,
+    /// A breakpoint was reached.
+    Break,
+    Waiting { deadline: Option<u64> },
+    Done(CallReturn),
+    /// Got an exit signal with the reason.
+    Exited(Term),
+}
+
+impl ExecutionContext {
+
+    /// The process the code runs in.
+    pub fn self_pid(&self) -> Pid {
+        self.current.get()
+    }
+
+    /// The virtual time in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.clock.get()
+    }
+
+    /// Starts a process calling the function, it first runs once the
+    /// current process is preempted or waits.
+    pub fn spawn(&self, module: Atom, fun: FunctionIdent, args: Vec<Term>) -> Pid {
+        let pid = Pid(self.next_pid.get());
+        self.next_pid.set(pid.0 + 1);
+
+        self.processes.borrow_mut().insert(pid, Process {
+            frames: Vec::new(),
+            start: Some((module, fun, args)),
+            status: Status::Runnable,
+            reductions: REDUCTIONS,
+        });
+        self.mailboxes.borrow_mut().insert(pid, Mailbox::new());
+        self.signals.borrow_mut().insert(pid, Signals::new());
+        self.run_queue.borrow_mut().push_back(pid);
+        pid
+    }
+
+    /// Adds a message to the mailbox of a process, waking it up if it
+    /// waits in a receive. Messages to processes that have exited are
+    /// dropped.
+    pub fn send(&self, pid: Pid, message: Term) {
+        match self.mailboxes.borrow_mut().get_mut(&pid) {
+            Some(mailbox) => mailbox.messages.push(message),
+            None => return,
+        }
+        self.wake(pid);
+    }
+
+    /// Puts a process waiting in a receive back in the run queue.
+    pub fn wake(&self, pid: Pid) {
+        if let Some(process) = self.processes.borrow_mut().get_mut(&pid) {
+            if let Status::Waiting { .. } = process.status {
+                process.status = Status::Runnable;
+                self.run_queue.borrow_mut().push_back(pid);
+            }
+        }
+    }
+
+    /// Calls `fun` with the mailbox of the current process.
+    pub(super) fn with_mailbox<F, R>(&self, fun: F) -> R where F: FnOnce(&mut Mailbox) -> R {
+        let mut mailboxes = self.mailboxes.borrow_mut();
+        fun(mailboxes.get_mut(&self.current.get()).unwrap())
+    }
+
+    /// Starts a call in the root process, made when it is first run.
+    pub(super) fn start_root(&self, module: Atom, fun: FunctionIdent, args: Vec<Term>) {
+        {
+            let mut processes = self.processes.borrow_mut();
+            let root = processes.get_mut(&ROOT_PID)
+                .expect("calls can not be made from within a process");
+            match root.status {
+                Status::Idle => (),
+                _ => panic!("the root process is already making a call"),
+            }
+            root.start = Some((module, fun, args));
+            root.status = Status::Runnable;
+        }
+        self.run_queue.borrow_mut().push_front(ROOT_PID);
+    }
+
+    /// Runs the scheduler for at most `budget` OPs, or until the call of
+    /// the root process returns.
+    pub fn run(&self, budget: usize) -> RunResult {
+        match self.processes.borrow().get(&ROOT_PID) {
+            Some(&Process { status: Status::Idle, .. }) => panic!("no call has been started"),
+            None => panic!("calls can not be made from within a process"),
+            Some(_) => (),
+        }
+
+        let mut budget = budget;
+        loop {
+            let next = self.run_queue.borrow_mut().pop_front();
+            let pid = match next {
+                Some(pid) => pid,
+                None => {
+                    if !self.advance_clock() {
+                        return RunResult::Finished(self.abandon_root());
+                    }
+                    continue;
+                },
+            };
+
+            let mut process = self.processes.borrow_mut().remove(&pid).unwrap();
+            self.current.set(pid);
+            let slice = match self.take_exit(pid) {
+                Some(reason) => Slice::Exited(reason),
+                None => self.run_slice(&mut process, &mut budget),
+            };
+            self.current.set(ROOT_PID);
+
+            match slice {
+                Slice::Preempted => {
+                    process.reductions = REDUCTIONS;
+                    self.run_queue.borrow_mut().push_back(pid);
+                },
+                Slice::Yielded => {
+                    // The slice goes on with the next run
+                    self.run_queue.borrow_mut().push_front(pid);
+                    self.processes.borrow_mut().insert(pid, process);
+                    return RunResult::Yielded;
+                },
+                Slice::Break => {
+                    self.run_queue.borrow_mut().push_front(pid);
+                    self.processes.borrow_mut().insert(pid, process);
+                    return RunResult::Breakpoint { pid };
+                },
+                Slice::Waiting { deadline } => {
+                    process.reductions = REDUCTIONS;
+                    process.status = Status::Waiting { deadline };
+                },
+                Slice::Done(ret) => {
+                    if pid == ROOT_PID {
+                        process.reductions = REDUCTIONS;
+                        process.status = Status::Idle;
+                        self.processes.borrow_mut().insert(pid, process);
+                        return RunResult::Finished(ret);
+                    }
+                    let reason = match ret {
+                        CallReturn::Return { .. } => Term::new_atom("normal"),
+                        CallReturn::Throw { exception } => exception.exit_reason(),
+                    };
+                    self.exit_process(pid, reason);
+                    continue;
+                },
+                Slice::Exited(reason) => {
+                    self.exit_process(pid, reason.clone());
+                    if pid == ROOT_PID {
+                        process.frames.clear();
+                        process.reductions = REDUCTIONS;
+                        process.status = Status::Idle;
+                        self.processes.borrow_mut().insert(pid, process);
+                        return RunResult::Finished(CallReturn::Throw {
+                            exception: Exception::new(ExceptionClass::Exit, reason),
+                        });
+                    }
+                    continue;
+                },
+            }
+            self.processes.borrow_mut().insert(pid, process);
+        }
+    }
+
+    /// Runs the scheduler for a single OP.
+    pub fn step(&self) -> RunResult {
+        self.run(1)
+    }
+
+    fn run_slice(&self, process: &mut Process, budget: &mut usize) -> Slice {
+        if let Some((module, fun, args)) = process.start.take() {
+            if let Some(ret) = self.enter(&mut process.frames, module, fun, args) {
+                return Slice::Done(ret);
+            }
+            if self.at_breakpoint(&process.frames) {
+                return Slice::Break;
+            }
+        }
+
+        loop {
+            if process.reductions == 0 {
+                return Slice::Preempted;
+            }
+            if *budget == 0 {
+                return Slice::Yielded;
+            }
+            *budget -= 1;
+            match self.step_frames(&mut process.frames) {
+                Step::Op => (),
+                Step::Call => process.reductions -= 1,
+                Step::Wait { deadline } => return Slice::Waiting { deadline },
+                Step::Done(ret) => return Slice::Done(ret),
+            }
+            // The OP may have made the process get an exit signal
+            if let Some(reason) = self.take_exit(self.current.get()) {
+                return Slice::Exited(reason);
+            }
+            if self.at_breakpoint(&process.frames) {
+                return Slice::Break;
+            }
+        }
+    }
+
+    fn take_exit(&self, pid: Pid) -> Option<Term> {
+        self.signals.borrow_mut().get_mut(&pid).and_then(|signals| signals.exit.take())
+    }
+
+    /// Moves the clock to the earliest deadline of the waiting processes,
+    /// and wakes up the processes that time out. Returns false if no
+    /// process has a deadline.
+    fn advance_clock(&self) -> bool {
+        let mut processes = self.processes.borrow_mut();
+        let now = processes.values()
+            .filter_map(|process| match process.status {
+                Status::Waiting { deadline } => deadline,
+                _ => None,
+            })
+            .min();
+        let now = match now {
+            Some(now) => now,
+            None => return false,
+        };
+        self.clock.set(now);
+
+        for (pid, process) in processes.iter_mut() {
+            match process.status {
+                Status::Waiting { deadline: Some(deadline) } if deadline <= now => {
+                    process.status = Status::Runnable;
+                    self.run_queue.borrow_mut().push_back(*pid);
+                },
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /// Ends the call of the root process when it waits for a message
+    /// that can not arrive. The other processes keep waiting.
+    fn abandon_root(&self) -> CallReturn {
+        let mut processes = self.processes.borrow_mut();
+        let root = processes.get_mut(&ROOT_PID).unwrap();
+        let mut exception = Exception::error(Term::new_atom("deadlock"));
+        exception.stacktrace = stacktrace(&root.frames);
+        root.frames.clear();
+        root.reductions = REDUCTIONS;
+        root.status = Status::Idle;
+
+        let mut mailboxes = self.mailboxes.borrow_mut();
+        let mailbox = mailboxes.get_mut(&ROOT_PID).unwrap();
+        mailbox.recv_cursor = 0;
+        mailbox.recv_deadline = None;
+        CallReturn::Throw { exception }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, Term, ExceptionClass };
+
+    #[test]
+    fn scheduling() {
+        let core = r##"
+module 'sched' ['race'/0, 'timers'/0, 'echo'/0, 'count'/3, 'delay'/3]
+    attributes []
+'count'/3 =
+    fun (N, To, Tag) ->
+        case N of
+          <0> when 'true' -> call 'erlang':'!'(To, Tag)
+          <_Other> when 'true' ->
+              let <N1> = call 'erlang':'-'(N, 1)
+              in apply 'count'/3(N1, To, Tag)
+        end
+'delay'/3 =
+    fun (Time, To, Tag) ->
+        receive
+          <'never'> when 'true' -> 'never'
+        after Time -> call 'erlang':'!'(To, Tag)
+'collect'/1 =
+    fun (N) ->
+        case N of
+          <0> when 'true' -> []
+          <_Other> when 'true' ->
+              receive
+                <Msg> when 'true' ->
+                    let <N1> = call 'erlang':'-'(N, 1)
+                    in [Msg|apply 'collect'/1(N1)]
+              after 'infinity' -> 'never'
+        end
+'race'/0 =
+    fun () ->
+        let <Self> = call 'erlang':'self'()
+        in do call 'erlang':'spawn'('sched', 'count', [10000, Self, 'slow'])
+              do call 'erlang':'spawn'('sched', 'count', [10, Self, 'fast'])
+                 apply 'collect'/1(2)
+'timers'/0 =
+    fun () ->
+        let <Self> = call 'erlang':'self'()
+        in do call 'erlang':'spawn'('sched', 'delay', [200, Self, 'late'])
+              do call 'erlang':'spawn'('sched', 'delay', [100, Self, 'early'])
+                 receive
+                   <'never'> when 'true' -> 'never'
+                 after 150 -> apply 'collect'/1(2)
+'echo'/0 =
+    fun () ->
+        let <Self> = call 'erlang':'self'()
+        in let <Pid> = call 'erlang':'spawn'(fun () ->
+                receive
+                  <{From, Msg}> when 'true' -> call 'erlang':'!'(From, {'echo', Msg})
+                after 'infinity' -> 'never'
+              )
+        in do call 'erlang':'!'(Pid, {Self, 'hello'})
+              receive
+                <{'echo', Msg}> when 'true' -> Msg
+              after 'infinity' -> 'never'
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(::interpreter::lib::make_erlang());
+        ctx.add_erlang_module(::ir::from_parsed(&parsed.0));
+
+        let call = |name: &str| match ctx.call("sched", name, &[]) {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+        };
+        let atoms = |atoms: &[&str]| Term::proper_list(
+            atoms.iter().map(|a| Term::new_atom(a)).collect());
+
+        // The slow process is preempted
+        let ret = call("race");
+        assert!(ret.erl_exact_eq(&atoms(&["fast", "slow"])), "{}", ret);
+
+        // Both timers expire while waiting, in order
+        let ret = call("timers");
+        assert!(ret.erl_exact_eq(&atoms(&["early", "late"])), "{}", ret);
+        assert!(ctx.now() == 200);
+
+        let ret = call("echo");
+        assert!(ret.erl_exact_eq(&Term::new_atom("hello")), "{}", ret);
+
+        // The spawned processes have exited
+        assert!(ctx.processes.borrow().len() == 1);
+    }
+
+    #[test]
+    fn deadlock() {
+        let core = r##"
+module 'lock' ['wait'/0, 'ok'/0] attributes []
+'wait'/0 =
+    fun () ->
+        receive <M> when 'true' -> M
+        after 'infinity' -> 'never'
+'ok'/0 =
+    fun () -> 'ok'
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(::interpreter::lib::make_erlang());
+        ctx.add_erlang_module(::ir::from_parsed(&parsed.0));
+
+        match ctx.call("lock", "wait", &[]) {
+            CallReturn::Throw { exception } => {
+                assert!(exception.class == ExceptionClass::Error);
+                assert!(exception.reason.erl_exact_eq(&Term::new_atom("deadlock")),
+                        "{}", exception);
+                assert!(exception.stacktrace.len() == 1);
+            },
+            ret => panic!("expected throw, got {:?}", ret),
+        }
+
+        // The root process can make calls again
+        match ctx.call("lock", "ok", &[]) {
+            CallReturn::Return { term } => assert!(term.erl_exact_eq(&Term::new_atom("ok"))),
+            ret => panic!("unexpected {:?}", ret),
+        }
+    }
+
+}
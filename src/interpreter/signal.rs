@@ -0,0 +1,268 @@
+//! Links, monitors and exit signals between processes.
+//!
+//! When a process exits, every linked process gets an exit signal with
+//! the exit reason, and every process monitoring it a `'DOWN'` message.
+//! A process trapping exits turns exit signals into `'EXIT'` messages,
+//! other processes exit with the reason unless it is `normal`. The
+//! signal is acted upon when the process next runs, a running process
+//! stops after the OP that sent it.
+
+use ::std::collections::{ BTreeMap, BTreeSet };
+
+use super::{ ExecutionContext, Term, Pid, Reference };
+use super::process::ROOT_PID;
+
+/// The links, monitors and exit signals of a live process.
+pub struct Signals {
+    links: BTreeSet<Pid>,
+    /// Monitors on the process, along with the monitoring process.
+    monitors: BTreeMap<Reference, Pid>,
+    trap_exit: bool,
+    /// Reason of an exit signal the process exits with.
+    pub exit: Option<Term>,
+}
+impl Signals {
+
+    pub fn new() -> Self {
+        Signals {
+            links: BTreeSet::new(),
+            monitors: BTreeMap::new(),
+            trap_exit: false,
+            exit: None,
+        }
+    }
+
+}
+
+impl ExecutionContext {
+
+    pub fn is_alive(&self, pid: Pid) -> bool {
+        self.signals.borrow().contains_key(&pid)
+    }
+
+    /// Links the current process to another. Returns false if the other
+    /// process is not alive.
+    pub fn link(&self, pid: Pid) -> bool {
+        let current = self.current.get();
+        if pid == current {
+            return true;
+        }
+        let mut signals = self.signals.borrow_mut();
+        match signals.get_mut(&pid) {
+            Some(other) => other.links.insert(current),
+            None => return false,
+        };
+        signals.get_mut(&current).unwrap().links.insert(pid);
+        true
+    }
+
+    pub fn unlink(&self, pid: Pid) {
+        let current = self.current.get();
+        let mut signals = self.signals.borrow_mut();
+        if let Some(other) = signals.get_mut(&pid) {
+            other.links.remove(&current);
+        }
+        signals.get_mut(&current).unwrap().links.remove(&pid);
+    }
+
+    /// Monitors a process from the current one. A process that is not
+    /// alive gets a `'DOWN'` message with the reason `noproc` right away.
+    pub fn monitor(&self, pid: Pid) -> Reference {
+        let reference = self.make_ref();
+        let monitored = match self.signals.borrow_mut().get_mut(&pid) {
+            Some(other) => {
+                other.monitors.insert(reference, self.current.get());
+                true
+            },
+            None => false,
+        };
+        if !monitored {
+            self.send(self.current.get(), down_message(reference, pid, Term::new_atom("noproc")));
+        }
+        reference
+    }
+
+    pub fn demonitor(&self, reference: Reference) {
+        for signals in self.signals.borrow_mut().values_mut() {
+            signals.monitors.remove(&reference);
+        }
+    }
+
+    pub fn traps_exit(&self) -> bool {
+        self.signals.borrow()[&self.current.get()].trap_exit
+    }
+
+    /// Sets whether the current process traps exits, returning the old
+    /// value.
+    pub fn set_trap_exit(&self, trap_exit: bool) -> bool {
+        let mut signals = self.signals.borrow_mut();
+        let current = signals.get_mut(&self.current.get()).unwrap();
+        ::std::mem::replace(&mut current.trap_exit, trap_exit)
+    }
+
+    /// Sends an exit signal from a process to another. A `kill` signal
+    /// can not be trapped, and makes the process exit with the reason
+    /// `killed`.
+    pub fn exit_signal(&self, from: Pid, to: Pid, reason: Term, kill: bool) {
+        {
+            let mut signals = self.signals.borrow_mut();
+            let target = match signals.get_mut(&to) {
+                Some(target) => target,
+                None => return,
+            };
+            if target.trap_exit && !kill {
+                drop(signals);
+                let message = Term::Tuple(vec![Term::new_atom("EXIT"), Term::Pid(from), reason]);
+                self.send(to, message);
+                return;
+            }
+            if reason == Term::new_atom("normal") && !kill {
+                return;
+            }
+            if target.exit.is_none() {
+                target.exit = Some(if kill { Term::new_atom("killed") } else { reason });
+            }
+        }
+        self.wake(to);
+    }
+
+    /// Signals the links and monitors of a process that has exited. The
+    /// root process stays alive, without links or monitors.
+    pub fn exit_process(&self, pid: Pid, reason: Term) {
+        let signals = if pid == ROOT_PID {
+            ::std::mem::replace(
+                self.signals.borrow_mut().get_mut(&pid).unwrap(), Signals::new())
+        } else {
+            self.mailboxes.borrow_mut().remove(&pid);
+            self.signals.borrow_mut().remove(&pid).unwrap()
+        };
+
+        for (reference, watcher) in signals.monitors {
+            self.send(watcher, down_message(reference, pid, reason.clone()));
+        }
+        for linked in signals.links {
+            if let Some(other) = self.signals.borrow_mut().get_mut(&linked) {
+                other.links.remove(&pid);
+            }
+            self.exit_signal(pid, linked, reason.clone(), false);
+        }
+    }
+
+}
+
+fn down_message(reference: Reference, pid: Pid, reason: Term) -> Term {
+    Term::Tuple(vec![
+        Term::new_atom("DOWN"), Term::Reference(reference),
+        Term::new_atom("process"), Term::Pid(pid), reason,
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use ::interpreter::{ ExecutionContext, CallReturn, ExceptionClass, Term };
+
+    #[test]
+    fn exit_signals() {
+        let core = r##"
+module 'sup' ['crash'/0, 'chain'/0, 'watch'/0, 'kill'/0, 'unlinked'/0,
+              'linked'/0, 'worker'/1, 'middle'/0]
+    attributes []
+'worker'/1 =
+    fun (Reason) -> call 'erlang':'exit'(Reason)
+'middle'/0 =
+    fun () ->
+        do call 'erlang':'spawn_link'('sup', 'worker', ['boom'])
+           receive
+             <'never'> when 'true' -> 'never'
+           after 'infinity' -> 'never'
+'exit_reason'/1 =
+    fun (Pid) ->
+        receive
+          <{'EXIT', From, Reason}> when call 'erlang':'=:='(From, Pid) -> Reason
+        after 'infinity' -> 'never'
+'down_reason'/1 =
+    fun (Ref) ->
+        receive
+          <{'DOWN', R, 'process', _Pid, Reason}> when call 'erlang':'=:='(R, Ref) -> Reason
+        after 'infinity' -> 'never'
+'crash'/0 =
+    fun () ->
+        do call 'erlang':'process_flag'('trap_exit', 'true')
+           let <Pid> = call 'erlang':'spawn_link'(fun () -> call 'erlang':'+'('a', 1))
+           in let <Reason> = apply 'exit_reason'/1(Pid)
+              in call 'erlang':'element'(1, Reason)
+'chain'/0 =
+    fun () ->
+        let <Pid> = call 'erlang':'spawn_link'('sup', 'middle', [])
+        in apply 'exit_reason'/1(Pid)
+'watch'/0 =
+    fun () ->
+        let <Pid> = call 'erlang':'spawn'('sup', 'worker', ['normal'])
+        in let <Ref> = call 'erlang':'monitor'('process', Pid)
+           in let <Normal> = apply 'down_reason'/1(Ref)
+              in let <Ref2> = call 'erlang':'monitor'('process', Pid)
+                 in {Normal, apply 'down_reason'/1(Ref2)}
+'kill'/0 =
+    fun () ->
+        let <Pid> = call 'erlang':'spawn'(fun () ->
+                do call 'erlang':'process_flag'('trap_exit', 'true')
+                   receive
+                     <'never'> when 'true' -> 'never'
+                   after 'infinity' -> 'never')
+        in let <Ref> = call 'erlang':'monitor'('process', Pid)
+           in do call 'erlang':'exit'(Pid, 'kill')
+                 apply 'down_reason'/1(Ref)
+'unlinked'/0 =
+    fun () ->
+        do call 'erlang':'process_flag'('trap_exit', 'false')
+           let <Pid> = call 'erlang':'spawn_link'('sup', 'worker', ['fatal'])
+           in do call 'erlang':'unlink'(Pid)
+                 receive
+                   <'never'> when 'true' -> 'never'
+                 after 10 -> 'ok'
+'linked'/0 =
+    fun () ->
+        do call 'erlang':'spawn_link'('sup', 'worker', ['fatal'])
+           receive
+             <'never'> when 'true' -> 'never'
+           after 'infinity' -> 'never'
+end
+"##;
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.add_native_module(::interpreter::lib::make_erlang());
+        ctx.add_erlang_module(::ir::from_parsed(&parsed.0));
+
+        let call = |name: &str| match ctx.call("sup", name, &[]) {
+            CallReturn::Return { term } => term,
+            CallReturn::Throw { exception } => panic!("unexpected throw {}", exception),
+        };
+
+        // Errors exit with the reason and the stack trace
+        assert!(call("crash").erl_exact_eq(&Term::new_atom("badarith")));
+
+        // The exit of the worker takes the middle process with it
+        assert!(call("chain").erl_exact_eq(&Term::new_atom("boom")));
+
+        let ret = call("watch");
+        let expected = Term::Tuple(vec![Term::new_atom("normal"), Term::new_atom("noproc")]);
+        assert!(ret.erl_exact_eq(&expected), "{}", ret);
+
+        // Kill signals are not trapped
+        assert!(call("kill").erl_exact_eq(&Term::new_atom("killed")));
+
+        assert!(call("unlinked").erl_exact_eq(&Term::new_atom("ok")));
+
+        // The root process does not trap exits any more, its call exits
+        match ctx.call("sup", "linked", &[]) {
+            CallReturn::Throw { exception } => {
+                assert!(exception.class == ExceptionClass::Exit);
+                assert!(exception.reason.erl_exact_eq(&Term::new_atom("fatal")));
+            },
+            ret => panic!("expected exit, got {:?}", ret),
+        }
+        assert!(ctx.processes.borrow().len() == 1);
+        assert!(ctx.signals.borrow().len() == 1);
+    }
+
+}
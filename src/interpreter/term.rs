@@ -0,0 +1,509 @@
+//! Erlang terms, with the standard term order.
+//!
+//! Terms are ordered by type first,
+//! `number < atom < reference < fun < pid < tuple < map < nil < list < bitstring`,
+//! then by value. Comparisons come in two flavours:
+//!
+//! * `erl_cmp` and `erl_eq` compare numbers by value, `1 == 1.0`.
+//! * `erl_exact_eq` and the `Eq`/`Ord` impls tell integers and floats
+//!   apart, `1 =/= 1.0`, with integers ordered before equal floats. This is
+//!   the order used for map keys.
+
+use ::std::cmp::Ordering;
+use ::std::collections::BTreeMap;
+use ::std::fmt::{ Display, Formatter };
+use ::std::str::FromStr;
+
+use ::num_bigint::BigInt;
+use ::num_traits::FromPrimitive;
+
+use ::intern::Atom;
+use ::ir::FunctionIdent;
+use ::parser::{ AtomicLiteral, Constant, write_atom };
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reference(pub usize);
+
+/// A sequence of bits. A binary is a bitstring with a length divisible
+/// by 8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitString {
+    /// Starts at the most significant bit of the first byte. Bits past
+    /// `bit_len` are always zero.
+    pub bytes: Vec<u8>,
+    pub bit_len: usize,
+}
+impl BitString {
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let bit_len = bytes.len() * 8;
+        BitString {
+            bytes,
+            bit_len,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.bit_len.is_multiple_of(8)
+    }
+
+    pub fn bit(&self, idx: usize) -> bool {
+        assert!(idx < self.bit_len);
+        (self.bytes[idx / 8] >> (7 - idx % 8)) & 1 == 1
+    }
+
+    pub fn push_bit(&mut self, bit: bool) {
+        if self.bit_len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            self.bytes[self.bit_len / 8] |= 1 << (7 - self.bit_len % 8);
+        }
+        self.bit_len += 1;
+    }
+
+    pub fn append(&mut self, other: &BitString) {
+        for idx in 0..other.bit_len {
+            self.push_bit(other.bit(idx));
+        }
+    }
+
+    /// The `len` bits starting at `start`.
+    pub fn slice(&self, start: usize, len: usize) -> BitString {
+        assert!(start + len <= self.bit_len);
+        let mut slice = BitString::from_bytes(vec![]);
+        for idx in start..(start + len) {
+            slice.push_bit(self.bit(idx));
+        }
+        slice
+    }
+
+}
+impl Ord for BitString {
+    fn cmp(&self, other: &BitString) -> Ordering {
+        let common = ::std::cmp::min(self.bit_len, other.bit_len);
+        for idx in 0..common {
+            match self.bit(idx).cmp(&other.bit(idx)) {
+                Ordering::Equal => (),
+                ord => return ord,
+            }
+        }
+        self.bit_len.cmp(&other.bit_len)
+    }
+}
+impl PartialOrd for BitString {
+    fn partial_cmp(&self, other: &BitString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Term {
+    /// Integers are of arbitrary size, small and big integers are not
+    /// told apart.
+    Integer(BigInt),
+    Float(f64),
+    Atom(Atom),
+    Tuple(Vec<Term>),
+    Nil,
+    /// A chain of cons cells holding the elements, ending in the tail.
+    /// Always has at least one element, and the tail is never itself a
+    /// `List`. Use `Term::list` to construct.
+    List(Vec<Term>, Box<Term>),
+    Map(BTreeMap<Term, Term>),
+    BitString(BitString),
+    /// A local fun, together with the values it closes over.
+    BoundLambda {
+        module: Atom,
+        fun: FunctionIdent,
+        env: Vec<Term>,
+    },
+    /// An external fun, `fun M:F/A`.
+    CapturedFunction {
+        module: Atom,
+        name: Atom,
+        arity: u32,
+    },
+    Pid(Pid),
+    Reference(Reference),
+}
+
+impl Term {
+
+    pub fn new_i64(num: i64) -> Term {
+        Term::Integer(BigInt::from(num))
+    }
+
+    pub fn new_atom(name: &str) -> Term {
+        Term::Atom(Atom::from_str(name).unwrap())
+    }
+
+    pub fn new_bool(val: bool) -> Term {
+        Term::new_atom(if val { "true" } else { "false" })
+    }
+
+    /// Builds the list of `head` ending in `tail`, keeping the `List`
+    /// invariants.
+    pub fn list(mut head: Vec<Term>, tail: Term) -> Term {
+        match tail {
+            Term::List(tail_head, tail_tail) => {
+                head.extend(tail_head);
+                Term::list(head, *tail_tail)
+            }
+            tail => {
+                if head.is_empty() {
+                    tail
+                } else {
+                    Term::List(head, Box::new(tail))
+                }
+            }
+        }
+    }
+
+    pub fn proper_list(elems: Vec<Term>) -> Term {
+        Term::list(elems, Term::Nil)
+    }
+
+    /// The elements of a proper list, None if the term is not one.
+    pub fn as_proper_list(&self) -> Option<&[Term]> {
+        match *self {
+            Term::Nil => Some(&[]),
+            Term::List(ref head, ref tail) if **tail == Term::Nil => Some(head),
+            _ => None,
+        }
+    }
+
+    pub fn from_literal(literal: &AtomicLiteral) -> Term {
+        match *literal {
+            AtomicLiteral::Integer(ref int) =>
+                Term::Integer(BigInt::from_str(&int.to_string()).unwrap()),
+            AtomicLiteral::Float(float) => Term::Float(float.as_f64()),
+            AtomicLiteral::Atom(ref atom) => Term::Atom(atom.clone()),
+            AtomicLiteral::Nil => Term::Nil,
+            AtomicLiteral::Char(c) => Term::new_i64(c as i64),
+            AtomicLiteral::String(ref string) => Term::proper_list(
+                string.chars().map(|c| Term::new_i64(c as i64)).collect()),
+        }
+    }
+
+    pub fn from_constant(constant: &Constant) -> Term {
+        match *constant {
+            Constant::Atomic(ref literal) => Term::from_literal(literal),
+            Constant::Tuple(ref elems) =>
+                Term::Tuple(elems.iter().map(Term::from_constant).collect()),
+            Constant::List(ref head, ref tail) =>
+                Term::list(head.iter().map(Term::from_constant).collect(),
+                           Term::from_constant(tail)),
+            Constant::Map(ref entries) => Term::Map(
+                entries.iter()
+                    .map(|(k, v)| (Term::from_constant(k), Term::from_constant(v)))
+                    .collect()),
+        }
+    }
+
+    pub fn atom_str(&self) -> &str {
+        if let Term::Atom(ref atom) = *self {
+            atom
+        } else {
+            panic!();
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(*self, Term::Integer(_) | Term::Float(_))
+    }
+
+    /// Position of the type in the term order.
+    fn type_rank(&self) -> u8 {
+        match *self {
+            Term::Integer(_) | Term::Float(_) => 0,
+            Term::Atom(_) => 1,
+            Term::Reference(_) => 2,
+            Term::BoundLambda { .. } | Term::CapturedFunction { .. } => 3,
+            Term::Pid(_) => 4,
+            Term::Tuple(_) => 5,
+            Term::Map(_) => 6,
+            Term::Nil => 7,
+            Term::List(_, _) => 8,
+            Term::BitString(_) => 9,
+        }
+    }
+
+    /// Compares in the standard term order. When `exact` is set, integers
+    /// sort before floats of the same value instead of comparing equal.
+    fn compare(&self, other: &Term, exact: bool) -> Ordering {
+        match (self, other) {
+            (Term::Integer(a), Term::Integer(b)) => a.cmp(b),
+            (&Term::Float(a), &Term::Float(b)) => a.partial_cmp(&b).unwrap(),
+            (Term::Integer(a), &Term::Float(b)) => {
+                match compare_int_float(a, b) {
+                    Ordering::Equal if exact => Ordering::Less,
+                    ord => ord,
+                }
+            }
+            (&Term::Float(a), Term::Integer(b)) => {
+                match compare_int_float(b, a).reverse() {
+                    Ordering::Equal if exact => Ordering::Greater,
+                    ord => ord,
+                }
+            }
+            (Term::Atom(a), Term::Atom(b)) => {
+                let a: &str = a;
+                let b: &str = b;
+                a.cmp(b)
+            }
+            (&Term::Reference(a), &Term::Reference(b)) => a.cmp(&b),
+            (&Term::Pid(a), &Term::Pid(b)) => a.cmp(&b),
+            (Term::Tuple(a), Term::Tuple(b)) => {
+                a.len().cmp(&b.len())
+                    .then_with(|| compare_seq(a, b, exact))
+            }
+            (Term::Map(a), Term::Map(b)) => {
+                // Keys are always compared exactly
+                a.len().cmp(&b.len())
+                    .then_with(|| {
+                        let a_keys: Vec<_> = a.keys().cloned().collect();
+                        let b_keys: Vec<_> = b.keys().cloned().collect();
+                        compare_seq(&a_keys, &b_keys, true)
+                    })
+                    .then_with(|| {
+                        let a_vals: Vec<_> = a.values().cloned().collect();
+                        let b_vals: Vec<_> = b.values().cloned().collect();
+                        compare_seq(&a_vals, &b_vals, exact)
+                    })
+            }
+            (&Term::Nil, &Term::Nil) => Ordering::Equal,
+            (Term::List(a_head, a_tail), Term::List(b_head, b_tail)) => {
+                for (a, b) in a_head.iter().zip(b_head.iter()) {
+                    match a.compare(b, exact) {
+                        Ordering::Equal => (),
+                        ord => return ord,
+                    }
+                }
+                // Continue with whatever remains of the longer list
+                let common = ::std::cmp::min(a_head.len(), b_head.len());
+                match a_head.len().cmp(&b_head.len()) {
+                    Ordering::Equal => a_tail.compare(b_tail, exact),
+                    Ordering::Less => a_tail.compare(
+                        &Term::List(b_head[common..].to_vec(), b_tail.clone()), exact),
+                    Ordering::Greater => Term::List(a_head[common..].to_vec(), a_tail.clone())
+                        .compare(b_tail, exact),
+                }
+            }
+            (Term::BitString(a), Term::BitString(b)) => a.cmp(b),
+            (Term::BoundLambda { module: a_mod, fun: a_fun, env: a_env },
+             Term::BoundLambda { module: b_mod, fun: b_fun, env: b_env }) => {
+                let a_mod: &str = a_mod;
+                let b_mod: &str = b_mod;
+                a_mod.cmp(b_mod)
+                    .then_with(|| a_fun.to_string().cmp(&b_fun.to_string()))
+                    .then_with(|| a_env.len().cmp(&b_env.len()))
+                    .then_with(|| compare_seq(a_env, b_env, exact))
+            }
+            (&Term::CapturedFunction { module: ref a_mod, name: ref a_name, arity: a_arity },
+             &Term::CapturedFunction { module: ref b_mod, name: ref b_name, arity: b_arity }) => {
+                let (a_mod, a_name): (&str, &str) = (a_mod, a_name);
+                let (b_mod, b_name): (&str, &str) = (b_mod, b_name);
+                (a_mod, a_name, a_arity).cmp(&(b_mod, b_name, b_arity))
+            }
+            // Local funs sort before external ones
+            (&Term::BoundLambda { .. }, &Term::CapturedFunction { .. }) => Ordering::Less,
+            (&Term::CapturedFunction { .. }, &Term::BoundLambda { .. }) => Ordering::Greater,
+            _ => {
+                let ord = self.type_rank().cmp(&other.type_rank());
+                assert!(ord != Ordering::Equal);
+                ord
+            }
+        }
+    }
+
+    /// Standard term order as used by `<`, `==` and friends.
+    pub fn erl_cmp(&self, other: &Term) -> Ordering {
+        self.compare(other, false)
+    }
+
+    /// `==`
+    pub fn erl_eq(&self, other: &Term) -> bool {
+        self.erl_cmp(other) == Ordering::Equal
+    }
+
+    /// `=:=`
+    pub fn erl_exact_eq(&self, other: &Term) -> bool {
+        self.compare(other, true) == Ordering::Equal
+    }
+
+}
+
+fn compare_seq(a: &[Term], b: &[Term], exact: bool) -> Ordering {
+    for (a, b) in a.iter().zip(b.iter()) {
+        match a.compare(b, exact) {
+            Ordering::Equal => (),
+            ord => return ord,
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn compare_int_float(int: &BigInt, float: f64) -> Ordering {
+    if float.is_infinite() {
+        return if float > 0.0 { Ordering::Less } else { Ordering::Greater };
+    }
+    let floor = float.floor();
+    match int.cmp(&BigInt::from_f64(floor).unwrap()) {
+        Ordering::Equal if float > floor => Ordering::Less,
+        ord => ord,
+    }
+}
+
+impl PartialEq for Term {
+    fn eq(&self, other: &Term) -> bool {
+        self.erl_exact_eq(other)
+    }
+}
+impl Eq for Term {}
+impl Ord for Term {
+    fn cmp(&self, other: &Term) -> Ordering {
+        self.compare(other, true)
+    }
+}
+impl PartialOrd for Term {
+    fn partial_cmp(&self, other: &Term) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn write_seq(f: &mut Formatter, elems: &[Term]) -> ::std::fmt::Result {
+    for (idx, elem) in elems.iter().enumerate() {
+        if idx != 0 { write!(f, ",")?; }
+        write!(f, "{}", elem)?;
+    }
+    Ok(())
+}
+
+impl Display for Term {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        match *self {
+            Term::Integer(ref int) => write!(f, "{}", int),
+            Term::Float(float) => write!(f, "{:?}", float),
+            Term::Atom(ref atom) => write_atom(f, atom),
+            Term::Tuple(ref elems) => {
+                write!(f, "{{")?;
+                write_seq(f, elems)?;
+                write!(f, "}}")
+            }
+            Term::Nil => write!(f, "[]"),
+            Term::List(ref head, ref tail) => {
+                write!(f, "[")?;
+                write_seq(f, head)?;
+                match **tail {
+                    Term::Nil => (),
+                    ref tail => write!(f, "|{}", tail)?,
+                }
+                write!(f, "]")
+            }
+            Term::Map(ref entries) => {
+                write!(f, "#{{")?;
+                for (idx, (key, value)) in entries.iter().enumerate() {
+                    if idx != 0 { write!(f, ",")?; }
+                    write!(f, "{} => {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Term::BitString(ref bits) => {
+                write!(f, "<<")?;
+                let full = bits.bit_len / 8;
+                for (idx, byte) in bits.bytes[..full].iter().enumerate() {
+                    if idx != 0 { write!(f, ",")?; }
+                    write!(f, "{}", byte)?;
+                }
+                let rest = bits.bit_len % 8;
+                if rest != 0 {
+                    if full != 0 { write!(f, ",")?; }
+                    write!(f, "{}:{}", bits.bytes[full] >> (8 - rest), rest)?;
+                }
+                write!(f, ">>")
+            }
+            Term::BoundLambda { ref module, ref fun, .. } =>
+                write!(f, "#Fun<{}.{}>", module, fun),
+            Term::CapturedFunction { ref module, ref name, arity } =>
+                write!(f, "fun {}:{}/{}", module, name, arity),
+            Term::Pid(pid) => write!(f, "<0.{}.0>", pid.0),
+            Term::Reference(reference) => write!(f, "#Ref<0.{}>", reference.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::cmp::Ordering;
+    use super::{ Term, BitString, Pid, Reference };
+
+    #[test]
+    fn term_order() {
+        let ascending = vec![
+            Term::new_i64(-3),
+            Term::Float(-2.5),
+            Term::new_i64(1),
+            Term::Float(1.5),
+            Term::new_atom("a"),
+            Term::new_atom("b"),
+            Term::Reference(Reference(0)),
+            Term::CapturedFunction {
+                module: "m".parse().unwrap(),
+                name: "f".parse().unwrap(),
+                arity: 0,
+            },
+            Term::Pid(Pid(0)),
+            Term::Tuple(vec![Term::new_atom("z")]),
+            Term::Tuple(vec![Term::new_i64(1), Term::new_i64(2)]),
+            Term::Map(vec![(Term::new_i64(1), Term::Nil)].into_iter().collect()),
+            Term::Nil,
+            Term::list(vec![Term::new_i64(1)], Term::new_atom("a")),
+            Term::proper_list(vec![Term::new_i64(1)]),
+            Term::proper_list(vec![Term::new_i64(1), Term::new_i64(2)]),
+            Term::proper_list(vec![Term::new_i64(2)]),
+            Term::BitString(BitString { bytes: vec![0b1000_0000], bit_len: 1 }),
+            Term::BitString(BitString::from_bytes(vec![128])),
+        ];
+        for (idx, a) in ascending.iter().enumerate() {
+            for (jdx, b) in ascending.iter().enumerate() {
+                assert!(a.erl_cmp(b) == idx.cmp(&jdx), "{} {}", a, b);
+            }
+        }
+
+        // Lists are compared as chains of cons cells
+        let split = Term::list(vec![Term::new_i64(1)],
+                               Term::proper_list(vec![Term::new_i64(2)]));
+        assert!(split.erl_exact_eq(
+            &Term::proper_list(vec![Term::new_i64(1), Term::new_i64(2)])));
+    }
+
+    #[test]
+    fn exact_equality() {
+        let int = Term::new_i64(1);
+        let float = Term::Float(1.0);
+        assert!(int.erl_eq(&float));
+        assert!(!int.erl_exact_eq(&float));
+        assert!(int.cmp(&float) == Ordering::Less);
+
+        // Inexact comparison carries into containers, but not map keys
+        let t1 = Term::Tuple(vec![int.clone()]);
+        let t2 = Term::Tuple(vec![float.clone()]);
+        assert!(t1.erl_eq(&t2) && !t1.erl_exact_eq(&t2));
+        let m1 = Term::Map(vec![(int.clone(), int.clone())].into_iter().collect());
+        let m2 = Term::Map(vec![(int.clone(), float.clone())].into_iter().collect());
+        let m3 = Term::Map(vec![(float.clone(), int.clone())].into_iter().collect());
+        assert!(m1.erl_eq(&m2) && !m1.erl_exact_eq(&m2));
+        assert!(!m1.erl_eq(&m3));
+
+        // Big integers against floats
+        let big: Term = Term::Integer("100000000000000000000".parse().unwrap());
+        assert!(big.erl_eq(&Term::Float(1.0e20)));
+        assert!(big.erl_cmp(&Term::Float(1.5e20)) == Ordering::Less);
+        assert!(Term::Float(f64::INFINITY).erl_cmp(&big) == Ordering::Greater);
+    }
+
+}
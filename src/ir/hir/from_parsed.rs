@@ -14,6 +14,7 @@ impl Module {
         Module {
             name: module.name.clone(),
             attributes: module.attributes.clone(),
+            constants: ::ir::lir::ConstantPool::new(),
             functions: module.definitions.iter()
                 .map(|f| {
                     let name = f.name.0.name.clone();
@@ -33,6 +34,7 @@ impl Module {
                         lir_function: None,
                     }
                 }).collect(),
+            pattern_warnings: Vec::new(),
         }
     }
 }
@@ -42,6 +44,8 @@ impl Function {
         Function {
             args: fun.vars.iter().map(|a| AVariable::new(a.0.clone())).collect(),
             body: SingleExpression::from_parsed(&fun.body),
+            lambda_env: None,
+            letrec: Vec::new(),
         }
     }
 }
@@ -241,6 +245,7 @@ impl SingleExpression {
                     args: op.args.iter()
                         .map(|a| SingleExpression::from_parsed(a))
                         .collect(),
+                    values: Vec::new(),
                 }
             },
             PSE::Do(ref d1, ref d2) => {
@@ -318,8 +323,8 @@ impl SingleExpression {
             },
             PSE::Map(ref kv, ref merge) => {
                 let kv_h = kv.iter()
-                    .map(|&(ref k, ref v)| {
-                        (SingleExpression::from_parsed(k),
+                    .map(|&(ref k, typ, ref v)| {
+                        (SingleExpression::from_parsed(k), typ,
                          SingleExpression::from_parsed(v))
                     }).collect();
                 let merge = merge.as_ref().map(
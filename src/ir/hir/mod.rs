@@ -15,6 +15,13 @@ pub trait EachSingleExpression {
 pub struct Function {
     pub args: Vec<AVariable>,
     pub body: SingleExpression,
+    /// Set on lambdas, the variables they capture from the environment
+    /// are passed to them after the arguments.
+    pub lambda_env: Option<LambdaEnvIdx>,
+    /// For lambdas bound by a `letrec`, the functions bound together with
+    /// it. These can be referred to by name, and are not passed in the
+    /// environment.
+    pub letrec: Vec<(parser::FunctionName, FunctionIdent)>,
 }
 
 impl EachSingleExpression for Function {
@@ -25,6 +32,50 @@ impl EachSingleExpression for Function {
     }
 }
 
+impl Function {
+
+    /// The Core variables the SSA variables of the function were assigned
+    /// for, as far as they are bound in the HIR.
+    pub fn variable_names(&self) -> HashMap<SSAVariable, Variable> {
+        use self::SingleExpressionKind as SEK;
+
+        let mut names = HashMap::new();
+        for arg in self.args.iter() {
+            names.insert(arg.ssa, arg.var.clone());
+        }
+        // The traversal is only available on a mutable body
+        let mut body = self.body.clone();
+        body.each_single_expression_mut(&mut |expr: &mut SingleExpression| {
+            match expr.kind {
+                SEK::Variable(ref var) => {
+                    names.insert(var.ssa, var.var.clone());
+                },
+                SEK::Let { ref vars, .. } => {
+                    for var in vars.iter() {
+                        names.insert(var.ssa, var.var.clone());
+                    }
+                },
+                SEK::Try { ref then_vars, ref catch_vars, .. } => {
+                    for var in then_vars.iter().chain(catch_vars.iter()) {
+                        names.insert(var.ssa, var.var.clone());
+                    }
+                },
+                SEK::Case { ref clauses, .. } | SEK::Receive { ref clauses, .. } => {
+                    for pattern in clauses.iter().flat_map(|c| c.patterns.iter()) {
+                        for &(ref var, ssa) in pattern.binds.iter() {
+                            names.insert(ssa, var.clone());
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }, false);
+        names.remove(&::ir::INVALID_SSA);
+        names
+    }
+
+}
+
 #[derive(Debug, Clone)]
 pub struct Expression {
     pub values: Vec<SingleExpression>,
@@ -100,7 +151,7 @@ impl EachSingleExpression for SingleExpression {
                 tail.each_single_expression_mut(f, enter_lambdas);
             },
             SEK::Map { ref mut values, ref mut merge } => {
-                for &mut (ref mut key, ref mut val) in values.iter_mut() {
+                for &mut (ref mut key, _, ref mut val) in values.iter_mut() {
                     key.each_single_expression_mut(f, enter_lambdas);
                     val.each_single_expression_mut(f, enter_lambdas);
                 }
@@ -219,12 +270,15 @@ pub enum SingleExpressionKind {
     Atomic(parser::AtomicLiteral),
     Tuple(Vec<SingleExpression>),
     List { head: Vec<SingleExpression>, tail: Box<SingleExpression> },
-    Map { values: Vec<(SingleExpression, SingleExpression)>,
+    Map { values: Vec<(SingleExpression, parser::MapPairType, SingleExpression)>,
           merge: Option<Box<SingleExpression>> },
     Binary(Vec<(SingleExpression, Vec<SingleExpression>)>),
 
     // Calls
-    PrimOp { name: Atom, args: Vec<SingleExpression> },
+    /// Primops returning several values, like `recv_peek_message`, are
+    /// bound by a let. The values after the first are assigned to
+    /// `values`, the first to the expression itself.
+    PrimOp { name: Atom, args: Vec<SingleExpression>, values: Vec<SSAVariable> },
     ApplyCall { fun: Box<SingleExpression>, args: Vec<SingleExpression> },
     InterModuleCall { module: Box<SingleExpression>, name: Box<SingleExpression>,
                       args: Vec<SingleExpression> },
@@ -40,6 +40,15 @@ impl LambdaCollector {
         ident
     }
 
+    /// Marks the lambdas as bound together by a `letrec`.
+    fn set_letrec(&mut self, letrec: &[(::parser::FunctionName, FunctionIdent)]) {
+        for lambda in self.lambdas.iter_mut() {
+            if letrec.iter().any(|(_, ident)| *ident == lambda.ident) {
+                lambda.hir_fun.letrec = letrec.to_vec();
+            }
+        }
+    }
+
     pub fn finish(self) -> Vec<FunctionDefinition> {
         self.lambdas
     }
@@ -53,16 +62,23 @@ pub fn extract_lambdas<T>(func: &mut T,
     func.each_single_expression_mut(&mut |expr| {
         match expr.kind {
             SEK::BindClosure { ref mut closure, .. } => {
-                let fun = closure.fun.take().unwrap();
+                let mut fun = closure.fun.take().unwrap();
+                fun.lambda_env = closure.env;
                 let name = lambdas.collect(*fun);
                 closure.ident = Some(name);
             },
             SEK::BindClosures { ref mut closures, .. } => {
                 for closure in closures.iter_mut() {
-                    let fun = closure.fun.take().unwrap();
+                    let mut fun = closure.fun.take().unwrap();
+                    fun.lambda_env = closure.env;
                     let name = lambdas.collect(*fun);
                     closure.ident = Some(name);
                 }
+                let letrec: Vec<_> = closures.iter()
+                    .map(|c| (c.alias.as_ref().unwrap().var.clone(),
+                              c.ident.clone().unwrap()))
+                    .collect();
+                lambdas.set_letrec(&letrec);
             }
             _ => (),
         }
@@ -1,2 +1,3 @@
 pub mod ssa;
 pub mod extract_lambda;
+pub mod pattern;
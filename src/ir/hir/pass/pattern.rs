@@ -0,0 +1,590 @@
+//! Compiles the patterns of `Case` and `Receive` expressions to a decision
+//! tree in order to find clauses that can never match, and values that are
+//! not matched by any clause.
+
+use ::std::collections::HashMap;
+use ::std::fmt;
+
+use ::petgraph::{ Graph, Direction };
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::visit::EdgeRef;
+
+use ::pattern_compiler::{ PatternProvider, ExpandedClauseNodes, PatternCfg };
+use ::pattern_compiler::analysis::{ self, VariableConstraint, FailPath };
+
+use ::ir::{ FunctionDefinition, FunctionIdent, SSAVariable };
+use ::ir::hir::{ SingleExpression, SingleExpressionKind, Clause, Pattern,
+                 PatternNode, EachSingleExpression };
+use ::parser::{ AtomicLiteral, Integer };
+
+/// Maximum number of example values reported for a non exhaustive match.
+const MAX_EXAMPLES: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatchKind {
+    Case,
+    Receive,
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternWarning {
+    /// The clause is shadowed by earlier clauses, and will never be
+    /// selected.
+    UnreachableClause {
+        function: FunctionIdent,
+        kind: MatchKind,
+        expression: SSAVariable,
+        clause: usize,
+    },
+    /// Some values are not matched by any clause. `examples` contains a
+    /// few of them in Erlang term syntax.
+    NonExhaustive {
+        function: FunctionIdent,
+        expression: SSAVariable,
+        examples: Vec<String>,
+    },
+}
+impl fmt::Display for PatternWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatternWarning::UnreachableClause {
+                ref function, kind, expression, clause } => {
+                let kind_str = match kind {
+                    MatchKind::Case => "case",
+                    MatchKind::Receive => "receive",
+                };
+                write!(f, "{}: clause {} of {} {:?} can never match",
+                       function, clause, kind_str, expression)
+            },
+            PatternWarning::NonExhaustive {
+                ref function, expression, ref examples } => {
+                write!(f, "{}: case {:?} does not match all values, for example {}",
+                       function, expression, examples.join(", "))
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+pub struct CfgVar(usize);
+impl fmt::Debug for CfgVar {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "${}", self.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum NodeKind {
+    Wildcard,
+    Tuple(usize),
+    ListCell,
+    Nil,
+    /// Index into the literal table of the `ErlPatternProvider`.
+    Literal(usize),
+    /// Only checks that the value is a map. Any keys in the pattern
+    /// need to be checked separately.
+    Map,
+    /// Only checks that the value is a binary. The elements of the
+    /// pattern need to be checked separately.
+    Binary,
+}
+
+/// Pattern provider for HIR patterns.
+///
+/// Every clause has one pattern node for every matched value. Strings are
+/// expanded to lists of characters, variable bindings are ignored.
+#[derive(Debug, Clone)]
+pub struct ErlPatternProvider {
+    /// Edges are labeled with the index of the child in the parent.
+    pattern: Graph<NodeKind, usize>,
+    literals: Vec<AtomicLiteral>,
+    literal_map: HashMap<AtomicLiteral, usize>,
+
+    root_vars: Vec<CfgVar>,
+    root_nodes: Vec<NodeIndex>,
+    guards: Vec<bool>,
+    num_clauses: usize,
+    curr_var: CfgVar,
+}
+
+impl ErlPatternProvider {
+
+    pub fn new(num_values: usize) -> Self {
+        ErlPatternProvider {
+            pattern: Graph::new(),
+            literals: Vec::new(),
+            literal_map: HashMap::new(),
+
+            root_vars: (0..num_values).map(CfgVar).collect(),
+            root_nodes: Vec::new(),
+            guards: Vec::new(),
+            num_clauses: 0,
+            curr_var: CfgVar(num_values),
+        }
+    }
+
+    /// Adds a clause. Patterns with checks not performed by the decision
+    /// tree are treated as if the clause had a guard.
+    pub fn add_clause(&mut self, patterns: &[&PatternNode], has_guard: bool) {
+        assert!(patterns.len() == self.root_vars.len());
+        for pattern in patterns {
+            let node = self.add_node(pattern);
+            self.root_nodes.push(node);
+        }
+        let residual = patterns.iter().any(|p| has_residual_checks(p));
+        self.guards.push(has_guard || residual);
+        self.num_clauses += 1;
+    }
+
+    pub fn get_literal(&self, num: usize) -> &AtomicLiteral {
+        &self.literals[num]
+    }
+
+    fn add_kind(&mut self, kind: NodeKind, children: &[NodeIndex]) -> NodeIndex {
+        let node = self.pattern.add_node(kind);
+        for (idx, child) in children.iter().enumerate() {
+            self.pattern.add_edge(node, *child, idx);
+        }
+        node
+    }
+
+    /// Literals of equal value share a node kind, so that characters are
+    /// the same as the integers of their code points.
+    fn add_literal(&mut self, literal: &AtomicLiteral) -> NodeIndex {
+        let literal = match *literal {
+            AtomicLiteral::Char(c) => AtomicLiteral::Integer(Integer::from_i64(c as i64)),
+            AtomicLiteral::Integer(ref int) => AtomicLiteral::Integer(int.normalized()),
+            ref literal => literal.clone(),
+        };
+        let next = self.literals.len();
+        let num = *self.literal_map.entry(literal.clone()).or_insert(next);
+        if num == next {
+            self.literals.push(literal);
+        }
+        self.add_kind(NodeKind::Literal(num), &[])
+    }
+
+    fn add_node(&mut self, node: &PatternNode) -> NodeIndex {
+        match *node {
+            PatternNode::Wildcard => self.add_kind(NodeKind::Wildcard, &[]),
+            PatternNode::BindVar(_, ref inner) => self.add_node(inner),
+            PatternNode::Atomic(AtomicLiteral::Nil) =>
+                self.add_kind(NodeKind::Nil, &[]),
+            PatternNode::Atomic(AtomicLiteral::String(ref string)) => {
+                let mut tail = self.add_kind(NodeKind::Nil, &[]);
+                for c in string.chars().rev() {
+                    let head = self.add_literal(&AtomicLiteral::Char(c));
+                    tail = self.add_kind(NodeKind::ListCell, &[head, tail]);
+                }
+                tail
+            },
+            PatternNode::Atomic(ref literal) => self.add_literal(literal),
+            PatternNode::Tuple(ref elems) => {
+                let children: Vec<_> = elems.iter()
+                    .map(|elem| self.add_node(elem))
+                    .collect();
+                self.add_kind(NodeKind::Tuple(children.len()), &children)
+            },
+            PatternNode::List(ref head, ref tail) => {
+                let mut tail = self.add_node(tail);
+                for elem in head.iter().rev() {
+                    let head = self.add_node(elem);
+                    tail = self.add_kind(NodeKind::ListCell, &[head, tail]);
+                }
+                tail
+            },
+            PatternNode::Map(_) => self.add_kind(NodeKind::Map, &[]),
+            PatternNode::Binary(_) => self.add_kind(NodeKind::Binary, &[]),
+        }
+    }
+
+    fn children(&self, node: NodeIndex) -> Vec<NodeIndex> {
+        let mut children: Vec<_> = self.pattern
+            .edges_directed(node, Direction::Outgoing)
+            .map(|edge| (*edge.weight(), edge.target()))
+            .collect();
+        children.sort_by_key(|&(idx, _)| idx);
+        children.iter().map(|&(_, child)| child).collect()
+    }
+
+    /// Renders an example value following the given fail path, in Erlang
+    /// term syntax. Multiple values are rendered as a Core value list.
+    pub fn format_fail_path(&self, path: &FailPath<Self>) -> String {
+        let mut values: Vec<String> = path.roots.iter()
+            .map(|var| self.format_var(path, *var))
+            .collect();
+        if values.len() == 1 {
+            values.remove(0)
+        } else {
+            format!("<{}>", values.join(", "))
+        }
+    }
+
+    fn format_var(&self, path: &FailPath<Self>, var: CfgVar) -> String {
+        match path.constraints.get(&var) {
+            None => "_".to_string(),
+            Some(VariableConstraint::IsNot(kinds)) =>
+                self.format_not(kinds),
+            Some(&VariableConstraint::Is(kind, ref children)) => match kind {
+                NodeKind::Wildcard => "_".to_string(),
+                NodeKind::Tuple(_) => {
+                    let elems: Vec<_> = children.iter()
+                        .map(|child| self.format_var(path, *child))
+                        .collect();
+                    format!("{{{}}}", elems.join(", "))
+                },
+                NodeKind::ListCell => {
+                    let mut elems = vec![self.format_var(path, children[0])];
+                    let mut tail = children[1];
+                    loop {
+                        match path.constraints.get(&tail) {
+                            Some(&VariableConstraint::Is(NodeKind::ListCell, ref cell)) => {
+                                elems.push(self.format_var(path, cell[0]));
+                                tail = cell[1];
+                            },
+                            Some(&VariableConstraint::Is(NodeKind::Nil, _)) =>
+                                return format!("[{}]", elems.join(", ")),
+                            _ => break,
+                        }
+                    }
+                    format!("[{}|{}]", elems.join(", "), self.format_var(path, tail))
+                },
+                NodeKind::Nil => "[]".to_string(),
+                NodeKind::Literal(num) => format!("{}", self.literals[num]),
+                NodeKind::Map => "#{}".to_string(),
+                NodeKind::Binary => "<<>>".to_string(),
+            },
+        }
+    }
+
+    /// Finds a value that is of none of the given kinds.
+    fn format_not(&self, kinds: &[NodeKind]) -> String {
+        if kinds.is_empty() {
+            return "_".to_string();
+        }
+        if !kinds.contains(&NodeKind::Nil) {
+            return "[]".to_string();
+        }
+        if !kinds.contains(&NodeKind::Tuple(0)) {
+            return "{}".to_string();
+        }
+
+        let excluded: Vec<String> = kinds.iter()
+            .filter_map(|kind| match *kind {
+                NodeKind::Literal(num) => Some(format!("{}", self.literals[num])),
+                _ => None,
+            })
+            .collect();
+        (0..).map(|num: usize| num.to_string())
+            .find(|candidate| !excluded.contains(candidate))
+            .unwrap()
+    }
+
+}
+
+impl PatternProvider for ErlPatternProvider {
+
+    type PatternNodeKey = NodeIndex;
+    type PatternNodeKind = NodeKind;
+    type CfgVariable = CfgVar;
+
+    const WILDCARD: NodeKind = NodeKind::Wildcard;
+
+    fn get_root(&self) -> ExpandedClauseNodes<
+            Self::CfgVariable, Self::PatternNodeKey> {
+        ExpandedClauseNodes {
+            variables: self.root_vars.clone(),
+            clauses: self.num_clauses,
+            nodes: self.root_nodes.clone(),
+        }
+    }
+
+    fn kind_includes(&self, kind: Self::PatternNodeKind,
+                     key: Self::PatternNodeKey) -> bool {
+        let key_kind = self.pattern[key];
+        key_kind == kind || key_kind == NodeKind::Wildcard
+    }
+
+    fn expand_clause_nodes(&mut self, clause_nodes: Vec<Self::PatternNodeKey>)
+                           -> ExpandedClauseNodes<
+            Self::CfgVariable, Self::PatternNodeKey>
+    {
+        // Wildcards are included in every specialization, the arity is
+        // determined by the first non-wildcard node.
+        let arity = clause_nodes.iter()
+            .find(|n| self.pattern[**n] != NodeKind::Wildcard)
+            .map(|n| self.get_arity(*n))
+            .unwrap_or(0);
+
+        let mut curr_var = self.curr_var;
+        let variables = (0..arity)
+            .map(|_| {
+                curr_var.0 += 1;
+                curr_var
+            })
+            .collect();
+        self.curr_var = curr_var;
+
+        let mut nodes = Vec::new();
+        for node in clause_nodes.iter() {
+            if self.pattern[*node] == NodeKind::Wildcard {
+                for _ in 0..arity {
+                    nodes.push(self.pattern.add_node(NodeKind::Wildcard));
+                }
+            } else {
+                let children = self.children(*node);
+                assert!(children.len() == arity);
+                nodes.extend(children);
+            }
+        }
+
+        ExpandedClauseNodes {
+            variables,
+            clauses: clause_nodes.len(),
+            nodes,
+        }
+    }
+
+    fn get_kind(&self, key: Self::PatternNodeKey) -> Self::PatternNodeKind {
+        self.pattern[key]
+    }
+
+    fn clause_has_guard(&self, clause: usize) -> bool {
+        self.guards[clause]
+    }
+
+    fn get_arity(&self, key: Self::PatternNodeKey) -> usize {
+        self.pattern.edges_directed(key, Direction::Outgoing).count()
+    }
+
+}
+
+pub fn is_true(expr: &SingleExpression) -> bool {
+    match expr.kind {
+        SingleExpressionKind::Atomic(AtomicLiteral::Atom(ref atom)) =>
+            &**atom == "true",
+        _ => false,
+    }
+}
+
+/// Whether the pattern contains checks that are not performed by the
+/// decision tree.
+fn has_residual_checks(pattern: &PatternNode) -> bool {
+    let mut residual = false;
+    pattern.traverse_pattern(&mut |node| {
+        match *node {
+            PatternNode::Map(ref entries) if !entries.is_empty() =>
+                residual = true,
+            PatternNode::Binary(_) => residual = true,
+            _ => (),
+        }
+    });
+    residual
+}
+
+/// The fallback clause generated by erlc, which raises a `match_fail`
+/// when nothing else matched. It is expected to be unreachable.
+fn is_generated_fallback(clause: &Clause) -> bool {
+    let is_match_fail = match clause.body.kind {
+        SingleExpressionKind::PrimOp { ref name, .. } => &**name == "match_fail",
+        _ => false,
+    };
+    let all_wildcard = clause.patterns.iter().all(|pattern| {
+        let mut node = &pattern.node;
+        while let PatternNode::BindVar(_, ref inner) = *node {
+            node = inner;
+        }
+        matches!(*node, PatternNode::Wildcard)
+    });
+    is_match_fail && all_wildcard && is_true(&clause.guard)
+}
+
+/// A compiled decision tree, together with the provider needed to
+/// interpret it.
+#[derive(Clone)]
+pub struct DecisionTree {
+    pub provider: ErlPatternProvider,
+    pub cfg: PatternCfg<ErlPatternProvider>,
+}
+impl fmt::Debug for DecisionTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The full tree is too large to be useful in op listings, use
+        // `PatternCfg::to_dot` to inspect it.
+        write!(f, "DecisionTree({} nodes)", self.cfg.graph.node_count())
+    }
+}
+
+impl DecisionTree {
+
+    /// Compiles the given clauses, each given as its patterns and whether
+    /// it has a guard.
+    pub fn compile<'a, I>(num_values: usize, clauses: I) -> Self
+        where I: Iterator<Item = (&'a [Pattern], bool)>
+    {
+        let mut provider = ErlPatternProvider::new(num_values);
+        for (patterns, has_guard) in clauses {
+            let nodes: Vec<_> = patterns.iter().map(|p| &p.node).collect();
+            provider.add_clause(&nodes, has_guard);
+        }
+        let cfg = ::pattern_compiler::to_decision_tree(&mut provider);
+        DecisionTree {
+            provider,
+            cfg,
+        }
+    }
+
+}
+
+/// Returns the unreachable clauses, and examples of values not matched by
+/// any clause.
+///
+/// Clauses with guards never shadow later clauses, and do not contribute
+/// to exhaustiveness.
+pub fn check_clauses(num_values: usize, clauses: &[Clause])
+                     -> (Vec<usize>, Vec<String>)
+{
+    let tree = DecisionTree::compile(
+        num_values,
+        clauses.iter().map(|c| (c.patterns.as_slice(), !is_true(&c.guard))));
+
+    let unreachable = analysis::unreachable_clauses(&tree.cfg);
+    let examples: Vec<String> = analysis::fail_paths(&tree.cfg, MAX_EXAMPLES).iter()
+        .map(|path| tree.provider.format_fail_path(path))
+        .collect();
+
+    (unreachable, examples)
+}
+
+/// Checks every `Case` and `Receive` in the function.
+pub fn check_patterns(fun: &mut FunctionDefinition) -> Vec<PatternWarning> {
+    let ident = fun.ident.clone();
+    let mut warnings = Vec::new();
+
+    fun.hir_fun.each_single_expression_mut(&mut |expr| {
+        let (kind, num_values, clauses) = match expr.kind {
+            SingleExpressionKind::Case { ref val, ref clauses, .. } =>
+                (MatchKind::Case, val.values.len(), clauses),
+            SingleExpressionKind::Receive { ref clauses, .. } =>
+                (MatchKind::Receive, 1, clauses),
+            _ => return,
+        };
+
+        let (unreachable, examples) = check_clauses(num_values, clauses);
+
+        for clause in unreachable {
+            if clause == clauses.len() - 1 && is_generated_fallback(&clauses[clause]) {
+                continue;
+            }
+            warnings.push(PatternWarning::UnreachableClause {
+                function: ident.clone(),
+                kind,
+                expression: expr.ssa,
+                clause,
+            });
+        }
+
+        // Messages not matched by a receive are left in the mailbox.
+        if kind == MatchKind::Case && !examples.is_empty() {
+            warnings.push(PatternWarning::NonExhaustive {
+                function: ident.clone(),
+                expression: expr.ssa,
+                examples,
+            });
+        }
+    }, false);
+
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::PatternWarning;
+
+    fn warnings(core: &str) -> Vec<PatternWarning> {
+        let parsed = ::parser::annotated_module(core).unwrap();
+        ::ir::from_parsed(&parsed.0).pattern_warnings
+    }
+
+    #[test]
+    fn unreachable_and_non_exhaustive() {
+        let core = "module 'test' ['a'/1] attributes []
+'a'/1 =
+    fun (_cor0) ->
+	case _cor0 of
+	  <{'ok',X}> when 'true' ->
+	      X
+	  <[H]> when 'true' ->
+	      H
+	  <{'ok',_cor1}> when 'true' ->
+	      'never'
+	end
+end
+";
+        let res = warnings(core);
+        assert!(res.len() == 2);
+
+        match res[0] {
+            PatternWarning::UnreachableClause { clause, .. } =>
+                assert!(clause == 2),
+            _ => panic!(),
+        }
+        match res[1] {
+            PatternWarning::NonExhaustive { ref examples, .. } => {
+                assert!(!examples.is_empty());
+                println!("{:?}", examples);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn guards_do_not_shadow() {
+        let core = "module 'test' ['a'/1] attributes []
+'a'/1 =
+    fun (_cor0) ->
+	case _cor0 of
+	  <X> when call 'erlang':'is_atom'(X) ->
+	      X
+	  <[]> when 'true' ->
+	      'nil'
+	  <_cor1> when 'true' ->
+	      'other'
+	  ( <_cor2> when 'true' ->
+		primop 'match_fail'
+		    ({'case_clause',_cor2})
+	    -| ['compiler_generated'] )
+	end
+end
+";
+        assert!(warnings(core).is_empty());
+    }
+
+    #[test]
+    fn chars_are_integers() {
+        let core = "module 'test' ['a'/1] attributes []
+'a'/1 =
+    fun (_cor0) ->
+	case _cor0 of
+	  <\"a\"> when 'true' ->
+	      'string'
+	  <[97]> when 'true' ->
+	      'list'
+	  <$b> when 'true' ->
+	      'char'
+	  <0098> when 'true' ->
+	      'integer'
+	  <_cor1> when 'true' ->
+	      'other'
+	end
+end
+";
+        let clauses: Vec<_> = warnings(core).iter()
+            .map(|warning| match *warning {
+                PatternWarning::UnreachableClause { clause, .. } => clause,
+                _ => panic!("unexpected {}", warning),
+            })
+            .collect();
+        assert!(clauses == vec![1, 3], "{:?}", clauses);
+    }
+
+}
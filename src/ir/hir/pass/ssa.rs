@@ -6,6 +6,8 @@
 //! simpler to convert this into a stricter form of SSA in a later
 //! pass, since it dramatically simplifies some other compilation
 //! passes. (See pattern match compilation)
+//!
+//! The conversion to strict SSA is done on LIR by `lir::pass::strict_ssa`.
 
 use ::std::collections::{ HashSet, HashMap };
 use ::ir::{ AVariable, AFunctionName, SSAVariable };
@@ -69,6 +71,14 @@ impl ScopeTracker {
         self.ssa_var_generator.next()
     }
 
+    /// The variables captured by the closures bound with the environment.
+    /// Every capture is given as the definition, the variable outside the
+    /// closures and the variable inside them.
+    pub fn lambda_env(&self, idx: LambdaEnvIdx)
+                      -> &[(ScopeDefinition, SSAVariable, SSAVariable)] {
+        &self.lambda_envs[idx.0]
+    }
+
     fn get(&mut self, var: &ScopeDefinition) -> Option<SSAVariable> {
         let mut ssa_var_root: Option<SSAVariable> = None;
         let mut end_idx: usize = 0;
@@ -134,9 +144,20 @@ pub fn assign_ssa_single_expression(env: &mut ScopeTracker,
         SingleExpressionKind::Let { ref mut val, ref mut vars, ref mut body } => {
             assign_ssa_expression(env, val);
 
+            // A single primop may return all the values
+            if vars.len() > 1 && val.values.len() == 1 {
+                if let SingleExpressionKind::PrimOp { ref mut values, .. } = val.values[0].kind {
+                    *values = (1..vars.len()).map(|_| env.new_ssa()).collect();
+                }
+            }
+
             let mut scope = HashMap::new();
             for (idx, var) in vars.iter_mut().enumerate() {
-                var.ssa = val.values[idx].ssa;
+                var.ssa = match (idx, &val.values[0].kind) {
+                    (idx, SingleExpressionKind::PrimOp { values, .. })
+                        if idx > 0 && !values.is_empty() => values[idx - 1],
+                    _ => val.values[idx].ssa,
+                };
                 scope.insert(ScopeDefinition::Variable(var.var.clone()), var.ssa);
             }
             env.push_scope(scope);
@@ -234,7 +255,7 @@ pub fn assign_ssa_single_expression(env: &mut ScopeTracker,
             expr.ssa = env.new_ssa();
         },
         SingleExpressionKind::Map { ref mut values, ref mut merge } => {
-            for &mut (ref mut key, ref mut val) in values.iter_mut() {
+            for &mut (ref mut key, _, ref mut val) in values.iter_mut() {
                 assign_ssa_single_expression(env, key);
                 assign_ssa_single_expression(env, val);
             }
@@ -357,7 +378,7 @@ pub fn assign_ssa_single_expression(env: &mut ScopeTracker,
             env.pop_scope();
 
             *env_ssa = env.new_ssa();
-            expr.ssa = env.new_ssa();
+            expr.ssa = body.ssa;
         },
         ref e => panic!("Unhandled: {:?}", e),
     }
@@ -1,12 +1,12 @@
 use ::ir::SSAVariable;
 use super::{ Label, Phi, OpKind, Source, Op };
-use ::petgraph::Graph;
+use ::petgraph::stable_graph::StableGraph;
 
 fn idx_of(lbl: Label) -> usize {
     (lbl.0 - 1) as usize
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct LabelN(pub ::petgraph::graph::NodeIndex);
 impl ::std::fmt::Display for LabelN {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
@@ -14,7 +14,7 @@ impl ::std::fmt::Display for LabelN {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct EdgeN(pub ::petgraph::graph::EdgeIndex);
 
 #[derive(Debug)]
@@ -24,27 +24,36 @@ pub struct BasicBlock {
     pub outgoing_edges: Vec<EdgeN>,
 }
 
+/// Blocks may be removed by passes, a `StableGraph` is used to keep
+/// the labels and edge ids stored in the CFG valid when that happens.
 #[derive(Debug)]
 pub struct FunctionCfg {
     pub entry: LabelN,
-    pub cfg: Graph<BasicBlock, BasicBlockEdge>,
+    pub cfg: StableGraph<BasicBlock, BasicBlockEdge>,
 }
 
+/// When the edge is taken, `writes[n]` is assigned `reads[n]` as a
+/// parallel copy. Only used once phi nodes have been removed by
+/// `out_of_ssa`.
 #[derive(Debug)]
 pub struct BasicBlockEdge {
-    writes: Vec<SSAVariable>,
+    pub writes: Vec<SSAVariable>,
+    pub reads: Vec<Source>,
 }
 
 #[derive(Debug)]
 pub struct FunctionCfgBuilder<'a> {
     target: &'a mut FunctionCfg,
     current: LabelN,
+    /// Blocks the throw edges of calls should jump to instead of
+    /// returning from the function. Innermost last.
+    throw_targets: Vec<LabelN>,
 }
 
 impl FunctionCfg {
 
     pub fn new() -> Self {
-        let mut cfg = Graph::new();
+        let mut cfg = StableGraph::new();
 
         let entry = cfg.add_node(BasicBlock {
             phi_nodes: vec![],
@@ -59,15 +68,11 @@ impl FunctionCfg {
     }
 
     pub fn blocks_iter<'a>(&'a self) -> Box<Iterator<Item = &BasicBlock> + 'a> {
-        Box::new(self.cfg.raw_nodes().iter()
-                 .map(|n| &n.weight))
+        Box::new(self.cfg.node_indices()
+                 .map(move |n| &self.cfg[n]))
     }
 
-    pub fn blocks_iter_mut<'a>(&'a mut self) -> Box<Iterator<Item = &mut BasicBlock> + 'a> {
-        Box::new(self.cfg.node_weights_mut())
-    }
-
-    pub fn labels_iter(&self) -> Box<Iterator<Item = LabelN>> {
+    pub fn labels_iter<'a>(&'a self) -> Box<dyn Iterator<Item = LabelN> + 'a> {
         Box::new(self.cfg.node_indices().map(|i| LabelN(i)))
     }
 
@@ -79,6 +84,10 @@ impl FunctionCfg {
         self.cfg.node_weight(lbl.0).unwrap()
     }
 
+    pub fn block_mut(&mut self, lbl: LabelN) -> &mut BasicBlock {
+        self.cfg.node_weight_mut(lbl.0).unwrap()
+    }
+
     pub fn jumps_iter<'a>(&'a self, lbl: LabelN) -> impl Iterator<Item = EdgeN> + 'a {
         let node = self.cfg.node_weight(lbl.0).unwrap();
         node.outgoing_edges.iter().map(|e| *e)
@@ -95,6 +104,66 @@ impl FunctionCfg {
         LabelN(self.cfg.edge_endpoints(lbl.0).unwrap().1)
     }
 
+    pub fn predecessors(&self, lbl: LabelN) -> Vec<LabelN> {
+        self.cfg
+            .neighbors_directed(lbl.0, ::petgraph::Direction::Incoming)
+            .map(LabelN)
+            .collect()
+    }
+
+    pub fn add_block(&mut self) -> LabelN {
+        LabelN(self.cfg.add_node(BasicBlock {
+            phi_nodes: vec![],
+            ops: vec![],
+            outgoing_edges: vec![],
+        }))
+    }
+
+    /// Adds an edge from `source` to `target`, after the existing
+    /// outgoing edges of `source`.
+    pub fn add_edge(&mut self, source: LabelN, target: LabelN) -> EdgeN {
+        let edge_id = self.cfg.add_edge(source.0, target.0, BasicBlockEdge {
+            writes: vec![],
+            reads: vec![],
+        });
+        self.block_mut(source).outgoing_edges.push(EdgeN(edge_id));
+        EdgeN(edge_id)
+    }
+
+    /// Moves the target of an edge, keeping its position among the
+    /// outgoing edges of the source block. Phi nodes are not updated.
+    pub fn redirect_edge(&mut self, edge: EdgeN, target: LabelN) -> EdgeN {
+        let (source, _) = self.cfg.edge_endpoints(edge.0).unwrap();
+        let weight = self.cfg.remove_edge(edge.0).unwrap();
+        let new_edge = EdgeN(self.cfg.add_edge(source, target.0, weight));
+
+        let block = self.block_mut(LabelN(source));
+        let pos = block.outgoing_edges.iter().position(|e| *e == edge).unwrap();
+        block.outgoing_edges[pos] = new_edge;
+        new_edge
+    }
+
+    /// Removes an edge. If no other edge connects the same blocks, the
+    /// phi entries for the source are removed from the target.
+    pub fn remove_edge(&mut self, edge: EdgeN) {
+        let (source, target) = self.cfg.edge_endpoints(edge.0).unwrap();
+        self.cfg.remove_edge(edge.0).unwrap();
+        self.block_mut(LabelN(source)).outgoing_edges.retain(|e| *e != edge);
+
+        if self.cfg.find_edge(source, target).is_none() {
+            for phi in self.block_mut(LabelN(target)).phi_nodes.iter_mut() {
+                phi.entries.retain(|&(pred, _)| pred.0 != source);
+            }
+        }
+    }
+
+    /// Removes a block along with all edges to and from it. Phi nodes
+    /// and outgoing edge lists of other blocks are not updated.
+    pub fn remove_block(&mut self, lbl: LabelN) -> BasicBlock {
+        assert!(lbl != self.entry);
+        self.cfg.remove_node(lbl.0).unwrap()
+    }
+
 }
 
 impl<'a> FunctionCfgBuilder<'a> {
@@ -103,6 +172,7 @@ impl<'a> FunctionCfgBuilder<'a> {
         FunctionCfgBuilder {
             current: cfg.entry,
             target: cfg,
+            throw_targets: vec![],
         }
     }
 
@@ -120,7 +190,7 @@ impl<'a> FunctionCfgBuilder<'a> {
                    pred: LabelN, pred_instr: SSAVariable,
                    node: LabelN, node_instr: SSAVariable) {
 
-        assert!(self.target.cfg.contains_edge(pred.0, node.0));
+        assert!(self.target.cfg.find_edge(pred.0, node.0).is_some());
         let block = self.target.cfg.node_weight_mut(node.0).unwrap();
         assert!(block.ops.len() == 0);
 
@@ -142,6 +212,7 @@ impl<'a> FunctionCfgBuilder<'a> {
     pub fn add_jump(&mut self, source: LabelN, dest: LabelN) {
         let edge_id = self.target.cfg.add_edge(source.0, dest.0, BasicBlockEdge {
             writes: vec![],
+            reads: vec![],
         });
         let node = self.target.cfg.node_weight_mut(source.0).unwrap();
         node.outgoing_edges.push(EdgeN(edge_id));
@@ -164,6 +235,18 @@ impl<'a> FunctionCfgBuilder<'a> {
         self.current
     }
 
+    pub fn push_throw_target(&mut self, block: LabelN) {
+        self.throw_targets.push(block);
+    }
+
+    pub fn pop_throw_target(&mut self) {
+        self.throw_targets.pop().unwrap();
+    }
+
+    pub fn throw_target(&self) -> Option<LabelN> {
+        self.throw_targets.last().cloned()
+    }
+
 }
 
 //#[derive(Debug, Clone)]
@@ -0,0 +1,63 @@
+use ::std::collections::HashMap;
+
+use ::parser::Constant;
+
+/// Index of a term in a `ConstantPool`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ConstId(pub usize);
+impl ::std::fmt::Display for ConstId {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "K{}", self.0)
+    }
+}
+
+/// Literal terms of a module, read by `Source::Literal`. Equal terms
+/// are only stored once.
+#[derive(Debug, Clone)]
+pub struct ConstantPool {
+    constants: Vec<Constant>,
+    ids: HashMap<Constant, ConstId>,
+}
+
+impl Default for ConstantPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConstantPool {
+
+    pub fn new() -> Self {
+        ConstantPool {
+            constants: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, constant: Constant) -> ConstId {
+        if let Some(id) = self.ids.get(&constant) {
+            return *id;
+        }
+        let id = ConstId(self.constants.len());
+        self.constants.push(constant.clone());
+        self.ids.insert(constant, id);
+        id
+    }
+
+    pub fn get(&self, id: ConstId) -> &Constant {
+        &self.constants[id.0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constants.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (ConstId, &'a Constant)> + 'a {
+        self.constants.iter().enumerate().map(|(idx, c)| (ConstId(idx), c))
+    }
+
+}
@@ -1,9 +1,11 @@
-use ::ir::{ Module, FunctionDefinition };
+use ::ir::{ Module, FunctionDefinition, FunctionIdent };
 use ::ir::SSAVariable;
 use ::ir::hir;
 use ::ir::lir;
 use ::ir::lir::Source;
-use ::ir::hir::pass::ssa::ScopeTracker;
+use ::ir::hir::pass::ssa::{ ScopeTracker, ScopeDefinition };
+use ::ir::hir::pass::pattern::is_true;
+use ::parser::MapPairType;
 
 pub fn do_lower(module: &mut Module, env: &mut ScopeTracker) {
     module.lower(env)
@@ -23,9 +25,37 @@ impl FunctionDefinition {
 
         {
             let mut builder = lir::cfg::FunctionCfgBuilder::new(&mut cfg);
-            builder.basic_op(
-                lir::OpKind::Arguments, vec![],
-                self.hir_fun.args.iter().map(|a| a.ssa).collect());
+
+            // Lambdas take their captured variables after the arguments.
+            // The functions bound in the same letrec are not captured, but
+            // bound again from the environment.
+            let mut arg_vars: Vec<_> = self.hir_fun.args.iter().map(|a| a.ssa).collect();
+            let mut letrec_vars = Vec::new();
+            if let Some(env_idx) = self.hir_fun.lambda_env {
+                for &(ref def, _, inner) in env.lambda_env(env_idx) {
+                    match letrec_ident(&self.hir_fun.letrec, def) {
+                        Some(ident) => letrec_vars.push((ident, inner)),
+                        None => arg_vars.push(inner),
+                    }
+                }
+            }
+            builder.basic_op(lir::OpKind::Arguments, vec![], arg_vars.clone());
+
+            if !letrec_vars.is_empty() {
+                let env_ssa = env.new_ssa();
+                builder.basic_op(
+                    lir::OpKind::MakeClosureEnv {
+                        env_idx: self.hir_fun.lambda_env.unwrap(),
+                    },
+                    arg_vars[self.hir_fun.args.len()..].iter()
+                        .map(|v| Source::Variable(*v)).collect(),
+                    vec![env_ssa]);
+                for (ident, var) in letrec_vars {
+                    builder.basic_op(
+                        lir::OpKind::BindClosure { ident },
+                        vec![Source::Variable(env_ssa)], vec![var]);
+                }
+            }
 
             let ret = self.hir_fun.body.lower(&mut builder, env);
             builder.basic_op(
@@ -94,6 +124,44 @@ impl FunctionDefinition {
 //    //}
 //}
 
+/// The function in the letrec the definition refers to, if any.
+fn letrec_ident(letrec: &[(::parser::FunctionName, FunctionIdent)],
+                def: &ScopeDefinition) -> Option<FunctionIdent> {
+    match *def {
+        ScopeDefinition::Function(ref name) => letrec.iter()
+            .find(|&(alias, _)| alias == name)
+            .map(|(_, ident)| ident.clone()),
+        _ => None,
+    }
+}
+
+/// Returns the block the throw edge of a call should jump to. Outside of
+/// guards this is a new block returning the exception from the function.
+fn lower_throw_target(b: &mut lir::cfg::FunctionCfgBuilder) -> lir::LabelN {
+    if let Some(target) = b.throw_target() {
+        return target;
+    }
+    let throw_block = b.add_block();
+    b.set_block(throw_block);
+    b.basic_op(lir::OpKind::ReturnThrow, vec![], vec![]);
+    throw_block
+}
+
+/// Adds an OP that may throw, continuing in a new block when it does not.
+fn lower_throwing_op(b: &mut lir::cfg::FunctionCfgBuilder, kind: lir::OpKind,
+                     reads: Vec<lir::Source>, writes: Vec<SSAVariable>) {
+    b.basic_op(kind, reads, writes);
+    let prev_block = b.get_block();
+
+    let throw_block = lower_throw_target(b);
+
+    let resume_block = b.add_block();
+    b.set_block(resume_block);
+
+    b.add_jump(prev_block, resume_block);
+    b.add_jump(prev_block, throw_block);
+}
+
 use self::hir::SingleExpressionKind as HSEK;
 impl hir::SingleExpression {
     fn lower(&self, b: &mut lir::cfg::FunctionCfgBuilder,
@@ -106,18 +174,7 @@ impl hir::SingleExpression {
                     .map(|r| lir::Source::Variable(*r))
                     .collect();
 
-                b.basic_op(lir::OpKind::Call, reads, vec![self.ssa]);
-                let prev_block = b.get_block();
-
-                let throw_block = b.add_block();
-                b.set_block(throw_block);
-                b.basic_op(lir::OpKind::ReturnThrow, vec![], vec![]);
-
-                let resume_block = b.add_block();
-                b.set_block(resume_block);
-
-                b.add_jump(prev_block, resume_block);
-                b.add_jump(prev_block, throw_block);
+                lower_throwing_op(b, lir::OpKind::Call, reads, vec![self.ssa]);
 
                 self.ssa
             },
@@ -128,18 +185,7 @@ impl hir::SingleExpression {
                     .map(|r| lir::Source::Variable(*r))
                     .collect();
 
-                b.basic_op(lir::OpKind::Apply, reads, vec![self.ssa]);
-                let prev_block = b.get_block();
-
-                let throw_block = b.add_block();
-                b.set_block(throw_block);
-                b.basic_op(lir::OpKind::ReturnThrow, vec![], vec![]);
-
-                let resume_block = b.add_block();
-                b.set_block(resume_block);
-
-                b.add_jump(prev_block, resume_block);
-                b.add_jump(prev_block, throw_block);
+                lower_throwing_op(b, lir::OpKind::Apply, reads, vec![self.ssa]);
 
                 self.ssa
             },
@@ -187,11 +233,35 @@ impl hir::SingleExpression {
             },
             HSEK::Try { ref body, ref then,
                         ref catch_vars, ref catch, .. } => {
+                let catch_label = b.add_block();
+                let done_label = b.add_block();
+
+                // Exceptions in the body are caught, the then branch
+                // is outside of the try
+                b.push_throw_target(catch_label);
                 for expr in body.values.iter() {
                     expr.lower(b, env);
                 }
-                then.lower(b, env);
-                then.ssa
+                b.pop_throw_target();
+
+                let then_ret = then.lower(b, env);
+                b.basic_op(lir::OpKind::Jump, vec![], vec![]);
+                let then_done_label = b.get_block();
+                b.add_jump(then_done_label, done_label);
+
+                b.set_block(catch_label);
+                b.basic_op(lir::OpKind::CatchValues, vec![],
+                           catch_vars.iter().map(|v| v.ssa).collect());
+                let catch_ret = catch.lower(b, env);
+                b.basic_op(lir::OpKind::Jump, vec![], vec![]);
+                let catch_done_label = b.get_block();
+                b.add_jump(catch_done_label, done_label);
+
+                b.add_phi(then_done_label, then_ret, done_label, self.ssa);
+                b.add_phi(catch_done_label, catch_ret, done_label, self.ssa);
+                b.set_block(done_label);
+
+                self.ssa
             },
             HSEK::Case { ref val, ref clauses, ref values } => {
 
@@ -209,7 +279,6 @@ impl hir::SingleExpression {
                 let from_label = b.get_block();
                 let match_body_label = b.add_block();
                 let done_label = b.add_block();
-                let case_fail_label = b.add_block();
 
                 let case_structure_ssa = env.new_ssa();
 
@@ -219,9 +288,8 @@ impl hir::SingleExpression {
                            vec![], vec![]);
                 b.add_jump(from_label, match_body_label);
 
-                // Fail leaf
-                b.set_block(case_fail_label);
-                b.basic_op(lir::OpKind::ReturnThrow, vec![], vec![]);
+                // Fail leaf, the case_clause error is thrown
+                let case_fail_label = lower_throw_target(b);
 
                 let mut leaves: Vec<_> = clauses.iter()
                     .map(|clause| {
@@ -236,14 +304,17 @@ impl hir::SingleExpression {
                                         .map(|binding| binding.1)
                                 }).collect(),
                         );
+                        // An exception in the guard fails the guard
+                        let guard_fail_label = b.add_block();
+                        b.push_throw_target(guard_fail_label);
                         let guard_ret = clause.guard.lower(b, env);
+                        b.pop_throw_target();
                         b.basic_op(
                             lir::OpKind::IfTruthy,
                             vec![lir::Source::Variable(guard_ret)],
                             vec![]
                         );
                         let guard_ret_label = b.get_block();
-                        let guard_fail_label = b.add_block();
                         let leaf_body_label = b.add_block();
                         b.add_jump(guard_ret_label, leaf_body_label);
                         b.add_jump(guard_ret_label, guard_fail_label);
@@ -278,12 +349,14 @@ impl hir::SingleExpression {
                 let mut clauses: Vec<_> = clauses.iter().map(|c| {
                     lir::Clause {
                         patterns: c.patterns.clone(),
+                        has_guard: !is_true(&c.guard),
                     }
                 }).collect();
                 b.basic_op(lir::OpKind::Case {
                     vars: val.values.iter().map(|v| v.ssa).collect(),
                     clauses: clauses,
                     value_vars: value_vars,
+                    decision_tree: None,
                 }, vec![], vec![case_structure_ssa]);
                 for leaf in leaves.iter() {
                     b.add_jump(match_body_label, *leaf);
@@ -323,7 +396,7 @@ impl hir::SingleExpression {
             },
             HSEK::Map { ref values, ref merge } => {
                 let mut reads = Vec::new();
-                for &(ref key, ref value) in values.iter() {
+                for (key, _, value) in values.iter() {
                     key.lower(b, env);
                     value.lower(b, env);
 
@@ -331,11 +404,24 @@ impl hir::SingleExpression {
                     reads.push(lir::Source::Variable(value.ssa));
                 }
 
-                merge.as_ref().map(|m| m.lower(b, env));
-                // TODO INCORRECT CONTROL FLOW: Merge!
+                // Only updates can fail
+                let types: Vec<_> = values.iter().map(|&(_, typ, _)| typ).collect();
+                if merge.is_none() && !types.contains(&MapPairType::Exact) {
+                    b.basic_op(lir::OpKind::MakeMap,
+                               reads, vec![self.ssa]);
+                    return self.ssa;
+                }
 
-                b.basic_op(lir::OpKind::MakeMap,
-                           reads, vec![self.ssa]);
+                let base = match *merge {
+                    Some(ref merge) => merge.lower(b, env),
+                    None => {
+                        let empty = env.new_ssa();
+                        b.basic_op(lir::OpKind::MakeMap, vec![], vec![empty]);
+                        empty
+                    },
+                };
+                reads.insert(0, lir::Source::Variable(base));
+                lower_throwing_op(b, lir::OpKind::UpdateMap(types), reads, vec![self.ssa]);
 
                 self.ssa
             },
@@ -348,22 +434,19 @@ impl hir::SingleExpression {
                     opts_ssa.insert(0, val_ssa);
                     opts_ssa
                 }).map(|var| lir::Source::Variable(var)).collect();
-                b.basic_op(
-                    lir::OpKind::MakeBinary,
-                    reads,
-                    vec![self.ssa]
-                );
+                lower_throwing_op(b, lir::OpKind::MakeBinary, reads, vec![self.ssa]);
                 self.ssa
             },
-            HSEK::PrimOp { ref name, ref args } => {
-                println!("PrimOp: {}", name);
+            HSEK::PrimOp { ref name, ref args, ref values } => {
                 for arg in args.iter() {
                     arg.lower(b, env);
                 }
-                b.basic_op(
-                    lir::OpKind::PrimOp(name.clone()),
+                let mut writes = vec![self.ssa];
+                writes.extend(values.iter().cloned());
+                lower_throwing_op(
+                    b, lir::OpKind::PrimOp(name.clone()),
                     args.iter().map(|a| lir::Source::Variable(a.ssa)).collect(),
-                    vec![self.ssa]);
+                    writes);
                 self.ssa
             },
             HSEK::Do(ref d1, ref d2) => {
@@ -401,7 +484,9 @@ impl hir::SingleExpression {
                 b.basic_op(lir::OpKind::ReceiveStart,
                            vec![lir::Source::Variable(timeout_time_var)],
                            vec![receive_structure_ssa]);
+                let throw_label = lower_throw_target(b);
                 b.add_jump(start_label, receive_loop_label);
+                b.add_jump(start_label, throw_label);
 
                 // Receive loop block (#receive_loop)
                 b.set_block(receive_loop_label);
@@ -413,10 +498,6 @@ impl hir::SingleExpression {
 
                 // Timeout branch
                 b.set_block(timeout_body_label);
-                b.basic_op(lir::OpKind::CaseValues,
-                           vec![lir::Source::Variable(case_structure_ssa)], vec![]);
-                b.basic_op(lir::OpKind::TombstoneSSA(case_structure_ssa),
-                           vec![], vec![]);
                 b.basic_op(lir::OpKind::TombstoneSSA(receive_structure_ssa),
                            vec![], vec![]);
                 let clause_ret = timeout_body.lower(b, env);
@@ -440,14 +521,17 @@ impl hir::SingleExpression {
                                         .map(|binding| binding.1)
                                 }).collect(),
                         );
+                        // An exception in the guard fails the guard
+                        let guard_fail_label = b.add_block();
+                        b.push_throw_target(guard_fail_label);
                         let guard_ret = clause.guard.lower(b, env);
+                        b.pop_throw_target();
                         b.basic_op(
                             lir::OpKind::IfTruthy,
                             vec![lir::Source::Variable(guard_ret)],
                             vec![]
                         );
                         let guard_ret_label = b.get_block();
-                        let guard_fail_label = b.add_block();
                         let leaf_body_label = b.add_block();
                         b.add_jump(guard_ret_label, leaf_body_label);
                         b.add_jump(guard_ret_label, guard_fail_label);
@@ -492,6 +576,7 @@ impl hir::SingleExpression {
                     assert!(c.patterns.len() == 1);
                     lir::Clause {
                         patterns: c.patterns.clone(),
+                        has_guard: !is_true(&c.guard),
                     }
                 }).collect();
                 b.basic_op(
@@ -499,6 +584,7 @@ impl hir::SingleExpression {
                         vars: vec![message_ssa],
                         clauses: clauses,
                         value_vars: value_vars,
+                        decision_tree: None,
                     },
                     vec![lir::Source::Variable(message_ssa)],
                     vec![case_structure_ssa]
@@ -512,12 +598,14 @@ impl hir::SingleExpression {
                 self.ssa
             },
             HSEK::BindClosure { ref closure, lambda_env, env_ssa } => {
-                // TODO
+                let captured = env.lambda_env(lambda_env.unwrap()).iter()
+                    .map(|&(_, outer, _)| Source::Variable(outer))
+                    .collect();
                 b.basic_op(
                     lir::OpKind::MakeClosureEnv {
                         env_idx: lambda_env.unwrap()
                     },
-                    vec![], vec![env_ssa]);
+                    captured, vec![env_ssa]);
                 b.basic_op(
                     lir::OpKind::BindClosure {
                         ident: closure.ident.clone().unwrap(),
@@ -527,12 +615,29 @@ impl hir::SingleExpression {
                 self.ssa
             },
             HSEK::BindClosures { ref closures, lambda_env, ref body, env_ssa } => {
-                // TODO
+                // The closures are bound after the environment is made,
+                // references between them are not captured.
+                let letrec: Vec<_> = closures.iter()
+                    .map(|c| (c.alias.as_ref().unwrap().var.clone(),
+                              c.ident.clone().unwrap()))
+                    .collect();
+                let captured = env.lambda_env(lambda_env.unwrap()).iter()
+                    .filter(|&(def, _, _)| letrec_ident(&letrec, def).is_none())
+                    .map(|&(_, outer, _)| Source::Variable(outer))
+                    .collect();
                 b.basic_op(
                     lir::OpKind::MakeClosureEnv {
                         env_idx: lambda_env.unwrap()
                     },
-                    vec![], vec![env_ssa]);
+                    captured, vec![env_ssa]);
+                for closure in closures.iter() {
+                    b.basic_op(
+                        lir::OpKind::BindClosure {
+                            ident: closure.ident.clone().unwrap(),
+                        },
+                        vec![Source::Variable(env_ssa)],
+                        vec![closure.alias.as_ref().unwrap().ssa]);
+                }
                 body.lower(b, env);
                 self.ssa
             },
@@ -1,7 +1,9 @@
 use super::SSAVariable;
 use ::ir::hir::{ Pattern, LambdaEnvIdx };
+use ::ir::hir::pass::pattern::DecisionTree;
 use ::ir::FunctionIdent;
 use ::Atom;
+use ::parser::MapPairType;
 
 pub mod from_hir;
 pub mod to_dot;
@@ -10,6 +12,9 @@ pub mod pass;
 pub mod cfg;
 pub use self::cfg::{ FunctionCfg, FunctionCfgBuilder, LabelN, BasicBlock };
 
+pub mod constant_pool;
+pub use self::constant_pool::{ ConstantPool, ConstId };
+
 //#[derive(Debug, Clone)]
 //pub struct BasicBlock {
 //    label: Label,
@@ -26,16 +31,18 @@ pub use self::cfg::{ FunctionCfg, FunctionCfgBuilder, LabelN, BasicBlock };
 #[derive(Debug, Clone, Copy)]
 pub struct Label(u32);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Source {
     Variable(SSAVariable),
     Constant(::parser::AtomicLiteral),
+    /// A term in the constant pool of the module.
+    Literal(ConstId),
 }
 
 #[derive(Debug, Clone)]
 pub struct Phi {
-    entries: Vec<(LabelN, SSAVariable)>,
-    ssa: SSAVariable,
+    pub entries: Vec<(LabelN, SSAVariable)>,
+    pub ssa: SSAVariable,
 }
 
 #[derive(Debug, Clone)]
@@ -44,59 +51,135 @@ pub struct Op {
     pub reads: Vec<Source>,
     pub writes: Vec<SSAVariable>,
 }
+impl Op {
+
+    /// Every variable read by the OP, including the ones referenced
+    /// in the OP kind.
+    pub fn read_vars(&self) -> Vec<SSAVariable> {
+        let mut reads: Vec<SSAVariable> = self.reads.iter()
+            .filter_map(|r| match *r {
+                Source::Variable(var) => Some(var),
+                _ => None,
+            })
+            .collect();
+        match self.kind {
+            OpKind::TombstoneSSA(var) => reads.push(var),
+            OpKind::Case { ref vars, ref value_vars, .. } => {
+                reads.extend(vars.iter().cloned());
+                reads.extend(value_vars.iter().cloned());
+            },
+            _ => (),
+        }
+        reads
+    }
+
+    /// Calls `fun` on every variable read by the OP, see `read_vars`.
+    pub fn map_read_vars<F>(&mut self, mut fun: F) where F: FnMut(&mut SSAVariable) {
+        for read in self.reads.iter_mut() {
+            if let Source::Variable(ref mut var) = *read {
+                fun(var);
+            }
+        }
+        match self.kind {
+            OpKind::TombstoneSSA(ref mut var) => fun(var),
+            OpKind::Case { ref mut vars, ref mut value_vars, .. } => {
+                for var in vars.iter_mut().chain(value_vars.iter_mut()) {
+                    fun(var);
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
 
 #[derive(Debug, Clone)]
 pub enum OpKind {
     /// Must be the first OP in a function.
-    /// Write number must be equal to the function arity
+    /// Write number must be equal to the function arity. Lambdas also
+    /// write the variables captured in their environment, after the
+    /// arguments.
     Arguments,
 
     /// Move r[0] into w[0]
     Move,
 
-    /// Calls r[0]:r[1] with args r[2..]
+    /// Calls r[0]:r[1] with args r[2..]. Jumps to edge 0 when the call
+    /// returns, and to edge 1 when it throws.
     Call,
-    /// Calls r[0] with args r[1..]
+    /// Calls r[0] with args r[1..]. Edges as for Call.
     Apply,
+    /// Calls r[0]:r[1] with args r[2..], returning its result from the
+    /// current function. Ends the block with no outgoing edges.
+    TailCall,
+    /// Calls r[0] with args r[1..], returning its result from the
+    /// current function. Ends the block with no outgoing edges.
+    TailApply,
     CaptureNamedFunction(::ir::FunctionIdent),
     CaptureExternalNamedFunction(Atom, ::ir::FunctionIdent),
 
     MakeTuple,
     MakeList,
     MakeMap,
+    /// Updates the map in r[0] with the keys and values in r[1..], a
+    /// key and a value for every pair type. Edges as for Call, fails
+    /// with badmap when r[0] is not a map, and with badkey when the key
+    /// of an exact pair is not in it.
+    UpdateMap(Vec<MapPairType>),
+    /// Reads the segments of the binary, each as the value followed by
+    /// its four options. Edges as for Call, construction fails when a
+    /// value does not fit its segment.
     MakeBinary,
 
+    /// Makes the environment of a set of closures from the captured
+    /// variables in r[0..].
     MakeClosureEnv {
         env_idx: LambdaEnvIdx,
     },
+    /// Binds the lambda to the environment in r[0].
     BindClosure {
         ident: FunctionIdent,
     },
 
     Jump,
 
+    /// Calls the primitive operation with args r[0..], writing one
+    /// variable for each value it returns. Edges as for Call.
     PrimOp(Atom),
 
     ReturnOk,
+    /// Rethrows the exception passed along the throw edge into this
+    /// block.
     ReturnThrow,
 
+    /// Must be the first OP of the block the throw edges in the body of a
+    /// try lead to. Writes the class, reason and raw stack trace of the
+    /// exception passed along the throw edge.
+    CatchValues,
+
     IfTruthy,
 
     // High level matching construct, lowered to explicit control flow
     // in a LIR compiler pass.
     // This OP indicates the start of a case structure.
     // The number of outgoing edges must be equal to the number of
-    // clauses.
-    // All outgoing edges must start with a CaseValues OP.
+    // clauses plus one. Edge 0 is taken when no clause matches, edge
+    // n + 1 when clause n matches. Edge 0 passes a case_clause error
+    // along, and may be a throw edge.
+    // All outgoing edges except edge 0 must start with a CaseValues OP.
     // Once going through a CaseValues, control flow must either return
     // to the case through a GuardFail, or leave the structure through
     // a GuardOk.
     // Returns a pseudo-value which is used to fetch the matched values
     // in any subsequent blocks.
+    // The decision tree is filled in by the compile_pattern pass. Clauses
+    // with guards have a guard node in the tree, the pseudo-value keeps
+    // track of which one was last passed through.
     Case {
         vars: Vec<SSAVariable>,
         clauses: Vec<Clause>,
         value_vars: Vec<SSAVariable>,
+        decision_tree: Option<Box<DecisionTree>>,
     },
     // Must have one incoming edge, which must end with a Case OP.
     // The number of writes are the same as the number of bindings
@@ -108,11 +191,15 @@ pub enum OpKind {
     // a GuardOk or GuardFail. Returning while inside the structure is
     // a hard error!
     CaseGuardOk,
+    // Must jump back to the Case OP. Matching resumes from the failure
+    // edge of the guard node of the clause that was just tried, values
+    // that have already been tested are not tested again.
     CaseGuardFail,
 
 
-    // Indicates the start of a receive structure, must jump to a block
-    // containing a single ReceiveWait.
+    // Indicates the start of a receive structure, edge 0 must lead to a
+    // block containing a single ReceiveWait. Edge 1 is taken as for Call
+    // when the timeout is not a valid timeout value.
     // No further ReceiveStart or function termination is allowed
     // before control flow is passed through a ReceiveFinish or exited
     // the structure through the timeout edge.
@@ -134,7 +221,7 @@ pub enum OpKind {
     //
     // #start:
     //   ...
-    //   %receive_context = ReceiveStart(%timeout, #receive_loop)
+    //   %receive_context = ReceiveStart(%timeout, #receive_loop, #throw)
     // #receive_loop:
     //   ReceiveWait(%receive_context, #match_body, #timeout_body)
     // #match_body:
@@ -177,23 +264,40 @@ pub enum OpKind {
 
 #[derive(Debug, Clone)]
 pub struct Clause {
-    patterns: Vec<Pattern>,
+    pub patterns: Vec<Pattern>,
+    pub has_guard: bool,
 }
 
 impl OpKind {
 
+    /// Returns true if the OP has no effects other than assigning its
+    /// writes, and can never fail. Such an OP can be removed when none
+    /// of its writes are read.
+    pub fn is_pure(&self) -> bool {
+        matches!(*self, OpKind::Move | OpKind::MakeTuple | OpKind::MakeList | OpKind::MakeMap | OpKind::CaptureNamedFunction(_) | OpKind::CaptureExternalNamedFunction(_, _) | OpKind::MakeClosureEnv { .. } | OpKind::BindClosure { .. })
+    }
+
+    /// The number of outgoing edges of a block terminated by this OP.
+    /// Returns None if the OP is not a terminator.
     fn num_jumps(&self) -> Option<usize> {
         match *self {
             OpKind::Call => Some(2),
             OpKind::Apply => Some(2),
+            OpKind::PrimOp(_) => Some(2),
+            OpKind::MakeBinary => Some(2),
+            OpKind::UpdateMap(_) => Some(2),
             OpKind::Jump => Some(1),
-            OpKind::Case { ref clauses, .. } => Some(clauses.len()),
+            OpKind::IfTruthy => Some(2),
+            OpKind::Case { ref clauses, .. } => Some(clauses.len() + 1),
+            OpKind::CaseGuardFail => Some(1),
             // TODO
             //OpKind::Match { ref types } => Some(types.len()),
             OpKind::ReturnOk => Some(0),
             OpKind::ReturnThrow => Some(0),
-            OpKind::ReceiveStart { .. } => Some(1),
-            OpKind::ReceiveGetMessage => Some(2),
+            OpKind::TailCall => Some(0),
+            OpKind::TailApply => Some(0),
+            OpKind::ReceiveStart => Some(2),
+            OpKind::ReceiveWait => Some(2),
             _ => None,
         }
     }
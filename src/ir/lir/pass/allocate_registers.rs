@@ -0,0 +1,362 @@
+//! Linear scan register allocation, as described in "Linear Scan Register
+//! Allocation" by Poletto and Sarkar.
+//!
+//! Variables are assigned to slots modeled after the BEAM: a bounded
+//! number of `X` registers which are clobbered by calls, and an unbounded
+//! number of `Y` slots on the stack which survive them. A variable that is
+//! live across a call is always put in a `Y` slot. When there are no free
+//! `X` registers, the variable whose live range ends last is spilled to a
+//! `Y` slot.
+//!
+//! Blocks are laid out in reverse postorder, and every variable is given a
+//! single live range covering all positions where it is live in that
+//! order. Expects unreachable blocks to have been removed.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::petgraph::visit::DfsPostOrder;
+
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, OpKind };
+use super::liveness::{ liveness, uses };
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Slot {
+    /// Register clobbered by calls.
+    X(usize),
+    /// Stack slot preserved across calls.
+    Y(usize),
+}
+
+#[derive(Debug)]
+pub struct Allocation {
+    pub slots: HashMap<SSAVariable, Slot>,
+    /// Number of X registers used.
+    pub num_x: usize,
+    /// Number of Y slots in the stack frame.
+    pub num_y: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Interval {
+    var: SSAVariable,
+    start: usize,
+    end: usize,
+}
+
+/// OPs after which no X register keeps its value.
+fn clobbers_registers(kind: &OpKind) -> bool {
+    matches!(*kind, OpKind::Call | OpKind::Apply | OpKind::ReceiveWait)
+}
+
+/// Blocks in reverse postorder from the entry.
+fn block_order(cfg: &FunctionCfg) -> Vec<LabelN> {
+    let mut order = Vec::new();
+    let mut dfs = DfsPostOrder::new(&cfg.cfg, cfg.entry.0);
+    while let Some(node) = dfs.next(&cfg.cfg) {
+        order.push(LabelN(node));
+    }
+    order.reverse();
+    order
+}
+
+/// Lays out the blocks and computes the live range of every variable.
+/// Also returns the positions of OPs clobbering registers.
+fn build_intervals(cfg: &FunctionCfg) -> (Vec<Interval>, Vec<usize>) {
+    let live = liveness(cfg);
+
+    let mut ranges: HashMap<SSAVariable, (usize, usize)> = HashMap::new();
+    let mut order: Vec<SSAVariable> = Vec::new();
+    let mut extend = |var: SSAVariable, pos: usize| {
+        let range = ranges.entry(var).or_insert_with(|| {
+            order.push(var);
+            (pos, pos)
+        });
+        range.0 = ::std::cmp::min(range.0, pos);
+        range.1 = ::std::cmp::max(range.1, pos);
+    };
+    let mut clobbers = Vec::new();
+
+    // Every block starts with a position for its phis, followed by one
+    // position per OP.
+    let mut pos = 0;
+    for label in block_order(cfg) {
+        let block = cfg.block(label);
+        let start = pos;
+        let end = pos + block.ops.len();
+        pos = end + 1;
+
+        for phi in block.phi_nodes.iter() {
+            extend(phi.ssa, start);
+        }
+        for var in live.live_in[&label].iter() {
+            extend(*var, start);
+        }
+        for (idx, op) in block.ops.iter().enumerate() {
+            let op_pos = start + 1 + idx;
+            for var in uses(op) {
+                extend(var, op_pos);
+            }
+            for var in op.writes.iter() {
+                extend(*var, op_pos);
+            }
+            if clobbers_registers(&op.kind) {
+                clobbers.push(op_pos);
+            }
+        }
+        for var in live.live_out[&label].iter() {
+            extend(*var, end);
+        }
+    }
+
+    let mut intervals: Vec<_> = order.iter()
+        .map(|var| Interval {
+            var: *var,
+            start: ranges[var].0,
+            end: ranges[var].1,
+        })
+        .collect();
+    intervals.sort_by_key(|i| i.start);
+    (intervals, clobbers)
+}
+
+/// Hands out the lowest free slot number, reusing released ones.
+struct SlotPool {
+    free: Vec<usize>,
+    used: usize,
+}
+impl SlotPool {
+    fn new() -> Self {
+        SlotPool {
+            free: Vec::new(),
+            used: 0,
+        }
+    }
+    fn take(&mut self) -> usize {
+        if self.free.is_empty() {
+            self.fresh()
+        } else {
+            self.free.sort_by(|a, b| b.cmp(a));
+            self.free.pop().unwrap()
+        }
+    }
+    /// A slot never handed out before.
+    fn fresh(&mut self) -> usize {
+        self.used += 1;
+        self.used - 1
+    }
+    fn release(&mut self, slot: usize) {
+        self.free.push(slot);
+    }
+}
+
+/// Assigns every variable in the function a slot, using at most `num_x`
+/// X registers.
+pub fn allocate_registers(cfg: &FunctionCfg, num_x: usize) -> Allocation {
+    let (intervals, clobbers) = build_intervals(cfg);
+
+    let mut slots = HashMap::new();
+    let mut x_pool = SlotPool::new();
+    let mut y_pool = SlotPool::new();
+    // Intervals currently holding a slot
+    let mut active: Vec<Interval> = Vec::new();
+
+    for interval in intervals.iter() {
+        // Release the slots of intervals ending before this one starts
+        active.retain(|a| {
+            if a.end < interval.start {
+                match slots[&a.var] {
+                    Slot::X(n) => x_pool.release(n),
+                    Slot::Y(n) => y_pool.release(n),
+                }
+                false
+            } else {
+                true
+            }
+        });
+
+        let across_call = clobbers.iter()
+            .any(|&c| interval.start < c && c < interval.end);
+        let num_active_x = active.iter()
+            .filter(|a| matches!(slots[&a.var], Slot::X(_)))
+            .count();
+
+        if across_call {
+            slots.insert(interval.var, Slot::Y(y_pool.take()));
+        } else if num_active_x < num_x {
+            slots.insert(interval.var, Slot::X(x_pool.take()));
+        } else {
+            // Spill whichever of the active X intervals and this one
+            // ends last.
+            let spill = active.iter()
+                .filter(|a| matches!(slots[&a.var], Slot::X(_)))
+                .max_by_key(|a| a.end)
+                .cloned();
+            match spill {
+                Some(spill) if spill.end > interval.end => {
+                    // Released Y slots may have been in use when the
+                    // spilled interval started.
+                    let reg = slots[&spill.var];
+                    slots.insert(spill.var, Slot::Y(y_pool.fresh()));
+                    slots.insert(interval.var, reg);
+                },
+                _ => {
+                    slots.insert(interval.var, Slot::Y(y_pool.take()));
+                },
+            }
+        }
+        active.push(*interval);
+    }
+
+    Allocation {
+        slots,
+        num_x: x_pool.used,
+        num_y: y_pool.used,
+    }
+}
+
+/// Checks that no two variables live at the same time share a slot, and
+/// that no variable in an X register is live across a call. Returns the
+/// variables in conflict.
+pub fn check_allocation(cfg: &FunctionCfg, allocation: &Allocation)
+                        -> Vec<(SSAVariable, SSAVariable)> {
+    let live = liveness(cfg);
+    let mut conflicts = Vec::new();
+
+    let check = |vars: &HashSet<SSAVariable>, conflicts: &mut Vec<_>| {
+        let mut owners: HashMap<Slot, SSAVariable> = HashMap::new();
+        for var in vars.iter() {
+            if let Some(other) = owners.insert(allocation.slots[var], *var) {
+                conflicts.push((other, *var));
+            }
+        }
+    };
+
+    for label in block_order(cfg) {
+        let block = cfg.block(label);
+        let after = live.live_after_ops(cfg, label);
+
+        let mut at_start = live.live_in[&label].clone();
+        at_start.extend(block.phi_nodes.iter().map(|phi| phi.ssa));
+        check(&at_start, &mut conflicts);
+
+        for (op, live_after) in block.ops.iter().zip(after.iter()) {
+            // Writes must not clobber anything still live after the OP
+            let mut vars = live_after.clone();
+            vars.extend(op.writes.iter().cloned());
+            check(&vars, &mut conflicts);
+
+            if clobbers_registers(&op.kind) {
+                for var in live_after.iter() {
+                    let is_x = matches!(allocation.slots[var], Slot::X(_));
+                    if is_x && !op.writes.contains(var) {
+                        conflicts.push((*var, *var));
+                    }
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+
+    use ::Atom;
+    use ::parser::AtomicLiteral;
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use super::{ allocate_registers, check_allocation, Slot };
+
+    fn atom(name: &str) -> Source {
+        Source::Constant(AtomicLiteral::Atom(Atom::from_str(name).unwrap()))
+    }
+
+    #[test]
+    fn spills_across_calls() {
+        let mut env = ScopeTracker::new();
+        let a = env.new_ssa();
+        let b_ = env.new_ssa();
+        let c = env.new_ssa();
+        let t1 = env.new_ssa();
+        let t2 = env.new_ssa();
+        let res = env.new_ssa();
+
+        // a, b, c = args
+        // t1 = {a, b}
+        // t2 = {t1, c}
+        // res = foo:bar(t2)
+        // return {res, a}
+
+        let mut cfg = FunctionCfg::new();
+        {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            b.basic_op(OpKind::Arguments, vec![], vec![a, b_, c]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(a), Source::Variable(b_)],
+                       vec![t1]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(t1), Source::Variable(c)],
+                       vec![t2]);
+            b.basic_op(OpKind::Call, vec![atom("foo"), atom("bar"), Source::Variable(t2)],
+                       vec![res]);
+            let call_block = b.get_block();
+            let throw_block = b.add_block();
+            let resume_block = b.add_block();
+            b.add_jump(call_block, resume_block);
+            b.add_jump(call_block, throw_block);
+            b.set_block(throw_block);
+            b.basic_op(OpKind::ReturnThrow, vec![], vec![]);
+            b.set_block(resume_block);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(res), Source::Variable(a)],
+                       vec![]);
+        }
+
+        let alloc = allocate_registers(&cfg, 8);
+        assert!(check_allocation(&cfg, &alloc).is_empty());
+        assert!(alloc.slots[&a] == Slot::Y(0));
+        assert!(alloc.num_y == 1);
+        for var in [b_, c, t1, t2, res].iter() {
+            match alloc.slots[var] {
+                Slot::X(_) => (),
+                _ => panic!(),
+            }
+        }
+        // t1 can reuse the register of b, t2 the one of t1 or c
+        assert!(alloc.num_x == 3);
+
+        // With two registers something has to be spilled
+        let alloc = allocate_registers(&cfg, 2);
+        assert!(check_allocation(&cfg, &alloc).is_empty());
+        assert!(alloc.num_x <= 2);
+        assert!(alloc.num_y == 2);
+    }
+
+    #[test]
+    fn lowered_functions_allocate() {
+        let core = "module 'test' ['f'/2] attributes []
+'f'/2 =
+    fun (A, B) ->
+        case A of
+          <{'ok', X}> when 'true' ->
+              let <Y> = call 'foo':'bar'(X)
+              in {Y, B, X}
+          <_Other> when 'true' ->
+              receive
+                <M> when 'true' -> {M, B}
+              after 'infinity' -> B
+        end
+end
+";
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let module = ::ir::from_parsed(&parsed.0);
+        for fun in module.functions.iter() {
+            let cfg = fun.lir_function.as_ref().unwrap();
+            for num_x in [1, 2, 16].iter() {
+                let alloc = allocate_registers(cfg, *num_x);
+                assert!(check_allocation(cfg, &alloc).is_empty());
+                assert!(alloc.num_x <= *num_x);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,199 @@
+//! Removes blocks that are not reachable from the entry, pure OPs whose
+//! writes are never read, and phis whose values are never read.
+//!
+//! Liveness is computed by marking everything read by an impure OP as
+//! live, then following the definitions of live variables. Reads by
+//! `TombstoneSSA` are not considered uses, tombstones of removed
+//! variables are removed along with them.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::petgraph::visit::{ Dfs, Walker };
+
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, OpKind };
+
+#[derive(Debug, Copy, Clone)]
+enum Definition {
+    Phi(LabelN, usize),
+    Op(LabelN, usize),
+}
+
+pub fn eliminate_dead_code(cfg: &mut FunctionCfg) {
+    remove_unreachable_blocks(cfg);
+    remove_dead_assigns(cfg);
+}
+
+fn remove_unreachable_blocks(cfg: &mut FunctionCfg) {
+    let reachable: HashSet<_> = Dfs::new(&cfg.cfg, cfg.entry.0)
+        .iter(&cfg.cfg)
+        .collect();
+    let unreachable: Vec<_> = cfg.cfg.node_indices()
+        .filter(|n| !reachable.contains(n))
+        .collect();
+    if unreachable.is_empty() {
+        return;
+    }
+
+    // Removing a node also removes its edges. Edges from a reachable block
+    // always lead to a reachable block, so only phi entries need fixing.
+    for node in unreachable.iter() {
+        cfg.cfg.remove_node(*node);
+    }
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        for phi in cfg.block_mut(label).phi_nodes.iter_mut() {
+            phi.entries.retain(|&(pred, _)| reachable.contains(&pred.0));
+        }
+    }
+}
+
+fn remove_dead_assigns(cfg: &mut FunctionCfg) {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+
+    let mut definitions = HashMap::new();
+    let mut worklist: Vec<SSAVariable> = Vec::new();
+    for label in labels.iter() {
+        let block = cfg.block(*label);
+        for (idx, phi) in block.phi_nodes.iter().enumerate() {
+            definitions.insert(phi.ssa, Definition::Phi(*label, idx));
+        }
+        for (idx, op) in block.ops.iter().enumerate() {
+            for write in op.writes.iter() {
+                definitions.insert(*write, Definition::Op(*label, idx));
+            }
+            match op.kind {
+                OpKind::TombstoneSSA(_) => (),
+                ref kind if kind.is_pure() => (),
+                _ => worklist.extend(op.read_vars()),
+            }
+        }
+    }
+
+    let mut live_vars = HashSet::new();
+    let mut live_phis = HashSet::new();
+    let mut live_ops = HashSet::new();
+    while let Some(var) = worklist.pop() {
+        if !live_vars.insert(var) {
+            continue;
+        }
+        match definitions.get(&var) {
+            Some(&Definition::Phi(label, idx)) => {
+                live_phis.insert((label, idx));
+                let phi = &cfg.block(label).phi_nodes[idx];
+                worklist.extend(phi.entries.iter().map(|&(_, ssa)| ssa));
+            },
+            Some(&Definition::Op(label, idx)) => {
+                live_ops.insert((label, idx));
+                worklist.extend(cfg.block(label).ops[idx].read_vars());
+            },
+            None => (),
+        }
+    }
+
+    let mut removed = HashSet::new();
+    for label in labels.iter() {
+        let block = cfg.block_mut(*label);
+
+        let mut idx = 0;
+        block.phi_nodes.retain(|phi| {
+            let live = live_phis.contains(&(*label, idx));
+            idx += 1;
+            if !live {
+                removed.insert(phi.ssa);
+            }
+            live
+        });
+
+        let mut idx = 0;
+        block.ops.retain(|op| {
+            let dead = op.kind.is_pure() && !live_ops.contains(&(*label, idx));
+            idx += 1;
+            if dead {
+                removed.extend(op.writes.iter().cloned());
+            }
+            !dead
+        });
+    }
+
+    for label in labels.iter() {
+        cfg.block_mut(*label).ops.retain(|op| match op.kind {
+            OpKind::TombstoneSSA(var) => !removed.contains(&var),
+            _ => true,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use ::ir::lir::pass::validate;
+    use ::parser::AtomicLiteral;
+    use super::eliminate_dead_code;
+
+    #[test]
+    fn removes_dead_ops_phis_and_blocks() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let dead = env.new_ssa();
+        let tuple = env.new_ssa();
+        let left_val = env.new_ssa();
+        let right_val = env.new_ssa();
+        let phi_val = env.new_ssa();
+        let unreachable_val = env.new_ssa();
+
+        //     entry       unreachable
+        //     /   \      /
+        //  left  right  /
+        //     \   /    /
+        //     join <---
+
+        let mut cfg = FunctionCfg::new();
+        let (join, unreachable) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![dead]);
+            b.basic_op(OpKind::TombstoneSSA(dead), vec![], vec![]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(arg)], vec![tuple]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(arg)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let join = b.add_block();
+            let unreachable = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+
+            for &(branch, val) in [(left, left_val), (right, right_val),
+                                   (unreachable, unreachable_val)].iter() {
+                b.set_block(branch);
+                b.basic_op(OpKind::Move, vec![Source::Constant(AtomicLiteral::Nil)],
+                           vec![val]);
+                b.basic_op(OpKind::Jump, vec![], vec![]);
+                b.add_jump(branch, join);
+                b.add_phi(branch, val, join, phi_val);
+            }
+
+            b.set_block(join);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(tuple)], vec![]);
+
+            (join, unreachable)
+        };
+
+        eliminate_dead_code(&mut cfg);
+        assert!(validate(&cfg).is_empty());
+
+        assert!(!cfg.cfg.contains_node(unreachable.0));
+        assert!(cfg.block(join).phi_nodes.is_empty());
+        let entry_ops: Vec<_> = cfg.block(cfg.entry()).ops.iter()
+            .map(|op| format!("{:?}", op.kind))
+            .collect();
+        assert!(entry_ops == vec!["Arguments", "MakeTuple", "IfTruthy"]);
+        for block in cfg.blocks_iter() {
+            assert!(block.ops.iter().all(|op| !op.writes.contains(&right_val)));
+        }
+    }
+
+}
@@ -0,0 +1,182 @@
+//! Moves construction of tuples, lists and maps made up entirely of
+//! constants into the constant pool of the module.
+//!
+//! Constructions that only read constants, pool literals or variables
+//! holding other constant constructions are replaced by a `Move` of a
+//! `Source::Literal`. Nested constructions are hoisted as a whole, inner
+//! constructions not read by anything else are left in place for
+//! `eliminate_dead_code` to remove.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::parser::Constant;
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, ConstantPool, Op, OpKind, Source };
+
+pub fn hoist_constants(cfg: &mut FunctionCfg, pool: &mut ConstantPool) {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+
+    // Definitions dominate their uses everywhere except in phis, which
+    // are never constant here. Iterate until every construction reading
+    // another one has been seen after it.
+    let mut known: HashMap<SSAVariable, Constant> = HashMap::new();
+    loop {
+        let mut changed = false;
+        for label in labels.iter() {
+            for op in cfg.block(*label).ops.iter() {
+                if op.writes.len() != 1 || known.contains_key(&op.writes[0]) {
+                    continue;
+                }
+                if let Some(constant) = constant_value(op, &known, pool) {
+                    known.insert(op.writes[0], constant);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let is_hoisted = |op: &Op| match op.kind {
+        OpKind::MakeTuple | OpKind::MakeList | OpKind::MakeMap =>
+            known.contains_key(&op.writes[0]),
+        _ => false,
+    };
+
+    // Only add terms to the pool that are read by something other than
+    // another hoisted construction.
+    let mut needed = HashSet::new();
+    for label in labels.iter() {
+        let block = cfg.block(*label);
+        for phi in block.phi_nodes.iter() {
+            needed.extend(phi.entries.iter().map(|&(_, ssa)| ssa));
+        }
+        for op in block.ops.iter() {
+            if !is_hoisted(op) {
+                needed.extend(op.read_vars());
+            }
+        }
+    }
+
+    for label in labels.iter() {
+        for op in cfg.block_mut(*label).ops.iter_mut() {
+            if is_hoisted(op) && needed.contains(&op.writes[0]) {
+                let id = pool.insert(known[&op.writes[0]].clone());
+                op.kind = OpKind::Move;
+                op.reads = vec![Source::Literal(id)];
+            }
+        }
+    }
+}
+
+fn source_value(source: &Source, known: &HashMap<SSAVariable, Constant>,
+                pool: &ConstantPool) -> Option<Constant> {
+    match *source {
+        Source::Variable(ref var) => known.get(var).cloned(),
+        Source::Constant(ref literal) => Some(Constant::Atomic(literal.clone())),
+        Source::Literal(id) => Some(pool.get(id).clone()),
+    }
+}
+
+/// The term written by the OP, if it only depends on constants.
+fn constant_value(op: &Op, known: &HashMap<SSAVariable, Constant>,
+                  pool: &ConstantPool) -> Option<Constant> {
+    match op.kind {
+        OpKind::Move | OpKind::MakeTuple | OpKind::MakeList | OpKind::MakeMap => (),
+        _ => return None,
+    }
+
+    let mut values = Vec::with_capacity(op.reads.len());
+    for read in op.reads.iter() {
+        values.push(source_value(read, known, pool)?);
+    }
+
+    match op.kind {
+        OpKind::Move => values.pop(),
+        OpKind::MakeTuple => Some(Constant::Tuple(values)),
+        OpKind::MakeList => {
+            // Reads are the tail followed by the head elements
+            let tail = values.remove(0);
+            Some(Constant::List(values, Box::new(tail)))
+        },
+        OpKind::MakeMap => {
+            let mut entries = Vec::with_capacity(values.len() / 2);
+            let mut iter = values.into_iter();
+            while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+                entries.push((key, value));
+            }
+            Some(Constant::Map(entries))
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+
+    use ::Atom;
+    use ::parser::{ AtomicLiteral, Constant };
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, ConstantPool, OpKind, Source };
+    use ::ir::lir::pass::{ validate, eliminate_dead_code };
+    use super::hoist_constants;
+
+    #[test]
+    fn hoists_nested_constructions() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let inner = env.new_ssa();
+        let list = env.new_ssa();
+        let outer = env.new_ssa();
+        let dynamic = env.new_ssa();
+
+        let a = AtomicLiteral::Atom(Atom::from_str("a").unwrap());
+
+        // outer = {[{a}], a}
+        // dynamic = {arg, [{a}]}
+        let mut cfg = FunctionCfg::new();
+        {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Constant(a.clone())],
+                       vec![inner]);
+            b.basic_op(OpKind::MakeList, vec![Source::Constant(AtomicLiteral::Nil),
+                                              Source::Variable(inner)],
+                       vec![list]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(list),
+                                               Source::Constant(a.clone())],
+                       vec![outer]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(arg),
+                                               Source::Variable(list)],
+                       vec![dynamic]);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(outer),
+                                              Source::Variable(dynamic)], vec![]);
+        }
+
+        let mut pool = ConstantPool::new();
+        hoist_constants(&mut cfg, &mut pool);
+        eliminate_dead_code(&mut cfg);
+        assert!(validate(&cfg).is_empty());
+
+        let inner_c = Constant::Tuple(vec![Constant::Atomic(a.clone())]);
+        let list_c = Constant::List(vec![inner_c.clone()],
+                                    Box::new(Constant::Atomic(AtomicLiteral::Nil)));
+        let outer_c = Constant::Tuple(vec![list_c.clone(), Constant::Atomic(a)]);
+
+        assert!(pool.len() == 2);
+        let ops = &cfg.block(cfg.entry()).ops;
+        let kinds: Vec<_> = ops.iter().map(|op| format!("{:?}", op.kind)).collect();
+        assert!(kinds == vec!["Arguments", "Move", "Move", "MakeTuple", "ReturnOk"]);
+        for op in ops[1..3].iter() {
+            match op.reads[0] {
+                Source::Literal(id) => assert!(*pool.get(id) == list_c
+                                               || *pool.get(id) == outer_c),
+                _ => panic!(),
+            }
+        }
+        assert!(format!("{}", outer_c) == "{[{a}],a}");
+    }
+
+}
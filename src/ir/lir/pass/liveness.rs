@@ -0,0 +1,188 @@
+//! Computes the variables live at the start and end of every block.
+//!
+//! Phi nodes assign their variable at the start of the block, and read
+//! their entries at the end of the corresponding predecessor. An entry is
+//! only live out of the predecessor it belongs to, not into the block of
+//! the phi.
+//!
+//! Reads by `TombstoneSSA` are not uses, a tombstoned variable is dead
+//! from its last real read.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind };
+
+#[derive(Debug)]
+pub struct Liveness {
+    pub live_in: HashMap<LabelN, HashSet<SSAVariable>>,
+    pub live_out: HashMap<LabelN, HashSet<SSAVariable>>,
+}
+
+impl Liveness {
+
+    /// Variables live immediately after each OP in the block, in order.
+    pub fn live_after_ops(&self, cfg: &FunctionCfg, label: LabelN)
+                          -> Vec<HashSet<SSAVariable>> {
+        let ops = &cfg.block(label).ops;
+        let mut live = self.live_out[&label].clone();
+        let mut after = vec![HashSet::new(); ops.len()];
+        for (idx, op) in ops.iter().enumerate().rev() {
+            after[idx] = live.clone();
+            for write in op.writes.iter() {
+                live.remove(write);
+            }
+            live.extend(uses(op));
+        }
+        after
+    }
+
+}
+
+/// Variables read by the OP, not counting tombstones.
+pub fn uses(op: &Op) -> Vec<SSAVariable> {
+    match op.kind {
+        OpKind::TombstoneSSA(_) => vec![],
+        _ => op.read_vars(),
+    }
+}
+
+pub fn liveness(cfg: &FunctionCfg) -> Liveness {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+
+    // Upward exposed reads and writes of every block. Phi assignments
+    // count as writes at the start of the block.
+    let mut gen: HashMap<LabelN, HashSet<SSAVariable>> = HashMap::new();
+    let mut kill: HashMap<LabelN, HashSet<SSAVariable>> = HashMap::new();
+    // Phi entries read at the end of a predecessor
+    let mut phi_uses: HashMap<LabelN, HashSet<SSAVariable>> = HashMap::new();
+    for label in labels.iter() {
+        let block = cfg.block(*label);
+        let mut block_gen = HashSet::new();
+        let mut block_kill: HashSet<SSAVariable> = block.phi_nodes.iter()
+            .map(|phi| phi.ssa)
+            .collect();
+        for op in block.ops.iter() {
+            for var in uses(op) {
+                if !block_kill.contains(&var) {
+                    block_gen.insert(var);
+                }
+            }
+            block_kill.extend(op.writes.iter().cloned());
+        }
+        for phi in block.phi_nodes.iter() {
+            for &(pred, ssa) in phi.entries.iter() {
+                phi_uses.entry(pred).or_default().insert(ssa);
+            }
+        }
+        gen.insert(*label, block_gen);
+        kill.insert(*label, block_kill);
+    }
+
+    let mut live_in: HashMap<LabelN, HashSet<SSAVariable>> = labels.iter()
+        .map(|l| (*l, HashSet::new()))
+        .collect();
+    let mut live_out: HashMap<LabelN, HashSet<SSAVariable>> = labels.iter()
+        .map(|l| (*l, phi_uses.get(l).cloned().unwrap_or(HashSet::new())))
+        .collect();
+
+    loop {
+        let mut changed = false;
+        for label in labels.iter().rev() {
+            let mut out = live_out[label].clone();
+            for edge in cfg.jumps_iter(*label) {
+                out.extend(live_in[&cfg.edge_target(edge)].iter().cloned());
+            }
+
+            let mut inn = gen[label].clone();
+            inn.extend(out.difference(&kill[label]).cloned());
+
+            if out.len() != live_out[label].len() || inn.len() != live_in[label].len() {
+                changed = true;
+                live_out.insert(*label, out);
+                live_in.insert(*label, inn);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    Liveness {
+        live_in,
+        live_out,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use super::liveness;
+
+    #[test]
+    fn phis_and_tombstones() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let left_val = env.new_ssa();
+        let right_val = env.new_ssa();
+        let phi_val = env.new_ssa();
+        let structure = env.new_ssa();
+
+        let mut cfg = FunctionCfg::new();
+        let (left, right, join) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![arg, structure]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(arg)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let join = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+
+            b.set_block(left);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(arg)], vec![left_val]);
+            b.basic_op(OpKind::TombstoneSSA(structure), vec![], vec![]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(left, join);
+
+            b.set_block(right);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(structure)],
+                       vec![right_val]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(right, join);
+
+            b.add_phi(left, left_val, join, phi_val);
+            b.add_phi(right, right_val, join, phi_val);
+            b.set_block(join);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(phi_val),
+                                              Source::Variable(arg)], vec![]);
+
+            (left, right, join)
+        };
+
+        let live = liveness(&cfg);
+
+        assert!(live.live_in[&cfg.entry()].is_empty());
+        let entry_out = &live.live_out[&cfg.entry()];
+        assert!(entry_out.len() == 2);
+        assert!(entry_out.contains(&arg) && entry_out.contains(&structure));
+
+        // The tombstone does not keep the structure alive
+        assert!(live.live_in[&left].len() == 1);
+        assert!(live.live_out[&left].contains(&left_val));
+        assert!(!live.live_out[&left].contains(&right_val));
+        assert!(live.live_in[&right].contains(&structure));
+        assert!(live.live_out[&right].contains(&right_val));
+
+        let join_in = &live.live_in[&join];
+        assert!(join_in.len() == 1 && join_in.contains(&arg));
+
+        let after = live.live_after_ops(&cfg, left);
+        assert!(after[0].contains(&left_val) && after[0].contains(&arg));
+        assert!(after[2] == live.live_out[&left]);
+    }
+
+}
@@ -1,8 +1,35 @@
-mod propagate_atomics;
-pub use self::propagate_atomics::propagate_atomics;
+mod propagate_constants;
+pub use self::propagate_constants::propagate_constants;
+
+mod strict_ssa;
+pub use self::strict_ssa::strict_ssa;
+
+mod hoist_constants;
+pub use self::hoist_constants::hoist_constants;
+
+mod value_numbering;
+pub use self::value_numbering::value_numbering;
+
+mod tail_calls;
+pub use self::tail_calls::tail_calls;
+
+mod eliminate_dead_code;
+pub use self::eliminate_dead_code::eliminate_dead_code;
+
+mod simplify_cfg;
+pub use self::simplify_cfg::simplify_cfg;
+
+mod liveness;
+pub use self::liveness::{ liveness, Liveness };
+
+mod allocate_registers;
+pub use self::allocate_registers::{ allocate_registers, check_allocation, Allocation, Slot };
+
+mod out_of_ssa;
+pub use self::out_of_ssa::{ out_of_ssa, sequentialize };
 
 mod validate;
-pub use self::validate::validate;
+pub use self::validate::{ validate, Violation, ViolationKind, Location };
 
 mod compile_pattern;
 pub use self::compile_pattern::compile_pattern;
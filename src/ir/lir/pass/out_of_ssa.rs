@@ -0,0 +1,343 @@
+//! Translates out of SSA by replacing phi nodes with copies.
+//!
+//! A phi assigns all its variables at the same time on the edge it is
+//! entered through. The values are first attached to the incoming edges
+//! as parallel copies, then sequentialised into `Move`s at the end of the
+//! predecessor, as described in "Revisiting Out-of-SSA Translation for
+//! Correctness, Code Quality, and Efficiency" by Boissinot et al.
+//!
+//! The copies can only be placed in the predecessor if it ends with a
+//! plain `Jump`. Any other edge into a block with phis is split first, so
+//! critical edges, and edges from terminators assigning variables used by
+//! the copies, get a block of their own.
+//!
+//! After this pass the CFG is no longer in SSA form, variables may be
+//! assigned more than once.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::petgraph::Direction;
+use ::petgraph::visit::EdgeRef;
+
+use ::ir::SSAVariable;
+use ::ir::hir::pass::ssa::ScopeTracker;
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind, Source };
+
+pub fn out_of_ssa(cfg: &mut FunctionCfg, env: &mut ScopeTracker) {
+    split_edges(cfg);
+    insert_copies(cfg);
+    sequentialize_edges(cfg, env);
+}
+
+/// True if copies for edges leaving the block can be placed before its
+/// terminator.
+fn ends_with_jump(cfg: &FunctionCfg, label: LabelN) -> bool {
+    let block = cfg.block(label);
+    match block.ops.last().map(|op| &op.kind) {
+        Some(&OpKind::Jump) => block.outgoing_edges.len() == 1,
+        _ => false,
+    }
+}
+
+fn split_edges(cfg: &mut FunctionCfg) {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        if cfg.block(label).phi_nodes.is_empty() {
+            continue;
+        }
+
+        let incoming: Vec<_> = cfg.cfg.edges_directed(label.0, Direction::Incoming)
+            .map(|e| (LabelN(e.source()), e.id()))
+            .collect();
+        let mut split_preds = Vec::new();
+        for (pred, edge) in incoming {
+            if ends_with_jump(cfg, pred) {
+                continue;
+            }
+
+            let split = cfg.add_block();
+            cfg.block_mut(split).ops.push(Op {
+                kind: OpKind::Jump,
+                reads: vec![],
+                writes: vec![],
+            });
+            cfg.redirect_edge(::ir::lir::cfg::EdgeN(edge), split);
+            cfg.add_edge(split, label);
+
+            // The split block takes the values of the edge it replaces
+            for phi in cfg.block_mut(label).phi_nodes.iter_mut() {
+                let value = phi.entries.iter()
+                    .find(|&&(l, _)| l == pred)
+                    .unwrap().1;
+                phi.entries.push((split, value));
+            }
+            split_preds.push(pred);
+        }
+
+        for phi in cfg.block_mut(label).phi_nodes.iter_mut() {
+            phi.entries.retain(|&(l, _)| !split_preds.contains(&l));
+        }
+    }
+}
+
+/// Moves the values of the phi nodes to parallel copies on the incoming
+/// edges, and removes the phis.
+fn insert_copies(cfg: &mut FunctionCfg) {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        let phis = std::mem::take(&mut cfg.block_mut(label).phi_nodes);
+        if phis.is_empty() {
+            continue;
+        }
+
+        let incoming: Vec<_> = cfg.cfg.edges_directed(label.0, Direction::Incoming)
+            .map(|e| (LabelN(e.source()), e.id()))
+            .collect();
+        for (pred, edge) in incoming {
+            let weight = cfg.cfg.edge_weight_mut(edge).unwrap();
+            for phi in phis.iter() {
+                let value = phi.entries.iter()
+                    .find(|&&(l, _)| l == pred)
+                    .unwrap().1;
+                weight.writes.push(phi.ssa);
+                weight.reads.push(Source::Variable(value));
+            }
+        }
+    }
+}
+
+fn sequentialize_edges(cfg: &mut FunctionCfg, env: &mut ScopeTracker) {
+    let edges: Vec<_> = cfg.cfg.edge_indices().collect();
+    for edge in edges {
+        let copies: Vec<_> = {
+            let weight = cfg.cfg.edge_weight_mut(edge).unwrap();
+            let writes = std::mem::take(&mut weight.writes);
+            let reads = std::mem::take(&mut weight.reads);
+            writes.into_iter().zip(reads).collect()
+        };
+        if copies.is_empty() {
+            continue;
+        }
+
+        let (pred, _) = cfg.cfg.edge_endpoints(edge).unwrap();
+        let moves = sequentialize(&copies, || env.new_ssa());
+
+        let ops = &mut cfg.block_mut(LabelN(pred)).ops;
+        let jump = ops.pop().unwrap();
+        for (write, read) in moves {
+            ops.push(Op {
+                kind: OpKind::Move,
+                reads: vec![read],
+                writes: vec![write],
+            });
+        }
+        ops.push(jump);
+    }
+}
+
+/// Orders a parallel copy into a sequence of copies with the same
+/// effect. Every variable may only be written once. `temp` is called to
+/// get a variable for breaking cycles, such as swaps.
+pub fn sequentialize<F>(copies: &[(SSAVariable, Source)], mut temp: F)
+                        -> Vec<(SSAVariable, Source)>
+    where F: FnMut() -> SSAVariable {
+
+    let mut result = Vec::new();
+
+    // Constants read no variables, assigning them last can not overwrite
+    // anything still to be read.
+    let mut constants = Vec::new();
+
+    // For every written variable, the variable it should get the value of
+    let mut pred: HashMap<SSAVariable, SSAVariable> = HashMap::new();
+    // Where the original value of a variable can currently be found
+    let mut loc: HashMap<SSAVariable, SSAVariable> = HashMap::new();
+    let mut todo = Vec::new();
+    for &(write, ref read) in copies.iter() {
+        match *read {
+            Source::Variable(var) if var == write => (),
+            Source::Variable(var) => {
+                loc.insert(var, var);
+                pred.insert(write, var);
+                todo.push(write);
+            },
+            _ => constants.push((write, read.clone())),
+        }
+    }
+
+    // Variables that are written, but whose values are not needed
+    let mut ready: Vec<SSAVariable> = todo.iter()
+        .filter(|w| !loc.contains_key(w))
+        .cloned()
+        .collect();
+
+    let mut done = HashSet::new();
+    let mut temp_var = None;
+    loop {
+        while let Some(b) = ready.pop() {
+            let a = pred[&b];
+            let c = loc[&a];
+            result.push((b, Source::Variable(c)));
+            done.insert(b);
+            loc.insert(a, b);
+            if a == c && pred.contains_key(&a) {
+                ready.push(a);
+            }
+        }
+
+        // Anything left is part of a cycle, break it by saving the value
+        // of one variable in the temporary.
+        let write = match todo.pop() {
+            Some(write) => write,
+            None => break,
+        };
+        if !done.contains(&write) {
+            let t = *temp_var.get_or_insert_with(&mut temp);
+            result.push((t, Source::Variable(write)));
+            loc.insert(write, t);
+            ready.push(write);
+        }
+    }
+
+    result.extend(constants);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::collections::HashMap;
+
+    use ::parser::{ AtomicLiteral, Integer };
+    use ::ir::SSAVariable;
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use super::{ out_of_ssa, sequentialize };
+
+    /// Runs the copies one after the other on an environment where every
+    /// variable holds its own number, and returns the result.
+    fn run(copies: &[(SSAVariable, Source)], vars: &[SSAVariable])
+           -> HashMap<SSAVariable, String> {
+        let mut values: HashMap<SSAVariable, String> = vars.iter()
+            .map(|v| (*v, format!("{:?}", v)))
+            .collect();
+        for &(write, ref read) in copies.iter() {
+            let value = match *read {
+                Source::Variable(var) => values[&var].clone(),
+                ref other => format!("{:?}", other),
+            };
+            values.insert(write, value);
+        }
+        values
+    }
+
+    fn check(copies: Vec<(SSAVariable, Source)>, vars: &[SSAVariable],
+             env: &mut ScopeTracker) -> usize {
+        // A parallel copy reads all values before writing any
+        let mut expected = run(&[], vars);
+        let parallel = run(&[], vars);
+        for &(write, ref read) in copies.iter() {
+            let value = match *read {
+                Source::Variable(var) => parallel[&var].clone(),
+                ref other => format!("{:?}", other),
+            };
+            expected.insert(write, value);
+        }
+
+        let mut temps = 0;
+        let seq = sequentialize(&copies, || { temps += 1; env.new_ssa() });
+        let result = run(&seq, vars);
+        for var in vars.iter() {
+            assert!(result[var] == expected[var], "{:?} -> {:?}", copies, seq);
+        }
+        temps
+    }
+
+    #[test]
+    fn sequentialize_cycles() {
+        let mut env = ScopeTracker::new();
+        let v: Vec<_> = (0..6).map(|_| env.new_ssa()).collect();
+        let var = |n: usize| Source::Variable(v[n]);
+        let one = Source::Constant(AtomicLiteral::Integer(Integer::from_i64(1)));
+
+        // Swap
+        assert!(check(vec![(v[0], var(1)), (v[1], var(0))], &v, &mut env) == 1);
+        // Rotation, the copy out of it saves a value and avoids a temporary
+        assert!(check(vec![(v[0], var(1)), (v[1], var(2)), (v[2], var(0)),
+                           (v[3], var(2)), (v[4], one.clone()), (v[5], var(4))],
+                      &v, &mut env) == 0);
+        // Rotation with a constant overwriting a source
+        assert!(check(vec![(v[0], var(1)), (v[1], var(2)), (v[2], var(0)),
+                           (v[4], one.clone()), (v[5], var(4))],
+                      &v, &mut env) == 1);
+        // Chain and fan out, no temporary needed
+        assert!(check(vec![(v[1], var(0)), (v[2], var(1)), (v[3], var(1)),
+                           (v[4], var(4))], &v, &mut env) == 0);
+        // Two separate swaps
+        assert!(check(vec![(v[0], var(1)), (v[1], var(0)),
+                           (v[2], var(3)), (v[3], var(2))], &v, &mut env) == 1);
+    }
+
+    #[test]
+    fn swap_in_loop() {
+        let mut env = ScopeTracker::new();
+        let a = env.new_ssa();
+        let b_ = env.new_ssa();
+        let c = env.new_ssa();
+        let x = env.new_ssa();
+        let y = env.new_ssa();
+
+        //   entry
+        //     |
+        //   loop <-+  x, y = phi [a, b], [y, x]
+        //   /  \___|
+        // exit
+
+        let mut cfg = FunctionCfg::new();
+        let (lp, exit) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![a, b_, c]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            let lp = b.add_block();
+            let exit = b.add_block();
+            b.add_jump(entry, lp);
+
+            b.set_block(lp);
+            b.add_jump(lp, lp);
+            b.add_jump(lp, exit);
+            b.add_phi(entry, a, lp, x);
+            b.add_phi(lp, y, lp, x);
+            b.add_phi(entry, b_, lp, y);
+            b.add_phi(lp, x, lp, y);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(c)], vec![]);
+
+            b.set_block(exit);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(x), Source::Variable(y)],
+                       vec![]);
+            (lp, exit)
+        };
+
+        out_of_ssa(&mut cfg, &mut env);
+
+        assert!(cfg.blocks_iter().all(|b| b.phi_nodes.is_empty()));
+        assert!(cfg.cfg.node_count() == 4);
+
+        // The back edge is split, exit is still reached directly
+        let targets: Vec<_> = cfg.jumps_iter(lp).map(|e| cfg.edge_target(e)).collect();
+        assert!(targets[0] != lp && targets[1] == exit);
+
+        let entry_ops: Vec<_> = cfg.block(cfg.entry()).ops.iter()
+            .map(|op| format!("{:?}", op.kind))
+            .collect();
+        assert!(entry_ops == vec!["Arguments", "Move", "Move", "Jump"]);
+
+        let split_ops = &cfg.block(targets[0]).ops;
+        assert!(split_ops.len() == 4);
+        let copies: Vec<_> = split_ops[..3].iter()
+            .map(|op| (op.writes[0], op.reads[0].clone()))
+            .collect();
+        let result = run(&copies, &[x, y]);
+        assert!(result[&x] == format!("{:?}", y) && result[&y] == format!("{:?}", x));
+    }
+
+}
@@ -0,0 +1,596 @@
+//! Sparse conditional constant propagation, as described in "Constant
+//! Propagation with Conditional Branches" by Wegman and Zadeck.
+//!
+//! Every variable starts out as `Top`, meaning no assignment to it has
+//! been seen executing yet. Values only ever move down the lattice, from
+//! `Top` to a single constant to `Bottom`. Only edges that can be taken
+//! given the current values are followed, which lets values that only
+//! differ along untaken edges stay constant.
+//!
+//! When done, reads of constant variables are replaced by the constant,
+//! and `IfTruthy` and calls to pure BIFs on constants are folded,
+//! removing the edges that can never be taken. Blocks that become
+//! unreachable are left for `eliminate_dead_code`.
+
+use ::std::collections::{ HashMap, HashSet };
+use ::std::cmp::Ordering;
+use ::std::str::FromStr;
+
+use ::Atom;
+use ::parser::{ AtomicLiteral, Integer };
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind, Source };
+
+/// A constant term known at compile time. Floats are not folded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Atom(Atom),
+    Nil,
+    Tuple(Vec<Value>),
+    Cons(Box<Value>, Box<Value>),
+}
+impl Value {
+
+    pub fn from_literal(literal: &AtomicLiteral) -> Option<Value> {
+        match *literal {
+            AtomicLiteral::Integer(ref int) => int.as_i64().map(Value::Integer),
+            AtomicLiteral::Float(_) => None,
+            AtomicLiteral::Atom(ref atom) => Some(Value::Atom(atom.clone())),
+            AtomicLiteral::Nil => Some(Value::Nil),
+            AtomicLiteral::Char(c) => Some(Value::Integer(c as i64)),
+            AtomicLiteral::String(ref string) => Some(
+                string.chars().rev().fold(Value::Nil, |tail, c| {
+                    Value::Cons(Box::new(Value::Integer(c as i64)), Box::new(tail))
+                })),
+        }
+    }
+
+    /// Returns None if the value can not be represented as a literal.
+    pub fn to_literal(&self) -> Option<AtomicLiteral> {
+        match *self {
+            Value::Integer(num) => Some(AtomicLiteral::Integer(Integer::from_i64(num))),
+            Value::Atom(ref atom) => Some(AtomicLiteral::Atom(atom.clone())),
+            Value::Nil => Some(AtomicLiteral::Nil),
+            _ => None,
+        }
+    }
+
+    fn atom(name: &str) -> Value {
+        Value::Atom(Atom::from_str(name).unwrap())
+    }
+
+    fn boolean(value: bool) -> Value {
+        Value::atom(if value { "true" } else { "false" })
+    }
+
+    fn as_boolean(&self) -> Option<bool> {
+        match *self {
+            Value::Atom(ref atom) if &**atom == "true" => Some(true),
+            Value::Atom(ref atom) if &**atom == "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Position of the type in the Erlang term order.
+    fn type_order(&self) -> u8 {
+        match *self {
+            Value::Integer(_) => 0,
+            Value::Atom(_) => 1,
+            Value::Tuple(_) => 2,
+            Value::Nil => 3,
+            Value::Cons(_, _) => 4,
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (&Value::Integer(a), &Value::Integer(b)) => a.cmp(&b),
+            (Value::Atom(a), Value::Atom(b)) => (**a).cmp(&**b),
+            (Value::Tuple(a), Value::Tuple(b)) => {
+                a.len().cmp(&b.len()).then_with(|| {
+                    a.iter().zip(b.iter())
+                        .map(|(a, b)| a.compare(b))
+                        .find(|o| *o != Ordering::Equal)
+                        .unwrap_or(Ordering::Equal)
+                })
+            },
+            (Value::Cons(a_head, a_tail),
+             Value::Cons(b_head, b_tail)) =>
+                a_head.compare(b_head).then_with(|| a_tail.compare(b_tail)),
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+
+    fn list_len(&self) -> Option<i64> {
+        match *self {
+            Value::Nil => Some(0),
+            Value::Cons(_, ref tail) => tail.list_len().map(|n| n + 1),
+            _ => None,
+        }
+    }
+
+}
+
+/// Evaluates a call to a pure function in the `erlang` module. Returns
+/// None if the function is unknown or the call would raise.
+pub fn eval_bif(name: &str, args: &[Value]) -> Option<Value> {
+    use self::Value::*;
+
+    match (name, args) {
+        ("+", &[Integer(a), Integer(b)]) => a.checked_add(b).map(Integer),
+        ("-", &[Integer(a), Integer(b)]) => a.checked_sub(b).map(Integer),
+        ("*", &[Integer(a), Integer(b)]) => a.checked_mul(b).map(Integer),
+        ("div", &[Integer(a), Integer(b)]) => a.checked_div(b).map(Integer),
+        ("rem", &[Integer(a), Integer(b)]) => a.checked_rem(b).map(Integer),
+        ("band", &[Integer(a), Integer(b)]) => Some(Integer(a & b)),
+        ("bor", &[Integer(a), Integer(b)]) => Some(Integer(a | b)),
+        ("bxor", &[Integer(a), Integer(b)]) => Some(Integer(a ^ b)),
+        ("-", &[Integer(a)]) => a.checked_neg().map(Integer),
+        ("+", &[Integer(a)]) => Some(Integer(a)),
+        ("abs", &[Integer(a)]) => a.checked_abs().map(Integer),
+
+        // There are no floats, so exact and arithmetic equality are the
+        // same.
+        ("=:=", &[ref a, ref b]) | ("==", &[ref a, ref b]) =>
+            Some(Value::boolean(a.compare(b) == Ordering::Equal)),
+        ("=/=", &[ref a, ref b]) | ("/=", &[ref a, ref b]) =>
+            Some(Value::boolean(a.compare(b) != Ordering::Equal)),
+        ("<", [a, b]) => Some(Value::boolean(a.compare(b) == Ordering::Less)),
+        (">", [a, b]) => Some(Value::boolean(a.compare(b) == Ordering::Greater)),
+        ("=<", [a, b]) => Some(Value::boolean(a.compare(b) != Ordering::Greater)),
+        (">=", [a, b]) => Some(Value::boolean(a.compare(b) != Ordering::Less)),
+
+        ("not", [a]) => a.as_boolean().map(|a| Value::boolean(!a)),
+        ("and", [a, b]) => match (a.as_boolean(), b.as_boolean()) {
+            (Some(a), Some(b)) => Some(Value::boolean(a && b)),
+            _ => None,
+        },
+        ("or", [a, b]) => match (a.as_boolean(), b.as_boolean()) {
+            (Some(a), Some(b)) => Some(Value::boolean(a || b)),
+            _ => None,
+        },
+        ("xor", [a, b]) => match (a.as_boolean(), b.as_boolean()) {
+            (Some(a), Some(b)) => Some(Value::boolean(a != b)),
+            _ => None,
+        },
+
+        ("is_atom", [a]) => Some(Value::boolean(matches!(*a, Atom(_)))),
+        ("is_boolean", [a]) => Some(Value::boolean(a.as_boolean().is_some())),
+        ("is_integer", &[ref a]) | ("is_number", &[ref a]) =>
+            Some(Value::boolean(matches!(*a, Integer(_)))),
+        ("is_tuple", [a]) => Some(Value::boolean(matches!(*a, Tuple(_)))),
+        ("is_list", [a]) => Some(Value::boolean(matches!(*a, Nil | Cons(_, _)))),
+        ("is_float", &[_]) | ("is_binary", &[_]) | ("is_bitstring", &[_])
+            | ("is_map", &[_]) | ("is_function", &[_]) | ("is_pid", &[_])
+            | ("is_port", &[_]) | ("is_reference", &[_]) =>
+            Some(Value::boolean(false)),
+
+        ("element", &[Integer(idx), Tuple(ref elems)]) => {
+            if idx >= 1 && idx as usize <= elems.len() {
+                Some(elems[idx as usize - 1].clone())
+            } else {
+                None
+            }
+        },
+        ("tuple_size", &[Tuple(ref elems)]) => Some(Integer(elems.len() as i64)),
+        ("hd", &[Cons(ref head, _)]) => Some((**head).clone()),
+        ("tl", &[Cons(_, ref tail)]) => Some((**tail).clone()),
+        ("length", [list]) => list.list_len().map(Integer),
+
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lattice {
+    Top,
+    Const(Value),
+    Bottom,
+}
+impl Lattice {
+    fn meet(&self, other: &Lattice) -> Lattice {
+        match (self, other) {
+            (&Lattice::Top, _) => other.clone(),
+            (_, &Lattice::Top) => self.clone(),
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => self.clone(),
+            _ => Lattice::Bottom,
+        }
+    }
+}
+
+struct Solver<'a> {
+    cfg: &'a FunctionCfg,
+    values: HashMap<SSAVariable, Lattice>,
+    executable_edges: HashSet<(LabelN, LabelN)>,
+    executable_blocks: HashSet<LabelN>,
+    worklist: Vec<LabelN>,
+    uses: HashMap<SSAVariable, Vec<LabelN>>,
+}
+
+impl<'a> Solver<'a> {
+
+    fn new(cfg: &'a FunctionCfg) -> Self {
+        let mut uses: HashMap<SSAVariable, Vec<LabelN>> = HashMap::new();
+        for label in cfg.labels_iter() {
+            let block = cfg.block(label);
+            let phi_reads = block.phi_nodes.iter()
+                .flat_map(|phi| phi.entries.iter().map(|&(_, ssa)| ssa));
+            let op_reads = block.ops.iter().flat_map(|op| op.read_vars());
+            for var in phi_reads.chain(op_reads) {
+                uses.entry(var).or_insert(vec![]).push(label);
+            }
+        }
+
+        Solver {
+            cfg,
+            values: HashMap::new(),
+            executable_edges: HashSet::new(),
+            executable_blocks: HashSet::new(),
+            worklist: vec![],
+            uses,
+        }
+    }
+
+    fn solve(&mut self) {
+        let entry = self.cfg.entry();
+        self.executable_blocks.insert(entry);
+        self.worklist.push(entry);
+
+        while let Some(label) = self.worklist.pop() {
+            self.visit_block(label);
+        }
+    }
+
+    fn value(&self, var: SSAVariable) -> Lattice {
+        self.values.get(&var).cloned().unwrap_or(Lattice::Top)
+    }
+
+    fn source_value(&self, source: &Source) -> Lattice {
+        match *source {
+            Source::Variable(var) => self.value(var),
+            Source::Constant(ref literal) => Value::from_literal(literal)
+                .map(Lattice::Const)
+                .unwrap_or(Lattice::Bottom),
+            // Runs before constants are hoisted into the pool
+            Source::Literal(_) => Lattice::Bottom,
+        }
+    }
+
+    /// Values of all the sources, or the lattice value the result should
+    /// have if any of them is not constant.
+    fn const_sources(&self, sources: &[Source]) -> Result<Vec<Value>, Lattice> {
+        let mut values = Vec::new();
+        let mut result = Lattice::Top;
+        for source in sources {
+            match self.source_value(source) {
+                Lattice::Const(value) => values.push(value),
+                Lattice::Top => (),
+                Lattice::Bottom => result = Lattice::Bottom,
+            }
+        }
+        if values.len() == sources.len() {
+            Ok(values)
+        } else {
+            Err(result)
+        }
+    }
+
+    fn update(&mut self, var: SSAVariable, value: Lattice) {
+        let old = self.value(var);
+        let new = old.meet(&value);
+        if new != old {
+            self.values.insert(var, new);
+            if let Some(uses) = self.uses.get(&var) {
+                for label in uses.iter() {
+                    if self.executable_blocks.contains(label) {
+                        self.worklist.push(*label);
+                    }
+                }
+            }
+        }
+    }
+
+    fn mark_edge(&mut self, from: LabelN, slot: usize) {
+        let edge = self.cfg.block(from).outgoing_edges[slot];
+        let to = self.cfg.edge_target(edge);
+        if self.executable_edges.insert((from, to)) {
+            self.executable_blocks.insert(to);
+            self.worklist.push(to);
+        }
+    }
+
+    fn mark_all_edges(&mut self, from: LabelN) {
+        for slot in 0..self.cfg.block(from).outgoing_edges.len() {
+            self.mark_edge(from, slot);
+        }
+    }
+
+    fn visit_block(&mut self, label: LabelN) {
+        let cfg = self.cfg;
+        let block = cfg.block(label);
+
+        for phi in block.phi_nodes.iter() {
+            let mut value = Lattice::Top;
+            for &(pred, ssa) in phi.entries.iter() {
+                if self.executable_edges.contains(&(pred, label)) {
+                    value = value.meet(&self.value(ssa));
+                }
+            }
+            self.update(phi.ssa, value);
+        }
+
+        for op in block.ops.iter() {
+            self.visit_op(label, op);
+        }
+    }
+
+    fn visit_op(&mut self, label: LabelN, op: &Op) {
+        match op.kind {
+            OpKind::Move => {
+                let value = self.source_value(&op.reads[0]);
+                self.update(op.writes[0], value);
+            },
+            OpKind::MakeTuple => {
+                let value = match self.const_sources(&op.reads) {
+                    Ok(elems) => Lattice::Const(Value::Tuple(elems)),
+                    Err(value) => value,
+                };
+                self.update(op.writes[0], value);
+            },
+            OpKind::MakeList => {
+                // Reads are the tail followed by the head elements
+                let value = match self.const_sources(&op.reads) {
+                    Ok(mut elems) => {
+                        let tail = elems.remove(0);
+                        Lattice::Const(elems.into_iter().rev().fold(tail, |tail, head| {
+                            Value::Cons(Box::new(head), Box::new(tail))
+                        }))
+                    },
+                    Err(value) => value,
+                };
+                self.update(op.writes[0], value);
+            },
+            OpKind::Call => {
+                match self.eval_call(op) {
+                    Lattice::Top => (),
+                    Lattice::Const(value) => {
+                        self.update(op.writes[0], Lattice::Const(value));
+                        self.mark_edge(label, 0);
+                    },
+                    Lattice::Bottom => {
+                        self.update(op.writes[0], Lattice::Bottom);
+                        self.mark_all_edges(label);
+                    },
+                }
+            },
+            OpKind::IfTruthy => {
+                match self.source_value(&op.reads[0]) {
+                    Lattice::Top => (),
+                    Lattice::Const(ref value) if value.as_boolean() == Some(true) =>
+                        self.mark_edge(label, 0),
+                    Lattice::Const(_) => self.mark_edge(label, 1),
+                    Lattice::Bottom => self.mark_all_edges(label),
+                }
+            },
+            ref kind => {
+                for write in op.writes.iter() {
+                    self.update(*write, Lattice::Bottom);
+                }
+                if kind.num_jumps().is_some() {
+                    self.mark_all_edges(label);
+                }
+            },
+        }
+    }
+
+    /// Calls are `module:name(args...)`, only pure functions in the
+    /// `erlang` module are evaluated.
+    fn eval_call(&self, op: &Op) -> Lattice {
+        let values = match self.const_sources(&op.reads) {
+            Ok(values) => values,
+            Err(value) => return value,
+        };
+        let is_erlang = match values[0] {
+            Value::Atom(ref module) => &**module == "erlang",
+            _ => false,
+        };
+        match values[1] {
+            Value::Atom(ref name) if is_erlang => {
+                eval_bif(name, &values[2..])
+                    .map(Lattice::Const)
+                    .unwrap_or(Lattice::Bottom)
+            },
+            _ => Lattice::Bottom,
+        }
+    }
+
+}
+
+pub fn propagate_constants(cfg: &mut FunctionCfg) {
+    let (constants, executable_edges, executable_blocks) = {
+        let mut solver = Solver::new(cfg);
+        solver.solve();
+
+        let constants: HashMap<SSAVariable, AtomicLiteral> = solver.values.iter()
+            .filter_map(|(var, value)| match *value {
+                Lattice::Const(ref value) => value.to_literal().map(|l| (*var, l)),
+                _ => None,
+            })
+            .collect();
+        (constants, solver.executable_edges, solver.executable_blocks)
+    };
+
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels.iter() {
+        let block = cfg.block_mut(*label);
+        for op in block.ops.iter_mut() {
+            for read in op.reads.iter_mut() {
+                let constant = match *read {
+                    Source::Variable(ref var) => constants.get(var).cloned(),
+                    _ => None,
+                };
+                if let Some(constant) = constant {
+                    *read = Source::Constant(constant);
+                }
+            }
+
+            let folded = op.kind.is_pure() && op.writes.len() == 1
+                && constants.contains_key(&op.writes[0]);
+            if folded {
+                op.kind = OpKind::Move;
+                op.reads = vec![Source::Constant(constants[&op.writes[0]].clone())];
+            }
+        }
+    }
+
+    // Fold branches where only some edges can be taken
+    for label in labels.iter() {
+        if !executable_blocks.contains(label) {
+            continue;
+        }
+
+        let untaken: Vec<_> = cfg.jumps_iter(*label)
+            .filter(|e| !executable_edges.contains(&(*label, cfg.edge_target(*e))))
+            .collect();
+        if untaken.is_empty() {
+            continue;
+        }
+
+        let folded = {
+            let block = cfg.block_mut(*label);
+            let last = block.ops.pop().unwrap();
+            match last.kind {
+                OpKind::IfTruthy => {
+                    block.ops.push(Op {
+                        kind: OpKind::Jump,
+                        reads: vec![],
+                        writes: vec![],
+                    });
+                    true
+                },
+                OpKind::Call if constants.contains_key(&last.writes[0]) => {
+                    let result = last.writes[0];
+                    block.ops.push(Op {
+                        kind: OpKind::Move,
+                        reads: vec![Source::Constant(constants[&result].clone())],
+                        writes: vec![result],
+                    });
+                    block.ops.push(Op {
+                        kind: OpKind::Jump,
+                        reads: vec![],
+                        writes: vec![],
+                    });
+                    true
+                },
+                _ => {
+                    block.ops.push(last);
+                    false
+                },
+            }
+        };
+        if folded {
+            for edge in untaken {
+                cfg.remove_edge(edge);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+
+    use ::Atom;
+    use ::parser::{ AtomicLiteral, Integer };
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use ::ir::lir::pass::{ validate, eliminate_dead_code, simplify_cfg };
+    use super::{ propagate_constants, eval_bif, Value };
+
+    fn atom(name: &str) -> Source {
+        Source::Constant(AtomicLiteral::Atom(Atom::from_str(name).unwrap()))
+    }
+
+    fn int(num: i64) -> Source {
+        Source::Constant(AtomicLiteral::Integer(Integer::from_i64(num)))
+    }
+
+    #[test]
+    fn folds_calls_and_branches() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let sum = env.new_ssa();
+        let tuple = env.new_ssa();
+        let elem = env.new_ssa();
+        let cond = env.new_ssa();
+
+        // sum = 1 + 2
+        // elem = element(1, {sum})
+        // if elem =:= 3 -> yes else no
+
+        let mut cfg = FunctionCfg::new();
+        {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+
+            let call = |b: &mut FunctionCfgBuilder, reads: Vec<Source>, write| {
+                b.basic_op(OpKind::Call, reads, vec![write]);
+                let call_block = b.get_block();
+                let throw_block = b.add_block();
+                let resume_block = b.add_block();
+                b.add_jump(call_block, resume_block);
+                b.add_jump(call_block, throw_block);
+                b.set_block(throw_block);
+                b.basic_op(OpKind::ReturnThrow, vec![], vec![]);
+                b.set_block(resume_block);
+            };
+
+            call(&mut b, vec![atom("erlang"), atom("+"), int(1), int(2)], sum);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(sum)], vec![tuple]);
+            call(&mut b, vec![atom("erlang"), atom("element"), int(1),
+                              Source::Variable(tuple)], elem);
+            call(&mut b, vec![atom("erlang"), atom("=:="), Source::Variable(elem),
+                              int(3)], cond);
+
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(cond)], vec![]);
+            let branch = b.get_block();
+            let yes = b.add_block();
+            let no = b.add_block();
+            b.add_jump(branch, yes);
+            b.add_jump(branch, no);
+            b.set_block(yes);
+            b.basic_op(OpKind::ReturnOk, vec![atom("yes")], vec![]);
+            b.set_block(no);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(arg)], vec![]);
+        }
+
+        propagate_constants(&mut cfg);
+        eliminate_dead_code(&mut cfg);
+        simplify_cfg(&mut cfg);
+        assert!(validate(&cfg).is_empty(), "{:?}", validate(&cfg));
+
+        assert!(cfg.cfg.node_count() == 1);
+        let ops = &cfg.block(cfg.entry()).ops;
+        let last = ops.last().unwrap();
+        match (&last.kind, &last.reads[0]) {
+            (&OpKind::ReturnOk, &Source::Constant(AtomicLiteral::Atom(ref a))) =>
+                assert!(&**a == "yes"),
+            _ => panic!("{:?}", ops),
+        }
+    }
+
+    #[test]
+    fn bif_evaluation() {
+        let t = Value::Tuple(vec![Value::Integer(1)]);
+        assert!(eval_bif("<", &[Value::Integer(5), Value::Nil]) == Some(Value::boolean(true)));
+        assert!(eval_bif(">", &[t.clone(), Value::atom("a")]) == Some(Value::boolean(true)));
+        assert!(eval_bif("div", &[Value::Integer(-7), Value::Integer(2)])
+                == Some(Value::Integer(-3)));
+        assert!(eval_bif("div", &[Value::Integer(1), Value::Integer(0)]).is_none());
+        assert!(eval_bif("element", &[Value::Integer(2), t.clone()]).is_none());
+        assert!(eval_bif("is_tuple", &[t]) == Some(Value::boolean(true)));
+        assert!(eval_bif("+", &[Value::Integer(i64::MAX), Value::Integer(1)]).is_none());
+    }
+
+}
@@ -0,0 +1,255 @@
+//! Simplifies the control flow of a function by threading jumps through
+//! empty blocks and merging straight-line chains of blocks.
+//!
+//! A block containing nothing but a `Jump` is removed by pointing all of
+//! its incoming edges directly to its successor. A block with a single
+//! predecessor ending in a `Jump` is appended to that predecessor. Phi
+//! nodes left with a single entry by this are removed, and reads of them
+//! are replaced by the value of the entry.
+
+use ::std::collections::HashMap;
+
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, OpKind };
+
+pub fn simplify_cfg(cfg: &mut FunctionCfg) {
+    let mut substitutions = HashMap::new();
+
+    loop {
+        let mut changed = false;
+        let labels: Vec<_> = cfg.labels_iter().collect();
+        for label in labels {
+            if !cfg.cfg.contains_node(label.0) {
+                continue;
+            }
+            changed |= thread_jump(cfg, label);
+            changed |= merge_into_predecessor(cfg, label, &mut substitutions);
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    if substitutions.is_empty() {
+        return;
+    }
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        let block = cfg.block_mut(label);
+        for phi in block.phi_nodes.iter_mut() {
+            for entry in phi.entries.iter_mut() {
+                entry.1 = substitute(&substitutions, entry.1);
+            }
+        }
+        for op in block.ops.iter_mut() {
+            op.map_read_vars(|var| *var = substitute(&substitutions, *var));
+        }
+    }
+}
+
+fn substitute(substitutions: &HashMap<SSAVariable, SSAVariable>,
+              mut var: SSAVariable) -> SSAVariable {
+    while let Some(next) = substitutions.get(&var) {
+        var = *next;
+    }
+    var
+}
+
+fn single_successor(cfg: &FunctionCfg, label: LabelN) -> Option<LabelN> {
+    let block = cfg.block(label);
+    match block.ops.last().map(|op| &op.kind) {
+        Some(&OpKind::Jump) if block.outgoing_edges.len() == 1 =>
+            Some(cfg.edge_target(block.outgoing_edges[0])),
+        _ => None,
+    }
+}
+
+/// Removes the block if it contains nothing but a `Jump`, redirecting
+/// every incoming edge to its successor.
+fn thread_jump(cfg: &mut FunctionCfg, label: LabelN) -> bool {
+    if label == cfg.entry {
+        return false;
+    }
+    let block = cfg.block(label);
+    if !block.phi_nodes.is_empty() || block.ops.len() != 1 {
+        return false;
+    }
+    let target = match single_successor(cfg, label) {
+        Some(target) if target != label => target,
+        _ => return false,
+    };
+
+    let preds = cfg.predecessors(label);
+    if preds.is_empty() {
+        return false;
+    }
+
+    // If a predecessor already jumps to the target, the phis in the
+    // target could need different values for the two edges.
+    let target_preds = cfg.predecessors(target);
+    if !cfg.block(target).phi_nodes.is_empty()
+        && preds.iter().any(|p| target_preds.contains(p)) {
+        return false;
+    }
+
+    for pred in preds.iter() {
+        let edges: Vec<_> = cfg.jumps_iter(*pred)
+            .filter(|e| cfg.edge_target(*e) == label)
+            .collect();
+        for edge in edges {
+            cfg.redirect_edge(edge, target);
+        }
+    }
+
+    // Every entry for the removed block now comes from its predecessors
+    for phi in cfg.block_mut(target).phi_nodes.iter_mut() {
+        let pos = phi.entries.iter().position(|&(l, _)| l == label).unwrap();
+        let (_, value) = phi.entries.remove(pos);
+        for pred in preds.iter() {
+            if !phi.entries.iter().any(|&(l, _)| l == *pred) {
+                phi.entries.push((*pred, value));
+            }
+        }
+    }
+
+    cfg.remove_block(label);
+    true
+}
+
+/// Appends the block to its predecessor if the predecessor ends with a
+/// `Jump` to it, and it has no other predecessors.
+fn merge_into_predecessor(cfg: &mut FunctionCfg, label: LabelN,
+                          substitutions: &mut HashMap<SSAVariable, SSAVariable>)
+                          -> bool {
+    if label == cfg.entry {
+        return false;
+    }
+    let preds = cfg.predecessors(label);
+    if preds.len() != 1 || preds[0] == label {
+        return false;
+    }
+    let pred = preds[0];
+    if single_successor(cfg, pred) != Some(label) {
+        return false;
+    }
+
+    // ReceiveWait must stay alone in its block
+    let block = cfg.block(label);
+    let has_wait = block.ops.iter().any(|op| matches!(op.kind, OpKind::ReceiveWait));
+    if has_wait || block.phi_nodes.iter().any(|phi| phi.entries.len() != 1) {
+        return false;
+    }
+
+    let targets: Vec<_> = cfg.jumps_iter(label)
+        .map(|e| cfg.edge_target(e))
+        .collect();
+    let block = cfg.remove_block(label);
+
+    for phi in block.phi_nodes.iter() {
+        substitutions.insert(phi.ssa, phi.entries[0].1);
+    }
+
+    {
+        let pred_block = cfg.block_mut(pred);
+        pred_block.ops.pop();
+        pred_block.ops.extend(block.ops);
+        pred_block.outgoing_edges.clear();
+    }
+    for target in targets.iter() {
+        cfg.add_edge(pred, *target);
+    }
+
+    for target in targets.iter() {
+        for phi in cfg.block_mut(*target).phi_nodes.iter_mut() {
+            for entry in phi.entries.iter_mut() {
+                if entry.0 == label {
+                    entry.0 = pred;
+                }
+            }
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use ::ir::lir::pass::validate;
+    use super::simplify_cfg;
+
+    #[test]
+    fn threads_and_merges() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let right_val = env.new_ssa();
+        let mid_val = env.new_ssa();
+        let mid_phi = env.new_ssa();
+        let join_phi = env.new_ssa();
+
+        //     entry
+        //     /   \
+        //  left  right
+        //    |     |
+        //    |    mid
+        //     \   /
+        //     join
+
+        let mut cfg = FunctionCfg::new();
+        let (right, join) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(arg)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let mid = b.add_block();
+            let join = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+
+            b.set_block(left);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(left, join);
+
+            b.set_block(right);
+            b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![right_val]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(right, mid);
+
+            b.add_phi(right, right_val, mid, mid_phi);
+            b.set_block(mid);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(mid_phi)],
+                       vec![mid_val]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(mid, join);
+
+            b.add_phi(left, arg, join, join_phi);
+            b.add_phi(mid, mid_val, join, join_phi);
+            b.set_block(join);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(join_phi)], vec![]);
+
+            (right, join)
+        };
+
+        simplify_cfg(&mut cfg);
+        assert!(validate(&cfg).is_empty(), "{:?}", validate(&cfg));
+
+        // left is threaded, mid is merged into right
+        assert!(cfg.cfg.node_count() == 3);
+        let right_ops = &cfg.block(right).ops;
+        assert!(right_ops.len() == 3);
+        match right_ops[1].reads[0] {
+            Source::Variable(var) => assert!(var == right_val),
+            _ => panic!(),
+        }
+
+        let phi = &cfg.block(join).phi_nodes[0];
+        assert!(phi.entries.len() == 2);
+        assert!(phi.entries.iter().any(|&(l, v)| l == cfg.entry() && v == arg));
+        assert!(phi.entries.iter().any(|&(l, v)| l == right && v == mid_val));
+    }
+
+}
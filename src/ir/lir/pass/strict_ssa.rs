@@ -0,0 +1,354 @@
+//! Converts the permissive SSA produced when lowering from HIR into
+//! strict SSA, where every variable is assigned exactly once.
+//!
+//! Phi nodes are placed at the iterated dominance frontiers of the blocks
+//! assigning a variable, then every assignment is given a fresh variable
+//! while walking the dominator tree, rewriting the reads it reaches. This
+//! is the construction from "Efficiently Computing Static Single
+//! Assignment Form and the Control Dependence Graph" by Cytron et al.
+//!
+//! Variables that are only assigned once are left untouched.
+
+use ::std::collections::{ HashMap, HashSet };
+
+use ::petgraph::Direction;
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::algo::dominators::simple_fast;
+
+use ::ir::SSAVariable;
+use ::ir::hir::pass::ssa::ScopeTracker;
+use ::ir::lir::{ FunctionCfg, LabelN, Phi };
+use ::util::dominance_frontiers::dominance_frontiers;
+use ::util::ssa_variable::INVALID_SSA;
+
+pub fn strict_ssa(cfg: &mut FunctionCfg, env: &mut ScopeTracker) {
+    let entry = cfg.entry.0;
+    let dominators = simple_fast(&cfg.cfg, entry);
+    let reachable: Vec<NodeIndex> = cfg.cfg.node_indices()
+        .filter(|n| dominators.dominators(*n).is_some())
+        .collect();
+
+    // Find the variables that are assigned more than once, in order of
+    // first assignment to keep the output deterministic.
+    let mut multi_assigned = Vec::new();
+    let mut num_assigns: HashMap<SSAVariable, usize> = HashMap::new();
+    let mut assign_blocks: HashMap<SSAVariable, Vec<NodeIndex>> = HashMap::new();
+    for &node in reachable.iter() {
+        let block = &cfg.cfg[node];
+        let writes = block.phi_nodes.iter().map(|p| p.ssa)
+            .chain(block.ops.iter().flat_map(|op| op.writes.iter().cloned()));
+        for write in writes {
+            let num = num_assigns.entry(write).or_insert(0);
+            *num += 1;
+            if *num == 2 {
+                multi_assigned.push(write);
+            }
+            let blocks = assign_blocks.entry(write).or_insert(vec![]);
+            if !blocks.contains(&node) {
+                blocks.push(node);
+            }
+        }
+    }
+    if multi_assigned.is_empty() {
+        return;
+    }
+
+    // Place phis at the iterated dominance frontiers
+    let frontiers = dominance_frontiers(&cfg.cfg, entry);
+    for var in multi_assigned.iter() {
+        let mut has_phi: HashSet<NodeIndex> = reachable.iter()
+            .filter(|n| cfg.cfg[**n].phi_nodes.iter().any(|p| p.ssa == *var))
+            .cloned()
+            .collect();
+
+        let mut work = assign_blocks[var].clone();
+        while let Some(node) = work.pop() {
+            for &frontier in frontiers[&node].iter() {
+                if !has_phi.insert(frontier) {
+                    continue;
+                }
+
+                let mut preds: Vec<NodeIndex> = cfg.cfg
+                    .neighbors_directed(frontier, Direction::Incoming)
+                    .filter(|p| dominators.dominators(*p).is_some())
+                    .collect();
+                preds.sort();
+                preds.dedup();
+
+                cfg.cfg[frontier].phi_nodes.push(Phi {
+                    entries: preds.iter().map(|p| (LabelN(*p), *var)).collect(),
+                    ssa: *var,
+                });
+                work.push(frontier);
+            }
+        }
+    }
+
+    // Rename along the dominator tree
+    let mut children: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+    for &node in reachable.iter() {
+        if let Some(idom) = dominators.immediate_dominator(node) {
+            children.entry(idom).or_insert(vec![]).push(node);
+        }
+    }
+    let mut stacks: HashMap<SSAVariable, Vec<SSAVariable>> = multi_assigned.iter()
+        .map(|var| (*var, vec![]))
+        .collect();
+    rename_block(cfg, env, entry, &children, &mut stacks);
+
+    // A phi placed where the variable is not assigned along every incoming
+    // path, directly or through another such phi, is undefined on some
+    // path.
+    let mut undefined = HashSet::new();
+    undefined.insert(INVALID_SSA);
+    loop {
+        let mut changed = false;
+        for &node in reachable.iter() {
+            for phi in cfg.cfg[node].phi_nodes.iter() {
+                if !undefined.contains(&phi.ssa)
+                    && phi.entries.iter().any(|&(_, ssa)| undefined.contains(&ssa)) {
+                    undefined.insert(phi.ssa);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Such a phi can not be read by a valid program. Remove those that
+    // are not read by an OP, directly or through other phis. Those that
+    // are read are kept for the validator to report.
+    let mut live: HashSet<SSAVariable> = reachable.iter()
+        .flat_map(|node| cfg.cfg[*node].ops.iter())
+        .flat_map(|op| op.read_vars())
+        .filter(|var| undefined.contains(var))
+        .collect();
+    loop {
+        let mut changed = false;
+        for &node in reachable.iter() {
+            for phi in cfg.cfg[node].phi_nodes.iter() {
+                if !live.contains(&phi.ssa) {
+                    continue;
+                }
+                for &(_, ssa) in phi.entries.iter() {
+                    if undefined.contains(&ssa) && live.insert(ssa) {
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for &node in reachable.iter() {
+        cfg.cfg[node].phi_nodes
+            .retain(|phi| !undefined.contains(&phi.ssa) || live.contains(&phi.ssa));
+    }
+}
+
+fn rename_read(stacks: &HashMap<SSAVariable, Vec<SSAVariable>>,
+               var: &mut SSAVariable) {
+    // A read that is not dominated by an assignment is left alone, and
+    // reported by the validator.
+    if let Some(&top) = stacks.get(var).and_then(|s| s.last()) {
+        *var = top;
+    }
+}
+
+fn rename_write(stacks: &mut HashMap<SSAVariable, Vec<SSAVariable>>,
+                env: &mut ScopeTracker, pushed: &mut Vec<SSAVariable>,
+                var: &mut SSAVariable) {
+    if let Some(stack) = stacks.get_mut(var) {
+        let new = env.new_ssa();
+        stack.push(new);
+        pushed.push(*var);
+        *var = new;
+    }
+}
+
+fn rename_block(cfg: &mut FunctionCfg, env: &mut ScopeTracker, node: NodeIndex,
+                children: &HashMap<NodeIndex, Vec<NodeIndex>>,
+                stacks: &mut HashMap<SSAVariable, Vec<SSAVariable>>) {
+    let mut pushed = Vec::new();
+
+    {
+        let block = &mut cfg.cfg[node];
+        for phi in block.phi_nodes.iter_mut() {
+            rename_write(stacks, env, &mut pushed, &mut phi.ssa);
+        }
+        for op in block.ops.iter_mut() {
+            op.map_read_vars(|var| rename_read(stacks, var));
+            for write in op.writes.iter_mut() {
+                rename_write(stacks, env, &mut pushed, write);
+            }
+        }
+    }
+
+    // Fill in the phi entries for the edges leaving this block. Variables
+    // with no assignment reaching the edge are marked as invalid.
+    let succs: Vec<NodeIndex> = cfg.cfg
+        .neighbors_directed(node, Direction::Outgoing)
+        .collect();
+    for succ in succs {
+        for phi in cfg.cfg[succ].phi_nodes.iter_mut() {
+            for entry in phi.entries.iter_mut() {
+                if (entry.0).0 != node {
+                    continue;
+                }
+                if let Some(stack) = stacks.get(&entry.1) {
+                    entry.1 = *stack.last().unwrap_or(&INVALID_SSA);
+                }
+            }
+        }
+    }
+
+    if let Some(children_nodes) = children.get(&node) {
+        for child in children_nodes.iter() {
+            rename_block(cfg, env, *child, children, stacks);
+        }
+    }
+
+    for var in pushed {
+        stacks.get_mut(&var).unwrap().pop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, LabelN, OpKind, Source };
+    use ::ir::lir::pass::ViolationKind;
+    use ::util::ssa_variable::INVALID_SSA;
+    use super::strict_ssa;
+
+    #[test]
+    fn diamond_gets_phi() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let x = env.new_ssa();
+
+        //     entry
+        //     /   \
+        //  x = a  x = a
+        //     \   /
+        //    return x
+
+        let mut cfg = FunctionCfg::new();
+        let join = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(arg)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let join = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+
+            for &branch in [left, right].iter() {
+                b.set_block(branch);
+                b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![x]);
+                b.basic_op(OpKind::Jump, vec![], vec![]);
+                b.add_jump(branch, join);
+            }
+
+            b.set_block(join);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(x)], vec![]);
+            join
+        };
+
+        strict_ssa(&mut cfg, &mut env);
+        assert!(::ir::lir::pass::validate(&cfg).is_empty());
+
+        let block = cfg.block(join);
+        assert!(block.phi_nodes.len() == 1);
+        let phi = &block.phi_nodes[0];
+        assert!(phi.entries.len() == 2);
+        assert!(phi.entries[0].1 != phi.entries[1].1);
+        assert!(phi.entries.iter().all(|&(_, ssa)| ssa != x));
+        match block.ops[0].reads[0] {
+            Source::Variable(var) => assert!(var == phi.ssa),
+            _ => panic!(),
+        }
+    }
+
+    /// Builds a function where x is assigned twice on the left path only,
+    /// then assigned again after the join if `reassign`, and returned.
+    fn one_sided(env: &mut ScopeTracker, reassign: bool) -> (FunctionCfg, LabelN) {
+        let arg = env.new_ssa();
+        let x = env.new_ssa();
+
+        //     entry
+        //     /   \
+        //  x = a   |
+        //  x = a   |
+        //     \   /
+        //  [x = a]
+        //  return x
+
+        let mut cfg = FunctionCfg::new();
+        let join = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(arg)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let join = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+
+            b.set_block(left);
+            b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![x]);
+            b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![x]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(left, join);
+
+            b.set_block(right);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.add_jump(right, join);
+
+            b.set_block(join);
+            if reassign {
+                b.basic_op(OpKind::Move, vec![Source::Variable(arg)], vec![x]);
+            }
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(x)], vec![]);
+            join
+        };
+        (cfg, join)
+    }
+
+    #[test]
+    fn dead_undefined_phi_removed() {
+        let mut env = ScopeTracker::new();
+        let (mut cfg, join) = one_sided(&mut env, true);
+        strict_ssa(&mut cfg, &mut env);
+        assert!(cfg.block(join).phi_nodes.is_empty());
+        assert!(::ir::lir::pass::validate(&cfg).is_empty());
+    }
+
+    #[test]
+    fn read_undefined_phi_reported() {
+        let mut env = ScopeTracker::new();
+        let (mut cfg, join) = one_sided(&mut env, false);
+        strict_ssa(&mut cfg, &mut env);
+
+        // The phi is read, removing it would leave the read without an
+        // assignment
+        let block = cfg.block(join);
+        assert!(block.phi_nodes.len() == 1);
+        match block.ops[0].reads[0] {
+            Source::Variable(var) => assert!(var == block.phi_nodes[0].ssa),
+            _ => panic!(),
+        }
+        let violations = ::ir::lir::pass::validate(&cfg);
+        assert!(violations.len() == 1, "{:?}", violations);
+        assert!(violations[0].kind == ViolationKind::UnassignedUse(INVALID_SSA));
+    }
+
+}
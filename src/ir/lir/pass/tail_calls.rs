@@ -0,0 +1,151 @@
+//! Rewrites calls in tail position to `TailCall` and `TailApply`.
+//!
+//! A call is in tail position when its result is returned unchanged: the
+//! resume edge leads, through blocks only passing the value along with
+//! `Move`s, phis and `Jump`s, to a `ReturnOk` of the value. The throw edge
+//! must lead to a block that returns the exception, calls inside a try are
+//! never tail calls.
+//!
+//! The rewritten call ends its block without any outgoing edges, blocks
+//! only reachable through it are left for `eliminate_dead_code`.
+
+use ::std::collections::HashSet;
+
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, OpKind, Source };
+
+pub fn tail_calls(cfg: &mut FunctionCfg) {
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        let tail_kind = match cfg.block(label).ops.last().map(|op| &op.kind) {
+            Some(&OpKind::Call) => OpKind::TailCall,
+            Some(&OpKind::Apply) => OpKind::TailApply,
+            _ => continue,
+        };
+        if !is_tail_call(cfg, label) {
+            continue;
+        }
+
+        let edges: Vec<_> = cfg.jumps_iter(label).collect();
+        for edge in edges {
+            cfg.remove_edge(edge);
+        }
+        let call = cfg.block_mut(label).ops.last_mut().unwrap();
+        call.kind = tail_kind;
+        call.writes.clear();
+    }
+}
+
+fn only_rethrows(cfg: &FunctionCfg, label: LabelN) -> bool {
+    let mut ops = cfg.block(label).ops.iter().filter(|op| !matches!(op.kind, OpKind::TombstoneSSA(_)));
+    matches!((ops.next().map(|op| &op.kind), ops.next()), (Some(&OpKind::ReturnThrow), None))
+}
+
+fn is_tail_call(cfg: &FunctionCfg, label: LabelN) -> bool {
+    let block = cfg.block(label);
+    if block.outgoing_edges.len() != 2 {
+        return false;
+    }
+    if !only_rethrows(cfg, cfg.edge_target(block.outgoing_edges[1])) {
+        return false;
+    }
+
+    // Variables holding the result of the call
+    let mut carriers: HashSet<SSAVariable> = HashSet::new();
+    carriers.insert(block.ops.last().unwrap().writes[0]);
+
+    let mut visited = HashSet::new();
+    let mut from = label;
+    let mut current = cfg.edge_target(block.outgoing_edges[0]);
+    loop {
+        if !visited.insert(current) {
+            return false;
+        }
+
+        let block = cfg.block(current);
+        for phi in block.phi_nodes.iter() {
+            let carries = phi.entries.iter()
+                .any(|&(pred, ssa)| pred == from && carriers.contains(&ssa));
+            if carries {
+                carriers.insert(phi.ssa);
+            }
+        }
+
+        let mut next = None;
+        for op in block.ops.iter() {
+            let carried = |source: &Source| match *source {
+                Source::Variable(ref var) => carriers.contains(var),
+                _ => false,
+            };
+            match op.kind {
+                OpKind::TombstoneSSA(_) => (),
+                OpKind::Move if carried(&op.reads[0]) => {
+                    carriers.insert(op.writes[0]);
+                },
+                OpKind::Jump => {
+                    next = Some(cfg.edge_target(block.outgoing_edges[0]));
+                },
+                OpKind::ReturnOk => return carried(&op.reads[0]),
+                _ => return false,
+            }
+        }
+
+        match next {
+            Some(next) => {
+                from = current;
+                current = next;
+            },
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::lir::OpKind;
+    use ::ir::lir::pass::validate;
+
+    #[test]
+    fn tail_position_calls() {
+        let core = "module 'test' ['loop'/1, 'wrap'/1, 'safe'/1] attributes []
+'loop'/1 =
+    fun (A) ->
+        case A of
+          <'done'> when 'true' -> 'done'
+          <_Other> when 'true' -> call 'test':'loop'('done')
+        end
+'wrap'/1 =
+    fun (A) ->
+        let <R> = call 'test':'loop'(A)
+        in {R}
+'safe'/1 =
+    fun (A) ->
+        try call 'test':'loop'(A)
+        of <R> -> R
+        catch <_C, _R, _T> -> 'failed'
+end
+";
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let module = ::ir::from_parsed(&parsed.0);
+
+        for fun in module.functions.iter() {
+            let cfg = fun.lir_function.as_ref().unwrap();
+            assert!(validate(cfg).is_empty(), "{:?}", validate(cfg));
+
+            let kinds: Vec<_> = cfg.blocks_iter()
+                .flat_map(|b| b.ops.iter())
+                .filter_map(|op| match op.kind {
+                    OpKind::Call => Some("Call"),
+                    OpKind::TailCall => Some("TailCall"),
+                    _ => None,
+                })
+                .collect();
+            let expected = match &*fun.ident.name.to_string() {
+                "loop" => vec!["TailCall"],
+                _ => vec!["Call"],
+            };
+            assert!(kinds == expected, "{}: {:?}", fun.ident, kinds);
+        }
+    }
+
+}
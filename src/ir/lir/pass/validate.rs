@@ -1,52 +1,661 @@
-use ::std::collections::HashSet;
-use ::ir::lir::{ FunctionCfg, Source };
+//! Verifies the invariants of a LIR function.
+//!
+//! Expects the CFG to be in strict SSA form, see `strict_ssa`. Checks that
+//! every variable is assigned once and that every read is dominated by the
+//! assignment, that phi nodes have exactly one entry for every predecessor,
+//! that blocks are properly terminated, and the structural rules for Case
+//! and Receive documented on `OpKind`.
+//!
+//! Blocks that are not reachable from the entry are only checked for local
+//! properties.
 
-pub fn validate(cfg: &FunctionCfg) {
+use ::std::collections::{ HashMap, HashSet };
+use ::std::collections::hash_map::Entry;
+use ::std::fmt;
 
-    validate_proper_ssa(cfg);
+use ::petgraph::Direction;
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::algo::dominators::{ simple_fast, Dominators };
 
+use ::ir::SSAVariable;
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind, Source };
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Location {
+    Block(LabelN),
+    Phi(LabelN, usize),
+    Op(LabelN, usize),
+}
+impl Location {
+    pub fn block(&self) -> LabelN {
+        match *self {
+            Location::Block(block) => block,
+            Location::Phi(block, _) => block,
+            Location::Op(block, _) => block,
+        }
+    }
 }
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Block(block) => write!(f, "{}", block),
+            Location::Phi(block, idx) => write!(f, "{} phi {}", block, idx),
+            Location::Op(block, idx) => write!(f, "{} op {}", block, idx),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The variable is assigned more than once in the function.
+    DoubleAssign(SSAVariable),
+    /// The variable is never assigned in the function.
+    UnassignedUse(SSAVariable),
+    /// The assignment of the variable does not dominate the read.
+    UseNotDominated(SSAVariable),
+    /// The variable is read after being tombstoned.
+    UseAfterTombstone(SSAVariable),
+    /// The phi has an entry for a block that is not a predecessor.
+    PhiNotPredecessor(LabelN),
+    /// The phi has no entry for the predecessor.
+    PhiMissingPredecessor(LabelN),
+    /// The phi has more than one entry for the predecessor.
+    PhiDuplicatePredecessor(LabelN),
+    /// The block does not end with an OP that has jumps.
+    MissingTerminator,
+    /// An OP with jumps is not the last in its block.
+    TerminatorNotLast,
+    /// The number of outgoing edges does not match the terminator.
+    EdgeCount { expected: usize, actual: usize },
+    /// Arguments is not the first OP of the entry block.
+    MisplacedArguments,
+    /// ReceiveWait is not the only OP in its block.
+    ReceiveWaitNotAlone,
+    /// Edge 0 of ReceiveStart does not lead to a ReceiveWait.
+    ReceiveStartTarget,
+    /// Edge 0 from ReceiveWait does not lead to a ReceiveGetMessage.
+    ReceiveWaitTarget,
+    /// Edge from a Case to a clause does not lead to a CaseValues.
+    CaseEdgeTarget { edge: usize },
+    /// CaseValues is not the first OP in its block.
+    CaseValuesNotFirst,
+    /// CatchValues is not the first OP in its block.
+    CatchValuesNotFirst,
+    /// CaseValues does not have a single predecessor ending with a Case.
+    CaseValuesPredecessor,
+    /// CaseValues does not write the bindings of its clause.
+    CaseValuesWrites { expected: usize, actual: usize },
+    /// CaseGuardFail does not jump back to the Case it came from.
+    CaseGuardFailTarget,
+    /// The function can return before leaving the case structure through
+    /// a CaseGuardOk or CaseGuardFail.
+    ReturnInsideCase,
+    /// The function can return before leaving the receive structure
+    /// through a ReceiveFinish.
+    ReturnInsideReceive,
+}
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ViolationKind::DoubleAssign(var) =>
+                write!(f, "double assign of {:?}", var),
+            ViolationKind::UnassignedUse(var) =>
+                write!(f, "use of unassigned {:?}", var),
+            ViolationKind::UseNotDominated(var) =>
+                write!(f, "use of {:?} not dominated by its assignment", var),
+            ViolationKind::UseAfterTombstone(var) =>
+                write!(f, "use of {:?} after tombstone", var),
+            ViolationKind::PhiNotPredecessor(label) =>
+                write!(f, "phi entry for {} which is not a predecessor", label),
+            ViolationKind::PhiMissingPredecessor(label) =>
+                write!(f, "phi has no entry for predecessor {}", label),
+            ViolationKind::PhiDuplicatePredecessor(label) =>
+                write!(f, "phi has multiple entries for predecessor {}", label),
+            ViolationKind::MissingTerminator =>
+                write!(f, "block has no terminator"),
+            ViolationKind::TerminatorNotLast =>
+                write!(f, "terminator is not the last op in the block"),
+            ViolationKind::EdgeCount { expected, actual } =>
+                write!(f, "expected {} outgoing edges, got {}", expected, actual),
+            ViolationKind::MisplacedArguments =>
+                write!(f, "Arguments must be the first op of the function"),
+            ViolationKind::ReceiveWaitNotAlone =>
+                write!(f, "ReceiveWait must be alone in its block"),
+            ViolationKind::ReceiveStartTarget =>
+                write!(f, "ReceiveStart must jump to a ReceiveWait"),
+            ViolationKind::ReceiveWaitTarget =>
+                write!(f, "edge 0 of ReceiveWait must lead to a ReceiveGetMessage"),
+            ViolationKind::CaseEdgeTarget { edge } =>
+                write!(f, "edge {} of Case must lead to a CaseValues", edge),
+            ViolationKind::CaseValuesNotFirst =>
+                write!(f, "CaseValues must be the first op in its block"),
+            ViolationKind::CatchValuesNotFirst =>
+                write!(f, "CatchValues must be the first op in its block"),
+            ViolationKind::CaseValuesPredecessor =>
+                write!(f, "CaseValues must have a single predecessor ending with a Case"),
+            ViolationKind::CaseValuesWrites { expected, actual } =>
+                write!(f, "CaseValues binds {} values, clause has {}", actual, expected),
+            ViolationKind::CaseGuardFailTarget =>
+                write!(f, "CaseGuardFail must jump back to its Case"),
+            ViolationKind::ReturnInsideCase =>
+                write!(f, "return inside case structure"),
+            ViolationKind::ReturnInsideReceive =>
+                write!(f, "return inside receive structure"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub location: Location,
+    pub kind: ViolationKind,
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+/// Checks all invariants of the function, returning every violation
+/// found. An empty result means the function is valid.
+pub fn validate(cfg: &FunctionCfg) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let dominators = simple_fast(&cfg.cfg, cfg.entry.0);
+
+    validate_ssa(cfg, &dominators, &mut violations);
+    validate_phis(cfg, &mut violations);
+    validate_terminators(cfg, &mut violations);
+    validate_case(cfg, &mut violations);
+    validate_receive(cfg, &mut violations);
+
+    violations
+}
+
+fn violation(violations: &mut Vec<Violation>, location: Location,
+             kind: ViolationKind) {
+    violations.push(Violation {
+        location,
+        kind,
+    });
+}
+
+/// Position of an assignment or read within a block. Phis are all
+/// evaluated before the first OP.
+fn position(location: Location) -> Option<usize> {
+    match location {
+        Location::Block(_) | Location::Phi(_, _) => None,
+        Location::Op(_, idx) => Some(idx),
+    }
+}
+
+/// Returns true if `a` strictly dominates `b` at OP granularity.
+fn dominates(dominators: &Dominators<NodeIndex>, a: Location, b: Location) -> bool {
+    let a_block = a.block().0;
+    let b_block = b.block().0;
+    if a_block == b_block {
+        position(a) < position(b)
+    } else {
+        dominators.strict_dominators(b_block)
+            .map(|mut doms| doms.any(|d| d == a_block))
+            .unwrap_or(false)
+    }
+}
+
+fn validate_ssa(cfg: &FunctionCfg, dominators: &Dominators<NodeIndex>,
+                violations: &mut Vec<Violation>) {
+    let mut assigns: HashMap<SSAVariable, Location> = HashMap::new();
+    let mut tombstones: Vec<(SSAVariable, Location)> = Vec::new();
 
-fn validate_proper_ssa(cfg: &FunctionCfg) {
+    for label in cfg.labels_iter() {
+        let block = cfg.block(label);
+        let phi_writes = block.phi_nodes.iter().enumerate()
+            .map(|(idx, phi)| (phi.ssa, Location::Phi(label, idx)));
+        let op_writes = block.ops.iter().enumerate()
+            .flat_map(|(idx, op)| {
+                op.writes.iter().map(move |w| (*w, Location::Op(label, idx)))
+            });
+        for (var, location) in phi_writes.chain(op_writes) {
+            match assigns.entry(var) {
+                Entry::Occupied(_) => violation(
+                    violations, location, ViolationKind::DoubleAssign(var)),
+                Entry::Vacant(entry) => { entry.insert(location); },
+            }
+        }
 
-    let mut assigns = HashSet::new();
+        for (idx, op) in block.ops.iter().enumerate() {
+            if let OpKind::TombstoneSSA(var) = op.kind {
+                tombstones.push((var, Location::Op(label, idx)));
+            }
+        }
+    }
 
-    for block in cfg.blocks_iter() {
-        for phi in block.phi_nodes.iter() {
-            if assigns.contains(&phi.ssa) {
-                println!("Double assign of {:?}", phi.ssa);
+    let check_read = |violations: &mut Vec<Violation>, var: SSAVariable,
+                          read_at: Location, report_at: Location| {
+        // Only reads in reachable blocks have meaningful dominance
+        if dominators.dominators(read_at.block().0).is_none() {
+            return;
+        }
+        match assigns.get(&var) {
+            None => violation(violations, report_at,
+                              ViolationKind::UnassignedUse(var)),
+            Some(&assign) => {
+                if !dominates(dominators, assign, read_at) {
+                    violation(violations, report_at,
+                              ViolationKind::UseNotDominated(var));
+                }
+            },
+        }
+        // Phi entries are read on the edge, after any tombstone in the
+        // predecessor. The tombstone itself is never after itself.
+        if report_at != read_at {
+            return;
+        }
+        for &(tomb_var, tomb_at) in tombstones.iter() {
+            if tomb_var == var && dominates(dominators, tomb_at, read_at) {
+                violation(violations, report_at,
+                          ViolationKind::UseAfterTombstone(var));
             }
-            assigns.insert(phi.ssa);
         }
+    };
 
-        for op in block.ops.iter() {
-            for write in op.writes.iter() {
-                if assigns.contains(write) {
-                    println!("Double assign of {:?}", write);
+    for label in cfg.labels_iter() {
+        let block = cfg.block(label);
+        for (idx, phi) in block.phi_nodes.iter().enumerate() {
+            for &(pred, var) in phi.entries.iter() {
+                // The value is read at the end of the predecessor
+                let end = Location::Op(pred, cfg.block(pred).ops.len());
+                check_read(violations, var, end, Location::Phi(label, idx));
+            }
+        }
+        for (idx, op) in block.ops.iter().enumerate() {
+            for var in op.read_vars() {
+                let location = Location::Op(label, idx);
+                check_read(violations, var, location, location);
+            }
+        }
+    }
+}
+
+fn validate_phis(cfg: &FunctionCfg, violations: &mut Vec<Violation>) {
+    for label in cfg.labels_iter() {
+        let preds: HashSet<NodeIndex> = cfg.cfg
+            .neighbors_directed(label.0, Direction::Incoming)
+            .collect();
+
+        let block = cfg.block(label);
+        for (idx, phi) in block.phi_nodes.iter().enumerate() {
+            let location = Location::Phi(label, idx);
+            let mut seen = HashSet::new();
+            for &(pred, _) in phi.entries.iter() {
+                if !preds.contains(&pred.0) {
+                    violation(violations, location,
+                              ViolationKind::PhiNotPredecessor(pred));
+                } else if !seen.insert(pred.0) {
+                    violation(violations, location,
+                              ViolationKind::PhiDuplicatePredecessor(pred));
                 }
-                assigns.insert(*write);
+            }
+            let mut missing: Vec<_> = preds.difference(&seen).cloned().collect();
+            missing.sort();
+            for pred in missing {
+                violation(violations, location,
+                          ViolationKind::PhiMissingPredecessor(LabelN(pred)));
             }
         }
     }
+}
 
-    for block in cfg.blocks_iter() {
-        for phi in block.phi_nodes.iter() {
-            for &(_label, ssa) in phi.entries.iter() {
-                if !assigns.contains(&ssa) {
-                    println!("Use of unassigned {:?}", ssa);
+fn validate_terminators(cfg: &FunctionCfg, violations: &mut Vec<Violation>) {
+    for label in cfg.labels_iter() {
+        let block = cfg.block(label);
+
+        for (idx, op) in block.ops.iter().enumerate() {
+            let location = Location::Op(label, idx);
+            let is_last = idx + 1 == block.ops.len();
+            if !is_last && op.kind.num_jumps().is_some() {
+                violation(violations, location, ViolationKind::TerminatorNotLast);
+            }
+            if let OpKind::Arguments = op.kind {
+                if label != cfg.entry || idx != 0 {
+                    violation(violations, location,
+                              ViolationKind::MisplacedArguments);
+                }
+            }
+            if let OpKind::CatchValues = op.kind {
+                if idx != 0 {
+                    violation(violations, location,
+                              ViolationKind::CatchValuesNotFirst);
                 }
             }
         }
 
-        for op in block.ops.iter() {
-            for read in op.reads.iter() {
-                if let Source::Variable(ref ssa) = *read {
-                    if !assigns.contains(&ssa) {
-                        println!("Use of unassigned {:?}", ssa);
+        match block.ops.last().and_then(|op| op.kind.num_jumps()) {
+            None => violation(violations, Location::Block(label),
+                              ViolationKind::MissingTerminator),
+            Some(expected) => {
+                let actual = block.outgoing_edges.len();
+                if expected != actual {
+                    violation(violations,
+                              Location::Op(label, block.ops.len() - 1),
+                              ViolationKind::EdgeCount {
+                                  expected,
+                                  actual,
+                              });
+                }
+            },
+        }
+    }
+}
+
+fn first_op(cfg: &FunctionCfg, label: LabelN) -> Option<&OpKind> {
+    cfg.block(label).ops.first().map(|op| &op.kind)
+}
+
+fn successors(cfg: &FunctionCfg, label: LabelN) -> Vec<LabelN> {
+    cfg.jumps_iter(label).map(|edge| cfg.edge_target(edge)).collect()
+}
+
+/// Searches forward from the given OP for a path that returns from the
+/// function without passing through an OP satisfying `exits`. Paths that
+/// reach `stop_block` are not followed further.
+fn can_return_before<F>(cfg: &FunctionCfg, label: LabelN, start_op: usize,
+                        stop_block: Option<LabelN>, exits: F) -> bool
+    where F: Fn(&Op) -> bool
+{
+    let mut visited = HashSet::new();
+    let mut stack = vec![(label, start_op)];
+    while let Some((label, start_op)) = stack.pop() {
+        let block = cfg.block(label);
+        let mut exited = false;
+        for op in block.ops.iter().skip(start_op) {
+            if exits(op) {
+                exited = true;
+                break;
+            }
+            match op.kind {
+                OpKind::ReturnOk | OpKind::ReturnThrow => return true,
+                OpKind::TailCall | OpKind::TailApply => return true,
+                _ => (),
+            }
+        }
+        if exited {
+            continue;
+        }
+        for succ in successors(cfg, label) {
+            if Some(succ) != stop_block && visited.insert(succ) {
+                stack.push((succ, 0));
+            }
+        }
+    }
+    false
+}
+
+fn reads_var(op: &Op, var: SSAVariable) -> bool {
+    op.read_vars().contains(&var)
+}
+
+fn validate_case(cfg: &FunctionCfg, violations: &mut Vec<Violation>) {
+    for label in cfg.labels_iter() {
+        let block = cfg.block(label);
+
+        if let Some(op) = block.ops.last() {
+            if let OpKind::Case { .. } = op.kind {
+                let location = Location::Op(label, block.ops.len() - 1);
+                for (edge, target) in successors(cfg, label).iter().enumerate().skip(1) {
+                    match first_op(cfg, *target) {
+                        Some(&OpKind::CaseValues) => (),
+                        _ => violation(violations, location,
+                                       ViolationKind::CaseEdgeTarget { edge }),
                     }
                 }
             }
         }
+
+        for (idx, op) in block.ops.iter().enumerate() {
+            let location = Location::Op(label, idx);
+            match op.kind {
+                OpKind::CaseValues => {
+                    if idx != 0 {
+                        violation(violations, location,
+                                  ViolationKind::CaseValuesNotFirst);
+                    }
+                    validate_case_values(cfg, label, op, location, violations);
+
+                    if let Some(&Source::Variable(structure)) = op.reads.first() {
+                        let exits = |op: &Op| match op.kind {
+                            OpKind::CaseGuardOk | OpKind::CaseGuardFail =>
+                                reads_var(op, structure),
+                            _ => false,
+                        };
+                        if can_return_before(cfg, label, idx + 1, None, exits) {
+                            violation(violations, location,
+                                      ViolationKind::ReturnInsideCase);
+                        }
+                    }
+                },
+                OpKind::CaseGuardFail => {
+                    let targets_case = match op.reads.first() {
+                        Some(&Source::Variable(structure)) => {
+                            let succs = successors(cfg, label);
+                            succs.len() == 1 && cfg.block(succs[0]).ops.last()
+                                .map(|last| match last.kind {
+                                    OpKind::Case { .. } =>
+                                        last.writes.contains(&structure),
+                                    _ => false,
+                                })
+                                .unwrap_or(false)
+                        },
+                        _ => false,
+                    };
+                    if !targets_case {
+                        violation(violations, location,
+                                  ViolationKind::CaseGuardFailTarget);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+fn validate_case_values(cfg: &FunctionCfg, label: LabelN, op: &Op,
+                        location: Location, violations: &mut Vec<Violation>) {
+    let preds: Vec<NodeIndex> = cfg.cfg
+        .neighbors_directed(label.0, Direction::Incoming)
+        .collect();
+    if preds.len() != 1 {
+        violation(violations, location, ViolationKind::CaseValuesPredecessor);
+        return;
+    }
+
+    let pred = LabelN(preds[0]);
+    let case_op = match cfg.block(pred).ops.last() {
+        Some(case_op) => case_op,
+        None => {
+            violation(violations, location, ViolationKind::CaseValuesPredecessor);
+            return;
+        },
+    };
+    let clauses = match case_op.kind {
+        OpKind::Case { ref clauses, .. } => clauses,
+        _ => {
+            violation(violations, location, ViolationKind::CaseValuesPredecessor);
+            return;
+        },
+    };
+    let reads_structure = match op.reads.first() {
+        Some(&Source::Variable(var)) => case_op.writes.contains(&var),
+        _ => false,
+    };
+    if !reads_structure {
+        violation(violations, location, ViolationKind::CaseValuesPredecessor);
+    }
+
+    // Edge n + 1 leads to clause n
+    let edge = successors(cfg, pred).iter().position(|s| *s == label);
+    if let Some(clause) = edge.and_then(|e| e.checked_sub(1)).and_then(|c| clauses.get(c)) {
+        let expected: usize = clause.patterns.iter().map(|p| p.binds.len()).sum();
+        if expected != op.writes.len() {
+            violation(violations, location, ViolationKind::CaseValuesWrites {
+                expected,
+                actual: op.writes.len(),
+            });
+        }
+    }
+}
+
+fn validate_receive(cfg: &FunctionCfg, violations: &mut Vec<Violation>) {
+    for label in cfg.labels_iter() {
+        let block = cfg.block(label);
+        for (idx, op) in block.ops.iter().enumerate() {
+            let location = Location::Op(label, idx);
+            match op.kind {
+                OpKind::ReceiveStart => {
+                    let targets_wait = successors(cfg, label).first()
+                        .map(|s| matches!(first_op(cfg, *s), Some(&OpKind::ReceiveWait)))
+                        .unwrap_or(false);
+                    if !targets_wait {
+                        violation(violations, location,
+                                  ViolationKind::ReceiveStartTarget);
+                    }
+                },
+                OpKind::ReceiveWait => {
+                    if block.ops.len() != 1 {
+                        violation(violations, location,
+                                  ViolationKind::ReceiveWaitNotAlone);
+                    }
+
+                    let message_edge = successors(cfg, label).first().cloned();
+                    let targets_message = message_edge
+                        .map(|s| matches!(first_op(cfg, s), Some(&OpKind::ReceiveGetMessage)))
+                        .unwrap_or(false);
+                    if !targets_message {
+                        violation(violations, location,
+                                  ViolationKind::ReceiveWaitTarget);
+                        continue;
+                    }
+
+                    // Control flow entering through edge 0 must get back
+                    // to the ReceiveWait, or pass through ReceiveFinish.
+                    if let Some(&Source::Variable(structure)) = op.reads.first() {
+                        let exits = |op: &Op| match op.kind {
+                            OpKind::ReceiveFinish => reads_var(op, structure),
+                            _ => false,
+                        };
+                        if can_return_before(cfg, message_edge.unwrap(), 0,
+                                             Some(label), exits) {
+                            violation(violations, location,
+                                      ViolationKind::ReturnInsideReceive);
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use super::{ validate, Violation, ViolationKind, Location };
+
+    #[test]
+    fn reports_violations() {
+        let mut env = ScopeTracker::new();
+        let a = env.new_ssa();
+        let x = env.new_ssa();
+        let y = env.new_ssa();
+        let z = env.new_ssa();
+
+        //    entry
+        //    /   \
+        //  left -> right
+        //    \   /
+        //    join
+
+        let mut cfg = FunctionCfg::new();
+        let (left, right, join) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let entry = b.get_block();
+            b.basic_op(OpKind::Arguments, vec![], vec![a]);
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(a)], vec![]);
+
+            let left = b.add_block();
+            let right = b.add_block();
+            let join = b.add_block();
+            b.add_jump(entry, left);
+            b.add_jump(entry, right);
+            b.add_jump(left, join);
+            b.add_jump(left, right);
+            b.add_jump(right, join);
+
+            b.set_block(left);
+            b.basic_op(OpKind::Move, vec![Source::Variable(a)], vec![x]);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+
+            b.set_block(right);
+            b.basic_op(OpKind::Jump, vec![], vec![]);
+            b.basic_op(OpKind::Move, vec![Source::Variable(a)], vec![z]);
+
+            b.add_phi(left, x, join, y);
+            b.set_block(join);
+            b.basic_op(OpKind::ReturnOk, vec![Source::Variable(x)], vec![]);
+
+            (left, right, join)
+        };
+
+        let violations = validate(&cfg);
+        let expected = [
+            Violation {
+                location: Location::Op(join, 0),
+                kind: ViolationKind::UseNotDominated(x),
+            },
+            Violation {
+                location: Location::Phi(join, 0),
+                kind: ViolationKind::PhiMissingPredecessor(right),
+            },
+            Violation {
+                location: Location::Op(left, 1),
+                kind: ViolationKind::EdgeCount { expected: 1, actual: 2 },
+            },
+            Violation {
+                location: Location::Op(right, 0),
+                kind: ViolationKind::TerminatorNotLast,
+            },
+            Violation {
+                location: Location::Block(right),
+                kind: ViolationKind::MissingTerminator,
+            },
+        ];
+        for violation in expected.iter() {
+            assert!(violations.contains(violation), "missing {}", violation);
+        }
+        assert!(violations.len() == expected.len(), "{:?}", violations);
+    }
+
+    #[test]
+    fn lowered_receive_is_valid() {
+        let core = "module 'test' ['recv'/1] attributes []
+'recv'/1 =
+    fun (Hoo) ->
+	receive
+	  <{'send',_cor1}>
+	      when call 'erlang':'=:='
+		    (_cor1,
+		     Hoo) ->
+	      Hoo
+	after 'infinity' ->
+	  'true'
+end
+";
+        let parsed = ::parser::annotated_module(core).unwrap();
+        let module = ::ir::from_parsed(&parsed.0);
+        for fun in module.functions.iter() {
+            let violations = validate(fun.lir_function.as_ref().unwrap());
+            assert!(violations.is_empty(), "{:?}", violations);
+        }
     }
 
 }
@@ -0,0 +1,343 @@
+//! Global value numbering, removing OPs that compute a value already
+//! computed by an identical OP in a dominating position.
+//!
+//! The dominator tree is walked with a table of the values available at
+//! each block, scoped like the renaming in `strict_ssa`. An OP is
+//! identified by its kind and reads, so only OPs that always produce the
+//! same value for the same reads are considered: term constructions,
+//! function captures, pure primops and calls to pure BIFs in the `erlang`
+//! module.
+//!
+//! Calls and primops only produce their value along the resume edge, it
+//! is made available in the resume block when that block is only reached
+//! from the call. A redundant call is replaced by a `Jump`, and its throw
+//! edge removed.
+
+use ::std::collections::HashMap;
+
+use ::petgraph::graph::NodeIndex;
+use ::petgraph::algo::dominators::simple_fast;
+
+use ::Atom;
+use ::ir::{ FunctionIdent, SSAVariable };
+use ::ir::lir::{ FunctionCfg, LabelN, Op, OpKind, Source };
+use ::parser::AtomicLiteral;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum KeyKind {
+    MakeTuple,
+    MakeList,
+    MakeMap,
+    CaptureNamedFunction(FunctionIdent),
+    CaptureExternalNamedFunction(Atom, FunctionIdent),
+    PrimOp(Atom),
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ValueKey {
+    kind: KeyKind,
+    reads: Vec<Source>,
+}
+
+/// Functions in the `erlang` module without side effects, that always
+/// return or fail the same way for the same arguments.
+fn is_pure_bif(name: &str, arity: usize) -> bool {
+    matches!((name, arity), ("+", 1) | ("-", 1) | ("+", 2) | ("-", 2) | ("*", 2) | ("/", 2)
+            | ("div", 2) | ("rem", 2) | ("abs", 1)
+            | ("band", 2) | ("bor", 2) | ("bxor", 2) | ("bnot", 1)
+            | ("bsl", 2) | ("bsr", 2) | ("==", 2) | ("/=", 2) | ("=:=", 2) | ("=/=", 2)
+            | ("<", 2) | (">", 2) | ("=<", 2) | (">=", 2) | ("not", 1) | ("and", 2) | ("or", 2) | ("xor", 2) | ("is_atom", 1) | ("is_binary", 1) | ("is_bitstring", 1)
+            | ("is_boolean", 1) | ("is_float", 1) | ("is_function", 1)
+            | ("is_function", 2) | ("is_integer", 1) | ("is_list", 1)
+            | ("is_map", 1) | ("is_number", 1) | ("is_pid", 1)
+            | ("is_port", 1) | ("is_reference", 1) | ("is_tuple", 1) | ("element", 2) | ("setelement", 3) | ("tuple_size", 1)
+            | ("hd", 1) | ("tl", 1) | ("length", 1) | ("size", 1)
+            | ("map_size", 1) | ("map_get", 2) | ("is_map_key", 2))
+}
+
+/// Primops without side effects, that always return or fail the same
+/// way for the same arguments.
+fn is_pure_primop(name: &str) -> bool {
+    matches!(name, "bs_context_to_binary" | "build_stacktrace")
+}
+
+fn value_key(op: &Op) -> Option<ValueKey> {
+    let kind = match op.kind {
+        OpKind::MakeTuple => KeyKind::MakeTuple,
+        OpKind::MakeList => KeyKind::MakeList,
+        OpKind::MakeMap => KeyKind::MakeMap,
+        OpKind::CaptureNamedFunction(ref ident) =>
+            KeyKind::CaptureNamedFunction(ident.clone()),
+        OpKind::CaptureExternalNamedFunction(ref module, ref ident) =>
+            KeyKind::CaptureExternalNamedFunction(module.clone(), ident.clone()),
+        OpKind::PrimOp(ref name) if is_pure_primop(name) =>
+            KeyKind::PrimOp(name.clone()),
+        OpKind::Call => {
+            let pure = match (&op.reads[0], &op.reads[1]) {
+                (&Source::Constant(AtomicLiteral::Atom(ref module)),
+                 &Source::Constant(AtomicLiteral::Atom(ref name))) =>
+                    &**module == "erlang" && is_pure_bif(name, op.reads.len() - 2),
+                _ => false,
+            };
+            if !pure {
+                return None;
+            }
+            KeyKind::Call
+        },
+        _ => return None,
+    };
+    if op.writes.len() != 1 {
+        return None;
+    }
+    Some(ValueKey {
+        kind,
+        reads: op.reads.clone(),
+    })
+}
+
+struct Numbering {
+    available: HashMap<ValueKey, SSAVariable>,
+    substitutions: HashMap<SSAVariable, SSAVariable>,
+    children: HashMap<NodeIndex, Vec<NodeIndex>>,
+}
+
+pub fn value_numbering(cfg: &mut FunctionCfg) {
+    let entry = cfg.entry.0;
+    let dominators = simple_fast(&cfg.cfg, entry);
+
+    let mut children: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+    for node in cfg.cfg.node_indices() {
+        if let Some(idom) = dominators.immediate_dominator(node) {
+            children.entry(idom).or_insert(vec![]).push(node);
+        }
+    }
+
+    let mut numbering = Numbering {
+        available: HashMap::new(),
+        substitutions: HashMap::new(),
+        children,
+    };
+    number_block(cfg, &mut numbering, cfg.entry);
+
+    if numbering.substitutions.is_empty() {
+        return;
+    }
+    let substitutions = numbering.substitutions;
+    let labels: Vec<_> = cfg.labels_iter().collect();
+    for label in labels {
+        let block = cfg.block_mut(label);
+        for phi in block.phi_nodes.iter_mut() {
+            for entry in phi.entries.iter_mut() {
+                if let Some(new) = substitutions.get(&entry.1) {
+                    entry.1 = *new;
+                }
+            }
+        }
+        for op in block.ops.iter_mut() {
+            op.map_read_vars(|var| if let Some(new) = substitutions.get(var) {
+                *var = *new;
+            });
+        }
+    }
+}
+
+/// The call ending the only predecessor of the block, if the block is
+/// the resume target of that call and not reached through any other edge.
+fn resumed_call(cfg: &FunctionCfg, label: LabelN) -> Option<ValueKey> {
+    let preds = cfg.predecessors(label);
+    if preds.len() != 1 {
+        return None;
+    }
+    let pred = cfg.block(preds[0]);
+    let last = pred.ops.last()?;
+    match last.kind {
+        OpKind::Call | OpKind::PrimOp(_) => (),
+        _ => return None,
+    }
+    let targets: Vec<_> = pred.outgoing_edges.iter()
+        .map(|e| cfg.edge_target(*e))
+        .collect();
+    if targets[0] != label || targets[1..].contains(&label) {
+        return None;
+    }
+    value_key(last)
+}
+
+fn number_block(cfg: &mut FunctionCfg, numbering: &mut Numbering, label: LabelN) {
+    // Keys inserted in this block, removed again when leaving the subtree
+    let mut inserted = Vec::new();
+
+    if let Some(key) = resumed_call(cfg, label) {
+        let pred = cfg.predecessors(label)[0];
+        let write = cfg.block(pred).ops.last().unwrap().writes[0];
+        if !numbering.available.contains_key(&key) {
+            numbering.available.insert(key.clone(), write);
+            inserted.push(key);
+        }
+    }
+
+    let mut redundant_call = false;
+    {
+        let substitutions = &mut numbering.substitutions;
+        let available = &mut numbering.available;
+
+        let block = cfg.block_mut(label);
+        let mut idx = 0;
+        while idx < block.ops.len() {
+            block.ops[idx].map_read_vars(|var| if let Some(new) = substitutions.get(var) {
+                *var = *new;
+            });
+
+            let key = match value_key(&block.ops[idx]) {
+                Some(key) => key,
+                None => {
+                    idx += 1;
+                    continue;
+                },
+            };
+            let write = block.ops[idx].writes[0];
+            let is_call = matches!(block.ops[idx].kind, OpKind::Call | OpKind::PrimOp(_));
+
+            if let Some(existing) = available.get(&key).cloned() {
+                substitutions.insert(write, existing);
+                if is_call {
+                    redundant_call = true;
+                    idx += 1;
+                } else {
+                    block.ops.remove(idx);
+                }
+                continue;
+            }
+
+            // A call is only available in its resume block
+            if !is_call {
+                available.insert(key.clone(), write);
+                inserted.push(key);
+            }
+            idx += 1;
+        }
+    }
+
+    if redundant_call {
+        let throw_edges: Vec<_> = {
+            let block = cfg.block_mut(label);
+            let call = block.ops.last_mut().unwrap();
+            call.kind = OpKind::Jump;
+            call.reads.clear();
+            call.writes.clear();
+            block.outgoing_edges[1..].to_vec()
+        };
+        for edge in throw_edges {
+            cfg.remove_edge(edge);
+        }
+    }
+
+    let children = numbering.children.get(&label.0).cloned().unwrap_or(vec![]);
+    for child in children {
+        number_block(cfg, numbering, LabelN(child));
+    }
+
+    for key in inserted {
+        numbering.available.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::std::str::FromStr;
+
+    use ::Atom;
+    use ::parser::AtomicLiteral;
+    use ::ir::hir::pass::ssa::ScopeTracker;
+    use ::ir::lir::{ FunctionCfg, FunctionCfgBuilder, OpKind, Source };
+    use ::ir::lir::pass::{ validate, eliminate_dead_code, simplify_cfg };
+    use super::value_numbering;
+
+    fn atom(name: &str) -> Source {
+        Source::Constant(AtomicLiteral::Atom(Atom::from_str(name).unwrap()))
+    }
+
+    #[test]
+    fn deduplicates_dominated_values() {
+        let mut env = ScopeTracker::new();
+        let arg = env.new_ssa();
+        let tuple_1 = env.new_ssa();
+        let tuple_2 = env.new_ssa();
+        let size_1 = env.new_ssa();
+        let size_2 = env.new_ssa();
+        let wrapped_1 = env.new_ssa();
+        let wrapped_2 = env.new_ssa();
+
+        //      entry: t1 = {arg}, s1 = tuple_size(t1)
+        //        |
+        //      resume: t2 = {arg}, s2 = tuple_size(t2)
+        //     /     \
+        //   left    right
+        //
+        // left and right both compute {{arg}}, neither dominates the other
+
+        let mut cfg = FunctionCfg::new();
+        let (left, right) = {
+            let mut b = FunctionCfgBuilder::new(&mut cfg);
+            let size = |b: &mut FunctionCfgBuilder, tuple, write| {
+                b.basic_op(OpKind::Call, vec![atom("erlang"), atom("tuple_size"),
+                                              Source::Variable(tuple)], vec![write]);
+                let call_block = b.get_block();
+                let throw_block = b.add_block();
+                let resume_block = b.add_block();
+                b.add_jump(call_block, resume_block);
+                b.add_jump(call_block, throw_block);
+                b.set_block(throw_block);
+                b.basic_op(OpKind::ReturnThrow, vec![], vec![]);
+                b.set_block(resume_block);
+            };
+
+            b.basic_op(OpKind::Arguments, vec![], vec![arg]);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(arg)], vec![tuple_1]);
+            size(&mut b, tuple_1, size_1);
+            b.basic_op(OpKind::MakeTuple, vec![Source::Variable(arg)], vec![tuple_2]);
+            size(&mut b, tuple_2, size_2);
+
+            b.basic_op(OpKind::IfTruthy, vec![Source::Variable(size_2)], vec![]);
+            let branch = b.get_block();
+            let left = b.add_block();
+            let right = b.add_block();
+            b.add_jump(branch, left);
+            b.add_jump(branch, right);
+
+            for &(block, wrapped) in [(left, wrapped_1), (right, wrapped_2)].iter() {
+                b.set_block(block);
+                b.basic_op(OpKind::MakeTuple, vec![Source::Variable(tuple_2)],
+                           vec![wrapped]);
+                b.basic_op(OpKind::ReturnOk, vec![Source::Variable(wrapped),
+                                                  Source::Variable(size_1)], vec![]);
+            }
+            (left, right)
+        };
+
+        value_numbering(&mut cfg);
+        eliminate_dead_code(&mut cfg);
+        simplify_cfg(&mut cfg);
+        assert!(validate(&cfg).is_empty(), "{:?}", validate(&cfg));
+
+        let num_calls = cfg.blocks_iter()
+            .flat_map(|b| b.ops.iter())
+            .filter(|op| matches!(op.kind, OpKind::Call))
+            .count();
+        assert!(num_calls == 1);
+        for block in cfg.blocks_iter() {
+            for op in block.ops.iter() {
+                assert!(!op.writes.contains(&tuple_2));
+                assert!(!op.reads.contains(&Source::Variable(size_2)));
+            }
+        }
+
+        for &(label, wrapped) in [(left, wrapped_1), (right, wrapped_2)].iter() {
+            let ops = &cfg.block(label).ops;
+            assert!(ops[0].writes == vec![wrapped]);
+            assert!(ops[0].reads == vec![Source::Variable(tuple_1)]);
+        }
+    }
+
+}
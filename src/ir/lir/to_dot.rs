@@ -46,7 +46,7 @@ pub fn function_to_dot(function: &FunctionDefinition, w: &mut Write) -> ::std::i
             }
 
             match &op.kind {
-                ::ir::lir::OpKind::Case { ref vars, ref clauses, ref value_vars } => {
+                ::ir::lir::OpKind::Case { ref vars, ref clauses, ref value_vars, .. } => {
                     write!(w, "Case \\{{{}", DOT_BREAK)?;
 
                     let vars_fmt = format_label(&format!("{:?} ", vars));
@@ -80,8 +80,8 @@ pub fn function_to_dot(function: &FunctionDefinition, w: &mut Write) -> ::std::i
                     match *read {
                         Source::Variable(reg) =>
                             write!(w, "{}", format_label(&format!("{:?}, ", reg)))?,
-                        //Source::Literal(ref lit) => write!(w, "{}, ", format_label(
-                        //    &format!("{:?}", lit)))?,
+                        Source::Literal(id) =>
+                            write!(w, "{}", format_label(&format!("{}, ", id)))?,
                         Source::Constant(ref lit) =>
                             write!(w, "{}", format_label(&format!("{:?}, ", lit)))?,
                     }
@@ -15,6 +15,11 @@ pub struct Module {
     pub name: Atom,
     pub attributes: Vec<(Atom, parser::Constant)>,
     pub functions: Vec<FunctionDefinition>,
+    /// Literal terms read by the LIR of the functions.
+    pub constants: lir::ConstantPool,
+    /// Unreachable clauses and unmatched values of the case and receive
+    /// structures, for the caller to report.
+    pub pattern_warnings: Vec<hir::pass::pattern::PatternWarning>,
 }
 
 use ::ToDoc;
@@ -25,6 +30,9 @@ impl ::ToDoc for Module {
         let attrs: Doc<BoxDoc> = Doc::newline()
             .append(Doc::text(format!("attributes: {:?}", self.attributes)))
             .nest(2);
+        let constants = Doc::concat(self.constants.iter().map(|(id, constant)| {
+            Doc::newline().append(Doc::text(format!("{} = {}", id, constant)))
+        })).nest(2);
 
         let funs = self.functions.iter().map(|fun| {
             let args = Doc::intersperse(
@@ -89,7 +97,7 @@ impl ::ToDoc for Module {
         let funs_doc = Doc::concat(funs).nest(2);
 
         Doc::concat(vec![
-            head, attrs, funs_doc
+            head, attrs, constants, funs_doc
         ])
     }
 }
@@ -187,10 +195,11 @@ pub fn from_parsed(parsed: &parser::Module) -> Module {
     let mut lambdas = lambda_collector.finish();
     module.functions.extend(lambdas.drain(0..));
 
-    // Compile patterns to decision tree
-    //for fun in module.functions.iter_mut() {
-    //    ::ir::hir::pass::pattern::pattern_to_cfg(fun);
-    //}
+    // Check patterns for unreachable clauses and unmatched values
+    for fun in module.functions.iter_mut() {
+        let warnings = ::ir::hir::pass::pattern::check_patterns(fun);
+        module.pattern_warnings.extend(warnings);
+    }
 
     // Lower to LIR
     ::ir::lir::from_hir::do_lower(&mut module, &mut env);
@@ -198,9 +207,23 @@ pub fn from_parsed(parsed: &parser::Module) -> Module {
     for function in module.functions.iter_mut() {
         let lir_mut = function.lir_function.as_mut().unwrap();
         println!("Function: {}", function.ident);
+        ::ir::lir::pass::strict_ssa(lir_mut, &mut env);
+        ::ir::lir::pass::propagate_constants(lir_mut);
+        ::ir::lir::pass::hoist_constants(lir_mut, &mut module.constants);
+        ::ir::lir::pass::value_numbering(lir_mut);
+        ::ir::lir::pass::tail_calls(lir_mut);
+        ::ir::lir::pass::eliminate_dead_code(lir_mut);
+        ::ir::lir::pass::simplify_cfg(lir_mut);
         ::ir::lir::pass::compile_pattern(lir_mut);
-        //::ir::lir::pass::propagate_atomics(lir_mut);
-        ::ir::lir::pass::validate(lir_mut);
+        // The passes keep the LIR valid, check that they did
+        if cfg!(debug_assertions) {
+            let violations = ::ir::lir::pass::validate(lir_mut);
+            let messages: Vec<_> = violations.iter()
+                .map(|violation| violation.to_string())
+                .collect();
+            assert!(violations.is_empty(), "invalid LIR for {}:\n{}",
+                    function.ident, messages.join("\n"));
+        }
     }
 
 
@@ -4,6 +4,8 @@ extern crate petgraph;
 extern crate either;
 extern crate prettytable;
 extern crate pretty;
+extern crate num_bigint;
+extern crate num_traits;
 
 extern crate pattern_compiler;
 
@@ -13,6 +13,10 @@ fn main() {
     let res = core_erlang::parser::annotated_module(&text).unwrap();
     let hir = core_erlang::ir::from_parsed(&res.0);
 
+    for warning in hir.pattern_warnings.iter() {
+        println!("warning: {}", warning);
+    }
+
     for fun in hir.functions.iter() {
         println!("{}", fun.ident);
     }
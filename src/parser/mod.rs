@@ -33,37 +33,157 @@ impl Integer {
         assert!(self.sign);
         self.digits.parse().unwrap()
     }
+
+    /// Returns None if the value does not fit in an i64.
+    pub fn as_i64(&self) -> Option<i64> {
+        let sign = if self.sign { "" } else { "-" };
+        format!("{}{}", sign, self.digits).parse().ok()
+    }
+
+    pub fn from_i64(num: i64) -> Self {
+        let digits = num.to_string();
+        Integer {
+            sign: num >= 0,
+            digits: digits.trim_start_matches('-').to_string(),
+        }
+    }
+
+    /// The same value without leading zeros, and zero without a sign, so
+    /// that equal values compare equal.
+    pub fn normalized(&self) -> Self {
+        let digits = self.digits.trim_start_matches('0');
+        if digits.is_empty() {
+            Integer::from_i64(0)
+        } else {
+            Integer {
+                sign: self.sign,
+                digits: digits.to_string(),
+            }
+        }
+    }
+}
+
+/// Stored as the bits of the value, so that literals can be hashed and
+/// compared.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Float(u64);
+impl Float {
+    pub fn from_f64(num: f64) -> Self {
+        Float(num.to_bits())
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        f64::from_bits(self.0)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AtomicLiteral {
     Integer(Integer),
-    Float,
+    Float(Float),
     Atom(Atom),
     Nil,
     Char(char),
     String(String),
 }
+impl Display for Integer {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), ::std::fmt::Error> {
+        if self.sign {
+            write!(f, "{}", self.digits)
+        } else {
+            write!(f, "-{}", self.digits)
+        }
+    }
+}
+
+/// Reserved words of Erlang and Core Erlang, atoms spelled like them are
+/// quoted.
+const KEYWORDS: &[&str] = &[
+    "after", "and", "andalso", "apply", "attributes", "band", "begin",
+    "bnot", "bor", "bsl", "bsr", "bxor", "call", "case", "catch", "cond",
+    "div", "do", "end", "fun", "if", "in", "let", "letrec", "module", "not",
+    "of", "or", "orelse", "primop", "receive", "rem", "try", "when", "xor",
+];
+
+/// Writes an atom in Erlang term syntax, quoting it when required.
+pub fn write_atom(f: &mut Formatter, atom: &str) -> Result<(), ::std::fmt::Error> {
+    let bare = atom.chars().next().map(|c| c.is_ascii_lowercase()).unwrap_or(false)
+        && atom.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '@')
+        && !KEYWORDS.contains(&atom);
+    if bare {
+        write!(f, "{}", atom)
+    } else {
+        write!(f, "'{}'", atom.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+}
+
+/// Writes a float so that it reads back as the same value, always with a
+/// fraction as Erlang requires.
+pub fn write_float(f: &mut Formatter, float: f64) -> Result<(), ::std::fmt::Error> {
+    let repr = format!("{:?}", float);
+    let (mantissa, exponent) = match repr.find('e') {
+        Some(idx) => repr.split_at(idx),
+        None => (&repr[..], ""),
+    };
+    if mantissa.contains('.') {
+        write!(f, "{}{}", mantissa, exponent)
+    } else {
+        write!(f, "{}.0{}", mantissa, exponent)
+    }
+}
+
 impl Display for AtomicLiteral {
     fn fmt(&self, f: &mut Formatter) -> Result<(), ::std::fmt::Error> {
         match self {
-            &AtomicLiteral::Integer(ref int) => write!(f, "{}{}", int.sign, int.digits),
-            _ => write!(f, "unimpl"),
+            AtomicLiteral::Integer(int) => write!(f, "{}", int),
+            &AtomicLiteral::Float(float) => write_float(f, float.as_f64()),
+            AtomicLiteral::Atom(atom) => write_atom(f, atom),
+            &AtomicLiteral::Nil => write!(f, "[]"),
+            &AtomicLiteral::Char(c) => write!(f, "${}", c),
+            AtomicLiteral::String(string) => write!(f, "{:?}", string),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Constant {
     Atomic(AtomicLiteral),
     Tuple(Vec<Constant>),
     List(Vec<Constant>, Box<Constant>),
+    Map(Vec<(Constant, Constant)>),
 }
 impl Display for Constant {
     fn fmt(&self, f: &mut Formatter) -> Result<(), ::std::fmt::Error> {
         match self {
             &Constant::Atomic(ref lit) => write!(f, "{}", lit),
-            _ => write!(f, "unimpl"),
+            Constant::Tuple(elems) => {
+                write!(f, "{{")?;
+                for (idx, elem) in elems.iter().enumerate() {
+                    if idx != 0 { write!(f, ",")?; }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, "}}")
+            },
+            Constant::List(head, tail) => {
+                write!(f, "[")?;
+                for (idx, elem) in head.iter().enumerate() {
+                    if idx != 0 { write!(f, ",")?; }
+                    write!(f, "{}", elem)?;
+                }
+                match **tail {
+                    Constant::Atomic(AtomicLiteral::Nil) => (),
+                    ref tail => write!(f, "|{}", tail)?,
+                }
+                write!(f, "]")
+            },
+            Constant::Map(entries) => {
+                write!(f, "#{{")?;
+                for (idx, (key, value)) in entries.iter().enumerate() {
+                    if idx != 0 { write!(f, ",")?; }
+                    write!(f, "{} => {}", key, value)?;
+                }
+                write!(f, "}}")
+            },
         }
     }
 }
@@ -74,6 +194,14 @@ pub struct FunctionDefinition {
     pub fun: Annotated<Function>,
 }
 
+/// `=>` in a map expression, or `:=` which requires the key to be in
+/// the map that is updated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapPairType {
+    Assoc,
+    Exact,
+}
+
 #[derive(Debug, Clone)]
 pub enum SingleExpression {
     // Env reading
@@ -104,7 +232,7 @@ pub enum SingleExpression {
     AtomicLiteral(AtomicLiteral),
     Tuple(Vec<Expression>),
     List { head: Vec<Expression>, tail: Box<Expression> },
-    Map(Vec<(Expression, Expression)>, Option<Expression>),
+    Map(Vec<(Expression, MapPairType, Expression)>, Option<Expression>),
     Binary(Vec<(Expression, Vec<Expression>)>),
 }
 
@@ -154,3 +282,26 @@ pub use self::core_parser::annotatedModule as annotated_module;
 mod core_parser {
     include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ AtomicLiteral, Float };
+    use ::std::str::FromStr;
+
+    #[test]
+    fn display_literals() {
+        let atom = |a: &str| format!("{}", AtomicLiteral::Atom(FromStr::from_str(a).unwrap()));
+        assert!(atom("ok") == "ok");
+        assert!(atom("Ok") == "'Ok'");
+        assert!(atom("fun") == "'fun'");
+        assert!(atom("receive") == "'receive'");
+        assert!(atom("funs") == "funs");
+
+        for &float in &[0.1, -2.5, 3.0, 1e20, -1.5e-7, 0.0] {
+            let printed = format!("{}", AtomicLiteral::Float(Float::from_f64(float)));
+            assert!(printed.contains('.'), "{}", printed);
+            assert!(printed.parse::<f64>().unwrap() == float, "{}", printed);
+        }
+    }
+
+}
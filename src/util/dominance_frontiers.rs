@@ -1,35 +1,54 @@
-use ::petgraph::{ Graph, Direction };
-use ::petgraph::graph::NodeIndex;
+use ::petgraph::Direction;
+use ::petgraph::visit::{ IntoNeighborsDirected, IntoNodeIdentifiers, Visitable };
 use ::petgraph::algo::dominators::simple_fast;
 
 use std::collections::{ HashMap, HashSet };
+use std::hash::Hash;
 
-pub fn dominance_frontiers<NodeTyp, EdgeTyp>(
-    graph: &Graph<NodeTyp, EdgeTyp>, root: NodeIndex<u32>)
-    -> HashMap<NodeIndex<u32>, Vec<NodeIndex<u32>>>
+/// Computes the dominance frontier of every node in the graph, using the
+/// algorithm from "A Simple, Fast Dominance Algorithm" by Cooper et al.
+///
+/// Nodes that are not reachable from `root` have an empty frontier, and
+/// are never part of the frontier of another node.
+pub fn dominance_frontiers<G>(graph: G, root: G::NodeId)
+                              -> HashMap<G::NodeId, Vec<G::NodeId>>
+    where G: IntoNeighborsDirected + IntoNodeIdentifiers + Visitable,
+          G::NodeId: Eq + Hash + Ord
 {
     let dominators = simple_fast(graph, root);
 
     let mut frontiers = HashMap::new();
-    for node_idx in graph.node_indices() {
+    for node_idx in graph.node_identifiers() {
         frontiers.insert(node_idx, vec![]);
     }
 
-    for u in graph.node_indices() {
-        let incoming: Vec<NodeIndex<u32>> =
-            graph.neighbors_directed(u, Direction::Incoming).collect();
-        let loops_self = incoming.contains(&u);
+    for u in graph.node_identifiers() {
+        if dominators.dominators(u).is_none() {
+            continue;
+        }
+
+        let mut incoming: Vec<G::NodeId> = graph
+            .neighbors_directed(u, Direction::Incoming)
+            .filter(|v| dominators.dominators(*v).is_some())
+            .collect();
+        incoming.sort();
+        incoming.dedup();
 
-        if incoming.len() - if loops_self { 1 } else { 0 } >= 2 {
+        if incoming.len() >= 2 {
+            // The root has no immediate dominator, the runner walks all
+            // the way up the dominator tree for it.
+            let idom = dominators.immediate_dominator(u);
             let mut p = HashSet::new();
             for v_r in incoming.iter() {
-                let mut v = *v_r;
-                while v != dominators.immediate_dominator(u).unwrap() && !p.contains(&v) {
-                    p.insert(v);
-                    v = dominators.immediate_dominator(v).unwrap();
+                let mut v = Some(*v_r);
+                while let Some(runner) = v {
+                    if Some(runner) == idom || p.contains(&runner) {
+                        break;
+                    }
+                    p.insert(runner);
+                    v = dominators.immediate_dominator(runner);
                 }
             }
-            p.remove(&u);
             for v in p.iter() {
                 frontiers.get_mut(v).unwrap().push(u);
             }
@@ -87,4 +106,39 @@ mod test {
         assert!(df == expected_df);
     }
 
+    #[test]
+    fn loops_and_unreachable() {
+        let mut graph: Graph<(), ()> = Graph::new();
+
+        //   r
+        //   |
+        //   a <-
+        //   |  |
+        //   b --   u (unreachable)
+        //   |     /
+        //   c <---
+
+        let r = graph.add_node(());
+        let a = graph.add_node(());
+        let b = graph.add_node(());
+        let c = graph.add_node(());
+        let u = graph.add_node(());
+
+        graph.extend_with_edges([
+            (r, a),
+            (a, b),
+            (b, a),
+            (b, c),
+            (u, c),
+        ]);
+
+        let df = dominance_frontiers(&graph, r);
+
+        assert!(df[&r] == vec![]);
+        assert!(df[&a] == vec![a]);
+        assert!(df[&b] == vec![a]);
+        assert!(df[&c] == vec![]);
+        assert!(df[&u] == vec![]);
+    }
+
 }